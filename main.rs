@@ -1,34 +1,283 @@
 
 use clap::Parser;
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    cursor,
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers, MouseButton,
+        MouseEvent, MouseEventKind,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Style},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
     Frame, Terminal,
 };
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::{
     cmp,
-    collections::VecDeque,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fs::{self, File},
-    io::{self, BufRead, BufReader, BufWriter, Write},
-    path::PathBuf,
-    time::Instant,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
 };
+use encoding_rs::{Encoding, UTF_8};
+use memmap2::Mmap;
+use regex::{Regex, RegexBuilder};
 use syntect::{
-    highlighting::{ThemeSet, Theme},
-    parsing::SyntaxSet,
+    easy::HighlightLines,
+    highlighting::{FontStyle, HighlightState, ThemeSet, Theme},
+    parsing::{ParseState, SyntaxSet},
 };
 
 const MAX_LINE_LENGTH: usize = 10000;
+// Files whose first line is longer than MAX_LINE_LENGTH are shown in pieces of this size
+const LONG_LINE_SEGMENT: usize = 4096;
 const MAX_VISIBLE_LINES: usize = 1000;
+// A `LineIndex` remembers where every this-many-th line starts
+const LINE_INDEX_STRIDE: usize = 1024;
 const CHUNK_SIZE: usize = 1000;
+const MAX_REMEMBERED_FILES: usize = 100;
+const MAX_INPUT_LENGTH: usize = 4096;
+// Entries kept in each of the `:` and `/` histories
+const MAX_HISTORY: usize = 100;
+const MAX_UNDO_GROUPS: usize = 50;
+// Lines moved by one notch of the mouse wheel
+const MOUSE_SCROLL_LINES: usize = 3;
+// Largest count accepted in front of a normal mode command, so a stray `99999999` can't
+// hang the editor or fill memory
+const MAX_COUNT: usize = 10_000;
+// How deeply `@<reg>` may nest, so a macro that calls itself stops instead of overflowing
+const MAX_MACRO_DEPTH: usize = 100;
+// `searchcount` stops counting past this many matches and shows ">999"
+const MAX_SEARCH_COUNT: usize = 999;
+// Match positions kept for `n`/`N` by a whole-file search; the total is counted regardless
+const MAX_FILE_MATCHES: usize = 100_000;
+// Matches taken from one line, so a pattern matching everywhere in a huge line stays cheap
+const MAX_LINE_MATCHES: usize = 1000;
+const UNDO_COALESCE_WINDOW: Duration = Duration::from_millis(1000);
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+// How long a command runs before the busy indicator appears, so quick ones never flash it
+const BUSY_DELAY: Duration = Duration::from_millis(200);
+// Columns kept visible on either side of the cursor when long lines scroll sideways
+const HSCROLL_MARGIN: usize = 5;
+
+// Tabs are drawn up to the next multiple of this column
+const TAB_STOP: usize = 8;
+
+// Terminal cell width of a character: combining marks take none, East Asian wide and
+// emoji take two. Tabs depend on their column and are handled by `display_column`.
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+// Screen column at which the character starting at byte `byte_x` is drawn, with tab
+// stops every `tabstop` columns
+fn display_column(line: &str, byte_x: usize, tabstop: usize) -> usize {
+    let tabstop = tabstop.max(1);
+    let mut column = 0;
+    for (i, c) in line.char_indices() {
+        if i >= byte_x {
+            break;
+        }
+        column = if c == '\t' { (column / tabstop + 1) * tabstop } else { column + char_width(c) };
+    }
+    column
+}
+
+// Byte offset of the character drawn at screen column `column`, or the end of the line
+fn byte_at_column(line: &str, column: usize, tabstop: usize) -> usize {
+    let tabstop = tabstop.max(1);
+    let mut end = 0;
+    for (i, c) in line.char_indices() {
+        end = if c == '\t' { (end / tabstop + 1) * tabstop } else { end + char_width(c) };
+        if end > column {
+            return i;
+        }
+    }
+    line.len()
+}
+
+// Largest character boundary at or before byte `x`
+fn floor_char_boundary(line: &str, x: usize) -> usize {
+    let mut x = x.min(line.len());
+    while !line.is_char_boundary(x) {
+        x -= 1;
+    }
+    x
+}
+
+// Byte offset of the character at index `x`, or the end of the line past the last one.
+// Cursor columns count characters; strings are sliced by byte.
+fn char_to_byte(line: &str, x: usize) -> usize {
+    line.char_indices().nth(x).map_or(line.len(), |(i, _)| i)
+}
+
+// Index of the character at or around byte `x`
+fn byte_to_char(line: &str, x: usize) -> usize {
+    line[..floor_char_boundary(line, x)].chars().count()
+}
+
+// `line` cut to MAX_LINE_LENGTH bytes without splitting a character
+fn clip_line(line: &str) -> &str {
+    &line[..floor_char_boundary(line, MAX_LINE_LENGTH)]
+}
+
+// Dead keys that some layouts deliver as a standalone spacing accent, mapped to the
+// combining mark they stand for
+fn dead_key_mark(c: char) -> Option<char> {
+    match c {
+        '\u{B4}' => Some('\u{301}'),
+        '\u{A8}' => Some('\u{308}'),
+        '\u{2C6}' => Some('\u{302}'),
+        '\u{2DC}' => Some('\u{303}'),
+        '\u{B8}' => Some('\u{327}'),
+        _ => None,
+    }
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c as u32, 0x0300..=0x036F | 0x20D0..=0x20FF | 0xFE20..=0xFE2F)
+}
+
+// Precomposed form of `base` followed by combining `mark`, for the common Latin letters
+fn compose(base: char, mark: char) -> Option<char> {
+    let (bases, composed) = match mark {
+        '\u{300}' => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+        '\u{301}' => ("aeiouyAEIOUYcnsz", "áéíóúýÁÉÍÓÚÝćńśź"),
+        '\u{302}' => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+        '\u{303}' => ("anoANO", "ãñõÃÑÕ"),
+        '\u{308}' => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+        '\u{327}' => ("cCsS", "çÇşŞ"),
+        _ => return None,
+    };
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+// Parses a number as written in text, allowing a sign and `,` thousands separators
+fn parse_number(text: &str) -> Option<f64> {
+    let plain: String = text.chars().filter(|&c| c != ',').collect();
+    if plain.is_empty() || !plain.trim_start_matches(['-', '+']).starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    plain.parse().ok()
+}
+
+// Options of `:numfmt`; each one left as `None` keeps that aspect of the number as is
+#[derive(Default)]
+struct NumberFormat {
+    separator: Option<char>,
+    decimals: Option<usize>,
+    width: Option<usize>,
+}
+
+impl NumberFormat {
+    // `sep[=c]`, `round=N` and `width=N`, separated by spaces
+    fn parse(args: &str) -> Result<Self, String> {
+        let mut format = NumberFormat::default();
+        for arg in args.split_whitespace() {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg, None),
+            };
+            let count = || value.and_then(|v| v.parse::<usize>().ok());
+            match name {
+                "sep" => {
+                    let mut chars = value.unwrap_or(",").chars();
+                    format.separator = match (chars.next(), chars.next()) {
+                        (Some(c), None) if !c.is_ascii_digit() && c != '.' => Some(c),
+                        _ => return Err("sep expects a single non-digit character".to_string()),
+                    };
+                }
+                "round" => format.decimals = Some(count().ok_or("round expects a number of decimals")?),
+                "width" => format.width = Some(count().ok_or("width expects a number of columns")?),
+                _ => return Err(format!("Unknown numfmt option: {}", arg)),
+            }
+        }
+        if format.separator.is_none() && format.decimals.is_none() && format.width.is_none() {
+            return Err("Usage: numfmt [sep[=c]] [round=N] [width=N]".to_string());
+        }
+        Ok(format)
+    }
+
+    fn apply(&self, number: &str) -> Option<String> {
+        let value = parse_number(number)?;
+        let plain = match self.decimals {
+            Some(decimals) => format!("{:.*}", decimals, value),
+            None => number.chars().filter(|&c| c != ',').collect(),
+        };
+        let (sign, unsigned) = match plain.strip_prefix(['-', '+']) {
+            Some(rest) => (&plain[..1], rest),
+            None => ("", plain.as_str()),
+        };
+        let (int_part, frac_part) = match unsigned.find('.') {
+            Some(dot) => unsigned.split_at(dot),
+            None => (unsigned, ""),
+        };
+
+        let mut grouped = String::with_capacity(int_part.len() * 2);
+        for (i, c) in int_part.chars().enumerate() {
+            if let Some(separator) = self.separator {
+                if i > 0 && (int_part.len() - i) % 3 == 0 {
+                    grouped.push(separator);
+                }
+            }
+            grouped.push(c);
+        }
+        let result = format!("{}{}{}", sign, grouped, frac_part);
+        Some(match self.width {
+            Some(width) => format!("{:>width$}", result, width = width),
+            None => result,
+        })
+    }
+}
+
+// Parses `#rrggbb` or a basic color name as accepted by `:set` color options
+fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    let color = match value.to_ascii_lowercase().as_str() {
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "white" => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
 
 #[derive(Parser)]
 #[command(name = "tuxpad")]
@@ -36,6 +285,718 @@ const CHUNK_SIZE: usize = 1000;
 struct Args {
     #[arg(help = "File to open")]
     file: Option<PathBuf>,
+    #[arg(long = "no-restore", help = "Don't jump to the last edit position when opening a file")]
+    no_restore: bool,
+    #[arg(long = "no-syntax", help = "Skip loading syntax definitions for a faster start")]
+    no_syntax: bool,
+    #[arg(long = "mmap", help = "View the file read-only through a memory map (instant open for huge files)")]
+    mmap: bool,
+    #[arg(long = "clipboard", help = "Share copies and pastes with the system clipboard (:set clipboard)")]
+    clipboard: bool,
+    #[arg(short = 'R', long = "readonly", help = "View the file without allowing edits or saves (:set noreadonly)")]
+    readonly: bool,
+}
+
+// Pipes `input` through `sh -c command` and returns its stdout, or its stderr on failure
+fn run_filter(command: &str, input: &str) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run '{}': {}", command, e))?;
+
+    // Feed stdin from another thread so a chatty command can't deadlock on a full pipe
+    let mut stdin = child.stdin.take().ok_or("Failed to open stdin")?;
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || {
+        let _ = stdin.write_all(input.as_bytes());
+    });
+
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let _ = writer.join();
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(stderr.lines().next().unwrap_or("command failed").to_string())
+    }
+}
+
+// The desktop clipboard, reached through whichever of wl-copy, xclip, xsel or pbcopy
+// is installed. Without a display (SSH, a bare console) none of them work and callers
+// keep to the editor's own clipboard.
+struct SystemClipboard;
+
+impl SystemClipboard {
+    // (copy, paste) command pairs, most specific first
+    fn candidates() -> Vec<(&'static str, &'static str)> {
+        let mut candidates = Vec::new();
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            candidates.push(("wl-copy", "wl-paste --no-newline"));
+        }
+        if std::env::var_os("DISPLAY").is_some() {
+            candidates.push(("xclip -selection clipboard", "xclip -selection clipboard -o"));
+            candidates.push(("xsel --clipboard --input", "xsel --clipboard --output"));
+        }
+        if cfg!(target_os = "macos") {
+            candidates.push(("pbcopy", "pbpaste"));
+        }
+        candidates
+            .into_iter()
+            .filter(|(copy, _)| Self::installed(copy.split(' ').next().unwrap_or(copy)))
+            .collect()
+    }
+
+    fn installed(program: &str) -> bool {
+        std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+    }
+
+    // True once one of the tools took the text. Their stdout is not captured: wl-copy
+    // and xclip leave a child behind to serve the selection, which would hold the pipe.
+    fn copy(text: &str) -> bool {
+        Self::candidates().into_iter().any(|(copy, _)| {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(copy)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(_) => return false,
+            };
+            let written = child.stdin.take().is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+            child.wait().is_ok_and(|status| status.success()) && written
+        })
+    }
+
+    fn paste() -> Option<String> {
+        Self::candidates()
+            .into_iter()
+            .find_map(|(_, paste)| run_filter(paste, "").ok())
+    }
+}
+
+// For each line of `a`, the line of `b` it lines up with in a longest common
+// subsequence of the two. Lines shared at both ends are matched before the quadratic
+// part, which then only covers the region that changed.
+fn match_lines(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; a.len()];
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+    for (i, slot) in matched.iter_mut().enumerate().take(prefix) {
+        *slot = Some(i);
+    }
+    for (i, slot) in matched.iter_mut().rev().enumerate().take(suffix) {
+        *slot = Some(b.len() - 1 - i);
+    }
+
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    // lengths[i][j]: longest common subsequence of a_mid[i..] and b_mid[j..]
+    let width = b_mid.len() + 1;
+    let mut lengths = vec![0u32; (a_mid.len() + 1) * width];
+    for i in (0..a_mid.len()).rev() {
+        for j in (0..b_mid.len()).rev() {
+            lengths[i * width + j] = if a_mid[i] == b_mid[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < a_mid.len() && j < b_mid.len() {
+        if a_mid[i] == b_mid[j] {
+            matched[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matched
+}
+
+// Line-based three-way merge of two edited versions of `base`. Where only one side
+// changed a region its version wins; where both changed it differently the region is
+// left between `<<<<<<<` / `>>>>>>>` markers, with the base in the middle, and the
+// merged text comes back as the error.
+fn merge3(base: &str, ours: &str, theirs: &str) -> Result<String, String> {
+    let base: Vec<&str> = base.lines().collect();
+    let ours: Vec<&str> = ours.lines().collect();
+    let theirs: Vec<&str> = theirs.lines().collect();
+    let in_ours = match_lines(&base, &ours);
+    let in_theirs = match_lines(&base, &theirs);
+
+    let mut merged = String::new();
+    let mut put = |lines: &[&str]| {
+        for line in lines {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    };
+    let mut clean = true;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next base line both sides kept, or the ends of all three
+        let next = (i..base.len()).find_map(|at| match (in_ours[at], in_theirs[at]) {
+            (Some(o), Some(t)) => Some((at, o, t)),
+            _ => None,
+        });
+        let (at, o, t) = next.unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (was, mine, other) = (&base[i..at], &ours[j..o], &theirs[k..t]);
+        if mine == was || mine == other {
+            put(other);
+        } else if other == was {
+            put(mine);
+        } else {
+            clean = false;
+            put(&["<<<<<<< ours"]);
+            put(mine);
+            put(&["||||||| original"]);
+            put(was);
+            put(&["======="]);
+            put(other);
+            put(&[">>>>>>> theirs"]);
+        }
+        if at == base.len() {
+            break;
+        }
+        put(&[base[at]]);
+        (i, j, k) = (at + 1, o + 1, t + 1);
+    }
+    if clean {
+        Ok(merged)
+    } else {
+        Err(merged)
+    }
+}
+
+// Whether two paths name the same existing file
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// One quickfix entry: file, 0-based line and byte column, and the line's text
+type QuickfixEntry = (PathBuf, usize, usize, String);
+
+// Whole-word occurrences of `word` in the text files under `root`, sorted by file and
+// line. Hidden directories and build output are skipped, as are binary files.
+fn find_references(root: &Path, word: &str, words: &KeywordSet) -> Vec<QuickfixEntry> {
+    const SKIPPED_DIRS: &[&str] = &["target", "node_modules"];
+    let pattern = match Regex::new(&regex::escape(word)) {
+        Ok(pattern) => pattern,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            if file_type.is_dir() {
+                if !SKIPPED_DIRS.contains(&name.as_str()) {
+                    pending.push(path);
+                }
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let bytes = match fs::read(&path) {
+                Ok(bytes) if !bytes[..bytes.len().min(8192)].contains(&0) => bytes,
+                _ => continue,
+            };
+            let text = String::from_utf8_lossy(&bytes);
+            let display = path.strip_prefix(root).map(Path::to_path_buf).unwrap_or_else(|_| path.clone());
+            for (y, line) in text.lines().enumerate() {
+                for x in Editor::matches_in_line(line, &pattern, Some(words)) {
+                    found.push((display.clone(), y, x, line.trim().to_string()));
+                }
+            }
+        }
+    }
+    found.sort();
+    found.dedup();
+    found
+}
+
+fn default_formatters() -> HashMap<String, String> {
+    [
+        ("rs", "rustfmt --edition 2021"),
+        ("py", "black -q -"),
+        ("js", "prettier --parser babel"),
+        ("ts", "prettier --parser typescript"),
+        ("go", "gofmt"),
+    ]
+    .iter()
+    .map(|(ext, cmd)| (ext.to_string(), cmd.to_string()))
+    .collect()
+}
+
+// Line comment leader for a file extension, None for filetypes without one
+fn comment_prefix(extension: &str) -> Option<&'static str> {
+    match extension {
+        "rs" | "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "hxx" | "js" | "jsx" | "ts" | "tsx" | "go"
+        | "java" | "kt" | "swift" | "cs" | "scala" | "dart" | "zig" | "proto" => Some("//"),
+        "py" | "sh" | "bash" | "zsh" | "fish" | "rb" | "pl" | "toml" | "yaml" | "yml" | "conf"
+        | "ini" | "r" | "nix" | "cmake" | "mk" => Some("#"),
+        "lua" | "sql" | "hs" | "elm" | "ada" => Some("--"),
+        "lisp" | "el" | "clj" | "scm" | "asm" => Some(";"),
+        "tex" | "erl" | "m" => Some("%"),
+        "vim" => Some("\""),
+        _ => None,
+    }
+}
+
+// Per-user state directory: $XDG_CONFIG_HOME/tuxpad or ~/.config/tuxpad
+fn config_dir() -> Option<PathBuf> {
+    user_dir("XDG_CONFIG_HOME", ".config")
+}
+
+// Per-user cache directory: $XDG_CACHE_HOME/tuxpad or ~/.cache/tuxpad
+fn cache_dir() -> Option<PathBuf> {
+    user_dir("XDG_CACHE_HOME", ".cache")
+}
+
+// `$var/tuxpad`, or `~/fallback/tuxpad` without the variable. Tests get a directory of
+// their own process under the temp directory, so they never touch the user's files.
+fn user_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    if cfg!(test) {
+        let home = std::env::temp_dir().join(format!("tuxpad-{}-home", std::process::id()));
+        return Some(home.join(fallback).join("tuxpad"));
+    }
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))
+        .map(|dir| dir.join("tuxpad"))
+}
+
+// Session store of last cursor positions, most recent first, one `line\tcol\tpath` per line
+struct PositionStore;
+
+impl PositionStore {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("positions"))
+    }
+
+    fn load() -> Vec<(PathBuf, usize, usize)> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return Vec::new(),
+        };
+
+        content
+            .lines()
+            .filter_map(|entry| {
+                let mut fields = entry.splitn(3, '\t');
+                let y = fields.next()?.parse().ok()?;
+                let x = fields.next()?.parse().ok()?;
+                let file = PathBuf::from(fields.next()?);
+                Some((file, y, x))
+            })
+            .collect()
+    }
+
+    fn lookup(file: &PathBuf) -> Option<(usize, usize)> {
+        let key = fs::canonicalize(file).ok()?;
+        Self::load()
+            .into_iter()
+            .find(|(path, _, _)| *path == key)
+            .map(|(_, y, x)| (y, x))
+    }
+
+    fn remember(file: &PathBuf, y: usize, x: usize) -> io::Result<()> {
+        let key = fs::canonicalize(file)?;
+        let store = match Self::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut entries = Self::load();
+        entries.retain(|(path, _, _)| *path != key);
+        entries.insert(0, (key, y, x));
+        entries.truncate(MAX_REMEMBERED_FILES);
+
+        if let Some(parent) = store.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(store)?);
+        for (path, y, x) in entries {
+            writeln!(writer, "{}\t{}\t{}", y, x, path.display())?;
+        }
+        writer.flush()
+    }
+}
+
+// Lines entered at the `:` or `/` prompt, oldest first, recalled with Up and Down
+#[derive(Default)]
+struct History {
+    entries: VecDeque<String>,
+    // The entry shown while browsing, and the line typed before browsing started
+    browsing: Option<(usize, String)>,
+}
+
+impl History {
+    // Records an entered line, skipping empty ones and repeats of the previous entry
+    fn push(&mut self, entry: &str) {
+        self.browsing = None;
+        if entry.is_empty() || self.entries.back().is_some_and(|last| last == entry) {
+            return;
+        }
+        self.entries.push_back(entry.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.pop_front();
+        }
+    }
+
+    // Up: the entry before the one shown, starting from the newest
+    fn older(&mut self, typed: &str) -> Option<String> {
+        let index = match self.browsing {
+            Some((index, _)) => index.checked_sub(1)?,
+            None => self.entries.len().checked_sub(1)?,
+        };
+        let draft = self.browsing.take().map_or_else(|| typed.to_string(), |(_, draft)| draft);
+        self.browsing = Some((index, draft));
+        self.entries.get(index).cloned()
+    }
+
+    // Down: the entry after the one shown, then back to what was being typed
+    fn newer(&mut self) -> Option<String> {
+        let (index, draft) = self.browsing.take()?;
+        if index + 1 < self.entries.len() {
+            self.browsing = Some((index + 1, draft));
+            self.entries.get(index + 1).cloned()
+        } else {
+            Some(draft)
+        }
+    }
+}
+
+// Command and search history kept across sessions, one `:` or `/` entry per line
+struct HistoryStore;
+
+impl HistoryStore {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("history"))
+    }
+
+    fn load(commands: &mut History, searches: &mut History) {
+        let content = match Self::path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(content) => content,
+            None => return,
+        };
+        for line in content.lines() {
+            if let Some(entry) = line.strip_prefix(':') {
+                commands.push(entry);
+            } else if let Some(entry) = line.strip_prefix('/') {
+                searches.push(entry);
+            }
+        }
+    }
+
+    fn save(commands: &History, searches: &History) -> io::Result<()> {
+        let store = match Self::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(parent) = store.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(store)?);
+        for entry in &commands.entries {
+            writeln!(writer, ":{}", entry)?;
+        }
+        for entry in &searches.entries {
+            writeln!(writer, "/{}", entry)?;
+        }
+        writer.flush()
+    }
+}
+
+// Settings from the `.editorconfig` files above a file: every matching section from the
+// top-most file (or the nearest one marked `root = true`) down to the nearest, later
+// sections overriding earlier ones. Keys and values are lowercased as the format is
+// case-insensitive, and `unset` drops a value set further up.
+#[derive(Default)]
+struct EditorConfig {
+    properties: HashMap<String, String>,
+}
+
+// A section of an `.editorconfig` as `(glob, key/value pairs)`; the preamble before the
+// first section has no glob
+type EditorConfigSection = (Option<String>, Vec<(String, String)>);
+
+impl EditorConfig {
+    fn for_file(path: &Path) -> Self {
+        let mut config = Self::default();
+        let absolute = match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => fs::canonicalize(dir).ok().map(|dir| dir.join(path.file_name().unwrap_or_default())),
+            None => std::env::current_dir().ok().map(|dir| dir.join(path)),
+        };
+        let absolute = match absolute {
+            Some(absolute) => absolute,
+            None => return config,
+        };
+
+        let mut files = Vec::new();
+        for dir in absolute.ancestors().skip(1) {
+            if let Ok(content) = fs::read_to_string(dir.join(".editorconfig")) {
+                let root = Self::sections(&content)
+                    .first()
+                    .filter(|(glob, _)| glob.is_none())
+                    .is_some_and(|(_, pairs)| pairs.iter().any(|(k, v)| k == "root" && v == "true"));
+                files.push((dir.to_path_buf(), content));
+                if root {
+                    break;
+                }
+            }
+        }
+
+        for (dir, content) in files.iter().rev() {
+            let relative = match absolute.strip_prefix(dir) {
+                Ok(relative) => relative.to_string_lossy().into_owned(),
+                Err(_) => continue,
+            };
+            for (glob, pairs) in Self::sections(content) {
+                let matches = glob.is_some_and(|glob| {
+                    Regex::new(&Self::glob_to_regex(&glob)).is_ok_and(|re| re.is_match(&relative))
+                });
+                if matches {
+                    for (key, value) in pairs {
+                        if value == "unset" {
+                            config.properties.remove(&key);
+                        } else {
+                            config.properties.insert(key, value);
+                        }
+                    }
+                }
+            }
+        }
+        config
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(|value| value.as_str())
+    }
+
+    // The sections of the file, starting with the preamble
+    fn sections(content: &str) -> Vec<EditorConfigSection> {
+        let mut sections = vec![(None, Vec::new())];
+        for line in content.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                sections.push((Some(line[1..line.len() - 1].to_string()), Vec::new()));
+            } else if let Some((key, value)) = line.split_once('=') {
+                if let Some((_, pairs)) = sections.last_mut() {
+                    pairs.push((key.trim().to_lowercase(), value.trim().to_lowercase()));
+                }
+            }
+        }
+        sections
+    }
+
+    // Section globs: `*` stays within a directory, `**` crosses them, `?`, `[...]`,
+    // `[!...]` and `{a,b}` work as in the shell. Globs without a `/` match the file name
+    // in any directory below the `.editorconfig`.
+    fn glob_to_regex(glob: &str) -> String {
+        let mut regex = String::from("^");
+        if !glob.contains('/') {
+            regex.push_str("(?:.*/)?");
+        }
+        let mut chars = glob.trim_start_matches('/').chars().peekable();
+        let mut braces = 0;
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    regex.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        regex.push('^');
+                    }
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                    regex.push(']');
+                }
+                '{' => {
+                    braces += 1;
+                    regex.push_str("(?:");
+                }
+                ',' if braces > 0 => regex.push('|'),
+                '}' if braces > 0 => {
+                    braces -= 1;
+                    regex.push(')');
+                }
+                '\\' => {
+                    if let Some(next) = chars.next() {
+                        regex.push_str(&regex::escape(&next.to_string()));
+                    }
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        regex
+    }
+}
+
+// Whitespace hygiene counts gathered line by line for `:whitespace`
+#[derive(Default)]
+struct WhitespaceReport {
+    lines: usize,
+    trailing: Vec<usize>,
+    mixed: Vec<usize>,
+    tab_indented: usize,
+    space_indents: Vec<usize>,
+}
+
+impl WhitespaceReport {
+    // How many offending line numbers of each kind the popup lists
+    const SHOWN: usize = 5;
+
+    fn scan_line(&mut self, y: usize, line: &str) {
+        self.lines += 1;
+        if line.ends_with([' ', '\t']) {
+            self.trailing.push(y);
+        }
+        let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+        if indent.is_empty() || indent.len() == line.len() {
+            return;
+        }
+        match (indent.contains('\t'), indent.contains(' ')) {
+            (true, true) => self.mixed.push(y),
+            (true, false) => self.tab_indented += 1,
+            _ => self.space_indents.push(indent.len()),
+        }
+    }
+
+    // "tabs", "N spaces" or "none"; the space width is the largest of 8/4/2 that nearly
+    // every space indent is a multiple of
+    fn dominant_indent(&self) -> String {
+        if self.tab_indented == 0 && self.space_indents.is_empty() {
+            return "none".to_string();
+        }
+        if self.tab_indented >= self.space_indents.len() {
+            return "tabs".to_string();
+        }
+        let fits = |width: usize| self.space_indents.iter().filter(|&&n| n % width == 0).count();
+        let width = [8, 4, 2]
+            .into_iter()
+            .find(|&width| fits(width) * 10 >= self.space_indents.len() * 9)
+            .unwrap_or(1);
+        format!("{} space{}", width, if width == 1 { "" } else { "s" })
+    }
+
+    // Popup rows, each with the line it jumps to when it names an offender
+    fn rows(&self) -> Vec<(String, Option<usize>)> {
+        let mut rows = vec![
+            (format!("Lines scanned: {}", self.lines), None),
+            (format!("Dominant indent: {}", self.dominant_indent()), None),
+            (
+                format!(
+                    "Indented with tabs: {}, with spaces: {}",
+                    self.tab_indented,
+                    self.space_indents.len()
+                ),
+                None,
+            ),
+        ];
+        for (label, lines) in [("Trailing whitespace", &self.trailing), ("Mixed tabs/spaces indent", &self.mixed)] {
+            rows.push((format!("{}: {} line(s)", label, lines.len()), None));
+            for &y in lines.iter().take(Self::SHOWN) {
+                rows.push((format!("    line {}", y + 1), Some(y)));
+            }
+            if lines.len() > Self::SHOWN {
+                rows.push((format!("    … {} more", lines.len() - Self::SHOWN), None));
+            }
+        }
+        rows
+    }
+}
+
+// Marks a file as being edited by this process with a swap file in the cache directory
+// holding our pid, so a second tuxpad opening the same file can warn about it
+struct SwapFile;
+
+impl SwapFile {
+    // Named after the file's absolute path with `/` turned into `%`, like vim's, so
+    // nothing is left next to the file itself
+    fn path(file: &Path) -> Option<PathBuf> {
+        let absolute = fs::canonicalize(file).ok().or_else(|| {
+            let parent = file.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            Some(fs::canonicalize(parent).ok()?.join(file.file_name()?))
+        })?;
+        let name = absolute.to_str()?.replace('/', "%");
+        Some(cache_dir()?.join("swap").join(format!("{}.swp", name)))
+    }
+
+    // Takes the swap file over unless another live process holds it, in which case
+    // that process's pid is returned
+    fn claim(file: &Path) -> Option<u32> {
+        let swap = Self::path(file)?;
+        let ours = std::process::id();
+        if let Some(pid) = fs::read_to_string(&swap).ok().and_then(|s| s.trim().parse::<u32>().ok()) {
+            // A swap left behind by a crashed editor is stale and can be reclaimed
+            if pid != ours && PathBuf::from(format!("/proc/{}", pid)).exists() {
+                return Some(pid);
+            }
+        }
+        // Editing still works without a swap file, e.g. with an unwritable cache directory
+        if let Some(dir) = swap.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(&swap, format!("{}\n", ours));
+        None
+    }
+
+    fn release(file: &Path) {
+        if let Some(swap) = Self::path(file) {
+            let ours = std::process::id().to_string();
+            if fs::read_to_string(&swap).is_ok_and(|s| s.trim() == ours) {
+                let _ = fs::remove_file(swap);
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -45,759 +1006,8143 @@ enum Mode {
     Command,
     Search,
     Replace,
+    // Stepping through the matches of a replace, asking about each
+    ConfirmReplace,
+    Visual,
+    VisualLine,
+    VisualBlock,
 }
 
-#[derive(Debug, Clone)]
-struct Cursor {
-    x: usize,
+// How a key binding is typed
+enum KeyTrigger {
+    // Any one of these presses. Shift is not compared, as it is part of the character.
+    Press(&'static [(KeyCode, KeyModifiers)]),
+    // Characters typed one after another, in normal mode after an optional count. A
+    // trailing `<char>` or `<reg>` stands for any one character.
+    Sequence(&'static str),
+    // Listed by `:map` but handled outside the mode handlers
+    Elsewhere,
+}
+
+// A row of `:map`. The mode handlers run the first row of their mode whose trigger
+// matches, so the table is both the key reference and the dispatch. `run` gets the key
+// that completed the binding and the count typed before a sequence, if any.
+struct KeyBinding {
+    mode: &'static str,
+    keys: &'static str,
+    action: &'static str,
+    trigger: KeyTrigger,
+    run: fn(&mut Editor, KeyEvent, Option<usize>) -> io::Result<()>,
+}
+
+const fn char_key(c: char) -> (KeyCode, KeyModifiers) {
+    (KeyCode::Char(c), KeyModifiers::NONE)
+}
+
+const fn ctrl_key(c: char) -> (KeyCode, KeyModifiers) {
+    (KeyCode::Char(c), KeyModifiers::CONTROL)
+}
+
+const fn alt_key(c: char) -> (KeyCode, KeyModifiers) {
+    (KeyCode::Char(c), KeyModifiers::ALT)
+}
+
+const fn bare_key(code: KeyCode) -> (KeyCode, KeyModifiers) {
+    (code, KeyModifiers::NONE)
+}
+
+const ARROW_KEYS: &[(KeyCode, KeyModifiers)] =
+    &[bare_key(KeyCode::Up), bare_key(KeyCode::Down), bare_key(KeyCode::Left), bare_key(KeyCode::Right)];
+
+// Terminals send Ctrl+/ as Ctrl+7 (0x1f); newer keyboard protocols as Ctrl+/
+const COMMENT_KEYS: &[(KeyCode, KeyModifiers)] = &[ctrl_key('/'), ctrl_key('7')];
+
+const KEY_BINDINGS: &[KeyBinding] = &[
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+Q", action: "Quit (twice if modified)",
+        trigger: KeyTrigger::Press(&[ctrl_key('q')]),
+        run: |editor, _, _| {
+            if editor.any_modified() && !editor.quit_requested {
+                editor.status_message = "File modified! Press Ctrl+Q again to quit without saving".to_string();
+                editor.quit_requested = true;
+            } else {
+                editor.exit_editor();
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+S", action: "Save",
+        trigger: KeyTrigger::Press(&[ctrl_key('s')]),
+        run: |editor, _, _| {
+            let result = editor.save_file(false);
+            editor.report_error("Error saving", result);
+            editor.quit_requested = false;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "i", action: "Enter insert mode",
+        trigger: KeyTrigger::Press(&[char_key('i')]),
+        run: |editor, _, _| {
+            editor.start_insert(false);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "a", action: "Insert after cursor",
+        trigger: KeyTrigger::Press(&[char_key('a')]),
+        run: |editor, _, _| {
+            if editor.start_insert(false) {
+                editor.move_cursor(1, 0)?;
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "o", action: "Open a line below and insert",
+        trigger: KeyTrigger::Press(&[char_key('o')]),
+        run: |editor, _, _| {
+            if editor.start_insert(false) {
+                if let Some(line) = editor.buffer.get_line(editor.cursor.y) {
+                    editor.cursor.x = line.chars().count();
+                }
+                let result = editor.insert_newline();
+                editor.report_error("Insert error", result);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "R / Insert", action: "Enter overwrite mode",
+        trigger: KeyTrigger::Press(&[char_key('R'), bare_key(KeyCode::Insert)]),
+        run: |editor, _, _| {
+            editor.start_insert(true);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "v", action: "Start charwise visual selection",
+        trigger: KeyTrigger::Press(&[char_key('v')]),
+        run: |editor, _, _| {
+            editor.start_visual(Mode::Visual);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "V", action: "Start linewise visual selection",
+        trigger: KeyTrigger::Press(&[char_key('V')]),
+        run: |editor, _, _| {
+            editor.start_visual(Mode::VisualLine);
+            Ok(())
+        },
+    },
+    // Ctrl+V pastes, so block selection lives on Alt+V
+    KeyBinding {
+        mode: "normal", keys: "Alt+v", action: "Start block visual selection",
+        trigger: KeyTrigger::Press(&[alt_key('v')]),
+        run: |editor, _, _| {
+            editor.start_visual(Mode::VisualBlock);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "gS<char>", action: "Split the line on <char>",
+        trigger: KeyTrigger::Sequence("gS<char>"),
+        run: |editor, key, _| {
+            if let KeyCode::Char(c) = key.code {
+                editor.split_line_on(c);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]]<space>", action: "Add blank lines below",
+        trigger: KeyTrigger::Sequence("] "),
+        run: |editor, _, count| {
+            editor.insert_blank_lines(count.unwrap_or(1), true);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count][<space>", action: "Add blank lines above",
+        trigger: KeyTrigger::Sequence("[ "),
+        run: |editor, _, count| {
+            editor.insert_blank_lines(count.unwrap_or(1), false);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]]d", action: "Next marker (annotation, search match)",
+        trigger: KeyTrigger::Sequence("]d"),
+        run: |editor, _, count| editor.jump_to_marker(true, count.unwrap_or(1)),
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count][d", action: "Previous marker",
+        trigger: KeyTrigger::Sequence("[d"),
+        run: |editor, _, count| editor.jump_to_marker(false, count.unwrap_or(1)),
+    },
+    KeyBinding {
+        mode: "normal", keys: "gqq", action: "Reflow the paragraph to textwidth",
+        trigger: KeyTrigger::Sequence("gqq"),
+        run: |editor, _, _| {
+            let (start, end) = editor.paragraph_bounds(editor.cursor.y);
+            editor.reflow_lines(start, end);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]dd", action: "Cut lines into the clipboard",
+        trigger: KeyTrigger::Sequence("dd"),
+        run: |editor, _, count| editor.cut_lines(count.unwrap_or(1)),
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]yy", action: "Copy lines into the clipboard",
+        trigger: KeyTrigger::Sequence("yy"),
+        run: |editor, _, count| {
+            editor.copy_lines(count.unwrap_or(1));
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Alt+T", action: "Swap the word under the cursor with the next",
+        trigger: KeyTrigger::Press(&[alt_key('t')]),
+        run: |editor, _, _| {
+            editor.transpose_words();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "*", action: "Search forward for the word under the cursor",
+        trigger: KeyTrigger::Press(&[char_key('*')]),
+        run: |editor, _, _| {
+            editor.search_word_under_cursor(true);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "#", action: "Search backward for the word under the cursor",
+        trigger: KeyTrigger::Press(&[char_key('#')]),
+        run: |editor, _, _| {
+            editor.search_word_under_cursor(false);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "n / N", action: "Next / previous match of the last search",
+        trigger: KeyTrigger::Press(&[char_key('n'), char_key('N')]),
+        run: |editor, key, _| {
+            if !editor.search_query.is_empty() {
+                editor.jump_to_match(key.code == KeyCode::Char('n'));
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "gr", action: "Find references to the word under the cursor in the project",
+        trigger: KeyTrigger::Sequence("gr"),
+        run: |editor, _, _| {
+            editor.start_references();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "u / Ctrl+Z", action: "Undo",
+        trigger: KeyTrigger::Press(&[char_key('u'), ctrl_key('z')]),
+        run: |editor, _, _| {
+            let result = editor.undo();
+            editor.report_error("Undo error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: ".", action: "Repeat the last change (3. repeats it three times)",
+        trigger: KeyTrigger::Press(&[char_key('.')]),
+        run: |editor, _, _| editor.repeat_last_change().map(|_| ()),
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+Y", action: "Redo",
+        trigger: KeyTrigger::Press(&[ctrl_key('y')]),
+        run: |editor, _, _| {
+            let result = editor.redo();
+            editor.report_error("Redo error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "F", action: "Follow the file as it grows (tail -f)",
+        trigger: KeyTrigger::Press(&[char_key('F')]),
+        run: |editor, _, _| {
+            let follow = !editor.following;
+            editor.set_following(follow)
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: ":", action: "Enter command mode",
+        trigger: KeyTrigger::Press(&[char_key(':')]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Command;
+            editor.command_buffer.clear();
+            editor.status_message = "Command mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "/", action: "Search the whole file",
+        trigger: KeyTrigger::Press(&[char_key('/')]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Search;
+            editor.search_query.clear();
+            editor.search_whole_word = false;
+            editor.search_origin = Some((editor.cursor.clone(), editor.offset_y, editor.highlight_search));
+            editor.status_message = "Search mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+R", action: "Replace in the loaded chunk",
+        trigger: KeyTrigger::Press(&[ctrl_key('r')]),
+        run: |editor, _, _| {
+            if !editor.refuse_read_only() {
+                editor.mode = Mode::Replace;
+                editor.replace_query.clear();
+                editor.replace_with.clear();
+                editor.status_message = "Replace mode".to_string();
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+C", action: "Copy the current line",
+        trigger: KeyTrigger::Press(&[ctrl_key('c')]),
+        run: |editor, _, _| {
+            editor.copy_lines(1);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+X", action: "Cut the current line",
+        trigger: KeyTrigger::Press(&[ctrl_key('x')]),
+        run: |editor, _, _| {
+            let result = editor.cut_lines(1);
+            editor.report_error("Cut error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+V", action: "Paste a line below",
+        trigger: KeyTrigger::Press(&[ctrl_key('v')]),
+        run: |editor, _, _| {
+            let result = editor.paste_line();
+            editor.report_error("Paste error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]gt", action: "Next buffer tab (3gt goes to the third)",
+        trigger: KeyTrigger::Sequence("gt"),
+        run: |editor, _, count| {
+            match count {
+                Some(count) => editor.switch_to_buffer(count - 1),
+                None => editor.cycle_buffer(true),
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "gT", action: "Previous buffer tab",
+        trigger: KeyTrigger::Sequence("gT"),
+        run: |editor, _, _| {
+            editor.cycle_buffer(false);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Alt+1..9", action: "Buffer tab 1 to 9",
+        trigger: KeyTrigger::Press(&[
+            alt_key('1'), alt_key('2'), alt_key('3'), alt_key('4'), alt_key('5'),
+            alt_key('6'), alt_key('7'), alt_key('8'), alt_key('9'),
+        ]),
+        run: |editor, key, _| {
+            if let KeyCode::Char(c) = key.code {
+                editor.switch_to_buffer(c as usize - '1' as usize);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+W <arrow>", action: "Move to the other pane of a split (Ctrl+W c / o closes one)",
+        trigger: KeyTrigger::Press(&[ctrl_key('w')]),
+        run: |editor, _, _| {
+            editor.pending_window = true;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "F1", action: "Toggle help",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::F(1))]),
+        run: |editor, _, _| {
+            editor.show_help = !editor.show_help;
+            editor.status_message = if editor.show_help { "Help shown" } else { "Help hidden" }.to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "F2", action: "Cycle line number modes",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::F(2))]),
+        run: |editor, _, _| {
+            // Cycle absolute -> relative -> hybrid -> off
+            let (number, relative, label) = match (editor.show_line_numbers, editor.relative_numbers) {
+                (true, false) => (false, true, "Relative line numbers"),
+                (false, true) => (true, true, "Hybrid line numbers"),
+                (true, true) => (false, false, "Line numbers hidden"),
+                (false, false) => (true, false, "Line numbers shown"),
+            };
+            editor.show_line_numbers = number;
+            editor.relative_numbers = relative;
+            editor.status_message = label.to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Alt+Up / Alt+Down", action: "Move the line up / down",
+        trigger: KeyTrigger::Press(&[(KeyCode::Up, KeyModifiers::ALT), (KeyCode::Down, KeyModifiers::ALT)]),
+        run: |editor, key, _| {
+            editor.move_lines(key.code == KeyCode::Up);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Arrows", action: "Move the cursor",
+        trigger: KeyTrigger::Press(ARROW_KEYS),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Home / End", action: "First non-blank, then start / end of line (:set nosmarthome)",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Home), bare_key(KeyCode::End)]),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "w / b / e", action: "Next word / previous word / end of word",
+        trigger: KeyTrigger::Press(&[char_key('w'), char_key('b'), char_key('e')]),
+        run: |editor, key, _| match key.code {
+            KeyCode::Char('w') => editor.word_forward(),
+            KeyCode::Char('b') => editor.word_backward(),
+            _ => editor.word_end(),
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "%", action: "Jump to the matching bracket",
+        trigger: KeyTrigger::Press(&[char_key('%')]),
+        run: |editor, _, _| {
+            editor.jump_to_matching_bracket();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]<motion>", action: "Repeat arrows, w / b / e, n / N or u (5↓, 3w)",
+        trigger: KeyTrigger::Elsewhere,
+        run: |_, _, _| Ok(()),
+    },
+    // Only reached without a count; after one, `0` is another digit
+    KeyBinding {
+        mode: "normal", keys: "0", action: "Start of line",
+        trigger: KeyTrigger::Press(&[char_key('0')]),
+        run: |editor, _, _| {
+            editor.cursor.x = 0;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]gg", action: "First line (Ngg goes to line N)",
+        trigger: KeyTrigger::Sequence("gg"),
+        run: |editor, _, count| editor.goto_line(count.unwrap_or(1) - 1),
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]G", action: "Last line (NG goes to line N)",
+        trigger: KeyTrigger::Sequence("G"),
+        run: |editor, _, count| match count {
+            Some(count) => editor.goto_line(count - 1),
+            None => editor.jump_to_end_of_file(),
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "q", action: "Stop recording a macro (q<reg> starts one)",
+        trigger: KeyTrigger::Press(&[char_key('q')]),
+        run: |editor, _, _| {
+            if editor.recording.is_some() {
+                editor.stop_recording();
+            } else {
+                editor.pending_keys.push('q');
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "q<reg>", action: "Start recording a macro into <reg>",
+        trigger: KeyTrigger::Sequence("q<reg>"),
+        run: |editor, key, _| {
+            if let KeyCode::Char(c) = key.code {
+                editor.start_recording(c);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]@<reg> / @@", action: "Play a macro / the last one (10@a plays it ten times)",
+        trigger: KeyTrigger::Sequence("@<reg>"),
+        run: |editor, key, count| match key.code {
+            KeyCode::Char(c) => editor.play_macro(c, count.unwrap_or(1)).map(|_| ()),
+            _ => Ok(()),
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "PageUp / PageDown", action: "Move 20 lines",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::PageUp), bare_key(KeyCode::PageDown)]),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Alt+D / :t.", action: "Duplicate the line below",
+        trigger: KeyTrigger::Press(&[alt_key('d')]),
+        run: |editor, _, _| {
+            let line = editor.cursor.y;
+            editor.duplicate_lines(line, line);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+/", action: "Comment / uncomment the line",
+        trigger: KeyTrigger::Press(COMMENT_KEYS),
+        run: |editor, _, _| {
+            let line = editor.cursor.y;
+            editor.toggle_comment(line, line);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "[count]J", action: "Join lines with a space",
+        trigger: KeyTrigger::Sequence("J"),
+        run: |editor, _, count| {
+            editor.join_count(count.unwrap_or(1));
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "normal", keys: "Ctrl+D / Ctrl+U", action: "Scroll half a page down / up",
+        trigger: KeyTrigger::Press(&[ctrl_key('d'), ctrl_key('u')]),
+        run: |editor, key, _| editor.scroll_half_page(key.code == KeyCode::Char('d')),
+    },
+    KeyBinding {
+        mode: "normal", keys: "Mouse", action: "Click to move, drag to select, wheel to scroll, click a tab to switch",
+        trigger: KeyTrigger::Elsewhere,
+        run: |_, _, _| Ok(()),
+    },
+    KeyBinding {
+        mode: "normal", keys: "Esc", action: "Stay in normal mode, cancelling a pending quit",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.status_message = "Normal mode".to_string();
+            editor.quit_requested = false;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Esc", action: "Leave visual mode",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.status_message = "Normal mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "v / V", action: "Switch charwise / linewise, or leave",
+        trigger: KeyTrigger::Press(&[char_key('v'), char_key('V')]),
+        run: |editor, key, _| {
+            editor.switch_visual(if key.code == KeyCode::Char('v') { Mode::Visual } else { Mode::VisualLine });
+            Ok(())
+        },
+    },
+    // Ctrl+V only pastes in normal mode, so here it can mean block selection like vim
+    KeyBinding {
+        mode: "visual", keys: "Alt+v / Ctrl+V", action: "Switch to block selection",
+        trigger: KeyTrigger::Press(&[alt_key('v'), ctrl_key('v')]),
+        run: |editor, _, _| {
+            editor.switch_visual(Mode::VisualBlock);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "block", keys: "I", action: "Insert before the block on every row",
+        trigger: KeyTrigger::Press(&[char_key('I')]),
+        run: |editor, _, _| {
+            editor.start_block_insert();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "block", keys: "d / x", action: "Cut the block into the clipboard",
+        trigger: KeyTrigger::Press(&[char_key('d'), char_key('x')]),
+        run: |editor, _, _| {
+            editor.delete_block();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "r<char>", action: "Replace every selected character",
+        trigger: KeyTrigger::Sequence("r<char>"),
+        run: |editor, key, _| {
+            if let KeyCode::Char(c) = key.code {
+                editor.replace_selection(c);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "gq", action: "Reflow the selected lines",
+        trigger: KeyTrigger::Sequence("gq"),
+        run: |editor, _, _| {
+            let ((start_y, _), (end_y, _)) = editor.selection_bounds();
+            editor.mode = Mode::Normal;
+            editor.reflow_lines(start_y, end_y);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "y", action: "Copy the selection into the clipboard",
+        trigger: KeyTrigger::Press(&[char_key('y')]),
+        run: |editor, _, _| {
+            editor.yank_selection();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "d / x", action: "Cut the selection into the clipboard",
+        trigger: KeyTrigger::Press(&[char_key('d'), char_key('x')]),
+        run: |editor, _, _| editor.delete_selection(),
+    },
+    KeyBinding {
+        mode: "visual", keys: "J", action: "Join the selected lines with a space",
+        trigger: KeyTrigger::Press(&[char_key('J')]),
+        run: |editor, _, _| {
+            let ((start, _), (end, _)) = editor.selection_bounds();
+            editor.mode = Mode::Normal;
+            editor.join_lines(start, end, " ");
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Alt+D", action: "Duplicate the selected lines below",
+        trigger: KeyTrigger::Press(&[alt_key('d')]),
+        run: |editor, _, _| {
+            let ((start, _), (end, _)) = editor.selection_bounds();
+            editor.mode = Mode::Normal;
+            editor.duplicate_lines(start, end);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Ctrl+/", action: "Comment / uncomment the selected lines",
+        trigger: KeyTrigger::Press(COMMENT_KEYS),
+        run: |editor, _, _| {
+            let ((start, _), (end, _)) = editor.selection_bounds();
+            editor.toggle_comment(start, end);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: ":", action: "Run a command on the selected lines (block columns for :s)",
+        trigger: KeyTrigger::Press(&[char_key(':')]),
+        run: |editor, _, _| {
+            // Like vim, start a command on the selected lines
+            let ((start_y, _), (end_y, _)) = editor.selection_bounds();
+            editor.visual_lines = Some((start_y, end_y));
+            editor.visual_columns = if editor.mode == Mode::VisualBlock { Some(editor.block_columns()) } else { None };
+            editor.mode = Mode::Command;
+            editor.command_buffer = "'<,'>".to_string();
+            editor.status_message = "Command mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Tab / Shift+Tab", action: "Indent / dedent the selected lines",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Tab), bare_key(KeyCode::BackTab)]),
+        run: |editor, key, _| {
+            let ((start, _), (end, _)) = editor.selection_bounds();
+            editor.shift_lines(start, end, key.code == KeyCode::Tab);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Alt+Up / Alt+Down", action: "Move the selected lines up / down",
+        trigger: KeyTrigger::Press(&[(KeyCode::Up, KeyModifiers::ALT), (KeyCode::Down, KeyModifiers::ALT)]),
+        run: |editor, key, _| {
+            editor.move_lines(key.code == KeyCode::Up);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Arrows", action: "Extend the selection",
+        trigger: KeyTrigger::Press(ARROW_KEYS),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "visual", keys: "Home / End / PageUp / PageDown", action: "Extend the selection",
+        trigger: KeyTrigger::Press(&[
+            bare_key(KeyCode::Home), bare_key(KeyCode::End), bare_key(KeyCode::PageUp), bare_key(KeyCode::PageDown),
+        ]),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Esc", action: "Back to normal mode",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.overwrite = false;
+            editor.pending_dead_key = None;
+            editor.finish_block_insert();
+            editor.seal_undo_group();
+            // Ignore movement errors on mode switch
+            let _ = editor.move_cursor(-1, 0);
+            editor.status_message = "Normal mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Insert", action: "Toggle insert / overwrite",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Insert)]),
+        run: |editor, _, _| {
+            editor.overwrite = !editor.overwrite;
+            editor.status_message = editor.insert_mode_label();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Enter", action: "Split the line",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Enter)]),
+        run: |editor, _, _| {
+            let result = editor.insert_newline();
+            editor.report_error("Newline error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Backspace", action: "Delete before the cursor",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Backspace)]),
+        run: |editor, _, _| {
+            let result = editor.delete_char();
+            editor.report_error("Delete error", result);
+            Ok(())
+        },
+    },
+    // A real tab, or with `expandtab` spaces up to the next tab stop
+    KeyBinding {
+        mode: "insert", keys: "Tab", action: "Insert a tab (spaces to the next tab stop with expandtab)",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Tab)]),
+        run: |editor, _, _| {
+            let (c, count) = if editor.expandtab {
+                let column = editor.cursor_display_column();
+                (' ', editor.tabstop - column % editor.tabstop)
+            } else {
+                ('\t', 1)
+            };
+            for _ in 0..count {
+                if let Err(e) = editor.insert_char(c) {
+                    editor.status_message = format!("Tab insert error: {}", e);
+                    break;
+                }
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Ctrl+Z", action: "Undo",
+        trigger: KeyTrigger::Press(&[ctrl_key('z')]),
+        run: |editor, _, _| {
+            let result = editor.undo();
+            editor.report_error("Undo error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Ctrl+Y", action: "Redo",
+        trigger: KeyTrigger::Press(&[ctrl_key('y')]),
+        run: |editor, _, _| {
+            let result = editor.redo();
+            editor.report_error("Redo error", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Ctrl+T", action: "Swap the two characters before the cursor",
+        trigger: KeyTrigger::Press(&[ctrl_key('t')]),
+        run: |editor, _, _| {
+            editor.transpose_chars();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Ctrl+/", action: "Comment / uncomment the line",
+        trigger: KeyTrigger::Press(COMMENT_KEYS),
+        run: |editor, _, _| {
+            let line = editor.cursor.y;
+            editor.toggle_comment(line, line);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Ctrl+S", action: "Save",
+        trigger: KeyTrigger::Press(&[ctrl_key('s')]),
+        run: |editor, _, _| {
+            let result = editor.save_file(false);
+            editor.report_error("Error saving", result);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Alt+Up / Alt+Down", action: "Move the line up / down",
+        trigger: KeyTrigger::Press(&[(KeyCode::Up, KeyModifiers::ALT), (KeyCode::Down, KeyModifiers::ALT)]),
+        run: |editor, key, _| {
+            editor.move_lines(key.code == KeyCode::Up);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "insert", keys: "Arrows / Home", action: "Move the cursor",
+        trigger: KeyTrigger::Press(&[
+            bare_key(KeyCode::Up), bare_key(KeyCode::Down), bare_key(KeyCode::Left), bare_key(KeyCode::Right),
+            bare_key(KeyCode::Home),
+        ]),
+        run: |editor, key, _| {
+            editor.move_by_key(key.code);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "command", keys: "Enter", action: "Run the command",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Enter)]),
+        run: |editor, _, _| {
+            editor.command_history.push(&editor.command_buffer);
+            editor.execute_command()?;
+            editor.mode = Mode::Normal;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "command", keys: "Esc", action: "Cancel",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.command_buffer.clear();
+            editor.command_history.browsing = None;
+            editor.status_message = "Normal mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "command", keys: "Tab / Shift+Tab", action: "Complete, cycling through the menu",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Tab), bare_key(KeyCode::BackTab)]),
+        run: |editor, key, _| {
+            editor.cycle_completion(key.code == KeyCode::Tab);
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "command", keys: "Up / Down", action: "Older / newer commands from the history",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Up), bare_key(KeyCode::Down)]),
+        run: |editor, key, _| {
+            let entry = if key.code == KeyCode::Up {
+                editor.command_history.older(&editor.command_buffer)
+            } else {
+                editor.command_history.newer()
+            };
+            if let Some(entry) = entry {
+                editor.command_buffer = entry;
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "command", keys: "Backspace", action: "Delete the last character",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Backspace)]),
+        run: |editor, _, _| {
+            editor.command_buffer.pop();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Enter", action: "Jump to the next match",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Enter)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.search_history.push(&editor.search_query);
+            // The preview moved the cursor; the real search starts where `/` was pressed
+            if let Some((cursor, _, _)) = editor.search_origin.take() {
+                editor.cursor = cursor;
+            }
+            if !editor.search_query.is_empty() {
+                editor.jump_to_match(true);
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Esc", action: "Cancel",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.search_query.clear();
+            editor.search_history.browsing = None;
+            if let Some((cursor, offset_y, highlight)) = editor.search_origin.take() {
+                editor.cursor = cursor;
+                editor.offset_y = offset_y;
+                editor.highlight_search = highlight;
+            }
+            editor.status_message = "Normal mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Ctrl+R", action: "Toggle regex search",
+        trigger: KeyTrigger::Press(&[ctrl_key('r')]),
+        run: |editor, _, _| {
+            editor.search_is_regex = !editor.search_is_regex;
+            editor.status_message = if editor.search_is_regex { "Regex search" } else { "Plain text search" }.to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Alt+C", action: "Toggle ignorecase",
+        trigger: KeyTrigger::Press(&[alt_key('c')]),
+        run: |editor, _, _| {
+            editor.ignorecase = !editor.ignorecase;
+            editor.status_message = if editor.ignorecase { "Ignoring case" } else { "Matching case" }.to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Up / Down", action: "Older / newer searches from the history",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Up), bare_key(KeyCode::Down)]),
+        run: |editor, key, _| {
+            let entry = if key.code == KeyCode::Up {
+                editor.search_history.older(&editor.search_query)
+            } else {
+                editor.search_history.newer()
+            };
+            if let Some(entry) = entry {
+                editor.search_query = entry;
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "search", keys: "Backspace", action: "Delete the last character",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Backspace)]),
+        run: |editor, _, _| {
+            editor.search_query.pop();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "replace", keys: "Tab", action: "Move to the replacement text",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Tab)]),
+        run: |editor, _, _| {
+            editor.status_message = if editor.replace_query.is_empty() {
+                "Enter search term first"
+            } else {
+                "Enter replacement text:"
+            }.to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "replace", keys: "Enter", action: "Replace in the loaded chunk",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Enter)]),
+        run: |editor, _, _| {
+            if !editor.replace_query.is_empty() {
+                let search = editor.replace_query.clone();
+                let replace = editor.replace_with.clone();
+                let count = editor.replace_in_chunk(&search, &replace);
+                editor.status_message = format!("Replaced {} occurrences in current chunk", count);
+            }
+            editor.mode = Mode::Normal;
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "replace", keys: "Alt+Enter", action: "Replace through the whole file, confirming each match",
+        trigger: KeyTrigger::Press(&[(KeyCode::Enter, KeyModifiers::ALT)]),
+        run: |editor, _, _| {
+            if editor.replace_query.is_empty() {
+                editor.mode = Mode::Normal;
+            } else {
+                editor.start_confirm_replace();
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "replace", keys: "Backspace", action: "Delete the last character",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Backspace)]),
+        run: |editor, _, _| {
+            if !editor.replace_with.is_empty() {
+                editor.replace_with.pop();
+            } else {
+                editor.replace_query.pop();
+            }
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "replace", keys: "Esc", action: "Cancel",
+        trigger: KeyTrigger::Press(&[bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.mode = Mode::Normal;
+            editor.replace_query.clear();
+            editor.replace_with.clear();
+            editor.status_message = "Normal mode".to_string();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "confirm", keys: "y", action: "Replace this match",
+        trigger: KeyTrigger::Press(&[char_key('y')]),
+        run: |editor, _, _| {
+            editor.replace_confirmed_match();
+            editor.next_confirm_match();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "confirm", keys: "n", action: "Skip this match",
+        trigger: KeyTrigger::Press(&[char_key('n')]),
+        run: |editor, _, _| {
+            if let Some(state) = editor.confirm_replace.as_mut() {
+                state.from = state.current_end;
+            }
+            editor.next_confirm_match();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "confirm", keys: "a", action: "Replace this and every remaining match",
+        trigger: KeyTrigger::Press(&[char_key('a')]),
+        run: |editor, _, _| {
+            editor.replace_remaining_matches();
+            Ok(())
+        },
+    },
+    KeyBinding {
+        mode: "confirm", keys: "q / Esc", action: "Stop replacing",
+        trigger: KeyTrigger::Press(&[char_key('q'), bare_key(KeyCode::Esc)]),
+        run: |editor, _, _| {
+            editor.finish_confirm_replace();
+            Ok(())
+        },
+    },
+];
+
+// How far keys typed in turn have got through the sequence bindings
+enum SequenceMatch {
+    Complete(&'static KeyBinding),
+    // The start of at least one sequence; wait for the next key
+    Partial,
+    NoMatch,
+}
+#[derive(Debug, Clone, PartialEq)]
+enum PopupKind {
+    KeyMap,
+    // Recently edited files with their last (line, column)
+    OldFiles(Vec<(PathBuf, usize, usize)>),
+    // The quickfix list; rows line up with `Editor::quickfix`
+    Quickfix,
+    // `:whitespace` report rows and the line each one jumps to, if any
+    Whitespace(Vec<Option<usize>>),
+    // `:theme` without a name; rows are theme names
+    Themes,
+}
+
+// A scrollable list overlay that narrows its items as the user types
+struct Popup {
+    kind: PopupKind,
+    title: String,
+    items: Vec<String>,
+    filter: String,
+    selected: usize,
+    // Items drawn grayed out, e.g. files that no longer exist
+    dimmed: Vec<bool>,
+}
+
+impl Popup {
+    fn new(kind: PopupKind, title: &str, items: Vec<String>) -> Self {
+        Self {
+            kind,
+            title: title.to_string(),
+            dimmed: vec![false; items.len()],
+            items,
+            filter: String::new(),
+            selected: 0,
+        }
+    }
+
+    // Indices of the items matching the filter as a fuzzy, case-insensitive subsequence
+    fn visible_indices(&self) -> Vec<usize> {
+        let filter: Vec<char> = self.filter.to_lowercase().chars().collect();
+        self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| {
+                let mut wanted = filter.iter().peekable();
+                for c in item.to_lowercase().chars() {
+                    if wanted.peek() == Some(&&c) {
+                        wanted.next();
+                    }
+                }
+                wanted.peek().is_none()
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn visible_items(&self) -> Vec<&String> {
+        self.visible_indices().into_iter().map(|i| &self.items[i]).collect()
+    }
+
+    fn selected_index(&self) -> Option<usize> {
+        self.visible_indices().get(self.selected).copied()
+    }
+}
+
+// What may follow an ex command's name, after a space
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExArg {
+    None,
+    Text,
+    // A file name, completed from the file system
+    File,
+    // `:set` options, completed from SET_OPTIONS
+    Setting,
+}
+
+// How an ex command was called: the range typed before it, whether `!` followed the
+// name, and the text after it
+struct ExCall<'a> {
+    range: Option<(usize, usize)>,
+    bang: bool,
+    arg: &'a str,
+}
+
+// An ex command. Tab completion offers the first name; the others are abbreviations
+// and aliases. `execute_command` runs the row whose name starts the command line, so
+// the table is both the completion list and the dispatch. Commands that take a range
+// fall back to their own default one when none is typed.
+struct ExCommand {
+    names: &'static [&'static str],
+    bang: bool,
+    arg: ExArg,
+    range: bool,
+    run: fn(&mut Editor, ExCall<'_>),
+}
+
+const EX_COMMANDS: &[ExCommand] = &[
+    ExCommand {
+        names: &["q"], bang: true, arg: ExArg::None, range: false,
+        run: |editor, call| editor.quit_command(call.bang),
+    },
+    ExCommand {
+        names: &["w"], bang: true, arg: ExArg::File, range: false,
+        run: |editor, call| {
+            let target = call.arg.trim();
+            if target.is_empty() {
+                let result = editor.save_file(call.bang);
+                editor.report_error("Error saving", result);
+            } else {
+                let result = editor.write_to(target, false, call.bang);
+                editor.report_error("Error writing", result);
+            }
+        },
+    },
+    ExCommand {
+        names: &["wq"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.write_and_quit(),
+    },
+    ExCommand {
+        names: &["saveas"], bang: true, arg: ExArg::File, range: false,
+        run: |editor, call| {
+            let result = editor.write_to(call.arg.trim(), true, call.bang);
+            editor.report_error("Error saving", result);
+        },
+    },
+    ExCommand {
+        names: &["e"], bang: true, arg: ExArg::File, range: false,
+        run: |editor, call| {
+            let arg = call.arg.trim();
+            if arg.starts_with("++") {
+                let result = editor.reopen_with_options(arg, call.bang);
+                editor.report_error("Error reloading", result);
+            } else if arg.is_empty() {
+                let result = editor.reload_file(call.bang);
+                editor.report_error("Error reloading", result);
+            } else {
+                let result = editor.edit_file(arg, call.bang);
+                editor.report_error("Error loading file", result);
+            }
+        },
+    },
+    ExCommand {
+        names: &["A"], bang: true, arg: ExArg::None, range: false,
+        run: |editor, call| {
+            let result = editor.open_alternate(call.bang);
+            editor.report_error("Error loading file", result);
+        },
+    },
+    ExCommand {
+        names: &["merge"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| {
+            let result = editor.merge_from_disk();
+            editor.report_error("Error merging", result);
+        },
+    },
+    ExCommand {
+        names: &["split", "sp"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.open_split(false),
+    },
+    ExCommand {
+        names: &["vsplit", "vs"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.open_split(true),
+    },
+    ExCommand {
+        names: &["close"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.close_split(false),
+    },
+    ExCommand {
+        names: &["only"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.close_split(true),
+    },
+    ExCommand {
+        names: &["bn", "bnext"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.cycle_buffer(true),
+    },
+    ExCommand {
+        names: &["bp", "bprevious"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.cycle_buffer(false),
+    },
+    ExCommand {
+        names: &["bd"], bang: true, arg: ExArg::None, range: false,
+        run: |editor, call| editor.delete_buffer(call.bang),
+    },
+    ExCommand {
+        names: &["t."], bang: false, arg: ExArg::None, range: true,
+        run: |editor, call| {
+            let (start, end) = call.range.unwrap_or((editor.cursor.y, editor.cursor.y));
+            editor.duplicate_lines(start, end);
+        },
+    },
+    ExCommand {
+        names: &["join"], bang: true, arg: ExArg::Text, range: true,
+        run: |editor, call| {
+            let (start, end) = call.range.unwrap_or((editor.cursor.y, editor.cursor.y));
+            let separator = if call.bang { "" } else if call.arg.is_empty() { " " } else { call.arg };
+            editor.join_lines(start, end, separator);
+        },
+    },
+    ExCommand {
+        names: &["retab"], bang: false, arg: ExArg::None, range: true,
+        run: |editor, call| {
+            let range = call.range.unwrap_or((0, editor.buffer.total_lines.saturating_sub(1)));
+            editor.retab(range);
+        },
+    },
+    ExCommand {
+        names: &["sort"], bang: true, arg: ExArg::Text, range: true,
+        run: |editor, call| {
+            let range = call.range.unwrap_or_else(|| editor.loaded_range());
+            editor.sort_lines(range, call.bang, call.arg);
+        },
+    },
+    ExCommand {
+        names: &["uniq"], bang: false, arg: ExArg::Text, range: true,
+        run: |editor, call| {
+            let range = call.range.unwrap_or_else(|| editor.loaded_range());
+            editor.uniq_lines(range, call.arg);
+        },
+    },
+    ExCommand {
+        names: &["numfmt"], bang: false, arg: ExArg::Text, range: true,
+        run: |editor, call| {
+            let range = call.range.unwrap_or((editor.cursor.y, editor.cursor.y));
+            editor.format_numbers(range, call.arg);
+        },
+    },
+    ExCommand {
+        names: &["extract"], bang: true, arg: ExArg::File, range: true,
+        run: |editor, call| {
+            let range = call.range.unwrap_or((editor.cursor.y, editor.cursor.y));
+            let target = call.arg.trim();
+            let result = if target.is_empty() {
+                editor.extract_to_scratch(range)
+            } else {
+                editor.extract_to_file(range, &PathBuf::from(target), call.bang)
+            };
+            editor.report_error("Extract failed", result);
+        },
+    },
+    ExCommand {
+        names: &["dupcomment"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.duplicate_commented(),
+    },
+    ExCommand {
+        names: &["annotate"], bang: true, arg: ExArg::Text, range: false,
+        run: |editor, call| {
+            let text = call.arg.trim();
+            if call.bang {
+                editor.buffer.clear_all_virtual_text();
+                editor.status_message = "Annotations cleared".to_string();
+            } else if text.is_empty() {
+                editor.buffer.clear_virtual_text(editor.cursor.y);
+                editor.status_message = format!("Annotation removed from line {}", editor.cursor.y + 1);
+            } else {
+                let style = Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC);
+                editor.buffer.set_virtual_text(editor.cursor.y, text, style);
+                editor.status_message = format!("Annotated line {}", editor.cursor.y + 1);
+            }
+        },
+    },
+    ExCommand {
+        names: &["map"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.open_keymap(),
+    },
+    ExCommand {
+        names: &["browse oldfiles", "bro ol", "oldfiles", "History"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.open_oldfiles(),
+    },
+    ExCommand {
+        names: &["references", "refs"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.start_references(),
+    },
+    ExCommand {
+        names: &["copen", "cope"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| editor.open_quickfix(),
+    },
+    ExCommand {
+        names: &["cnext", "cn"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| {
+            let result = editor.step_quickfix(true);
+            editor.report_error("Error opening file", result);
+        },
+    },
+    ExCommand {
+        names: &["cprev", "cp"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| {
+            let result = editor.step_quickfix(false);
+            editor.report_error("Error opening file", result);
+        },
+    },
+    ExCommand {
+        names: &["theme", "colorscheme", "colo"], bang: false, arg: ExArg::Text, range: false,
+        run: |editor, call| match call.arg.trim() {
+            "" => editor.open_theme_list(),
+            name => editor.set_theme(name),
+        },
+    },
+    ExCommand {
+        names: &["noh", "nohlsearch"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| {
+            editor.highlight_search = false;
+            editor.status_message.clear();
+        },
+    },
+    ExCommand {
+        names: &["whitespace"], bang: false, arg: ExArg::None, range: false,
+        run: |editor, _| {
+            let result = editor.open_whitespace_report();
+            editor.report_error("whitespace", result);
+        },
+    },
+    ExCommand {
+        names: &["set"], bang: false, arg: ExArg::Setting, range: false,
+        run: |editor, call| {
+            let args = call.arg.trim();
+            // Command-valued options take the rest of the line, spaces included
+            let options: Vec<String> = if args.starts_with("formatprg=") {
+                vec![args.to_string()]
+            } else {
+                args.split_whitespace().map(|o| o.to_string()).collect()
+            };
+            for option in options {
+                editor.apply_setting(&option);
+            }
+        },
+    },
+];
+
+// How a `:set` option is written
+#[derive(Clone, Copy)]
+enum OptionSetter {
+    // `name` turns it on and `noname` off
+    Flag(fn(&mut Editor, bool)),
+    // `name=value`
+    Value(fn(&mut Editor, &str)),
+    // `name?` reports the current value
+    Query(fn(&mut Editor)),
+}
+
+// A `:set` option. `apply_setting` runs the row that names it, and Tab completion
+// offers the first name of each flag and value row.
+struct SetOption {
+    names: &'static [&'static str],
+    set: OptionSetter,
+}
+
+const SET_OPTIONS: &[SetOption] = &[
+    SetOption {
+        names: &["eob"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.show_eob = on;
+            editor.status_message = format!("End-of-buffer markers {}", if on { "shown" } else { "hidden" });
+        }),
+    },
+    SetOption {
+        names: &["number", "nu"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.show_line_numbers = on;
+            editor.status_message = format!("Line numbers {}", if on { "shown" } else { "hidden" });
+        }),
+    },
+    SetOption {
+        names: &["relativenumber", "rnu"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.relative_numbers = on;
+            editor.status_message = if on { "Relative line numbers" } else { "Relative line numbers off" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["undobreak"],
+        set: OptionSetter::Value(|editor, value| {
+            editor.undo_break = match value {
+                "char" => UndoBreak::Char,
+                "word" => UndoBreak::Word,
+                "insert" => UndoBreak::Insert,
+                other => {
+                    editor.status_message = format!("undobreak must be char, word or insert, not '{}'", other);
+                    return;
+                }
+            };
+            editor.status_message = format!("Typed text is undone per {}", value);
+        }),
+    },
+    SetOption {
+        names: &["autoformat"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.autoformat = on;
+            editor.status_message = if on { "Formatting on save" } else { "Not formatting on save" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["formatprg"],
+        set: OptionSetter::Value(|editor, command| {
+            let extension = editor.file_extension();
+            if command.is_empty() {
+                editor.formatters.remove(&extension);
+                editor.status_message = format!("No formatter for .{} files", extension);
+            } else {
+                editor.status_message = format!("Formatter for .{} files: {}", extension, command);
+                editor.formatters.insert(extension, command.to_string());
+            }
+        }),
+    },
+    SetOption {
+        names: &["follow"],
+        set: OptionSetter::Flag(|editor, on| {
+            let result = editor.set_following(on);
+            editor.report_error("Error following file", result);
+        }),
+    },
+    SetOption {
+        names: &["confirmmkdir"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.confirm_mkdir = on;
+            editor.status_message = if on {
+                "Will ask before creating directories on save"
+            } else {
+                "Directories are created on save without asking"
+            }
+            .to_string();
+        }),
+    },
+    SetOption {
+        names: &["iskeyword", "isk"],
+        set: OptionSetter::Value(|editor, spec| match KeywordSet::parse(spec) {
+            Ok(set) => {
+                editor.iskeyword = set;
+                editor.status_message = format!("iskeyword={}", spec);
+            }
+            Err(e) => editor.status_message = e,
+        }),
+    },
+    SetOption {
+        names: &["iskeyword", "isk"],
+        set: OptionSetter::Query(|editor| {
+            editor.status_message = format!("iskeyword={}", editor.iskeyword.spec);
+        }),
+    },
+    SetOption {
+        names: &["alternates"],
+        set: OptionSetter::Value(|editor, value| {
+            let pairs: Option<Vec<(String, String)>> = value
+                .split(',')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (a, b) = pair.split_once(':')?;
+                    let (a, b) = (a.trim_start_matches('.'), b.trim_start_matches('.'));
+                    if a.is_empty() || b.is_empty() { None } else { Some((a.to_string(), b.to_string())) }
+                })
+                .collect();
+            match pairs {
+                Some(pairs) => {
+                    editor.alternate_pairs = pairs;
+                    editor.status_message = format!("alternates={}", value);
+                }
+                None => editor.status_message = "alternates expects ext:ext pairs, e.g. c:h,cpp:hpp".to_string(),
+            }
+        }),
+    },
+    SetOption {
+        names: &["altpath"],
+        set: OptionSetter::Value(|editor, value| {
+            editor.alternate_dirs = value.split(',').filter(|d| !d.is_empty()).map(|d| d.to_string()).collect();
+            if editor.alternate_dirs.is_empty() {
+                editor.alternate_dirs.push(".".to_string());
+            }
+            editor.status_message = format!("altpath={}", value);
+        }),
+    },
+    SetOption {
+        names: &["savereminder"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<u64>() {
+            Ok(minutes) => {
+                editor.save_reminder = Duration::from_secs(minutes * 60);
+                editor.status_message = if minutes == 0 {
+                    "Save reminder disabled".to_string()
+                } else {
+                    format!("Reminding to save after {} minute(s)", minutes)
+                };
+            }
+            Err(_) => editor.status_message = "savereminder expects minutes".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["yankflash"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<u64>() {
+            Ok(ms) => {
+                editor.yank_flash_duration = Duration::from_millis(ms);
+                editor.status_message = if ms == 0 {
+                    "Yank flash disabled".to_string()
+                } else {
+                    format!("Yank flash lasts {} ms", ms)
+                };
+            }
+            Err(_) => editor.status_message = "yankflash expects milliseconds".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["yankflashcolor"],
+        set: OptionSetter::Value(|editor, value| match parse_color(value) {
+            Some(color) => {
+                editor.yank_flash_color = color;
+                editor.status_message = format!("Yank flash color set to {}", value);
+            }
+            None => editor.status_message = "yankflashcolor expects #rrggbb or a color name".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["readonly", "ro"],
+        set: OptionSetter::Flag(|editor, on| {
+            if on {
+                editor.read_only = true;
+                editor.status_message = "Buffer is readonly".to_string();
+                return;
+            }
+            if editor.buffer.mapped.is_some() {
+                editor.status_message = "Memory-mapped buffers are always readonly".to_string();
+                return;
+            }
+            if editor.read_only {
+                editor.read_only = false;
+                if let Some(path) = editor.filename.clone() {
+                    if let Some(pid) = SwapFile::claim(&path) {
+                        editor.status_message =
+                            format!("Warning: {} is already being edited by process {}", path.display(), pid);
+                        return;
+                    }
+                }
+            }
+            editor.status_message = "Buffer can be edited".to_string();
+        }),
+    },
+    SetOption {
+        names: &["expandtab", "et"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.expandtab = on;
+            editor.status_message = if on { "Indent with spaces" } else { "Indent with tabs" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["tabstop", "ts"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<usize>() {
+            Ok(width) if (1..=32).contains(&width) => {
+                editor.tabstop = width;
+                editor.status_message = format!("tabstop={}", width);
+            }
+            _ => editor.status_message = "tabstop expects a width from 1 to 32".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["markers"],
+        set: OptionSetter::Value(|editor, value| {
+            let kinds: Option<Vec<MarkerKind>> = value
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(|name| MarkerKind::ALL.iter().copied().find(|kind| kind.name() == name))
+                .collect();
+            match kinds {
+                Some(kinds) => {
+                    editor.marker_kinds = kinds;
+                    editor.status_message = format!("markers={}", value);
+                }
+                None => editor.status_message = "markers expects a list of: annotation, search".to_string(),
+            }
+        }),
+    },
+    SetOption {
+        names: &["textwidth", "tw"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<usize>() {
+            Ok(width) => {
+                editor.textwidth = width;
+                editor.status_message = if width == 0 {
+                    "Hard wrapping off".to_string()
+                } else {
+                    format!("Typed text wraps at column {}", width)
+                };
+            }
+            Err(_) => editor.status_message = "textwidth expects a column number".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["undolevels", "ul"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<usize>() {
+            Ok(levels) => {
+                editor.undolevels = levels;
+                editor.trim_undo_history();
+                editor.status_message = format!("Keeping {} undo steps", levels);
+            }
+            Err(_) => editor.status_message = "undolevels expects a number".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["winwidth", "wiw"],
+        set: OptionSetter::Value(|editor, value| match value.parse::<usize>() {
+            Ok(width) => {
+                editor.winwidth = width;
+                editor.status_message = if width == 0 {
+                    "Using the full terminal width".to_string()
+                } else {
+                    format!("Text laid out {} columns wide", width)
+                };
+            }
+            Err(_) => editor.status_message = "winwidth expects a number of columns".to_string(),
+        }),
+    },
+    SetOption {
+        names: &["clipboard"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.system_clipboard = on;
+            editor.status_message = if !on {
+                "Copy and paste stay inside the editor".to_string()
+            } else if SystemClipboard::candidates().is_empty() {
+                "No system clipboard found (needs wl-copy, xclip, xsel or pbcopy)".to_string()
+            } else {
+                "Copy and paste use the system clipboard".to_string()
+            };
+        }),
+    },
+    SetOption {
+        names: &["fileformat", "ff"],
+        set: OptionSetter::Value(|editor, value| {
+            let ending = match value {
+                "unix" => LineEnding::Unix,
+                "dos" => LineEnding::Dos,
+                _ => {
+                    editor.status_message = "fileformat expects unix or dos".to_string();
+                    return;
+                }
+            };
+            // Rewriting every line ending is a change worth saving
+            if ending != editor.buffer.line_ending {
+                editor.buffer.line_ending = ending;
+                editor.modified = true;
+            }
+            editor.status_message = format!("fileformat={}", value);
+        }),
+    },
+    // The terminator is the buffer's line ending, so `ff=dos` ends the file in \r\n
+    SetOption {
+        names: &["eol", "endofline"],
+        set: OptionSetter::Flag(|editor, on| {
+            if on != editor.buffer.final_newline {
+                editor.buffer.final_newline = on;
+                editor.modified = true;
+            }
+            editor.status_message = if on { "eol" } else { "noeol" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["ignorecase", "ic"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.ignorecase = on;
+            editor.status_message = format!("ignorecase {}", if on { "on" } else { "off" });
+        }),
+    },
+    SetOption {
+        names: &["smartcase", "scs"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.smartcase = on;
+            editor.status_message = format!("smartcase {}", if on { "on" } else { "off" });
+        }),
+    },
+    SetOption {
+        names: &["smarthome"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.smarthome = on;
+            editor.status_message = format!("smarthome {}", if on { "on" } else { "off" });
+        }),
+    },
+    SetOption {
+        names: &["stickyscroll"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.stickyscroll = on;
+            editor.status_message = format!("stickyscroll {}", if on { "on" } else { "off" });
+        }),
+    },
+    SetOption {
+        names: &["searchcount"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.searchcount = on;
+            editor.status_message = format!("searchcount {}", if on { "on" } else { "off" });
+        }),
+    },
+    SetOption {
+        names: &["wrap"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.wrap = on;
+            editor.status_message = if on { "Long lines wrap on screen" } else { "Long lines scroll sideways" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["wildmenu", "wmnu"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.show_wildmenu = on;
+            editor.status_message = format!("Completion menu {}", if on { "shown" } else { "hidden" });
+        }),
+    },
+    SetOption {
+        names: &["cursorline", "cul"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.cursorline = on;
+            editor.status_message = if on { "Cursor line highlighted" } else { "Cursor line not highlighted" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["cursorcolumn", "cuc"],
+        set: OptionSetter::Flag(|editor, on| {
+            editor.cursorcolumn = on;
+            editor.status_message =
+                if on { "Cursor column highlighted" } else { "Cursor column not highlighted" }.to_string();
+        }),
+    },
+    SetOption {
+        names: &["colorcolumn", "cc"],
+        set: OptionSetter::Value(|editor, value| {
+            let columns: Result<Vec<usize>, _> = value
+                .split(',')
+                .filter(|column| !column.is_empty())
+                .map(|column| column.parse::<usize>())
+                .collect();
+            match columns {
+                Ok(columns) if columns.iter().all(|&column| column > 0) => {
+                    // Columns are 1-based like the status line
+                    editor.colorcolumns = columns.into_iter().map(|column| column - 1).collect();
+                    editor.status_message = format!("colorcolumn={}", value);
+                }
+                _ => editor.status_message = "colorcolumn expects comma separated column numbers".to_string(),
+            }
+        }),
+    },
+    SetOption {
+        names: &["eobchar"],
+        set: OptionSetter::Value(|editor, value| {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => {
+                    editor.eob_char = c;
+                    editor.status_message = format!("End-of-buffer marker set to '{}'", c);
+                }
+                _ => editor.status_message = "eobchar expects a single character".to_string(),
+            }
+        }),
+    },
+];
+
+// Command-line completion candidates; `start` is where the completed word begins in
+// the command buffer and `original` is what the user had typed there
+struct Wildmenu {
+    items: Vec<String>,
+    selected: usize,
+    start: usize,
+    original: String,
+}
+
+// Sources of line markers visited by `]d` / `[d`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MarkerKind {
+    Annotation,
+    Search,
+}
+
+impl MarkerKind {
+    const ALL: [MarkerKind; 2] = [MarkerKind::Annotation, MarkerKind::Search];
+
+    fn name(&self) -> &'static str {
+        match self {
+            MarkerKind::Annotation => "annotation",
+            MarkerKind::Search => "search",
+        }
+    }
+}
+
+// Questions that wait for a y/n answer before an action goes ahead
+#[derive(Debug, Clone)]
+enum Prompt {
+    CreateDir { dir: PathBuf, quit_after: bool },
+    RecreateDeleted { quit_after: bool },
+    // `:bd` on a buffer with unsaved changes
+    CloseBuffer,
+    // Saving over changes another program made to the file
+    OverwriteChanged { quit_after: bool },
+    // `:e!` on a buffer with unsaved changes
+    DiscardAndReload,
+    // `:w <file>` or `:saveas <file>` onto a file that already exists
+    OverwriteFile { path: PathBuf, rename: bool },
+}
+
+// A single reversible buffer edit; `x` values are byte offsets into the line
+#[derive(Debug, Clone)]
+enum EditOp {
+    InsertChar { y: usize, x: usize, c: char },
+    DeleteChar { y: usize, x: usize, c: char },
+    SplitLine { y: usize, x: usize },
+    JoinLine { y: usize, x: usize },
+    ReplaceRange { start: usize, old: Vec<String>, new: Vec<String> },
+}
+
+impl EditOp {
+    fn inverse(&self) -> EditOp {
+        match self {
+            EditOp::InsertChar { y, x, c } => EditOp::DeleteChar { y: *y, x: *x, c: *c },
+            EditOp::DeleteChar { y, x, c } => EditOp::InsertChar { y: *y, x: *x, c: *c },
+            EditOp::SplitLine { y, x } => EditOp::JoinLine { y: *y, x: *x },
+            EditOp::JoinLine { y, x } => EditOp::SplitLine { y: *y, x: *x },
+            EditOp::ReplaceRange { start, old, new } => EditOp::ReplaceRange {
+                start: *start,
+                old: new.clone(),
+                new: old.clone(),
+            },
+        }
+    }
+
+    // Where the edit happened, used to put the cursor back on undo
+    fn position(&self) -> (usize, usize) {
+        match self {
+            EditOp::InsertChar { y, x, .. }
+            | EditOp::DeleteChar { y, x, .. }
+            | EditOp::SplitLine { y, x }
+            | EditOp::JoinLine { y, x } => (*y, *x),
+            EditOp::ReplaceRange { start, .. } => (*start, 0),
+        }
+    }
+}
+
+// Whole-file matches of a search, valid until the buffer changes
+struct SearchCache {
+    query: String,
+    regex: bool,
+    ignore_case: bool,
+    whole_word: bool,
+    matches: Vec<(usize, usize)>,
+    total: usize,
+}
+
+// Bytes each loaded row of a block selection covers, worked out when the selection
+// changes rather than on every redraw. Edits drop it.
+struct BlockCache {
+    anchor: (usize, usize),
+    cursor: (usize, usize),
+    tabstop: usize,
+    ranges: HashMap<usize, Option<(usize, usize)>>,
+}
+
+// Syntax colours of the loaded chunk, parsed from its first line. Each entry keeps the
+// text it was worked out from and the parser state after it, so a redraw only parses
+// again from the first changed line and never past the last one on screen.
+struct HighlightCache {
+    start_line: usize,
+    extension: String,
+    lines: Vec<HighlightedLine>,
+}
+
+struct HighlightedLine {
+    text: String,
+    state: (HighlightState, ParseState),
+    // Styled byte ranges of the line
+    styles: Vec<(Style, usize, usize)>,
+}
+
+// Progress of a replace that asks about each match in turn
+struct ConfirmReplace {
+    pattern: Regex,
+    replacement: String,
+    // Every line holding a match, found up front so the walk can move between chunks
+    lines: Vec<usize>,
+    // Index into `lines`, and the byte offset in that line to look from next
+    line: usize,
+    from: usize,
+    // End of the match under the cursor
+    current_end: usize,
+    seen: usize,
+    total: usize,
+    replaced: usize,
+    // Replacements made so far, recorded as one undo group when the walk ends
+    ops: Vec<EditOp>,
+}
+
+// `I` in block selection: text typed on the first row is copied to the others on Esc
+struct BlockInsert {
+    first: usize,
+    last: usize,
+    // Screen column the text goes in at, and the byte where it started on the first row
+    column: usize,
+    x: usize,
+    // The rows before typing, so the whole insert undoes as one step, and the last
+    // undo group id before it: groups after that one were typed during the insert
+    old: Vec<String>,
+    undo_mark: usize,
+}
+
+// Edits that are undone together with a single `u`
+struct UndoGroup {
+    // Increases with every group, so groups stay identifiable when old ones are
+    // trimmed from the front
+    id: usize,
+    ops: Vec<EditOp>,
+    last_edit: Instant,
+    sealed: bool,
+}
+
+// When a run of typed characters is split into separate undo steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UndoBreak {
+    Char,
+    Word,
+    // Everything typed between entering and leaving insert mode is one step
+    Insert,
+}
+
+// A position in the buffer: `y` is the line and `x` the character within it, not the byte
+#[derive(Debug, Clone)]
+struct Cursor {
+    x: usize,
     y: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineEnding {
+    Unix,
+    Dos,
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Dos => "\r\n",
+        }
+    }
+}
+
+// Characters that count as part of a word, parsed from a vim-style `iskeyword` spec:
+// comma separated items where `@` means alphabetic, `a-z` or `48-57` is a range, any
+// other single character is itself and a leading `^` excludes the item
+#[derive(Clone)]
+struct KeywordSet {
+    spec: String,
+    items: Vec<(bool, char, char)>,
+    alpha: Option<bool>,
+}
+
+impl KeywordSet {
+    const DEFAULT: &'static str = "@,48-57,_";
+
+    fn parse(spec: &str) -> Result<Self, String> {
+        let mut items = Vec::new();
+        let mut alpha = None;
+        for item in spec.split(',').filter(|item| !item.is_empty()) {
+            let (include, body) = match item.strip_prefix('^') {
+                Some(rest) if !rest.is_empty() => (false, rest),
+                _ => (true, item),
+            };
+            if body == "@" {
+                alpha = Some(include);
+                continue;
+            }
+            let bound = |part: &str| -> Option<char> {
+                let mut chars = part.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => part.parse::<u32>().ok().and_then(char::from_u32),
+                }
+            };
+            let range = match body.char_indices().skip(1).find(|&(_, c)| c == '-') {
+                Some((i, _)) if i + 1 < body.len() => (bound(&body[..i]), bound(&body[i + 1..])),
+                _ => {
+                    let c = bound(body);
+                    (c, c)
+                }
+            };
+            match range {
+                (Some(from), Some(to)) if from <= to => items.push((include, from, to)),
+                _ => return Err(format!("Invalid iskeyword item: {}", item)),
+            }
+        }
+        Ok(KeywordSet { spec: spec.to_string(), items, alpha })
+    }
+
+    // Later items win, so `@,^x` makes every letter but `x` a word character
+    fn contains(&self, c: char) -> bool {
+        let mut result = c.is_alphabetic() && self.alpha == Some(true);
+        for &(include, from, to) in &self.items {
+            if (from..=to).contains(&c) {
+                result = include;
+            }
+        }
+        result
+    }
+}
+
+impl Default for KeywordSet {
+    fn default() -> Self {
+        KeywordSet::parse(KeywordSet::DEFAULT).expect("default iskeyword is valid")
+    }
+}
+
+// Read-only view of a file through a memory map. Line starts are discovered lazily as
+// the user scrolls, so opening never has to scan the whole file.
+struct MappedFile {
+    map: Mmap,
+    line_starts: Vec<usize>,
+    complete: bool,
+    // When non-zero, lines are also cut every this many bytes, so a file that is one
+    // enormous line can still be scrolled through
+    segment_width: usize,
+}
+
+impl MappedFile {
+    fn open(path: &PathBuf) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the mapping is only read. Truncating the file while it is mapped makes
+        // reads past the new end fault with SIGBUS; `LineBuffer::check_mapping` stats the
+        // file before each read and drops a shrunk mapping, which narrows that window but
+        // cannot close it against a writer truncating mid-read.
+        let map = unsafe { Mmap::map(&file)? };
+        let complete = map.is_empty();
+        Ok(Self {
+            map,
+            line_starts: vec![0],
+            complete,
+            segment_width: 0,
+        })
+    }
+
+    fn open_segmented(path: &PathBuf, width: usize) -> io::Result<Self> {
+        let mut mapped = Self::open(path)?;
+        mapped.segment_width = width;
+        Ok(mapped)
+    }
+
+    // True when the file starts with a line too long to load as a single `String`
+    fn starts_with_long_line(path: &PathBuf) -> bool {
+        let mut head = Vec::with_capacity(MAX_LINE_LENGTH + 1);
+        let read = File::open(path)
+            .and_then(|file| file.take(MAX_LINE_LENGTH as u64 + 1).read_to_end(&mut head));
+        read.is_ok() && head.len() > MAX_LINE_LENGTH && !head.contains(&b'\n')
+    }
+
+    // Extends the line index until the start of `line + 1` is known or the file ends
+    fn scan_to(&mut self, line: usize) {
+        while !self.complete && self.line_starts.len() <= line.saturating_add(1) {
+            let from = self.line_starts[self.line_starts.len() - 1];
+            let limit = if self.segment_width == 0 {
+                self.map.len()
+            } else {
+                (from + self.segment_width).min(self.map.len())
+            };
+            let next = match self.map[from..limit].iter().position(|&b| b == b'\n') {
+                Some(pos) => from + pos + 1,
+                None if limit < self.map.len() => {
+                    // Never split a UTF-8 sequence between two segments
+                    let mut cut = limit;
+                    while cut > from + 1 && (self.map[cut] & 0xC0) == 0x80 {
+                        cut -= 1;
+                    }
+                    cut
+                }
+                None => self.map.len(),
+            };
+            if next < self.map.len() {
+                self.line_starts.push(next);
+            } else {
+                self.complete = true;
+            }
+        }
+    }
+
+    fn scan_all(&mut self) {
+        while !self.complete {
+            let known = self.line_starts.len();
+            self.scan_to(known + CHUNK_SIZE);
+        }
+    }
+
+    // Maps the file again after it grew, keeping the lines already indexed
+    fn remap_grown(&self, path: &PathBuf) -> io::Result<Self> {
+        let mut grown = Self::open(path)?;
+        grown.line_starts = self.line_starts.clone();
+        grown.segment_width = self.segment_width;
+        grown.complete = grown.map.is_empty();
+        Ok(grown)
+    }
+
+    fn known_lines(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    fn line(&self, index: usize) -> String {
+        let start = self.line_starts[index];
+        let end = self.line_starts.get(index + 1).copied().unwrap_or(self.map.len());
+        let mut bytes = &self.map[start..end];
+        if bytes.ends_with(b"\n") {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+        if bytes.ends_with(b"\r") {
+            bytes = &bytes[..bytes.len() - 1];
+        }
+        let bytes = &bytes[..bytes.len().min(MAX_LINE_LENGTH)];
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+// Byte offsets of every LINE_INDEX_STRIDE-th line start of a UTF-8 file, found by one
+// scan when the file is first opened. Loading a chunk seeks to the nearest offset at or
+// before it and reads only the lines it needs, and the whole index of a multi-gigabyte
+// file takes a few hundred kilobytes. It belongs to the file's size and mtime; once
+// those change the file is scanned again.
+struct LineIndex {
+    path: PathBuf,
+    stamp: (SystemTime, u64),
+    offsets: Vec<u64>,
+    total_lines: usize,
+}
+
+impl LineIndex {
+    fn stamp(path: &Path) -> io::Result<(SystemTime, u64)> {
+        let metadata = fs::metadata(path)?;
+        Ok((metadata.modified()?, metadata.len()))
+    }
+
+    // Counts lines the way `BufRead::lines` splits them: a final line without a newline
+    // still counts, an empty file has none
+    fn build(path: &Path) -> io::Result<Self> {
+        let stamp = Self::stamp(path)?;
+        let mut reader = BufReader::with_capacity(1 << 20, File::open(path)?);
+        let mut offsets = vec![0];
+        let mut newlines = 0;
+        let mut position = 0u64;
+        let mut last = b'\n';
+        loop {
+            let block = reader.fill_buf()?;
+            if block.is_empty() {
+                break;
+            }
+            for (i, &byte) in block.iter().enumerate() {
+                if byte == b'\n' {
+                    newlines += 1;
+                    if newlines % LINE_INDEX_STRIDE == 0 {
+                        offsets.push(position + i as u64 + 1);
+                    }
+                }
+            }
+            last = block[block.len() - 1];
+            let read = block.len();
+            position += read as u64;
+            reader.consume(read);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            stamp,
+            offsets,
+            total_lines: if last == b'\n' { newlines } else { newlines + 1 },
+        })
+    }
+
+    fn is_current(&self, path: &Path) -> bool {
+        self.path == path && Self::stamp(path).is_ok_and(|stamp| stamp == self.stamp)
+    }
+
+    // The lines of the file from `start` on
+    fn lines_from(&self, start: usize) -> io::Result<impl Iterator<Item = io::Result<String>>> {
+        let checkpoint = (start / LINE_INDEX_STRIDE).min(self.offsets.len() - 1);
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offsets[checkpoint]))?;
+        Ok(BufReader::new(file).lines().skip(start - checkpoint * LINE_INDEX_STRIDE))
+    }
+}
+
 struct LineBuffer {
+    mapped: Option<MappedFile>,
+    // Line offsets of the UTF-8 file chunks are read from
+    index: Option<LineIndex>,
     lines: VecDeque<String>,
     max_lines: usize,
     start_line_number: usize,
     total_lines: usize,
+    encoding: &'static Encoding,
+    line_ending: LineEnding,
+    // Annotations drawn after a line's text (diagnostics, blame, ...). They are never
+    // part of the text, so saving and cursor movement ignore them.
+    virtual_text: HashMap<usize, (String, Style)>,
+    // The file the loaded chunk came from and the lines of it the chunk replaces. Saving
+    // copies everything outside that range from the file, so edits can change the
+    // chunk's length without touching the rest.
+    source: Option<PathBuf>,
+    disk_start: usize,
+    disk_count: usize,
+    // Edited chunks put aside when another part of the file was loaded, keyed by the
+    // first line of `source` they replace, with how many lines they replace. They are
+    // written out with the next save.
+    stashed: BTreeMap<usize, (usize, VecDeque<String>)>,
+    // `.editorconfig` rules applied to every line written, including those streamed
+    // from outside the chunk
+    trim_trailing_whitespace: bool,
+    final_newline: bool,
 }
 
 impl LineBuffer {
     fn new(max_lines: usize) -> Self {
         Self {
+            mapped: None,
+            index: None,
             lines: VecDeque::new(),
             max_lines,
             start_line_number: 0,
             total_lines: 0,
+            encoding: UTF_8,
+            line_ending: LineEnding::Unix,
+            virtual_text: HashMap::new(),
+            source: None,
+            disk_start: 0,
+            disk_count: 0,
+            stashed: BTreeMap::new(),
+            trim_trailing_whitespace: false,
+            final_newline: true,
+        }
+    }
+
+    fn set_virtual_text(&mut self, line: usize, text: &str, style: Style) {
+        self.virtual_text.insert(line, (text.to_string(), style));
+    }
+
+    fn clear_virtual_text(&mut self, line: usize) {
+        self.virtual_text.remove(&line);
+    }
+
+    fn clear_all_virtual_text(&mut self) {
+        self.virtual_text.clear();
+    }
+
+    // Keeps annotations on their lines after `removed` lines at `at` were replaced by
+    // `added` ones; annotations on the removed lines go with them
+    fn shift_virtual_text(&mut self, at: usize, removed: usize, added: usize) {
+        if self.virtual_text.is_empty() || removed == added {
+            return;
+        }
+        self.virtual_text = std::mem::take(&mut self.virtual_text)
+            .into_iter()
+            .filter_map(|(line, text)| match line {
+                line if line < at => Some((line, text)),
+                line if line < at + removed => None,
+                line => Some((line - removed + added, text)),
+            })
+            .collect();
+    }
+
+    fn read_all_lines(&self, file_path: &PathBuf) -> io::Result<Vec<String>> {
+        if self.encoding == UTF_8 {
+            let reader = BufReader::new(File::open(file_path)?);
+            return reader.lines().collect();
+        }
+        let bytes = fs::read(file_path)?;
+        let (text, _, _) = self.encoding.decode(&bytes);
+        Ok(text.lines().map(|line| line.to_string()).collect())
+    }
+
+    fn write_encoded<W: Write>(
+        writer: &mut W,
+        line: &str,
+        encoding: &'static Encoding,
+        ending: Option<LineEnding>,
+    ) -> io::Result<()> {
+        if encoding == UTF_8 {
+            writer.write_all(line.as_bytes())?;
+        } else {
+            let (bytes, _, _) = encoding.encode(line);
+            writer.write_all(&bytes)?;
+        }
+        if let Some(ending) = ending {
+            writer.write_all(ending.as_str().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn load_chunk(&mut self, file_path: &PathBuf, start_line: usize) -> io::Result<()> {
+        if self.source.as_ref() != Some(file_path) || self.mapped.is_some() {
+            self.stashed.clear();
+        }
+        self.check_mapping(file_path)?;
+        if !self.stashed.is_empty() {
+            return self.load_stashed_chunk(file_path, start_line);
+        }
+        self.lines.clear();
+        self.source = None;
+        self.disk_start = 0;
+        self.disk_count = 0;
+        
+        if !file_path.exists() {
+            self.lines.push_back(String::new());
+            self.total_lines = 1;
+            self.start_line_number = 0;
+            return Ok(());
+        }
+        self.source = Some(file_path.clone());
+
+        if self.mapped.is_some() {
+            return self.load_mapped_chunk(file_path, start_line);
+        }
+
+        // UTF-8 files are read from the line index; legacy encodings are decoded whole
+        let (total, actual_start, chunk) = if self.encoding == UTF_8 {
+            if !self.index.as_ref().is_some_and(|index| index.is_current(file_path)) {
+                self.index = Some(LineIndex::build(file_path)?);
+            }
+            let index = match self.index.as_ref() {
+                Some(index) => index,
+                None => return Ok(()),
+            };
+            let total = index.total_lines;
+            let actual_start = start_line.min(total.saturating_sub(1));
+            let chunk = if total == 0 {
+                Vec::new()
+            } else {
+                index.lines_from(actual_start)?.take(self.max_lines).collect::<io::Result<Vec<String>>>()?
+            };
+            (total, actual_start, chunk)
+        } else {
+            let all_lines = self.read_all_lines(file_path)?;
+            let total = all_lines.len();
+            let actual_start = start_line.min(total.saturating_sub(1));
+            let chunk = all_lines.into_iter().skip(actual_start).take(self.max_lines).collect();
+            (total, actual_start, chunk)
+        };
+        
+        self.total_lines = total.max(1);
+        
+        if chunk.is_empty() {
+            self.lines.push_back(String::new());
+            self.start_line_number = 0;
+            return Ok(());
+        }
+
+        for line in chunk {
+            if line.len() > MAX_LINE_LENGTH {
+                self.lines.push_back(clip_line(&line).to_string());
+            } else {
+                self.lines.push_back(line);
+            }
+        }
+        
+        self.start_line_number = actual_start;
+        self.disk_start = actual_start;
+        self.disk_count = self.lines.len();
+        Ok(())
+    }
+
+    // Puts the chunk aside before another part of the file is loaded in its place, unless
+    // it still holds exactly the lines it was read from
+    fn stash_chunk(&mut self) -> io::Result<()> {
+        let source = match self.source.clone() {
+            Some(source) if self.mapped.is_none() => source,
+            _ => return Ok(()),
+        };
+        let lines = std::mem::take(&mut self.lines);
+        let mut same = lines.len() == self.disk_count;
+        if same && self.disk_count > 0 {
+            let mut y = 0;
+            let end = self.disk_start + self.disk_count - 1;
+            let compared = self.stream_lines(&source, self.disk_start, end, |line| {
+                same &= lines.get(y).is_some_and(|chunk_line| chunk_line == line);
+                y += 1;
+                Ok(())
+            });
+            if let Err(e) = compared {
+                self.lines = lines;
+                return Err(e);
+            }
+            same &= y == self.disk_count;
+        }
+        if same {
+            self.lines = lines;
+        } else {
+            self.stashed.insert(self.disk_start, (self.disk_count, lines));
+        }
+        Ok(())
+    }
+
+    // `load_chunk` while edited chunks are stashed: `start_line` counts lines as the
+    // buffer sees them. A stashed chunk the new window reaches is taken back whole, so the
+    // lines of one edit are never split between the window and the stash.
+    fn load_stashed_chunk(&mut self, file_path: &PathBuf, start_line: usize) -> io::Result<()> {
+        let disk_total = if self.encoding == UTF_8 {
+            if !self.index.as_ref().is_some_and(|index| index.is_current(file_path)) {
+                self.index = Some(LineIndex::build(file_path)?);
+            }
+            self.index.as_ref().map_or(0, |index| index.total_lines)
+        } else {
+            self.read_all_lines(file_path)?.len()
+        };
+        let total = self.stashed.values().fold(disk_total, |total, (count, lines)| total + lines.len() - count);
+        let start_line = start_line.min(total.saturating_sub(1));
+
+        // Find the line of the file the window starts at, or the stashed chunk it starts in
+        let (mut y, mut disk) = (0, 0);
+        let mut start = None;
+        for (&stash_start, (count, lines)) in self.stashed.iter() {
+            if start_line < y + (stash_start - disk) {
+                break;
+            }
+            y += stash_start - disk;
+            if start_line < y + lines.len() {
+                start = Some((y, stash_start));
+                break;
+            }
+            y += lines.len();
+            disk = stash_start + count;
+        }
+        let (actual_start, disk_start) = start.unwrap_or((start_line, disk + (start_line - y)));
+
+        // The window reaches at least `max_lines` past `start_line`, even when it had to
+        // start earlier
+        let window_end = start_line + self.max_lines;
+        let mut chunk = VecDeque::new();
+        let mut taken = Vec::new();
+        let mut disk = disk_start;
+        while actual_start + chunk.len() < window_end {
+            let next = self.stashed.range(disk..).next().map(|(&stash_start, _)| stash_start);
+            let wanted = (next.unwrap_or(disk_total) - disk).min(window_end - actual_start - chunk.len());
+            if wanted > 0 {
+                self.stream_lines(file_path, disk, disk + wanted - 1, |line| {
+                    chunk.push_back(clip_line(line).to_string());
+                    Ok(())
+                })?;
+                disk += wanted;
+            }
+            match next {
+                Some(stash_start) if stash_start == disk && actual_start + chunk.len() < window_end => {
+                    let (count, lines) = &self.stashed[&stash_start];
+                    chunk.extend(lines.iter().cloned());
+                    disk += count;
+                    taken.push(stash_start);
+                }
+                _ => break,
+            }
+        }
+        for stash_start in taken {
+            self.stashed.remove(&stash_start);
+        }
+        if chunk.is_empty() {
+            chunk.push_back(String::new());
+        }
+
+        self.lines = chunk;
+        self.source = Some(file_path.clone());
+        self.start_line_number = actual_start;
+        self.disk_start = disk_start;
+        self.disk_count = disk - disk_start;
+        self.total_lines = total.max(1);
+        Ok(())
+    }
+
+    // Writes the whole file: lines before and after the chunk come from `source`, the
+    // chunk and any stashed chunks from memory. Lines are separated by the buffer's line
+    // ending, which also ends the last line when `final_newline` is set, unless the
+    // buffer is a single empty line.
+    fn write_spliced<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let encoding = self.encoding;
+        let separator = self.line_ending.as_str();
+        let trim = self.trim_trailing_whitespace;
+        let mut first = true;
+        let mut empty = true;
+        let mut put = |line: &str| -> io::Result<()> {
+            if !first {
+                writer.write_all(separator.as_bytes())?;
+                empty = false;
+            }
+            first = false;
+            let line = if trim { line.trim_end() } else { line };
+            empty &= line.is_empty();
+            Self::write_encoded(writer, line, encoding, None)
+        };
+
+        self.for_each_file_line(|_, line| put(line))?;
+        if self.final_newline && !empty {
+            Self::write_encoded(writer, "", encoding, Some(self.line_ending))?;
+        }
+        Ok(())
+    }
+
+    // Visits every line of the file as the buffer sees it, with its line number
+    fn for_each_file_line<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnMut(usize, &str) -> io::Result<()>,
+    {
+        self.for_each_file_line_in(0, usize::MAX, f)
+    }
+
+    // Visits lines `start..=end` of the file as the buffer sees it: the chunk and the
+    // stashed chunks come from memory, the lines between them are streamed from `source`
+    fn for_each_file_line_in<F>(&mut self, start: usize, end: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(usize, &str) -> io::Result<()>,
+    {
+        let stashed = std::mem::take(&mut self.stashed);
+        let chunk = std::mem::take(&mut self.lines);
+        let mut pieces: Vec<(usize, usize, &VecDeque<String>)> =
+            stashed.iter().map(|(&disk_start, (count, lines))| (disk_start, *count, lines)).collect();
+        pieces.push((self.disk_start, self.disk_count, &chunk));
+        pieces.sort_by_key(|&(disk_start, _, _)| disk_start);
+        let result = self.visit_pieces(&pieces, start, end, &mut f);
+        self.stashed = stashed;
+        self.lines = chunk;
+        result
+    }
+
+    fn visit_pieces<F>(
+        &mut self,
+        pieces: &[(usize, usize, &VecDeque<String>)],
+        start: usize,
+        end: usize,
+        f: &mut F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(usize, &str) -> io::Result<()>,
+    {
+        let in_memory: usize = pieces.iter().map(|piece| piece.2.len()).sum();
+        let replaced: usize = pieces.iter().map(|piece| piece.1).sum();
+        let disk_total = (self.total_lines + replaced).saturating_sub(in_memory);
+        let source = self.source.clone();
+        let (mut y, mut disk) = (0, 0);
+        for &(disk_start, count, lines) in pieces {
+            if disk_start > disk {
+                if let Some(ref source) = source {
+                    self.stream_span(source, y, disk, disk_start - disk, (start, end), f)?;
+                }
+                y += disk_start - disk;
+            }
+            for line in lines {
+                if y > end {
+                    return Ok(());
+                }
+                if y >= start {
+                    f(y, line)?;
+                }
+                y += 1;
+            }
+            disk = disk_start + count;
+        }
+        // Only read the source again when the chunks end before the file does, so a
+        // fully loaded file still saves after being deleted on disk
+        if let Some(ref source) = source {
+            if disk < disk_total {
+                self.stream_span(source, y, disk, usize::MAX, (start, end), f)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Streams the `count` lines of `source` from `disk` on, which are lines `y..` of the
+    // buffer, to `f` where they fall inside `range`
+    fn stream_span<F>(
+        &mut self,
+        source: &PathBuf,
+        y: usize,
+        disk: usize,
+        count: usize,
+        range: (usize, usize),
+        f: &mut F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(usize, &str) -> io::Result<()>,
+    {
+        let first = range.0.max(y);
+        let last = range.1.min(y.saturating_add(count - 1));
+        if first > last {
+            return Ok(());
+        }
+        let mut at = first;
+        self.stream_lines(source, disk + (first - y), disk.saturating_add(last - y), |line| {
+            f(at, line)?;
+            at += 1;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Takes the line ending used by most lines in the first 64 KiB of the file and
+    // whether its last line is terminated, so saving writes them back the same way.
+    // A file that is one unterminated line stays that way; an empty one gets the
+    // defaults.
+    fn detect_line_format(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let mut head = Vec::new();
+        (&mut file).take(64 * 1024).read_to_end(&mut head)?;
+        let newlines = head.iter().filter(|&&b| b == b'\n').count();
+        if newlines == 0 {
+            if !head.is_empty() {
+                self.final_newline = false;
+            }
+            return Ok(());
+        }
+        let crlf = head.windows(2).filter(|pair| pair == b"\r\n").count();
+        self.line_ending = if crlf * 2 > newlines { LineEnding::Dos } else { LineEnding::Unix };
+
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        self.final_newline = last[0] == b'\n';
+        Ok(())
+    }
+
+    // After a save the file on disk holds exactly the buffer's lines
+    fn mark_saved(&mut self, path: &Path) {
+        self.stashed.clear();
+        self.source = Some(path.to_path_buf());
+        self.disk_start = self.start_line_number;
+        self.disk_count = self.lines.len();
+    }
+
+    // Feeds lines `start..=end` of the file on disk to `f` one at a time without loading
+    // the rest, so slices of huge files stay cheap. Returns how many lines were visited.
+    fn stream_lines<F>(&mut self, file_path: &PathBuf, start: usize, end: usize, mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+    {
+        let mut count = 0;
+        // `end` may be usize::MAX for "to the end of the file"
+        let wanted = end.saturating_add(1).saturating_sub(start);
+        self.check_mapping(file_path)?;
+        if let Some(mapped) = self.mapped.as_mut() {
+            mapped.scan_to(end.saturating_add(1));
+            for i in start..=end.min(mapped.known_lines() - 1) {
+                f(&mapped.line(i))?;
+                count += 1;
+            }
+        } else if self.encoding == UTF_8 {
+            // Seek close to `start` when the index still describes the file
+            let indexed = match self.index.as_ref() {
+                Some(index) if index.is_current(file_path) => Some(index.lines_from(start)?),
+                _ => None,
+            };
+            let lines: Box<dyn Iterator<Item = io::Result<String>>> = match indexed {
+                Some(lines) => Box::new(lines),
+                None => Box::new(BufReader::new(File::open(file_path)?).lines().skip(start)),
+            };
+            for line in lines.take(wanted) {
+                f(&line?)?;
+                count += 1;
+            }
+        } else {
+            // Legacy encodings are decoded as a whole
+            for line in self.read_all_lines(file_path)?.iter().skip(start).take(wanted) {
+                f(line)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // A mapped file that shrank faults on any read past its new end. A plain `--mmap`
+    // view stops using the mapping and reads the file normally from then on; a segmented
+    // long-line view has no other way to read the file, so it is mapped again.
+    fn check_mapping(&mut self, file_path: &PathBuf) -> io::Result<()> {
+        let (len, width) = match self.mapped.as_ref() {
+            Some(mapped) => (mapped.map.len() as u64, mapped.segment_width),
+            None => return Ok(()),
+        };
+        // A deleted file stays mapped in full, so only a smaller size on disk matters
+        if !fs::metadata(file_path).is_ok_and(|meta| meta.len() < len) {
+            return Ok(());
+        }
+        self.mapped = if width > 0 {
+            Some(MappedFile::open_segmented(file_path, width)?)
+        } else {
+            // The mapped view was read-only, so the loaded lines are not edits to stash
+            self.source = None;
+            None
+        };
+        Ok(())
+    }
+
+    // Indexes a mapped file through line `line`, or all of it for `usize::MAX`, so
+    // `total_lines` covers the lines asked for
+    fn scan_mapped(&mut self, file_path: &PathBuf, line: usize) -> io::Result<()> {
+        self.check_mapping(file_path)?;
+        if let Some(mapped) = self.mapped.as_mut() {
+            if line == usize::MAX {
+                mapped.scan_all();
+            } else {
+                mapped.scan_to(line);
+            }
+            self.total_lines = mapped.known_lines();
+        }
+        Ok(())
+    }
+
+    fn load_mapped_chunk(&mut self, file_path: &Path, start_line: usize) -> io::Result<()> {
+        let max_lines = self.max_lines;
+        let mapped = match self.mapped.as_mut() {
+            Some(mapped) => mapped,
+            None => return Ok(()),
+        };
+        // Index a chunk beyond the window so moving past its end triggers the next load
+        mapped.scan_to(start_line + 2 * max_lines);
+        let known = mapped.known_lines();
+        let actual_start = start_line.min(known - 1);
+        let end = (actual_start + max_lines).min(known);
+        for i in actual_start..end {
+            self.lines.push_back(mapped.line(i));
+        }
+
+        self.start_line_number = actual_start;
+        self.source = Some(file_path.to_path_buf());
+        self.disk_start = actual_start;
+        self.disk_count = self.lines.len();
+        self.total_lines = known;
+        Ok(())
+    }
+
+    fn get_line(&self, index: usize) -> Option<&String> {
+        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
+            self.lines.get(index - self.start_line_number)
+        } else {
+            None
+        }
+    }
+
+    fn get_line_mut(&mut self, index: usize) -> Option<&mut String> {
+        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
+            self.lines.get_mut(index - self.start_line_number)
+        } else {
+            None
+        }
+    }
+
+    fn insert_line(&mut self, index: usize, content: String) {
+        if index >= self.start_line_number && index <= self.start_line_number + self.lines.len() {
+            let local_index = index - self.start_line_number;
+            let truncated = if content.len() > MAX_LINE_LENGTH {
+                clip_line(&content).to_string()
+            } else {
+                content
+            };
+            // The chunk may outgrow `max_lines` while editing; dropping lines here would
+            // lose them on save
+            self.lines.insert(local_index, truncated);
+            self.total_lines += 1;
+            self.shift_virtual_text(index, 0, 1);
+        }
+    }
+
+    // Replaces `count` loaded lines starting at `index` with `new_lines`
+    fn replace_lines(&mut self, index: usize, count: usize, new_lines: &[String]) -> bool {
+        if index < self.start_line_number || index + count > self.start_line_number + self.lines.len() {
+            return false;
+        }
+
+        let local_index = index - self.start_line_number;
+        self.lines.drain(local_index..local_index + count);
+        for (i, line) in new_lines.iter().enumerate() {
+            let truncated = if line.len() > MAX_LINE_LENGTH {
+                clip_line(line).to_string()
+            } else {
+                line.clone()
+            };
+            self.lines.insert(local_index + i, truncated);
+        }
+
+        self.total_lines = (self.total_lines + new_lines.len()).saturating_sub(count);
+        self.shift_virtual_text(index, count, new_lines.len());
+        if self.total_lines == 0 {
+            self.lines.push_back(String::new());
+            self.total_lines = 1;
+        }
+        true
+    }
+
+    fn remove_line(&mut self, index: usize) -> Option<String> {
+        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
+            let local_index = index - self.start_line_number;
+            self.total_lines = self.total_lines.saturating_sub(1);
+            if self.total_lines == 0 {
+                self.total_lines = 1;
+            }
+            self.shift_virtual_text(index, 1, 0);
+            self.lines.remove(local_index)
+        } else {
+            None
+        }
+    }
+}
+
+// A file open in a buffer that isn't shown. The shown buffer's state lives in the
+// matching `Editor` fields; switching swaps it with a slot of `Editor::buffers`.
+struct BufferState {
+    buffer: LineBuffer,
+    cursor: Cursor,
+    offset_y: usize,
+    offset_x: usize,
+    filename: Option<PathBuf>,
+    modified: bool,
+    read_only: bool,
+    undo_stack: VecDeque<UndoGroup>,
+    redo_stack: Vec<UndoGroup>,
+    undo_base: usize,
+    saved_undo_state: Option<usize>,
+    merge_base: Option<String>,
+    on_disk: bool,
+    deleted: bool,
+    disk_stamp: Option<(SystemTime, u64)>,
+    changed_on_disk: bool,
+    last_saved: Option<Instant>,
+    modified_since: Option<Instant>,
+    following: bool,
+    follow_state: Option<(u64, u64)>,
+    // Indentation is per file, since `.editorconfig` may set it differently for each
+    tabstop: usize,
+    expandtab: bool,
+    indent_defaults: Option<(usize, bool)>,
+}
+
+impl BufferState {
+    fn new() -> Self {
+        Self {
+            buffer: LineBuffer::new(MAX_VISIBLE_LINES),
+            cursor: Cursor { x: 0, y: 0 },
+            offset_y: 0,
+            offset_x: 0,
+            filename: None,
+            modified: false,
+            read_only: false,
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            undo_base: 0,
+            saved_undo_state: Some(0),
+            merge_base: None,
+            on_disk: false,
+            deleted: false,
+            disk_stamp: None,
+            changed_on_disk: false,
+            last_saved: None,
+            modified_since: None,
+            following: false,
+            follow_state: None,
+            tabstop: TAB_STOP,
+            expandtab: true,
+            indent_defaults: None,
+        }
+    }
+}
+
+// Where one pane looks in the buffer. The focused pane's view is the editor's own
+// `cursor` and offsets; the other one's is kept in `Split` until focus moves.
+struct ViewState {
+    cursor: Cursor,
+    offset_y: usize,
+    offset_x: usize,
+}
+
+// `:split` / `:vsplit`: two panes on the same buffer
+struct Split {
+    // Side by side rather than one above the other
+    vertical: bool,
+    // Whether the focused pane is the right or bottom one
+    focus_second: bool,
+    other: ViewState,
+    // Lines the other pane shows from outside the loaded chunk, read from disk. `lines_key`
+    // records what they were read for, so they're only read again when that changes.
+    lines: HashMap<usize, String>,
+    lines_key: (usize, usize, usize, usize, usize),
+}
+
+struct Editor {
+    buffer: LineBuffer,
+    cursor: Cursor,
+    offset_y: usize,
+    mode: Mode,
+    filename: Option<PathBuf>,
+    modified: bool,
+    status_message: String,
+    command_buffer: String,
+    search_query: String,
+    replace_query: String,
+    replace_with: String,
+    confirm_replace: Option<ConfirmReplace>,
+    syntax_set: Option<SyntaxSet>,
+    theme: Option<Theme>,
+    // Loaded with the syntaxes, or by `:theme`; `theme_name` picks from it
+    theme_set: Option<ThemeSet>,
+    theme_name: String,
+    syntax_enabled: bool,
+    highlights: Option<HighlightCache>,
+    show_line_numbers: bool,
+    relative_numbers: bool,
+    show_help: bool,
+    show_eob: bool,
+    eob_char: char,
+    overwrite: bool,
+    confirm_mkdir: bool,
+    pending_prompt: Option<Prompt>,
+    pending_keys: String,
+    clipboard: String,
+    undo_stack: VecDeque<UndoGroup>,
+    // Groups taken back by undo, most recent last; any new edit discards them
+    redo_stack: Vec<UndoGroup>,
+    // The text is identified by the id of the last undo group applied to it, or
+    // `undo_base` with none left: the id of the last one trimmed, 0 for none at all.
+    // `saved_undo_state` is the one matching the file, if undo can still reach it.
+    undo_base: usize,
+    saved_undo_state: Option<usize>,
+    quit_requested: bool,
+    needs_reload: bool,
+    restore_position: bool,
+    search_whole_word: bool,
+    // `/` treats the query as a regex instead of literal text; toggled with Ctrl+R
+    search_is_regex: bool,
+    // Case folding for `/`, `n`/`N` and `*`/`#`, kept for the session; `smartcase`
+    // turns it off for queries with capitals
+    ignorecase: bool,
+    smartcase: bool,
+    // Matches of the last search are highlighted until `:noh`; the next search turns
+    // the highlighting back on
+    highlight_search: bool,
+    // Cursor, scroll offset and highlighting from before `/`, restored when the search
+    // is cancelled
+    search_origin: Option<(Cursor, usize, bool)>,
+    command_history: History,
+    search_history: History,
+    undo_break: UndoBreak,
+    visual_anchor: Cursor,
+    formatters: HashMap<String, String>,
+    autoformat: bool,
+    popup: Option<Popup>,
+    read_only: bool,
+    following: bool,
+    follow_state: Option<(u64, u64)>,
+    iskeyword: KeywordSet,
+    yank_flash: Option<(usize, usize, Instant)>,
+    yank_flash_duration: Duration,
+    yank_flash_color: Color,
+    cursorline: bool,
+    cursorcolumn: bool,
+    colorcolumns: Vec<usize>,
+    visual_lines: Option<(usize, usize)>,
+    visual_columns: Option<(usize, usize)>,
+    last_saved: Option<Instant>,
+    modified_since: Option<Instant>,
+    save_reminder: Duration,
+    alternate_pairs: Vec<(String, String)>,
+    alternate_dirs: Vec<String>,
+    pending_dead_key: Option<char>,
+    wildmenu: Option<Wildmenu>,
+    show_wildmenu: bool,
+    status_rows: u16,
+    tabstop: usize,
+    expandtab: bool,
+    marker_kinds: Vec<MarkerKind>,
+    // Hard wrapping of typed text (`textwidth`) and soft wrapping on screen (`wrap`)
+    // are separate settings; either works without the other. Without `wrap`, long
+    // lines scroll sideways from screen column `offset_x`.
+    textwidth: usize,
+    wrap: bool,
+    // Whether the file existed when it was last loaded or saved, and whether it has
+    // disappeared from disk since; checked every tick
+    on_disk: bool,
+    deleted: bool,
+    // Modification time and size of the file as last loaded or saved, and whether
+    // another program has rewritten it since
+    disk_stamp: Option<(SystemTime, u64)>,
+    changed_on_disk: bool,
+    searchcount: bool,
+    undolevels: usize,
+    // Set by `:q` and friends; the main loop exits and restores the terminal
+    exit_requested: bool,
+    search_cache: Option<SearchCache>,
+    block_cache: Option<BlockCache>,
+    // Locations from the last `:references`, stepped through with `:cn` / `:cp`
+    quickfix: Vec<QuickfixEntry>,
+    quickfix_index: usize,
+    // A `:references` search running on a background thread, with the word it looks for
+    references_job: Option<(String, mpsc::Receiver<Vec<QuickfixEntry>>)>,
+    // The file's text as last loaded or saved, the common ancestor for `:merge`. Only
+    // kept when the whole file fits in the buffer.
+    merge_base: Option<String>,
+    // `:set winwidth=N` lays the text out N columns wide, centred, as if the terminal
+    // were that narrow; 0 uses the full width
+    winwidth: usize,
+    // The `:set` indent settings an `.editorconfig` replaced, put back before the next
+    // file loads so one project's rules don't follow the user into another
+    indent_defaults: Option<(usize, bool)>,
+    offset_x: usize,
+    stickyscroll: bool,
+    // Home goes to the first non-blank before column 0 (`:set nosmarthome` turns it off)
+    smarthome: bool,
+    system_clipboard: bool,
+    block_insert: Option<BlockInsert>,
+    // Every open buffer, in the order opened. The active one's slot is a placeholder
+    // while its state is in the fields above.
+    buffers: Vec<BufferState>,
+    active_buffer: usize,
+    split: Option<Split>,
+    // Ctrl+W was pressed; the next key picks a pane
+    pending_window: bool,
+    // Where the last frame put the focused pane, the other pane of a split and the tab
+    // strip, for mapping mouse clicks
+    pane_area: Rect,
+    other_pane_area: Option<Rect>,
+    tab_bar_area: Rect,
+    // Where a left-button drag started; moving away from it starts a visual selection
+    mouse_anchor: Option<Cursor>,
+    // Keystrokes recorded with `q<reg>`, replayed with `@<reg>`
+    macros: HashMap<char, Vec<KeyEvent>>,
+    recording: Option<(char, Vec<KeyEvent>)>,
+    last_macro: Option<char>,
+    macro_depth: usize,
+    // Registers being played back right now, innermost last
+    playing_macros: Vec<char>,
+    // Edits recorded so far; a key sequence that moves it made a change
+    edit_count: usize,
+    // Keys typed since normal mode was last idle, with `edit_count` at the first of
+    // them. Once they finish an edit they become `last_change`, which `.` replays.
+    change_keys: Vec<KeyEvent>,
+    change_edits: usize,
+    last_change: Vec<KeyEvent>,
+}
+
+impl Editor {
+    fn new() -> Self {
+        Self {
+            buffer: LineBuffer::new(MAX_VISIBLE_LINES),
+            cursor: Cursor { x: 0, y: 0 },
+            offset_y: 0,
+            mode: Mode::Normal,
+            filename: None,
+            modified: false,
+            status_message: "TuxPad - Press F1 for help | ESC for normal mode".to_string(),
+            command_buffer: String::new(),
+            search_query: String::new(),
+            replace_query: String::new(),
+            replace_with: String::new(),
+            confirm_replace: None,
+            syntax_set: None,
+            theme: None,
+            theme_set: None,
+            theme_name: "base16-ocean.dark".to_string(),
+            syntax_enabled: true,
+            highlights: None,
+            show_line_numbers: true,
+            relative_numbers: false,
+            show_help: false,
+            show_eob: true,
+            eob_char: '~',
+            overwrite: false,
+            confirm_mkdir: true,
+            pending_prompt: None,
+            pending_keys: String::new(),
+            clipboard: String::new(),
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            undo_base: 0,
+            saved_undo_state: Some(0),
+            quit_requested: false,
+            needs_reload: false,
+            restore_position: true,
+            search_whole_word: false,
+            search_is_regex: false,
+            ignorecase: false,
+            smartcase: false,
+            highlight_search: false,
+            search_origin: None,
+            command_history: History::default(),
+            search_history: History::default(),
+            undo_break: UndoBreak::Word,
+            visual_anchor: Cursor { x: 0, y: 0 },
+            formatters: default_formatters(),
+            autoformat: false,
+            popup: None,
+            read_only: false,
+            following: false,
+            follow_state: None,
+            iskeyword: KeywordSet::default(),
+            yank_flash: None,
+            yank_flash_duration: Duration::from_millis(300),
+            yank_flash_color: Color::Rgb(120, 90, 20),
+            cursorline: true,
+            cursorcolumn: false,
+            colorcolumns: Vec::new(),
+            visual_lines: None,
+            visual_columns: None,
+            last_saved: None,
+            modified_since: None,
+            save_reminder: Duration::from_secs(10 * 60),
+            alternate_pairs: [("c", "h"), ("cc", "h"), ("cpp", "hpp"), ("cpp", "h"), ("cxx", "hxx"), ("m", "h")]
+                .iter()
+                .map(|(a, b)| (a.to_string(), b.to_string()))
+                .collect(),
+            alternate_dirs: [".", "../include", "../src", "include", "src"].iter().map(|d| d.to_string()).collect(),
+            pending_dead_key: None,
+            wildmenu: None,
+            show_wildmenu: true,
+            status_rows: 1,
+            tabstop: TAB_STOP,
+            expandtab: true,
+            marker_kinds: MarkerKind::ALL.to_vec(),
+            textwidth: 0,
+            wrap: false,
+            on_disk: false,
+            deleted: false,
+            disk_stamp: None,
+            changed_on_disk: false,
+            searchcount: true,
+            undolevels: MAX_UNDO_GROUPS,
+            exit_requested: false,
+            search_cache: None,
+            block_cache: None,
+            quickfix: Vec::new(),
+            quickfix_index: 0,
+            references_job: None,
+            merge_base: None,
+            winwidth: 0,
+            indent_defaults: None,
+            offset_x: 0,
+            stickyscroll: false,
+            smarthome: true,
+            system_clipboard: false,
+            block_insert: None,
+            buffers: vec![BufferState::new()],
+            active_buffer: 0,
+            split: None,
+            pending_window: false,
+            pane_area: Rect::default(),
+            other_pane_area: None,
+            tab_bar_area: Rect::default(),
+            mouse_anchor: None,
+            macros: HashMap::new(),
+            recording: None,
+            last_macro: None,
+            macro_depth: 0,
+            playing_macros: Vec::new(),
+            edit_count: 0,
+            change_keys: Vec::new(),
+            change_edits: 0,
+            last_change: Vec::new(),
+        }
+    }
+
+    fn load_file(&mut self, path: &PathBuf) -> io::Result<()> {
+        // A file that is one huge line would be cut at MAX_LINE_LENGTH, so view it in
+        // fixed-size pieces instead. Saving those pieces would add newlines, hence read-only.
+        let segmented = self.buffer.mapped.is_none() && MappedFile::starts_with_long_line(path);
+        if segmented {
+            self.buffer.mapped = Some(MappedFile::open_segmented(path, LONG_LINE_SEGMENT)?);
+            self.read_only = true;
+        }
+        // New files end with a newline; existing ones keep what they had
+        self.buffer.line_ending = LineEnding::Unix;
+        self.buffer.final_newline = true;
+        if path.is_file() {
+            self.buffer.detect_line_format(path)?;
+        }
+        self.apply_editorconfig(path);
+        self.buffer.stashed.clear();
+        self.buffer.load_chunk(path, 0)?;
+        
+        self.filename = Some(path.clone());
+        self.search_cache = None;
+        self.block_cache = None;
+        self.merge_base = self.buffer_text();
+        self.on_disk = path.exists();
+        self.deleted = false;
+        self.disk_stamp = self.read_disk_stamp();
+        self.changed_on_disk = false;
+        self.cursor = Cursor { x: 0, y: 0 };
+        self.offset_y = 0;
+        self.mark_unmodified();
+        self.status_message = format!("Loaded: {} ({} lines)", path.display(), self.buffer.total_lines);
+        if segmented {
+            self.status_message = format!(
+                "{} has very long lines, shown in {}-byte pieces [read-only]",
+                path.display(),
+                LONG_LINE_SEGMENT
+            );
+        } else {
+            self.load_syntax_for(path);
+        }
+        if !self.read_only {
+            if let Some(pid) = SwapFile::claim(path) {
+                self.status_message = format!(
+                    "Warning: {} is already being edited by process {}",
+                    path.display(),
+                    pid
+                );
+            }
+        }
+
+        if self.restore_position {
+            if let Some((y, x)) = PositionStore::lookup(path) {
+                self.jump_to_saved_position(y, x)?;
+            }
+            // Keeps the recent files list in opening order
+            self.remember_position();
+        }
+        Ok(())
+    }
+
+    // Applies the `.editorconfig` sections matching `path` on top of the `:set` values
+    // and the line format found in the file. They only take effect as the file loads,
+    // so a `:set` afterwards still wins.
+    fn apply_editorconfig(&mut self, path: &Path) {
+        if let Some((tabstop, expandtab)) = self.indent_defaults.take() {
+            self.tabstop = tabstop;
+            self.expandtab = expandtab;
+        }
+        let config = EditorConfig::for_file(path);
+        self.buffer.trim_trailing_whitespace = config.get("trim_trailing_whitespace") == Some("true");
+        match config.get("insert_final_newline") {
+            Some("true") => self.buffer.final_newline = true,
+            Some("false") => self.buffer.final_newline = false,
+            _ => {}
+        }
+
+        let defaults = (self.tabstop, self.expandtab);
+        match config.get("indent_style") {
+            Some("space") => self.expandtab = true,
+            Some("tab") => self.expandtab = false,
+            _ => {}
+        }
+        let width = config.get("tab_width")
+            .or(config.get("indent_size"))
+            .and_then(|width| width.parse::<usize>().ok())
+            .filter(|width| (1..=32).contains(width));
+        if let Some(width) = width {
+            self.tabstop = width;
+        }
+        if (self.tabstop, self.expandtab) != defaults {
+            self.indent_defaults = Some(defaults);
+        }
+
+        match config.get("end_of_line") {
+            Some("lf") => self.buffer.line_ending = LineEnding::Unix,
+            Some("crlf") => self.buffer.line_ending = LineEnding::Dos,
+            _ => {}
+        }
+        if let Some(charset) = config.get("charset") {
+            let label = if charset == "utf-8-bom" { "utf-8" } else { charset };
+            if let Some(encoding) = Encoding::for_label(label.as_bytes()) {
+                self.buffer.encoding = encoding;
+            }
+        }
+    }
+
+    // Syntax definitions are only worth loading for files that can be highlighted;
+    // plain text and `--no-syntax` starts skip them entirely
+    fn load_syntax_for(&mut self, path: &Path) {
+        if !self.syntax_enabled || self.syntax_set.is_some() {
+            return;
+        }
+        let highlightable = path.extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| !matches!(ext, "txt" | "log" | "csv" | "tsv"));
+        if !highlightable {
+            return;
+        }
+
+        let theme_set = self.theme_set.get_or_insert_with(ThemeSet::load_defaults);
+        self.theme = theme_set.themes.get(&self.theme_name)
+            .or_else(|| theme_set.themes.values().next())
+            .cloned();
+        self.syntax_set = Some(SyntaxSet::load_defaults_newlines());
+    }
+
+    // `:theme <name>` switches the colour scheme; the highlight cache is dropped so the
+    // next frame redraws every line in the new colours
+    fn set_theme(&mut self, name: &str) {
+        let theme_set = self.theme_set.get_or_insert_with(ThemeSet::load_defaults);
+        match theme_set.themes.get(name) {
+            Some(theme) => {
+                self.theme = Some(theme.clone());
+                self.theme_name = name.to_string();
+                self.highlights = None;
+                self.status_message = format!("Theme: {}", name);
+            }
+            None => self.status_message = format!("Unknown theme: {} (:theme lists them)", name),
+        }
+    }
+
+    // `:theme` alone lists the available themes; Enter picks one
+    fn open_theme_list(&mut self) {
+        let theme_set = self.theme_set.get_or_insert_with(ThemeSet::load_defaults);
+        let names: Vec<String> = theme_set.themes.keys().cloned().collect();
+        let mut popup = Popup::new(PopupKind::Themes, " Themes ", names);
+        popup.selected = popup.items.iter().position(|name| *name == self.theme_name).unwrap_or(0);
+        self.popup = Some(popup);
+    }
+
+    // The whole buffer as one newline-terminated string, when all of it is loaded
+    fn buffer_text(&self) -> Option<String> {
+        if self.buffer.start_line_number != 0 || self.buffer.lines.len() < self.buffer.total_lines {
+            return None;
+        }
+        let mut text = String::new();
+        for line in self.buffer.lines.iter() {
+            text.push_str(line);
+            text.push('\n');
+        }
+        Some(text)
+    }
+
+    // `:merge` reloads a file that changed on disk without dropping unsaved edits: a
+    // three-way merge of the text as loaded, the buffer and the file now. Clean merges
+    // are applied as one undoable edit; conflicting hunks are left in the buffer between
+    // `<<<<<<<` / `>>>>>>>` markers for the user to resolve.
+    fn merge_from_disk(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        let path = match self.filename.clone() {
+            Some(path) if path.exists() => path,
+            _ => {
+                self.status_message = "No file on disk to merge from".to_string();
+                return Ok(());
+            }
+        };
+        let (base, ours) = match (self.merge_base.clone(), self.buffer_text()) {
+            (Some(base), Some(ours)) => (base, ours),
+            _ => {
+                self.status_message = "Merging needs the whole file loaded".to_string();
+                return Ok(());
+            }
+        };
+        let mut theirs = String::new();
+        for line in self.buffer.read_all_lines(&path)? {
+            theirs.push_str(&line);
+            theirs.push('\n');
+        }
+        if theirs == base {
+            self.status_message = "File on disk is unchanged".to_string();
+            return Ok(());
+        }
+
+        let (merged, clean) = match merge3(&base, &ours, &theirs) {
+            Ok(merged) => (merged, true),
+            Err(conflicted) => (conflicted, false),
+        };
+        let merged: Vec<String> = merged.lines().map(|line| line.to_string()).collect();
+        let merged = if merged.is_empty() { vec![String::new()] } else { merged };
+        let count = self.buffer.lines.len();
+        let changed = self.buffer.lines.iter().ne(merged.iter());
+        if changed {
+            self.replace_line_range(0, count, merged);
+        }
+        self.merge_base = Some(theirs.clone());
+        self.disk_stamp = self.read_disk_stamp();
+        self.changed_on_disk = false;
+        self.modified = self.buffer_text().is_none_or(|text| text != theirs);
+        self.cursor.y = self.cursor.y.min(self.buffer.total_lines.saturating_sub(1));
+        self.cursor.x = 0;
+
+        if clean {
+            self.status_message = "Merged changes from disk".to_string();
+        } else {
+            let conflicts = self.buffer.lines.iter().filter(|line| line.starts_with("<<<<<<<")).count();
+            if let Some(first) = self.buffer.lines.iter().position(|line| line.starts_with("<<<<<<<")) {
+                self.cursor.y = first;
+            }
+            self.status_message = format!("{} merge conflict(s) marked with <<<<<<< ... >>>>>>>", conflicts);
+        }
+        Ok(())
+    }
+
+    // `:e` reloads the file from disk; `:e!` does so over unsaved edits, after asking
+    fn reload_file(&mut self, force: bool) -> io::Result<()> {
+        if self.filename.is_none() {
+            self.status_message = "No file to reload".to_string();
+            return Ok(());
+        }
+        if self.modified && force {
+            self.status_message = "Discard your unsaved changes and reload from disk? (y/n)".to_string();
+            self.pending_prompt = Some(Prompt::DiscardAndReload);
+            return Ok(());
+        }
+        self.reopen_with_options("", force)
+    }
+
+    // `:e ++enc=<name> ++ff=unix|dos` reloads the current file with a different decoding
+    fn reopen_with_options(&mut self, args: &str, force: bool) -> io::Result<()> {
+        let path = match self.filename.clone() {
+            Some(path) => path,
+            None => {
+                self.status_message = "No file to reload".to_string();
+                return Ok(());
+            }
+        };
+        if self.modified && !force {
+            self.status_message = "No write since last change (add ! to override)".to_string();
+            return Ok(());
+        }
+
+        let mut encoding = self.buffer.encoding;
+        let mut line_ending = self.buffer.line_ending;
+        for option in args.split_whitespace() {
+            if let Some(name) = option.strip_prefix("++enc=") {
+                match Encoding::for_label(name.as_bytes()) {
+                    Some(found) => encoding = found,
+                    None => {
+                        self.status_message = format!("Unknown encoding: {}", name);
+                        return Ok(());
+                    }
+                }
+            } else if let Some(format) = option.strip_prefix("++ff=") {
+                line_ending = match format {
+                    "unix" => LineEnding::Unix,
+                    "dos" => LineEnding::Dos,
+                    _ => {
+                        self.status_message = format!("Unknown file format: {}", format);
+                        return Ok(());
+                    }
+                };
+            } else {
+                self.status_message = format!("Unknown option: {}", option);
+                return Ok(());
+            }
+        }
+
+        let cursor = self.cursor.clone();
+        self.buffer.encoding = encoding;
+        self.buffer.line_ending = line_ending;
+        self.cursor.y = cursor.y.min(self.buffer.total_lines.saturating_sub(1));
+        // Unsaved edits, loaded or stashed, are dropped rather than stashed
+        self.modified = false;
+        self.buffer.stashed.clear();
+        self.buffer.load_chunk(&path, self.cursor.y.saturating_sub(MAX_VISIBLE_LINES / 2))?;
+
+        // Keep the cursor where it was if the reloaded text still has that position
+        self.cursor.y = cursor.y.min(self.buffer.total_lines.saturating_sub(1));
+        let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+        self.cursor.x = if cursor.x < line_len { cursor.x } else { 0 };
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.undo_base = self.edit_count;
+        self.mark_unmodified();
+        self.search_cache = None;
+        self.block_cache = None;
+        self.merge_base = self.buffer_text();
+        self.disk_stamp = self.read_disk_stamp();
+        self.changed_on_disk = false;
+        self.deleted = false;
+        self.status_message = format!(
+            "Reloaded {} as {} ({})",
+            path.display(),
+            encoding.name(),
+            if line_ending == LineEnding::Dos { "dos" } else { "unix" }
+        );
+        Ok(())
+    }
+
+    fn jump_to_end_of_file(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.filename {
+            self.buffer.scan_mapped(path, usize::MAX)?;
+        }
+        self.cursor.y = self.buffer.total_lines.saturating_sub(1);
+        self.cursor.x = 0;
+        self.reload_current_chunk()
+    }
+
+    fn file_identity(&self) -> Option<(u64, u64)> {
+        let path = self.filename.as_ref()?;
+        fs::metadata(path).ok().map(|meta| (meta.ino(), meta.len()))
+    }
+
+    fn set_following(&mut self, follow: bool) -> io::Result<()> {
+        if follow && self.modified && !self.read_only {
+            self.status_message = "Save or discard your changes before following the file".to_string();
+            return Ok(());
+        }
+        if follow && self.filename.is_none() {
+            self.status_message = "No file to follow".to_string();
+            return Ok(());
+        }
+
+        self.following = follow;
+        if follow {
+            self.follow_state = self.file_identity();
+            self.jump_to_end_of_file()?;
+            self.status_message = "Following file (scroll up to pause)".to_string();
+        } else {
+            self.follow_state = None;
+            self.status_message = "Stopped following file".to_string();
+        }
+        Ok(())
+    }
+
+    // Called from the main loop when no input arrived within a tick
+    fn on_tick(&mut self) -> io::Result<()> {
+        self.track_unsaved_time();
+        if self.yank_flash.is_some_and(|(_, _, until)| Instant::now() >= until) {
+            self.yank_flash = None;
+        }
+        if self.following {
+            self.poll_followed_file()?;
+        }
+        self.poll_deleted_file();
+        self.poll_changed_file();
+        self.poll_references();
+        Ok(())
+    }
+
+    fn read_disk_stamp(&self) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(self.filename.as_ref()?).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    // Notices another program rewriting the open file. The warning is shown once; a
+    // later save asks before overwriting, and `:e!` or `:merge` take the new text in.
+    fn poll_changed_file(&mut self) {
+        if self.following || self.changed_on_disk || self.disk_stamp.is_none() {
+            return;
+        }
+        let stamp = self.read_disk_stamp();
+        if stamp.is_some() && stamp != self.disk_stamp {
+            self.changed_on_disk = true;
+            self.status_message = if self.modified {
+                "Warning: the file changed on disk (:e! reloads and drops your edits, :merge combines them)"
+            } else {
+                "Warning: the file changed on disk (:e! reloads it)"
+            }.to_string();
+        }
+    }
+
+    // Notices the open file being removed (`rm`, `git checkout`, ...) or coming back
+    fn poll_deleted_file(&mut self) {
+        let exists = match self.filename {
+            Some(ref path) => path.exists(),
+            None => return,
+        };
+        if self.on_disk && !exists {
+            self.on_disk = false;
+            self.deleted = true;
+            self.status_message = "Warning: the file was deleted on disk".to_string();
+        } else if self.deleted && exists {
+            self.on_disk = true;
+            self.deleted = false;
+            self.status_message = "The file is back on disk".to_string();
+        }
+    }
+
+    fn poll_followed_file(&mut self) -> io::Result<()> {
+        let (path, (old_inode, old_len)) = match (self.filename.clone(), self.follow_state) {
+            (Some(path), Some(state)) => (path, state),
+            _ => return Ok(()),
+        };
+        // A missing file mid-rotation is expected; try again next tick
+        let (inode, len) = match self.file_identity() {
+            Some(identity) => identity,
+            None => return Ok(()),
+        };
+        if inode == old_inode && len == old_len {
+            return Ok(());
+        }
+
+        let rotated = inode != old_inode || len < old_len;
+        let at_bottom = self.cursor.y + 1 >= self.buffer.total_lines;
+        if let Some(mapped) = self.buffer.mapped.take() {
+            self.buffer.mapped = Some(if rotated {
+                MappedFile::open(&path)?
+            } else {
+                mapped.remap_grown(&path)?
+            });
+        }
+        self.follow_state = Some((inode, len));
+
+        if rotated {
+            self.cursor.y = 0;
+            self.status_message = "File was rotated; reopened".to_string();
+        }
+        // Manual scrolling away from the bottom pauses following until the user returns
+        if at_bottom || rotated {
+            self.jump_to_end_of_file()?;
+        } else {
+            self.reload_current_chunk()?;
+            self.status_message = "Following paused (file grew)".to_string();
+        }
+        Ok(())
+    }
+
+    fn jump_to_saved_position(&mut self, y: usize, x: usize) -> io::Result<()> {
+        let last_line = self.buffer.total_lines.saturating_sub(1);
+        self.cursor.y = y.min(last_line);
+        self.reload_current_chunk()?;
+        let line_len = self.buffer.get_line(self.cursor.y).map(|l| l.chars().count()).unwrap_or(0);
+        self.cursor.x = x.min(line_len.saturating_sub(1));
+
+        if y > last_line {
+            self.status_message = format!(
+                "Last position (line {}) is past the end of the file; moved to line {}",
+                y + 1,
+                self.cursor.y + 1
+            );
+        }
+        Ok(())
+    }
+
+    fn remember_position(&self) {
+        if let Some(ref path) = self.filename {
+            // Losing the remembered position is not worth interrupting the user for
+            let _ = PositionStore::remember(path, self.cursor.y, self.cursor.x);
+        }
+    }
+
+    // Leaves the current file: remembers where we were and gives up its swap file
+    fn close_file(&self) {
+        self.remember_position();
+        if let Some(ref path) = self.filename {
+            SwapFile::release(path);
+        }
+    }
+
+    // `:e <path>` opens another file in a new buffer, keeping the current one (and any
+    // unsaved changes in it) open in the background. A file that is already open is
+    // switched to rather than loaded twice, and an unnamed, untouched buffer like the
+    // one at startup is reused.
+    fn edit_file(&mut self, path: &str, force: bool) -> io::Result<()> {
+        let path = PathBuf::from(path);
+        let same_file = self.filename.as_ref().is_some_and(|current| same_path(current, &path));
+        if same_file {
+            if force {
+                return self.reload_file(true);
+            }
+            self.status_message = format!("{} is already open in this buffer", path.display());
+            return Ok(());
+        }
+        let open_elsewhere = self.buffers.iter().enumerate().position(|(i, state)| {
+            i != self.active_buffer && state.filename.as_ref().is_some_and(|other| same_path(other, &path))
+        });
+        if let Some(index) = open_elsewhere {
+            self.switch_to_buffer(index);
+            return Ok(());
+        }
+
+        let previous = self.active_buffer;
+        let reuse = self.filename.is_none() && !self.modified;
+        if !reuse {
+            self.remember_position();
+            let state = self.take_buffer_state();
+            self.buffers[previous] = state;
+            self.buffers.push(BufferState::new());
+            self.active_buffer = self.buffers.len() - 1;
+        }
+        if let Err(e) = self.load_file(&path) {
+            if !reuse {
+                // Nothing to show for the new buffer, so go back to where we were
+                self.buffers.pop();
+                self.active_buffer = previous;
+                let state = std::mem::replace(&mut self.buffers[previous], BufferState::new());
+                self.restore_buffer_state(state);
+            }
+            return Err(e);
+        }
+        if self.buffers.len() > 1 {
+            self.status_message = format!("{} [{}/{}]", self.status_message, self.active_buffer + 1, self.buffers.len());
+        }
+        Ok(())
+    }
+
+    // Moves the shown buffer's state out of the editor, leaving an empty buffer in its
+    // place with the indentation `.editorconfig` had overridden put back
+    fn take_buffer_state(&mut self) -> BufferState {
+        let (tabstop, expandtab, indent_defaults) = (self.tabstop, self.expandtab, self.indent_defaults.take());
+        if let Some((default_tabstop, default_expandtab)) = indent_defaults {
+            self.tabstop = default_tabstop;
+            self.expandtab = default_expandtab;
+        }
+        BufferState {
+            buffer: std::mem::replace(&mut self.buffer, LineBuffer::new(MAX_VISIBLE_LINES)),
+            cursor: std::mem::replace(&mut self.cursor, Cursor { x: 0, y: 0 }),
+            offset_y: std::mem::take(&mut self.offset_y),
+            offset_x: std::mem::take(&mut self.offset_x),
+            filename: self.filename.take(),
+            modified: std::mem::take(&mut self.modified),
+            read_only: std::mem::take(&mut self.read_only),
+            undo_stack: std::mem::take(&mut self.undo_stack),
+            redo_stack: std::mem::take(&mut self.redo_stack),
+            undo_base: std::mem::take(&mut self.undo_base),
+            saved_undo_state: self.saved_undo_state.replace(0),
+            merge_base: self.merge_base.take(),
+            on_disk: std::mem::take(&mut self.on_disk),
+            deleted: std::mem::take(&mut self.deleted),
+            disk_stamp: self.disk_stamp.take(),
+            changed_on_disk: std::mem::take(&mut self.changed_on_disk),
+            last_saved: self.last_saved.take(),
+            modified_since: self.modified_since.take(),
+            following: std::mem::take(&mut self.following),
+            follow_state: self.follow_state.take(),
+            tabstop,
+            expandtab,
+            indent_defaults,
+        }
+    }
+
+    fn restore_buffer_state(&mut self, state: BufferState) {
+        self.buffer = state.buffer;
+        self.cursor = state.cursor;
+        self.offset_y = state.offset_y;
+        self.offset_x = state.offset_x;
+        self.filename = state.filename;
+        self.modified = state.modified;
+        self.read_only = state.read_only;
+        self.undo_stack = state.undo_stack;
+        self.redo_stack = state.redo_stack;
+        self.undo_base = state.undo_base;
+        self.saved_undo_state = state.saved_undo_state;
+        self.merge_base = state.merge_base;
+        self.on_disk = state.on_disk;
+        self.deleted = state.deleted;
+        self.disk_stamp = state.disk_stamp;
+        self.changed_on_disk = state.changed_on_disk;
+        self.last_saved = state.last_saved;
+        self.modified_since = state.modified_since;
+        self.following = state.following;
+        self.follow_state = state.follow_state;
+        self.tabstop = state.tabstop;
+        self.expandtab = state.expandtab;
+        self.indent_defaults = state.indent_defaults;
+        self.search_cache = None;
+        self.block_cache = None;
+        self.block_insert = None;
+        self.mode = Mode::Normal;
+        if let Some(path) = self.filename.clone() {
+            self.load_syntax_for(&path);
+        }
+        // Both panes show the active buffer, so the other one starts over on it too
+        if let Some(split) = self.split.as_mut() {
+            split.other = ViewState { cursor: self.cursor.clone(), offset_y: self.offset_y, offset_x: self.offset_x };
+            split.lines.clear();
+            split.lines_key = Default::default();
+        }
+    }
+
+    // `:split` / `:vsplit` shows the buffer in two panes, both starting at the cursor
+    fn open_split(&mut self, vertical: bool) {
+        if self.split.is_some() {
+            self.status_message = "Only two panes are supported (:only to go back to one)".to_string();
+            return;
+        }
+        self.split = Some(Split {
+            vertical,
+            focus_second: false,
+            other: ViewState { cursor: self.cursor.clone(), offset_y: self.offset_y, offset_x: self.offset_x },
+            lines: HashMap::new(),
+            lines_key: Default::default(),
+        });
+        self.status_message = "Ctrl+W and an arrow key moves between panes".to_string();
+    }
+
+    // `:close` drops the focused pane, `:only` the other one
+    fn close_split(&mut self, keep_focused: bool) {
+        if self.split.is_none() {
+            self.status_message = "There is only one pane".to_string();
+            return;
+        }
+        if !keep_focused {
+            self.swap_views();
+            let _ = self.ensure_line_loaded(self.cursor.y);
+        }
+        self.split = None;
+    }
+
+    // Trades the editor's view for the other pane's
+    fn swap_views(&mut self) {
+        if let Some(split) = self.split.as_mut() {
+            std::mem::swap(&mut self.cursor, &mut split.other.cursor);
+            std::mem::swap(&mut self.offset_y, &mut split.other.offset_y);
+            std::mem::swap(&mut self.offset_x, &mut split.other.offset_x);
+        }
+    }
+
+    // Ctrl+W followed by an arrow, `hjkl` or `w`: moves focus to the other pane, loading
+    // the part of the file it shows
+    fn handle_window_key(&mut self, key: KeyEvent) -> io::Result<()> {
+        self.pending_window = false;
+        let vertical = match self.split.as_ref() {
+            Some(split) => split.vertical,
+            None => {
+                self.status_message = "There is only one pane".to_string();
+                return Ok(());
+            }
+        };
+        let toward_other = match key.code {
+            KeyCode::Char('w') | KeyCode::Char('W') => true,
+            KeyCode::Left | KeyCode::Right | KeyCode::Char('h') | KeyCode::Char('l') => vertical,
+            KeyCode::Up | KeyCode::Down | KeyCode::Char('k') | KeyCode::Char('j') => !vertical,
+            KeyCode::Char('c') => {
+                self.close_split(true);
+                return Ok(());
+            }
+            KeyCode::Char('o') => {
+                self.close_split(false);
+                return Ok(());
+            }
+            _ => false,
+        };
+        if toward_other {
+            self.focus_other_pane()?;
+        }
+        Ok(())
+    }
+
+    fn focus_other_pane(&mut self) -> io::Result<()> {
+        self.swap_views();
+        if let Some(split) = self.split.as_mut() {
+            split.focus_second = !split.focus_second;
+        }
+        self.ensure_line_loaded(self.cursor.y)
+    }
+
+    // Reads the lines `first..last` that the unfocused pane shows but the chunk doesn't
+    // hold, as the buffer sees them
+    fn load_split_lines(&mut self, first: usize, last: usize) {
+        let buffer = &mut self.buffer;
+        let split = match self.split.as_mut() {
+            Some(split) => split,
+            None => return,
+        };
+        let key = (first, last, buffer.start_line_number, buffer.lines.len(), buffer.disk_count);
+        if split.lines_key == key {
+            return;
+        }
+        split.lines_key = key;
+        split.lines.clear();
+        if buffer.source.is_none() || first >= last {
+            return;
+        }
+        let chunk_start = buffer.start_line_number;
+        let chunk_end = chunk_start + buffer.lines.len();
+        let _ = buffer.for_each_file_line_in(first, last - 1, |y, line| {
+            if y < chunk_start || y >= chunk_end {
+                split.lines.insert(y, line.to_string());
+            }
+            Ok(())
+        });
+    }
+
+    fn switch_to_buffer(&mut self, index: usize) {
+        if index >= self.buffers.len() || index == self.active_buffer {
+            return;
+        }
+        let state = self.take_buffer_state();
+        self.buffers[self.active_buffer] = state;
+        let state = std::mem::replace(&mut self.buffers[index], BufferState::new());
+        self.active_buffer = index;
+        self.restore_buffer_state(state);
+        self.status_message = format!(
+            "\"{}\" [{}/{}] {} lines{}",
+            self.buffer_name(),
+            index + 1,
+            self.buffers.len(),
+            self.buffer.total_lines,
+            if self.modified { " [modified]" } else { "" }
+        );
+    }
+
+    fn buffer_name(&self) -> String {
+        self.filename.as_ref().map_or_else(|| "[New File]".to_string(), |path| path.display().to_string())
+    }
+
+    // `:bn` / `:bp`
+    fn cycle_buffer(&mut self, forward: bool) {
+        let count = self.buffers.len();
+        if count == 1 {
+            self.status_message = "Only one buffer is open".to_string();
+            return;
+        }
+        let index = if forward { (self.active_buffer + 1) % count } else { (self.active_buffer + count - 1) % count };
+        self.switch_to_buffer(index);
+    }
+
+    // `:bd` closes the shown buffer and shows the next one. Unsaved changes are asked
+    // about first unless `!` is given. Closing the last buffer leaves an empty one.
+    fn delete_buffer(&mut self, force: bool) {
+        if self.modified && !force {
+            self.status_message = format!("{} has unsaved changes. Close it anyway? (y/n)", self.buffer_name());
+            self.pending_prompt = Some(Prompt::CloseBuffer);
+            return;
+        }
+        let name = self.buffer_name();
+        self.close_file();
+        self.take_buffer_state();
+        if self.buffers.len() > 1 {
+            self.buffers.remove(self.active_buffer);
+            self.active_buffer = self.active_buffer.min(self.buffers.len() - 1);
+            let state = std::mem::replace(&mut self.buffers[self.active_buffer], BufferState::new());
+            self.restore_buffer_state(state);
+        }
+        self.status_message = format!("Closed {}", name);
+    }
+
+    // The tab strip shows once more than one buffer is open
+    fn tab_bar_visible(&self) -> bool {
+        self.buffers.len() > 1
+    }
+
+    // Tab strip labels for every open buffer with the column each starts at. Full paths
+    // are shown when they all fit in `width`, otherwise just the file names.
+    fn tab_labels(&self, width: usize) -> Vec<(usize, String)> {
+        let label = |path: Option<&PathBuf>, modified: bool, short: bool| {
+            let name = match path {
+                Some(path) if short => path.file_name().map_or_else(
+                    || path.display().to_string(),
+                    |name| name.to_string_lossy().into_owned(),
+                ),
+                Some(path) => path.display().to_string(),
+                None => "[New File]".to_string(),
+            };
+            format!(" {}{} ", name, if modified { " ●" } else { "" })
+        };
+        let labels_with = |short: bool| -> Vec<String> {
+            self.buffers
+                .iter()
+                .enumerate()
+                .map(|(i, state)| {
+                    if i == self.active_buffer {
+                        label(self.filename.as_ref(), self.modified, short)
+                    } else {
+                        label(state.filename.as_ref(), state.modified, short)
+                    }
+                })
+                .collect()
+        };
+        let label_width = |label: &String| label.chars().map(char_width).sum::<usize>();
+
+        let mut labels = labels_with(false);
+        if labels.iter().map(|label| label_width(label) + 1).sum::<usize>() > width {
+            labels = labels_with(true);
+        }
+        let mut column = 0;
+        labels
+            .into_iter()
+            .map(|label| {
+                let start = column;
+                column += label_width(&label) + 1;
+                (start, label)
+            })
+            .collect()
+    }
+
+    fn render_tab_bar(&self, frame: &mut Frame, area: Rect) {
+        let mut spans = Vec::new();
+        for (i, (_, label)) in self.tab_labels(area.width as usize).into_iter().enumerate() {
+            let style = if i == self.active_buffer {
+                Style::default().bg(Color::Blue).fg(Color::White).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().bg(Color::Rgb(50, 50, 50)).fg(Color::Gray)
+            };
+            spans.push(Span::styled(label, style));
+            spans.push(Span::raw(" "));
+        }
+        let tabs = Paragraph::new(Line::from(spans)).style(Style::default().bg(Color::Rgb(30, 30, 30)));
+        frame.render_widget(tabs, area);
+    }
+
+    // True if this buffer or any in the background has changes that aren't saved
+    fn any_modified(&self) -> bool {
+        self.modified || self.others_modified()
+    }
+
+    fn others_modified(&self) -> bool {
+        self.buffers.iter().enumerate().any(|(i, state)| i != self.active_buffer && state.modified)
+    }
+
+    // On exit every open file remembers its position and gives up its swap file
+    fn close_all_buffers(&self) {
+        self.close_file();
+        for (i, state) in self.buffers.iter().enumerate() {
+            if let (true, Some(path)) = (i != self.active_buffer, state.filename.as_ref()) {
+                let _ = PositionStore::remember(path, state.cursor.y, state.cursor.x);
+                SwapFile::release(path);
+            }
+        }
+    }
+
+    // Files related to `path`: the other half of each extension pair (looked up in every
+    // alternate directory) and, for Rust, `foo.rs` <-> `foo/mod.rs`
+    fn alternate_candidates(&self, path: &Path) -> Vec<PathBuf> {
+        let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let stem = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => stem,
+            None => return Vec::new(),
+        };
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+        let mut candidates = Vec::new();
+        if ext == "rs" {
+            if stem == "mod" {
+                if let (Some(module), Some(grandparent)) = (dir.file_name(), dir.parent()) {
+                    candidates.push(grandparent.join(format!("{}.rs", module.to_string_lossy())));
+                }
+            } else {
+                candidates.push(dir.join(stem).join("mod.rs"));
+            }
+        }
+        for (a, b) in &self.alternate_pairs {
+            let other = if ext == a {
+                b
+            } else if ext == b {
+                a
+            } else {
+                continue;
+            };
+            for alt_dir in &self.alternate_dirs {
+                candidates.push(dir.join(alt_dir).join(format!("{}.{}", stem, other)));
+            }
+        }
+        candidates
+    }
+
+    // `:A` opens the first related file that exists
+    fn open_alternate(&mut self, force: bool) -> io::Result<()> {
+        let path = match self.filename.clone() {
+            Some(path) => path,
+            None => {
+                self.status_message = "No file name to find an alternate for".to_string();
+                return Ok(());
+            }
+        };
+        match self.alternate_candidates(&path).into_iter().find(|candidate| candidate.is_file()) {
+            Some(alternate) => self.edit_file(&alternate.to_string_lossy(), force),
+            None => {
+                self.status_message = format!("No alternate file found for {}", path.display());
+                Ok(())
+            }
+        }
+    }
+
+    // Exiting from here would skip `main`'s terminal cleanup, so only ask for it
+    fn exit_editor(&mut self) {
+        self.exit_requested = true;
+    }
+
+    fn file_extension(&self) -> String {
+        self.filename.as_ref()
+            .and_then(|p| p.extension())
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_string()
+    }
+
+    // Runs the filetype's formatter over the buffer, replacing it as one undoable edit.
+    // Returns an error description if formatting was skipped or failed.
+    fn format_buffer(&mut self) -> Result<(), String> {
+        if self.read_only {
+            return Err("Buffer is readonly".to_string());
+        }
+        let extension = self.file_extension();
+        let command = match self.formatters.get(&extension) {
+            Some(command) => command.clone(),
+            None => return Ok(()),
+        };
+        if self.buffer.start_line_number != 0 || self.buffer.lines.len() < self.buffer.total_lines {
+            return Err("file too large to format in memory".to_string());
+        }
+
+        let input: Vec<String> = self.buffer.lines.iter().cloned().collect();
+        let formatted = run_filter(&command, &(input.join("\n") + "\n"))?;
+        let new_lines: Vec<String> = formatted.lines().map(|line| line.to_string()).collect();
+        if new_lines != input {
+            let count = input.len();
+            self.replace_line_range(0, count, new_lines);
+            self.cursor.y = self.cursor.y.min(self.buffer.total_lines.saturating_sub(1));
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+        }
+        Ok(())
+    }
+
+    // Writes the buffer out. Without `force` an unmodified buffer whose file still exists
+    // and still holds what was loaded is left alone, so the mtime does not change and file
+    // watchers stay quiet.
+    fn save_file(&mut self, force: bool) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if !force && !self.modified && self.filename.as_ref().is_some_and(|path| path.is_file()) {
+            // Another program's changes make the write a real one, asked about below
+            self.poll_changed_file();
+            if !self.changed_on_disk {
+                self.status_message = "No changes to write (use :w! to write anyway)".to_string();
+                return Ok(());
+            }
+        }
+
+        // Saving would silently bring back a file someone meant to delete, so ask first
+        if self.deleted && !force && self.filename.is_some() {
+            self.status_message = "File was deleted externally. Recreate it? (y/n)".to_string();
+            self.pending_prompt = Some(Prompt::RecreateDeleted { quit_after: false });
+            return Ok(());
+        }
+        // Likewise for changes another program made since we loaded the file
+        if self.changed_on_disk && !force && self.filename.is_some() {
+            self.status_message = "File changed on disk since it was loaded. Overwrite it? (y/n)".to_string();
+            self.pending_prompt = Some(Prompt::OverwriteChanged { quit_after: false });
+            return Ok(());
+        }
+
+        let format_error = if self.autoformat && self.filename.is_some() {
+            self.format_buffer().err()
+        } else {
+            None
+        };
+        if self.buffer.trim_trailing_whitespace {
+            self.trim_loaded_lines();
+        }
+
+        if let Some(path) = self.filename.clone() {
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() && !parent.exists() && self.confirm_mkdir {
+                    self.status_message = format!("Create directory {}? (y/n)", parent.display());
+                    self.pending_prompt = Some(Prompt::CreateDir {
+                        dir: parent.to_path_buf(),
+                        quit_after: false,
+                    });
+                    return Ok(());
+                }
+                fs::create_dir_all(parent)?;
+            }
+            
+            // Remember mode and owner so rewriting never drops `+x` or hands the file to us
+            let original = fs::metadata(&path).ok();
+            let atomic = self.write_whole_file(&path)?;
+            self.buffer.mark_saved(&path);
+            self.merge_base = self.buffer_text();
+            let ownership_kept = match original {
+                Some(ref metadata) => Self::restore_metadata(&path, metadata)?,
+                None => true,
+            };
+            self.mark_unmodified();
+            self.on_disk = true;
+            self.deleted = false;
+            self.disk_stamp = self.read_disk_stamp();
+            self.changed_on_disk = false;
+            self.last_saved = Some(Instant::now());
+            self.modified_since = None;
+            self.remember_position();
+            self.status_message = format!(
+                "Saved: {} ({} lines, {} bytes)",
+                path.display(),
+                self.buffer.total_lines,
+                fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0)
+            );
+            if let Some(error) = format_error {
+                self.status_message.push_str(&format!(" │ format skipped: {}", error));
+            }
+            if !ownership_kept {
+                self.status_message.push_str(" │ warning: could not keep the file's owner");
+            }
+            if !atomic {
+                self.status_message.push_str(" │ warning: rewritten in place, not atomically");
+            }
+        } else {
+            self.status_message = "No filename specified. Use :w filename to save".to_string();
+        }
+        Ok(())
+    }
+
+    // Strips trailing whitespace from the loaded lines as one undoable edit, so the
+    // screen matches what `trim_trailing_whitespace` writes
+    fn trim_loaded_lines(&mut self) {
+        let start = self.buffer.start_line_number;
+        let mut edits = Vec::new();
+        for (i, line) in self.buffer.lines.iter_mut().enumerate() {
+            let trimmed = line.trim_end().to_string();
+            if trimmed.len() != line.len() {
+                let old = std::mem::replace(line, trimmed.clone());
+                edits.push(EditOp::ReplaceRange { start: start + i, old: vec![old], new: vec![trimmed] });
+            }
+        }
+        if !edits.is_empty() {
+            self.record_edits(edits);
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+        }
+    }
+
+    // `:w <file>` writes a copy and keeps editing under the current name, `:saveas <file>`
+    // writes and carries on under the new one. Another existing file is only replaced
+    // after asking, or straight away with `!`.
+    fn write_to(&mut self, target: &str, rename: bool, force: bool) -> io::Result<()> {
+        if target.is_empty() {
+            self.status_message = if rename { "Usage: :saveas <file>" } else { "Usage: :w <file>" }.to_string();
+            return Ok(());
+        }
+        let path = PathBuf::from(target);
+        // An unnamed buffer takes the name it is first written under
+        let rename = rename || self.filename.is_none();
+        if self.filename.as_ref().is_some_and(|current| same_path(current, &path)) {
+            return self.save_file(force);
+        }
+        if rename && self.refuse_read_only() {
+            return Ok(());
+        }
+        if path.exists() && !force {
+            self.status_message = format!("{} already exists. Overwrite it? (y/n)", path.display());
+            self.pending_prompt = Some(Prompt::OverwriteFile { path, rename });
+            return Ok(());
+        }
+        self.write_to_path(path, rename)
+    }
+
+    fn write_to_path(&mut self, path: PathBuf, rename: bool) -> io::Result<()> {
+        if rename {
+            if let Some(ref old) = self.filename {
+                SwapFile::release(old);
+            }
+            SwapFile::claim(&path);
+            self.filename = Some(path);
+            self.disk_stamp = None;
+            self.changed_on_disk = false;
+            self.deleted = false;
+            return self.save_file(true);
+        }
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                self.status_message = format!("No such directory: {}", parent.display());
+                return Ok(());
+            }
+        }
+        let atomic = self.write_whole_file(&path)?;
+        self.status_message = format!(
+            "Wrote {} ({} lines, {} bytes)",
+            path.display(),
+            self.buffer.total_lines,
+            fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0)
+        );
+        if !atomic {
+            self.status_message.push_str(" │ warning: rewritten in place, not atomically");
+        }
+        Ok(())
+    }
+
+    // Writes the buffer spliced into the rest of the file to a temporary file next to
+    // `path`, then renames it over `path` once it is flushed to disk: a failed write
+    // (disk full, crash) leaves the original untouched, and the lines being copied are
+    // never read from a half-written file. The temp file carries the process id so two
+    // editors saving the same file don't share it. A symlink is followed so the file it
+    // points to is replaced rather than the link. A file with other hard links, or in a
+    // directory we cannot create files in, is rewritten in place from memory instead,
+    // which a crash can leave half-written. Returns false when that happened.
+    fn write_whole_file(&mut self, path: &PathBuf) -> io::Result<bool> {
+        let path = &fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        let linked = fs::metadata(path).is_ok_and(|metadata| metadata.nlink() > 1);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("tuxpad");
+        let temp = path.with_file_name(format!(".{}.tuxpad.tmp.{}", name, std::process::id()));
+        let file = if linked { None } else { File::create(&temp).ok() };
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let mut contents = Vec::new();
+                self.buffer.write_spliced(&mut contents)?;
+                fs::write(path, contents)?;
+                return Ok(false);
+            }
+        };
+
+        let mut writer = BufWriter::new(file);
+        let written = self.buffer.write_spliced(&mut writer)
+            .and_then(|_| writer.flush())
+            .and_then(|_| writer.get_ref().sync_all());
+        drop(writer);
+        // The mode goes on before the rename so the file never shows up with the temp
+        // file's default permissions; ownership is restored by the caller
+        let written = written.and_then(|_| match fs::metadata(path) {
+            Ok(original) => fs::set_permissions(&temp, original.permissions()),
+            Err(_) => Ok(()),
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&temp, path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    // Reapplies the permission bits of the file as it was before saving and tries to keep
+    // its owner. Returns false when the owner could not be restored.
+    fn restore_metadata(path: &PathBuf, original: &fs::Metadata) -> io::Result<bool> {
+        fs::set_permissions(path, fs::Permissions::from_mode(original.mode() & 0o7777))?;
+        let current = fs::metadata(path)?;
+        if current.uid() == original.uid() && current.gid() == original.gid() {
+            return Ok(true);
+        }
+        Ok(std::os::unix::fs::chown(path, Some(original.uid()), Some(original.gid())).is_ok())
+    }
+
+    // Records an edit, folding it into the previous undo group when it continues a typing run
+    fn record_edit(&mut self, op: EditOp) {
+        self.edit_count += 1;
+        self.redo_stack.clear();
+        self.search_cache = None;
+        self.block_cache = None;
+        let continues = self.undo_stack.back()
+            .is_some_and(|group| self.continues_undo_group(group, &op));
+
+        if continues {
+            if let Some(group) = self.undo_stack.back_mut() {
+                group.ops.push(op);
+                group.last_edit = Instant::now();
+            }
+        } else {
+            self.push_undo_group(vec![op]);
+        }
+    }
+
+    // Records several edits as one undo step
+    fn record_edits(&mut self, ops: Vec<EditOp>) {
+        if !ops.is_empty() {
+            self.push_undo_group(ops);
+            self.seal_undo_group();
+        }
+    }
+
+    fn push_undo_group(&mut self, ops: Vec<EditOp>) {
+        self.edit_count += 1;
+        self.redo_stack.clear();
+        self.search_cache = None;
+        self.block_cache = None;
+        self.seal_undo_group();
+        self.undo_stack.push_back(UndoGroup {
+            id: self.edit_count,
+            ops,
+            last_edit: Instant::now(),
+            sealed: false,
+        });
+        self.trim_undo_history();
+    }
+
+    // Drops the oldest groups beyond `:set undolevels`
+    fn trim_undo_history(&mut self) {
+        while self.undo_stack.len() > self.undolevels {
+            if let Some(group) = self.undo_stack.pop_front() {
+                self.undo_base = group.id;
+            }
+        }
+    }
+
+    fn undo_state(&self) -> usize {
+        self.undo_stack.back().map_or(self.undo_base, |group| group.id)
+    }
+
+    // The text now matches the file. Sealing the last group keeps later typing out of it,
+    // so undo can come back to exactly this point.
+    fn mark_unmodified(&mut self) {
+        self.seal_undo_group();
+        self.saved_undo_state = Some(self.undo_state());
+        self.modified = false;
+    }
+
+    fn seal_undo_group(&mut self) {
+        if let Some(group) = self.undo_stack.back_mut() {
+            group.sealed = true;
+        }
+    }
+
+    fn continues_undo_group(&self, group: &UndoGroup, op: &EditOp) -> bool {
+        if group.sealed {
+            return false;
+        }
+        // Leaving insert mode seals the group, so this spans exactly one session
+        if self.undo_break == UndoBreak::Insert && self.mode == Mode::Insert {
+            return true;
+        }
+        if group.last_edit.elapsed() > UNDO_COALESCE_WINDOW {
+            return false;
+        }
+        let last = match group.ops.last() {
+            Some(last) => last,
+            None => return false,
+        };
+
+        match (last, op) {
+            (EditOp::InsertChar { y: ly, x: lx, c: lc }, EditOp::InsertChar { y, x, c }) => {
+                ly == y && lx + lc.len_utf8() == *x && match self.undo_break {
+                    UndoBreak::Char => false,
+                    UndoBreak::Insert => true,
+                    // Start a new step at the first character typed after whitespace
+                    UndoBreak::Word => !lc.is_whitespace() || c.is_whitespace(),
+                }
+            }
+            // Overwrite mode removes the character under the cursor before each insert
+            (EditOp::InsertChar { y: ly, x: lx, c: lc }, EditOp::DeleteChar { y, x, .. }) => {
+                self.overwrite && ly == y && lx + lc.len_utf8() == *x && self.undo_break != UndoBreak::Char
+            }
+            (EditOp::DeleteChar { y: ly, x: lx, .. }, EditOp::InsertChar { y, x, .. }) => {
+                self.overwrite && ly == y && lx == x
+            }
+            _ => false,
+        }
+    }
+
+    // Every editing operation asks this first. Read-only buffers (`-R`, `:set readonly`,
+    // memory-mapped files) refuse the change and say why.
+    fn refuse_read_only(&mut self) -> bool {
+        if self.read_only {
+            self.status_message = if self.buffer.mapped.is_some() {
+                "Buffer is readonly (memory-mapped file)".to_string()
+            } else {
+                "Buffer is readonly (:set noreadonly to edit)".to_string()
+            };
+        }
+        self.read_only
+    }
+
+    fn undo(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        let group = match self.undo_stack.pop_back() {
+            Some(group) => group,
+            None => {
+                self.status_message = "Already at oldest change".to_string();
+                return Ok(());
+            }
+        };
+
+        for op in group.ops.iter().rev() {
+            self.apply_edit(&op.inverse())?;
+        }
+        self.move_to_edit(&group);
+        self.modified = self.saved_undo_state != Some(self.undo_state());
+        self.status_message = format!("Undid {} change(s)", group.ops.len());
+        self.redo_stack.push(UndoGroup { sealed: true, ..group });
+        Ok(())
+    }
+
+    fn redo(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        let group = match self.redo_stack.pop() {
+            Some(group) => group,
+            None => {
+                self.status_message = "Already at newest change".to_string();
+                return Ok(());
+            }
+        };
+
+        for op in group.ops.iter() {
+            self.apply_edit(op)?;
+        }
+        self.move_to_edit(&group);
+        self.status_message = format!("Redid {} change(s)", group.ops.len());
+        // Pushed directly: going through push_undo_group would clear the rest of the redo stack
+        self.seal_undo_group();
+        self.undo_stack.push_back(group);
+        self.trim_undo_history();
+        self.modified = self.saved_undo_state != Some(self.undo_state());
+        Ok(())
+    }
+
+    // Puts the cursor where an undone or redone group started
+    fn move_to_edit(&mut self, group: &UndoGroup) {
+        if let Some(first) = group.ops.first() {
+            let (y, x) = first.position();
+            self.cursor.y = y.min(self.buffer.total_lines.saturating_sub(1));
+            self.set_cursor_byte(x);
+        }
+    }
+
+    fn ensure_line_loaded(&mut self, y: usize) -> io::Result<()> {
+        if self.buffer.get_line(y).is_none() {
+            self.cursor.y = y;
+            self.reload_current_chunk()?;
+        }
+        Ok(())
+    }
+
+    // Applies an edit to the buffer without recording it
+    fn apply_edit(&mut self, op: &EditOp) -> io::Result<()> {
+        self.search_cache = None;
+        self.block_cache = None;
+        match op {
+            EditOp::InsertChar { y, x, c } => {
+                self.ensure_line_loaded(*y)?;
+                if let Some(line) = self.buffer.get_line_mut(*y) {
+                    if line.is_char_boundary(*x) {
+                        line.insert(*x, *c);
+                    }
+                }
+            }
+            EditOp::DeleteChar { y, x, .. } => {
+                self.ensure_line_loaded(*y)?;
+                if let Some(line) = self.buffer.get_line_mut(*y) {
+                    Self::remove_char_at(line, *x);
+                }
+            }
+            EditOp::SplitLine { y, x } => {
+                self.ensure_line_loaded(*y)?;
+                if let Some(line) = self.buffer.get_line_mut(*y) {
+                    let split_pos = (*x).min(line.len());
+                    let tail = line.split_off(split_pos);
+                    self.buffer.insert_line(*y + 1, tail);
+                }
+            }
+            EditOp::JoinLine { y, .. } => {
+                self.ensure_line_loaded(*y)?;
+                if let Some(next) = self.buffer.remove_line(*y + 1) {
+                    if let Some(line) = self.buffer.get_line_mut(*y) {
+                        line.push_str(&next);
+                    }
+                }
+            }
+            EditOp::ReplaceRange { start, old, new } => {
+                self.ensure_line_loaded(*start)?;
+                self.buffer.replace_lines(*start, old.len(), new);
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces `count` lines starting at `start` as a single undoable edit
+    fn replace_line_range(&mut self, start: usize, count: usize, new_lines: Vec<String>) -> bool {
+        let old: Vec<String> = (start..start + count)
+            .filter_map(|i| self.buffer.get_line(i).cloned())
+            .collect();
+        if old.len() != count || !self.buffer.replace_lines(start, count, &new_lines) {
+            return false;
+        }
+        self.record_edits(vec![EditOp::ReplaceRange { start, old, new: new_lines }]);
+        self.modified = true;
+        true
+    }
+
+    fn insert_char(&mut self, c: char) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if self.buffer.get_line(self.cursor.y).is_none() {
+            // Need to reload chunk
+            self.reload_current_chunk()?;
+        }
+
+        let overwrite = self.overwrite;
+        let y = self.cursor.y;
+        let mut ops = Vec::new();
+        if let Some(line) = self.buffer.get_line_mut(y) {
+            if line.len() < MAX_LINE_LENGTH {
+                let insert_pos = char_to_byte(line, self.cursor.x);
+                // A combining mark decorates the previous character instead of replacing the next one
+                if overwrite && !is_combining_mark(c) {
+                    if let Some(removed) = Self::remove_char_at(line, insert_pos) {
+                        ops.push(EditOp::DeleteChar { y, x: insert_pos, c: removed });
+                    }
+                }
+                line.insert(insert_pos, c);
+                ops.push(EditOp::InsertChar { y, x: insert_pos, c });
+                self.cursor.x += 1;
+                self.modified = true;
+            } else {
+                self.status_message = "Line too long".to_string();
+            }
+        }
+        for op in ops {
+            self.record_edit(op);
+        }
+        Ok(())
+    }
+
+    // Removes the whole character starting at `pos` so overwriting never splits a UTF-8 sequence
+    fn remove_char_at(line: &mut String, pos: usize) -> Option<char> {
+        if pos < line.len() && line.is_char_boundary(pos) {
+            Some(line.remove(pos))
+        } else {
+            None
+        }
+    }
+
+    // Typed characters in insert mode go through here so that dead-key accents and
+    // separately delivered combining marks end up as one composed character
+    fn insert_typed_char(&mut self, c: char) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if let Some(dead) = self.pending_dead_key.take() {
+            let mark = dead_key_mark(dead).unwrap_or(dead);
+            return match compose(c, mark) {
+                Some(composed) => self.insert_char(composed),
+                // Accent + space gives the accent itself, like most compose implementations
+                None if c == ' ' => self.insert_char(dead),
+                None => {
+                    self.insert_char(dead)?;
+                    self.insert_char(c)
+                }
+            };
+        }
+        if dead_key_mark(c).is_some() {
+            self.pending_dead_key = Some(c);
+            self.status_message = format!("{} (compose {})", self.insert_mode_label(), c);
+            return Ok(());
+        }
+        if is_combining_mark(c) {
+            let y = self.cursor.y;
+            let previous = self.buffer.get_line(y).and_then(|line| {
+                line[..char_to_byte(line, self.cursor.x)].char_indices().next_back()
+            });
+            if let Some((x, base)) = previous {
+                if let Some(composed) = compose(base, c) {
+                    if let Some(line) = self.buffer.get_line_mut(y) {
+                        line.replace_range(x..x + base.len_utf8(), &composed.to_string());
+                        self.modified = true;
+                        self.record_edit(EditOp::DeleteChar { y, x, c: base });
+                        self.record_edit(EditOp::InsertChar { y, x, c: composed });
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        self.insert_char(c)?;
+        // Like vim, the break happens once a non-blank lands past the limit
+        if c != ' ' {
+            self.hard_wrap()?;
+        }
+        Ok(())
+    }
+
+    // With `textwidth` set, breaks the cursor line at the last space that keeps it within
+    // the limit. The edits join the current typing undo step.
+    fn hard_wrap(&mut self) -> io::Result<()> {
+        if self.textwidth == 0 {
+            return Ok(());
+        }
+        let y = self.cursor.y;
+        let line = match self.buffer.get_line(y) {
+            Some(line) => line.clone(),
+            None => return Ok(()),
+        };
+        if display_column(&line, line.len(), self.tabstop) <= self.textwidth {
+            return Ok(());
+        }
+        let indent_len = line.len() - line.trim_start().len();
+        // A single word longer than the limit stays as it is
+        let space = match line
+            .char_indices()
+            .filter(|&(i, c)| c == ' ' && i > indent_len && display_column(&line, i, self.tabstop) <= self.textwidth)
+            .map(|(i, _)| i)
+            .next_back()
+        {
+            Some(space) => space,
+            None => return Ok(()),
+        };
+
+        let cursor_x = self.cursor.x;
+        let space_x = byte_to_char(&line, space);
+        if let Some(line) = self.buffer.get_line_mut(y) {
+            line.remove(space);
+        }
+        self.record_edit(EditOp::DeleteChar { y, x: space, c: ' ' });
+        self.cursor.x = space_x;
+        self.insert_newline()?;
+        if cursor_x > space_x {
+            self.cursor.x = cursor_x - space_x - 1;
+        } else {
+            self.cursor = Cursor { x: cursor_x, y };
+        }
+        Ok(())
+    }
+
+    // The run of non-blank lines around `y`
+    fn paragraph_bounds(&self, y: usize) -> (usize, usize) {
+        let blank = |i: usize| self.buffer.get_line(i).is_none_or(|line| line.trim().is_empty());
+        let mut start = y;
+        while start > 0 && !blank(start - 1) {
+            start -= 1;
+        }
+        let mut end = y;
+        while end + 1 < self.buffer.total_lines && !blank(end + 1) {
+            end += 1;
+        }
+        (start, end)
+    }
+
+    // `gq`: refills lines `start..=end` to `textwidth` (79 when unset), keeping the first
+    // line's indentation
+    fn reflow_lines(&mut self, start: usize, end: usize) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let width = if self.textwidth == 0 { 79 } else { self.textwidth };
+        let lines: Vec<String> = (start..=end).filter_map(|i| self.buffer.get_line(i).cloned()).collect();
+        if lines.len() != end + 1 - start {
+            self.status_message = "Lines are outside the loaded chunk".to_string();
+            return;
+        }
+        let indent: String = lines[0].chars().take_while(|c| c.is_whitespace()).collect();
+
+        let mut reflowed = Vec::new();
+        let mut current = String::new();
+        for word in lines.iter().flat_map(|line| line.split_whitespace()) {
+            let word_width: usize = word.chars().map(char_width).sum();
+            if !current.is_empty() && display_column(&current, current.len(), self.tabstop) + 1 + word_width > width {
+                reflowed.push(std::mem::take(&mut current));
+            }
+            if current.is_empty() {
+                current.push_str(&indent);
+            } else {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        if !current.is_empty() || reflowed.is_empty() {
+            reflowed.push(current);
+        }
+
+        let produced = reflowed.len();
+        if reflowed != lines {
+            self.replace_line_range(start, end + 1 - start, reflowed);
+        }
+        self.cursor = Cursor { x: 0, y: start };
+        self.status_message = format!("Reflowed {} line(s) into {}", end + 1 - start, produced);
+    }
+
+    fn insert_mode_label(&self) -> String {
+        if self.overwrite { "-- REPLACE --" } else { "-- INSERT --" }.to_string()
+    }
+
+    fn delete_char(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if self.cursor.x > 0 {
+            let y = self.cursor.y;
+            let mut removed = None;
+            if let Some(line) = self.buffer.get_line_mut(y) {
+                // Step back over a whole character, multi-byte ones included
+                let end = char_to_byte(line, self.cursor.x);
+                if let Some((x, _)) = line[..end].char_indices().next_back() {
+                    removed = Some((x, line.remove(x)));
+                    self.cursor.x = byte_to_char(line, x);
+                    self.modified = true;
+                }
+            }
+            if let Some((x, c)) = removed {
+                self.record_edit(EditOp::DeleteChar { y, x, c });
+            }
+        } else if self.cursor.y > 0 {
+            // Handle line joining carefully for large files
+            if let (Some(current_line), Some(prev_line)) = (
+                self.buffer.get_line(self.cursor.y).cloned(),
+                self.buffer.get_line_mut(self.cursor.y - 1)
+            ) {
+                if prev_line.len() + current_line.len() < MAX_LINE_LENGTH {
+                    let new_x = prev_line.len();
+                    self.cursor.x = prev_line.chars().count();
+                    prev_line.push_str(&current_line);
+                    self.buffer.remove_line(self.cursor.y);
+                    self.cursor.y -= 1;
+                    self.modified = true;
+                    self.record_edit(EditOp::JoinLine { y: self.cursor.y, x: new_x });
+                } else {
+                    self.status_message = "Cannot join: resulting line would be too long".to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_newline(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if let Some(current_line) = self.buffer.get_line(self.cursor.y).cloned() {
+            let split_pos = char_to_byte(&current_line, self.cursor.x);
+            let new_line = current_line[split_pos..].to_string();
+            
+            if let Some(line) = self.buffer.get_line_mut(self.cursor.y) {
+                line.truncate(split_pos);
+            }
+            
+            self.buffer.insert_line(self.cursor.y + 1, new_line);
+            self.record_edit(EditOp::SplitLine { y: self.cursor.y, x: split_pos });
+            self.cursor.y += 1;
+            self.cursor.x = 0;
+            self.modified = true;
+        }
+        Ok(())
+    }
+
+    fn move_cursor(&mut self, dx: isize, dy: isize) -> io::Result<()> {
+        // Vertical moves keep the screen column, which differs from the character index
+        // once a line holds tabs or wide characters
+        let column = self.cursor_display_column();
+        
+        // Vertical movement
+        if dy != 0 {
+            let new_y = (self.cursor.y as isize + dy).max(0) as usize;
+            self.cursor.y = new_y.min(self.buffer.total_lines.saturating_sub(1));
+            
+            // Check if we need to reload chunk
+            if self.cursor.y < self.buffer.start_line_number || 
+               self.cursor.y >= self.buffer.start_line_number + self.buffer.lines.len() {
+                self.reload_current_chunk()?;
+            }
+        }
+        
+        // Horizontal movement
+        if let Some(line) = self.buffer.get_line(self.cursor.y) {
+            // Outside insert mode the cursor rests on the last character, not past it
+            let len = line.chars().count();
+            let line_end = match self.mode {
+                Mode::Insert => len,
+                _ => len.saturating_sub(1),
+            };
+            
+            if dx != 0 {
+                let x = (self.cursor.x.min(len) as isize + dx).max(0) as usize;
+                self.cursor.x = x.min(line_end);
+            } else if dy != 0 {
+                self.cursor.x = byte_to_char(line, byte_at_column(line, column, self.tabstop)).min(line_end);
+            }
+        } else {
+            self.cursor.x = 0;
+        }
+        
+        Ok(())
+    }
+
+    // Home: the first non-blank character, then column 0 on a second press
+    fn move_home(&mut self) {
+        let line = match self.buffer.get_line(self.cursor.y) {
+            Some(line) => line,
+            None => return,
+        };
+        let len = line.chars().count();
+        let mut indent = len - line.trim_start().chars().count();
+        if self.mode != Mode::Insert && indent == len {
+            // Outside insert mode the cursor can't rest past the last character
+            indent = len.saturating_sub(1);
+        }
+        self.cursor.x = if self.smarthome && self.cursor.x != indent { indent } else { 0 };
+    }
+
+    // Moves along the current line to byte offset `x` through move_cursor, so the
+    // end-of-line clamp stays in one place
+    fn move_cursor_to_byte(&mut self, x: usize) -> io::Result<()> {
+        let x = self.buffer.get_line(self.cursor.y).map_or(0, |line| byte_to_char(line, x));
+        self.move_cursor(x as isize - self.cursor.x as isize, 0)
+    }
+
+    // Word motions see three classes like vim: blanks, keyword runs and punctuation runs
+    fn char_class(&self, c: char) -> u8 {
+        if c.is_whitespace() {
+            0
+        } else if self.is_word_char(c) {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Steps to the first line below through move_cursor, which reloads the chunk when
+    // needed; false when there is no line to step onto
+    fn step_to_next_line(&mut self) -> io::Result<bool> {
+        let y = self.cursor.y;
+        self.cursor.x = 0;
+        self.move_cursor(0, 1)?;
+        Ok(self.cursor.y != y)
+    }
+
+    // `w`: start of the next word, where an empty line also counts as a word
+    fn word_forward(&mut self) -> io::Result<()> {
+        let origin = self.cursor.clone();
+        let line = self.buffer.get_line(self.cursor.y).cloned().unwrap_or_default();
+        let x = char_to_byte(&line, self.cursor.x);
+        let mut chars = line[x..].char_indices().map(|(i, c)| (x + i, c)).peekable();
+        if let Some(class) = chars.peek().map(|&(_, c)| self.char_class(c)) {
+            while chars.peek().is_some_and(|&(_, c)| self.char_class(c) == class) {
+                chars.next();
+            }
+        }
+        while chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            chars.next();
+        }
+        if let Some((i, _)) = chars.next() {
+            return self.move_cursor_to_byte(i);
+        }
+        while self.step_to_next_line()? {
+            let line = self.buffer.get_line(self.cursor.y).cloned().unwrap_or_default();
+            if line.is_empty() {
+                return Ok(());
+            }
+            if let Some(i) = line.find(|c: char| !c.is_whitespace()) {
+                return self.move_cursor_to_byte(i);
+            }
+        }
+        // No word left: like vim, stop on the last character
+        self.cursor = origin;
+        self.move_cursor_to_byte(line.len())
+    }
+
+    // `b`: start of the current word, or of the previous one when already there
+    fn word_backward(&mut self) -> io::Result<()> {
+        let mut crossed = false;
+        loop {
+            let line = self.buffer.get_line(self.cursor.y).cloned().unwrap_or_default();
+            let x = char_to_byte(&line, self.cursor.x);
+            let mut chars = line[..x].char_indices().rev().peekable();
+            while chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+                chars.next();
+            }
+            if let Some(class) = chars.peek().map(|&(_, c)| self.char_class(c)) {
+                let mut start = x;
+                while let Some(&(i, c)) = chars.peek() {
+                    if self.char_class(c) != class {
+                        break;
+                    }
+                    start = i;
+                    chars.next();
+                }
+                return self.move_cursor_to_byte(start);
+            }
+            if crossed && line.is_empty() {
+                return Ok(());
+            }
+            let y = self.cursor.y;
+            self.move_cursor(0, -1)?;
+            if self.cursor.y == y {
+                self.cursor.x = 0;
+                return Ok(());
+            }
+            self.cursor.x = self.buffer.get_line(self.cursor.y).map_or(0, |l| l.chars().count());
+            crossed = true;
+        }
+    }
+
+    // `e`: end of the current word, or of the next one when already there
+    fn word_end(&mut self) -> io::Result<()> {
+        let origin = self.cursor.clone();
+        let mut line = self.buffer.get_line(self.cursor.y).cloned().unwrap_or_default();
+        let x = char_to_byte(&line, self.cursor.x);
+        let mut from = line[x..].chars().next().map_or(line.len(), |c| x + c.len_utf8());
+        loop {
+            if let Some(i) = line[from..].find(|c: char| !c.is_whitespace()).map(|i| from + i) {
+                let class = line[i..].chars().next().map_or(0, |c| self.char_class(c));
+                let end = line[i..]
+                    .char_indices()
+                    .take_while(|&(_, c)| self.char_class(c) == class)
+                    .last()
+                    .map_or(i, |(j, _)| i + j);
+                return self.move_cursor_to_byte(end);
+            }
+            if !self.step_to_next_line()? {
+                self.cursor = origin;
+                return Ok(());
+            }
+            line = self.buffer.get_line(self.cursor.y).cloned().unwrap_or_default();
+            from = 0;
+        }
+    }
+
+    // The bracket pairing with the one under the cursor as (line, character), skipping
+    // nested pairs of the same kind. Only the loaded chunk is searched.
+    fn find_matching_bracket(&self) -> Option<(usize, usize)> {
+        let line = self.buffer.get_line(self.cursor.y)?;
+        let (open, close, forward) = match line.chars().nth(self.cursor.x)? {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => ('(', ')', false),
+            ']' => ('[', ']', false),
+            '}' => ('{', '}', false),
+            _ => return None,
+        };
+        let first = self.buffer.start_line_number;
+        let mut depth = 0usize;
+        let mut step = |c: char, deeper: char| {
+            if c == deeper {
+                depth += 1;
+            } else if c == open || c == close {
+                depth -= 1;
+            }
+            depth == 0
+        };
+
+        if forward {
+            for y in self.cursor.y..first + self.buffer.lines.len() {
+                let from = if y == self.cursor.y { self.cursor.x } else { 0 };
+                let line = self.buffer.get_line(y)?;
+                if let Some((i, _)) = line.chars().enumerate().skip(from).find(|&(_, c)| step(c, open)) {
+                    return Some((y, i));
+                }
+            }
+        } else {
+            for y in (first..=self.cursor.y).rev() {
+                let line = self.buffer.get_line(y)?;
+                let to = if y == self.cursor.y { self.cursor.x + 1 } else { line.chars().count() };
+                let chars: Vec<char> = line.chars().take(to).collect();
+                if let Some(i) = chars.iter().rposition(|&c| step(c, close)) {
+                    return Some((y, i));
+                }
+            }
+        }
+        None
+    }
+
+    // `%`: jumps to the bracket pairing with the one under the cursor
+    fn jump_to_matching_bracket(&mut self) {
+        match self.find_matching_bracket() {
+            Some((y, x)) => {
+                self.cursor.y = y;
+                self.cursor.x = x;
+            }
+            None => {
+                let on_bracket = self.buffer.get_line(self.cursor.y)
+                    .and_then(|line| line.chars().nth(self.cursor.x))
+                    .is_some_and(|c| "()[]{}".contains(c));
+                self.status_message = if on_bracket {
+                    "No matching bracket in the loaded lines".to_string()
+                } else {
+                    "Not on a bracket".to_string()
+                };
+            }
+        }
+    }
+
+    // Loads the window around the cursor. Unsaved edits in the one it replaces are
+    // stashed in memory until the next save.
+    fn reload_current_chunk(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.filename {
+            let chunk_start = self.cursor.y.saturating_sub(MAX_VISIBLE_LINES / 2);
+            self.buffer.stash_chunk()?;
+            self.buffer.load_chunk(path, chunk_start)?;
+        }
+        Ok(())
+    }
+
+    // Ctrl+C and `[count]yy`: copies `count` lines from the cursor down, stopping at the
+    // end of the loaded chunk. Several lines are kept newline-separated.
+    fn copy_lines(&mut self, count: usize) {
+        let y = self.cursor.y;
+        let lines: Vec<String> = (y..y.saturating_add(count))
+            .map_while(|i| self.buffer.get_line(i).cloned())
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+        let end = y + lines.len() - 1;
+        self.status_message = if lines.len() == 1 {
+            "Line copied".to_string()
+        } else {
+            format!("{} lines copied", lines.len())
+        };
+        self.yank(lines, y, end);
+    }
+
+    // Every copy into the clipboard goes through here: Ctrl+C, `yy` and `y` on a selection
+    fn yank(&mut self, lines: Vec<String>, start: usize, end: usize) {
+        self.set_clipboard(lines.join("\n"));
+        self.flash_yank(start, end);
+    }
+
+    // Briefly highlights yanked lines so the user can see what was grabbed
+    fn flash_yank(&mut self, start: usize, end: usize) {
+        if !self.yank_flash_duration.is_zero() {
+            self.yank_flash = Some((start, end, Instant::now() + self.yank_flash_duration));
+        }
+    }
+
+    fn track_unsaved_time(&mut self) {
+        if !self.modified {
+            self.modified_since = None;
+        } else if self.modified_since.is_none() {
+            self.modified_since = Some(Instant::now());
+        }
+    }
+
+    // Time the buffer has held unsaved changes, counted from the last save when there
+    // was one in this session
+    fn unsaved_for(&self) -> Option<Duration> {
+        if !self.modified {
+            return None;
+        }
+        self.last_saved.or(self.modified_since).map(|since| since.elapsed())
+    }
+
+    fn format_elapsed(elapsed: Duration) -> String {
+        let secs = elapsed.as_secs();
+        match secs {
+            0..=9 => "just now".to_string(),
+            10..=59 => format!("{}s ago", secs),
+            60..=3599 => format!("{}m ago", secs / 60),
+            _ => format!("{}h{}m ago", secs / 3600, secs % 3600 / 60),
+        }
+    }
+
+    // How long the main loop may block before the next tick is due
+    fn tick_timeout(&self) -> Duration {
+        match self.yank_flash {
+            Some((_, _, until)) => until.saturating_duration_since(Instant::now()).min(TICK_INTERVAL),
+            None => TICK_INTERVAL,
+        }
+    }
+
+    // Ctrl+X and `[count]dd`: cuts `count` lines from the cursor down into the clipboard,
+    // stopping at the end of the loaded chunk
+    fn cut_lines(&mut self, count: usize) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        let y = self.cursor.y;
+        let chunk_start = self.buffer.start_line_number;
+        let chunk_end = chunk_start + self.buffer.lines.len();
+        if y < chunk_start || y >= chunk_end {
+            return Ok(());
+        }
+        let mut count = count.min(chunk_end - y);
+        // An emptied chunk would leave the cursor on no line until the next save, so the
+        // last loaded line stays while more of the file lies outside it
+        if count == self.buffer.lines.len() && self.buffer.total_lines > count {
+            count -= 1;
+            if count == 0 {
+                self.status_message = "Save before cutting the last loaded line".to_string();
+                return Ok(());
+            }
+        }
+
+        let lines: Vec<String> = (y..y + count).filter_map(|i| self.buffer.get_line(i).cloned()).collect();
+        // Cutting every line leaves an empty one behind
+        let replacement = if count == self.buffer.total_lines { vec![String::new()] } else { Vec::new() };
+        self.replace_line_range(y, count, replacement);
+
+        let last_loaded = self.buffer.start_line_number + self.buffer.lines.len().max(1) - 1;
+        self.cursor.y = y.min(self.buffer.total_lines.saturating_sub(1)).min(last_loaded);
+        self.cursor.x = 0;
+        self.modified = true;
+        self.status_message = if count == 1 {
+            "Line cut".to_string()
+        } else {
+            format!("{} lines cut", count)
+        };
+        self.set_clipboard(lines.join("\n"));
+        Ok(())
+    }
+
+    // `:dupcomment`: keeps a commented-out copy of the current line above it and leaves
+    // the cursor on the live copy, as one undoable edit
+    fn duplicate_commented(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let prefix = match comment_prefix(&self.file_extension()) {
+            Some(prefix) => prefix,
+            None => {
+                self.status_message = "No comment syntax known for this filetype".to_string();
+                return;
+            }
+        };
+        let y = self.cursor.y;
+        let line = match self.buffer.get_line(y) {
+            Some(line) => line.clone(),
+            None => return,
+        };
+        let indent = line.len() - line.trim_start().len();
+        let commented = format!("{}{} {}", &line[..indent], prefix, &line[indent..]);
+        if self.replace_line_range(y, 1, vec![commented, line]) {
+            self.cursor.y = y + 1;
+            self.status_message = "Line duplicated, original commented out".to_string();
+        }
+    }
+
+    // Ctrl+/: comments out lines `start..=end` after their indentation, or uncomments
+    // them when every non-blank one already is. Blank lines are left alone.
+    fn toggle_comment(&mut self, start: usize, end: usize) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let prefix = match comment_prefix(&self.file_extension()) {
+            Some(prefix) => prefix,
+            None => {
+                self.status_message = "No comment syntax known for this filetype".to_string();
+                return;
+            }
+        };
+        let lines: Vec<String> = (start..=end).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        if lines.len() != end - start + 1 {
+            self.status_message = "Line is outside the loaded chunk".to_string();
+            return;
+        }
+        let uncomment = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .all(|line| line.trim_start().starts_with(prefix));
+        let toggled: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                let indent = line.len() - line.trim_start().len();
+                let (leading, rest) = line.split_at(indent);
+                if rest.is_empty() {
+                    line.clone()
+                } else if uncomment {
+                    let rest = &rest[prefix.len()..];
+                    format!("{}{}", leading, rest.strip_prefix(' ').unwrap_or(rest))
+                } else {
+                    format!("{}{} {}", leading, prefix, rest)
+                }
+            })
+            .collect();
+        if self.replace_line_range(start, toggled.len(), toggled) {
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+            self.status_message = format!(
+                "{} {} line(s)",
+                if uncomment { "Uncommented" } else { "Commented" },
+                end - start + 1
+            );
+        }
+    }
+
+    // Tab / Shift+Tab on a selection: adds one indent level (a tab, or `tabstop` spaces
+    // with expandtab) to each non-blank line, or takes off a leading tab or up to
+    // `tabstop` spaces. The selection stays so the keys can be repeated.
+    fn shift_lines(&mut self, start: usize, end: usize, indent: bool) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let lines: Vec<String> = (start..=end).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        if lines.len() != end - start + 1 {
+            self.status_message = "Line is outside the loaded chunk".to_string();
+            return;
+        }
+        let tabstop = self.tabstop.max(1);
+        let unit = if self.expandtab { " ".repeat(tabstop) } else { "\t".to_string() };
+        let shifted: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                if indent {
+                    if line.trim().is_empty() { line.clone() } else { format!("{}{}", unit, line) }
+                } else if let Some(rest) = line.strip_prefix('\t') {
+                    rest.to_string()
+                } else {
+                    let spaces = line.len() - line.trim_start_matches(' ').len();
+                    line[spaces.min(tabstop)..].to_string()
+                }
+            })
+            .collect();
+        if shifted == lines {
+            return;
+        }
+        if self.replace_line_range(start, shifted.len(), shifted) {
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+            let line_len = self.buffer.get_line(self.visual_anchor.y).map_or(0, |line| line.chars().count());
+            self.visual_anchor.x = self.visual_anchor.x.min(line_len);
+            self.status_message = format!("{} {} line(s)", if indent { "Indented" } else { "Dedented" }, end - start + 1);
+        }
+    }
+
+    // Keeps copied text, mirrored to the system clipboard under `:set clipboard`
+    fn set_clipboard(&mut self, text: String) {
+        if self.system_clipboard && !SystemClipboard::copy(&text) {
+            self.status_message.push_str(" (system clipboard unavailable)");
+        }
+        self.clipboard = text;
+    }
+
+    fn paste_line(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        // Text copied in other programs wins; without a system clipboard the editor's own is used
+        if self.system_clipboard {
+            if let Some(text) = SystemClipboard::paste().filter(|text| !text.is_empty()) {
+                let text = text.replace("\r\n", "\n");
+                self.clipboard = text.strip_suffix('\n').unwrap_or(&text).to_string();
+            }
+        }
+        if !self.clipboard.is_empty() {
+            let lines: Vec<String> = self.clipboard.split('\n').map(|line| line.to_string()).collect();
+            let count = lines.len();
+            if !self.replace_line_range(self.cursor.y + 1, 0, lines) {
+                return Ok(());
+            }
+            self.cursor.y += 1;
+            self.cursor.x = 0;
+            self.modified = true;
+            self.status_message = if count == 1 {
+                "Line pasted".to_string()
+            } else {
+                format!("{} lines pasted", count)
+            };
+        }
+        Ok(())
+    }
+
+    fn join_lines(&mut self, start: usize, end: usize, separator: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        // A single-line range joins with the line below, like vim
+        let end = if end <= start { start + 1 } else { end };
+        if end >= self.buffer.total_lines {
+            self.status_message = "Cannot join past the end of the file".to_string();
+            return;
+        }
+
+        let mut parts = Vec::new();
+        for line_idx in start..=end {
+            match self.buffer.get_line(line_idx) {
+                Some(line) => parts.push(line.clone()),
+                None => {
+                    self.status_message = "Join range is outside the loaded chunk".to_string();
+                    return;
+                }
+            }
+        }
+
+        let join_point = parts[0].chars().count();
+        let joined = parts.join(separator);
+        if joined.len() > MAX_LINE_LENGTH {
+            self.status_message = "Cannot join: resulting line would be too long".to_string();
+            return;
+        }
+
+        self.replace_line_range(start, parts.len(), vec![joined]);
+        self.cursor.y = start;
+        self.cursor.x = join_point;
+        self.status_message = format!("Joined {} lines", end - start + 1);
+    }
+
+    // `:t.` / Alt+D: copies lines `start..=end` below `end`. The cursor moves onto the
+    // copy, on the same line of it as it was on in the original.
+    fn duplicate_lines(&mut self, start: usize, end: usize) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let lines: Vec<String> = (start..=end).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        if lines.len() != end - start + 1 || !self.replace_line_range(end + 1, 0, lines) {
+            self.status_message = "Line is outside the loaded chunk".to_string();
+            return;
+        }
+        self.cursor.y = end + 1 + self.cursor.y.clamp(start, end) - start;
+        self.status_message = format!("Duplicated {} line(s)", end - start + 1);
+    }
+
+    // `J` joins the next line onto this one, `3J` three lines, stopping at the last line
+    fn join_count(&mut self, count: usize) {
+        let start = self.cursor.y;
+        let last = self.buffer.total_lines.saturating_sub(1);
+        self.join_lines(start, (start + count.max(2) - 1).min(last.max(start + 1)), " ");
+    }
+
+    fn split_line_on(&mut self, delimiter: char) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let line = match self.buffer.get_line(self.cursor.y) {
+            Some(line) => line.clone(),
+            None => return,
+        };
+        if !line.contains(delimiter) {
+            self.status_message = format!("No '{}' on this line", delimiter);
+            return;
+        }
+
+        let parts: Vec<String> = line.split(delimiter).map(|part| part.to_string()).collect();
+        let count = parts.len();
+        self.replace_line_range(self.cursor.y, 1, parts);
+        self.cursor.x = 0;
+        self.status_message = format!("Split into {} lines", count);
+    }
+
+    fn search(&self) -> Vec<(usize, usize)> {
+        self.find_matches(100)
+    }
+
+    // The current search as a regex: the query itself in regex mode (Ctrl+R while typing
+    // a search), otherwise the query matched literally
+    fn search_source(&self) -> String {
+        if self.search_is_regex {
+            self.search_query.clone()
+        } else {
+            regex::escape(&self.search_query)
+        }
+    }
+
+    fn search_pattern(&self) -> Result<Regex, regex::Error> {
+        RegexBuilder::new(&self.search_source())
+            .case_insensitive(self.search_ignores_case())
+            .build()
+    }
+
+    // `ignorecase`, unless `smartcase` is on and the query has a capital letter
+    fn search_ignores_case(&self) -> bool {
+        self.ignorecase && !(self.smartcase && self.search_query.chars().any(char::is_uppercase))
+    }
+
+    // Matches of the current search in the loaded chunk, stopping once more than `limit`
+    // are found. A pattern that doesn't compile matches nothing.
+    fn find_matches(&self, limit: usize) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        let words = if self.search_whole_word { Some(&self.iskeyword) } else { None };
+        let pattern = match self.search_pattern() {
+            Ok(pattern) if !self.search_query.is_empty() => pattern,
+            _ => return matches,
+        };
+        
+        // Only search in currently loaded chunk to avoid performance issues
+        for (local_idx, line) in self.buffer.lines.iter().enumerate() {
+            let line_idx = self.buffer.start_line_number + local_idx;
+            for found in Self::matches_in_line(line, &pattern, words) {
+                matches.push((line_idx, found));
+                if matches.len() > limit { // Limit matches to prevent slowdown
+                    break;
+                }
+            }
+            if matches.len() > limit {
+                break;
+            }
+        }
+        matches
+    }
+
+    // Byte offsets where `pattern` matches in `line`, at most MAX_LINE_MATCHES of them;
+    // with `words` set, only whole-word occurrences. Empty matches (`x*`) are skipped.
+    fn matches_in_line(line: &str, pattern: &Regex, words: Option<&KeywordSet>) -> Vec<usize> {
+        Self::match_ranges(line, pattern, words).into_iter().map(|(start, _)| start).collect()
+    }
+
+    // Like `matches_in_line`, with the byte range of each match
+    fn match_ranges(line: &str, pattern: &Regex, words: Option<&KeywordSet>) -> Vec<(usize, usize)> {
+        let whole = |found: &regex::Match, words: &KeywordSet| {
+            let before = line[..found.start()].chars().next_back();
+            let after = line[found.end()..].chars().next();
+            !before.is_some_and(|c| words.contains(c)) && !after.is_some_and(|c| words.contains(c))
+        };
+        pattern
+            .find_iter(line)
+            .filter(|found| !found.is_empty() && words.is_none_or(|words| whole(found, words)))
+            .take(MAX_LINE_MATCHES)
+            .map(|found| (found.start(), found.end()))
+            .collect()
+    }
+
+    // Every match of the current search in the whole file, not just the loaded chunk.
+    // Lines are streamed from disk, and the result is kept until the next edit.
+    fn file_matches(&mut self) -> io::Result<&SearchCache> {
+        let fresh = self.search_cache.as_ref().is_some_and(|cache| {
+            cache.query == self.search_query
+                && cache.regex == self.search_is_regex
+                && cache.ignore_case == self.search_ignores_case()
+                && cache.whole_word == self.search_whole_word
+        });
+        if !fresh {
+            let pattern = self.search_pattern()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let words = if self.search_whole_word { Some(self.iskeyword.clone()) } else { None };
+            let mut cache = SearchCache {
+                query: self.search_query.clone(),
+                regex: self.search_is_regex,
+                ignore_case: self.search_ignores_case(),
+                whole_word: self.search_whole_word,
+                matches: Vec::new(),
+                total: 0,
+            };
+            let empty = self.search_query.is_empty();
+            self.buffer.for_each_file_line(|y, line| {
+                if empty {
+                    return Ok(());
+                }
+                for x in Self::matches_in_line(line, &pattern, words.as_ref()) {
+                    cache.total += 1;
+                    if cache.matches.len() < MAX_FILE_MATCHES {
+                        cache.matches.push((y, x));
+                    }
+                }
+                Ok(())
+            })?;
+            self.search_cache = Some(cache);
+        }
+        Ok(self.search_cache.as_ref().expect("search cache was just filled"))
+    }
+
+    // Word classification shared by every word-aware feature, driven by `:set iskeyword=`
+    fn is_word_char(&self, c: char) -> bool {
+        self.iskeyword.contains(c)
+    }
+
+    // Resolves the word under (or just after) the cursor into its byte range and text
+    fn word_under_cursor(&self) -> Option<(usize, usize, String)> {
+        let line = self.buffer.get_line(self.cursor.y)?;
+        let cursor = char_to_byte(line, self.cursor.x);
+
+        // Like vim, fall forward to the next word when the cursor sits on non-word characters
+        let start_search = line
+            .char_indices()
+            .find(|&(i, c)| i >= cursor && self.is_word_char(c))
+            .map(|(i, _)| i)?;
+        let begin = line[..start_search]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| self.is_word_char(c))
+            .last()
+            .map_or(start_search, |(i, _)| i);
+        let end = line[start_search..]
+            .char_indices()
+            .find(|&(_, c)| !self.is_word_char(c))
+            .map_or(line.len(), |(i, _)| start_search + i);
+
+        Some((begin, end, line[begin..end].to_string()))
+    }
+
+    // Insert-mode Ctrl+T: swaps the two characters before the cursor, so `teh|` becomes `the|`
+    fn transpose_chars(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let y = self.cursor.y;
+        let line = match self.buffer.get_line(y) {
+            Some(line) => line.clone(),
+            None => return,
+        };
+        let cursor = char_to_byte(&line, self.cursor.x);
+        let mut before = line[..cursor].char_indices().rev();
+        let (second, first) = match (before.next(), before.next()) {
+            (Some(second), Some(first)) => (second, first),
+            _ => {
+                self.status_message = "Nothing to transpose".to_string();
+                return;
+            }
+        };
+        let swapped = format!("{}{}{}{}", &line[..first.0], second.1, first.1, &line[cursor..]);
+        self.replace_line_range(y, 1, vec![swapped]);
+    }
+
+    // Normal-mode Alt+T: swaps the word under the cursor with the next one and keeps the
+    // cursor on the moved word, so repeating it drags the word along the line
+    fn transpose_words(&mut self) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let y = self.cursor.y;
+        let (first_start, first_end, _) = match self.word_under_cursor() {
+            Some(word) => word,
+            None => {
+                self.status_message = "No word under cursor".to_string();
+                return;
+            }
+        };
+        self.set_cursor_byte(first_end);
+        let second = self.word_under_cursor();
+        self.set_cursor_byte(first_start);
+        let (second_start, second_end, _) = match second {
+            Some(word) => word,
+            None => {
+                self.status_message = "No next word to transpose with".to_string();
+                return;
+            }
+        };
+        let line = match self.buffer.get_line(y) {
+            Some(line) => line.clone(),
+            None => return,
+        };
+        let swapped = format!(
+            "{}{}{}{}{}",
+            &line[..first_start],
+            &line[second_start..second_end],
+            &line[first_end..second_start],
+            &line[first_start..first_end],
+            &line[second_end..]
+        );
+        if self.replace_line_range(y, 1, vec![swapped]) {
+            self.set_cursor_byte(second_end - (first_end - first_start));
+        }
+    }
+
+    // `*` and `#`: search for the word under the cursor as a whole word
+    fn search_word_under_cursor(&mut self, forward: bool) {
+        let (begin, _, word) = match self.word_under_cursor() {
+            Some(found) => found,
+            None => {
+                self.status_message = "No word under cursor".to_string();
+                return;
+            }
+        };
+        self.search_query = word;
+        self.search_is_regex = false;
+        self.search_whole_word = true;
+        self.set_cursor_byte(begin);
+        self.jump_to_match(forward);
+    }
+
+    // `[current/total]` for the status line while a search is active: the match at or
+    // before the cursor, counted up to MAX_SEARCH_COUNT
+    // Counted over the whole file, except while typing: rescanning after every keystroke
+    // would stall on big files, so insert mode counts the loaded chunk instead
+    fn search_count(&mut self) -> Option<String> {
+        if !self.searchcount || self.search_query.is_empty() || self.mode == Mode::Search {
+            return None;
+        }
+        let here = (self.cursor.y, self.cursor_byte());
+        let (current, total) = if self.search_cache.is_none() && self.mode == Mode::Insert {
+            let matches = self.find_matches(MAX_SEARCH_COUNT);
+            (matches.iter().take_while(|&&m| m <= here).count(), matches.len())
+        } else {
+            let cache = self.file_matches().ok()?;
+            (cache.matches.iter().take_while(|&&m| m <= here).count(), cache.total)
+        };
+        let show = |n: usize| {
+            if n > MAX_SEARCH_COUNT { format!(">{}", MAX_SEARCH_COUNT) } else { n.to_string() }
+        };
+        Some(format!("[{}/{}]", show(current), show(total)))
+    }
+
+    // Moves to the next/previous match of `search_query` anywhere in the file, wrapping
+    // around and loading the chunk that holds it
+    fn jump_to_match(&mut self, forward: bool) {
+        // Report a bad pattern as the regex crate explains it rather than as a failed search
+        if let Err(e) = self.search_pattern() {
+            self.status_message = format!("Invalid regex: {}", e);
+            return;
+        }
+        self.highlight_search = true;
+        let here = (self.cursor.y, self.cursor_byte());
+        let (target, index, total) = match self.file_matches() {
+            Ok(cache) if !cache.matches.is_empty() => {
+                let matches = &cache.matches;
+                let index = if forward {
+                    matches.iter().position(|&m| m > here).unwrap_or(0)
+                } else {
+                    matches.iter().rposition(|&m| m < here).unwrap_or(matches.len() - 1)
+                };
+                (matches[index], index, cache.total)
+            }
+            Ok(_) => {
+                self.status_message = format!("Pattern not found: {}", self.search_query);
+                return;
+            }
+            Err(e) => {
+                self.status_message = format!("Search failed: {}", e);
+                return;
+            }
+        };
+
+        self.cursor.y = target.0;
+        if let Err(e) = self.ensure_line_loaded(target.0) {
+            self.status_message = format!("Search failed: {}", e);
+            return;
+        }
+        if self.cursor.y != target.0 {
+            // Refused to leave a chunk with unsaved edits; the reason is in the status line
+            return;
+        }
+        self.set_cursor_byte(target.1);
+        self.status_message = format!("/{} [{}/{}]", self.search_query, index + 1, total);
+    }
+
+    fn replace_in_chunk(&mut self, search: &str, replace: &str) -> usize {
+        if self.refuse_read_only() {
+            return 0;
+        }
+        if search.is_empty() {
+            return 0;
+        }
+        
+        let mut count = 0;
+        let mut ops = Vec::new();
+        let start_line = self.buffer.start_line_number;
+        
+        for (local_idx, line) in self.buffer.lines.iter_mut().enumerate() {
+            let new_line = line.replace(search, replace);
+            if new_line != *line {
+                let old_line = line.clone();
+                if new_line.len() <= MAX_LINE_LENGTH {
+                    count += line.matches(search).count();
+                    *line = new_line;
+                } else {
+                    // Truncate if replacement makes line too long
+                    *line = clip_line(&new_line).to_string();
+                    count += 1;
+                }
+                ops.push(EditOp::ReplaceRange {
+                    start: start_line + local_idx,
+                    old: vec![old_line],
+                    new: vec![line.clone()],
+                });
+            }
+        }
+        
+        if count > 0 {
+            self.modified = true;
+        }
+        self.record_edits(ops);
+        count
+    }
+
+    // `s` followed by a punctuation delimiter, as in `s/old/new/g` or `s#a#b#`
+    fn is_substitute(cmd: &str) -> bool {
+        let mut chars = cmd.chars();
+        chars.next() == Some('s') && chars.next().is_some_and(|c| c.is_ascii_punctuation())
+    }
+
+    // Splits `s/pattern/replacement/flags` into its parts, honouring `\`-escaped delimiters
+    fn parse_substitute(cmd: &str) -> Option<(String, String, String)> {
+        let mut chars = cmd.chars().skip(1);
+        let delimiter = chars.next()?;
+        let mut parts = vec![String::new()];
+
+        while let Some(c) = chars.next() {
+            let field_count = parts.len();
+            let current = parts.last_mut()?;
+            if c == '\\' {
+                match chars.next() {
+                    Some(next) if next == delimiter => current.push(next),
+                    Some(next) => {
+                        current.push('\\');
+                        current.push(next);
+                    }
+                    None => current.push('\\'),
+                }
+            } else if c == delimiter && field_count < 3 {
+                parts.push(String::new());
+            } else {
+                current.push(c);
+            }
+        }
+
+        let mut parts = parts.into_iter();
+        let pattern = parts.next()?;
+        let replacement = parts.next()?;
+        let flags = parts.next().unwrap_or_default();
+        Some((pattern, replacement, flags))
+    }
+
+    // Rewrites vim-style `\1` group references and `\$` literals into the `regex` crate's
+    // `${1}` / `$$` syntax; `$1` and `${name}` pass through unchanged
+    fn translate_replacement(replacement: &str) -> String {
+        let mut result = String::with_capacity(replacement.len());
+        let mut chars = replacement.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    result.push_str("${");
+                    result.push(d);
+                    result.push('}');
+                }
+                Some('$') => result.push_str("$$"),
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        }
+        result
+    }
+
+    // Checks every `$N` / `${name}` reference in a translated replacement against the pattern
+    fn validate_replacement(regex: &Regex, replacement: &str) -> Result<(), String> {
+        let bytes = replacement.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'$' {
+                i += 1;
+                continue;
+            }
+            if bytes.get(i + 1) == Some(&b'$') {
+                i += 2;
+                continue;
+            }
+
+            let (name, next) = if bytes.get(i + 1) == Some(&b'{') {
+                match replacement[i + 2..].find('}') {
+                    Some(close) => (&replacement[i + 2..i + 2 + close], i + 3 + close),
+                    None => return Err("Unclosed ${ in replacement".to_string()),
+                }
+            } else {
+                let len = replacement[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(replacement.len() - i - 1);
+                (&replacement[i + 1..i + 1 + len], i + 1 + len)
+            };
+
+            if let Ok(index) = name.parse::<usize>() {
+                if index >= regex.captures_len() {
+                    return Err(format!(
+                        "Replacement refers to group {} but the pattern has {}",
+                        index,
+                        regex.captures_len() - 1
+                    ));
+                }
+            } else if !name.is_empty() && !regex.capture_names().any(|n| n == Some(name)) {
+                return Err(format!("Replacement refers to unknown group '{}'", name));
+            }
+            i = next;
+        }
+        Ok(())
+    }
+
+    // `:s/pattern/replacement/[gi]` over a range of lines as one undoable edit. Lines
+    // outside the loaded chunk are streamed to find the matches, then their chunks are
+    // loaded in turn to change them.
+    fn substitute(&mut self, range: (usize, usize), columns: Option<(usize, usize)>, cmd: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let (pattern, replacement, flags) = match Self::parse_substitute(cmd) {
+            Some(parts) => parts,
+            None => {
+                self.status_message = "Usage: s/pattern/replacement/[gi]".to_string();
+                return;
+            }
+        };
+
+        // An empty pattern reuses the last search, so its groups can go into the replacement.
+        // `i` and `I` force the case; otherwise `ignorecase` and `smartcase` decide.
+        let (pattern, ignore_case) = if !pattern.is_empty() {
+            let ignore_case = if flags.contains('I') {
+                false
+            } else {
+                flags.contains('i')
+                    || (self.ignorecase && !(self.smartcase && pattern.chars().any(char::is_uppercase)))
+            };
+            (pattern, ignore_case)
+        } else if !self.search_query.is_empty() {
+            (self.search_source(), flags.contains('i') || self.search_ignores_case())
+        } else {
+            self.status_message = "No previous search to substitute".to_string();
+            return;
+        };
+        let regex = match RegexBuilder::new(&pattern).case_insensitive(ignore_case).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.status_message = format!("Invalid pattern: {}", e);
+                return;
+            }
+        };
+        let replacement = Self::translate_replacement(&replacement);
+        if let Err(message) = Self::validate_replacement(&regex, &replacement) {
+            self.status_message = message;
+            return;
+        }
+
+        let global = flags.contains('g');
+        let tabstop = self.tabstop;
+        let mut count = 0;
+        let mut ops = Vec::new();
+        let scanned = self.buffer.for_each_file_line_in(range.0, range.1, |line_idx, line| {
+            let (from, to) = match columns {
+                Some((left, right)) => match Self::block_byte_range(line, left, right, tabstop) {
+                    Some(range) => range,
+                    None => return Ok(()),
+                },
+                None => (0, line.len()),
+            };
+            let segment = &line[from..to];
+            let hits = if global { regex.find_iter(segment).count() } else { regex.find(segment).map_or(0, |_| 1) };
+            if hits == 0 {
+                return Ok(());
+            }
+
+            let replaced = if global {
+                regex.replace_all(segment, replacement.as_str())
+            } else {
+                regex.replace(segment, replacement.as_str())
+            };
+            let new_line = format!("{}{}{}", &line[..from], replaced, &line[to..]);
+            if new_line.len() > MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {} would become too long", line_idx + 1),
+                ));
+            }
+            count += hits;
+            ops.push((line_idx, line.to_string(), new_line));
+            Ok(())
+        });
+        if let Err(e) = scanned {
+            self.status_message = format!("{}; nothing replaced", e);
+            return;
+        }
+
+        if ops.is_empty() {
+            self.status_message = format!("Pattern not found: {}", pattern);
+            return;
+        }
+
+        let cursor = self.cursor.clone();
+        let mut edits = Vec::new();
+        let mut failed = None;
+        for (line_idx, old, new) in ops {
+            if let Err(e) = self.ensure_line_loaded(line_idx) {
+                failed = Some(format!("Stopped at line {}: {}", line_idx + 1, e));
+                break;
+            }
+            if let Some(line) = self.buffer.get_line_mut(line_idx) {
+                *line = new.clone();
+                edits.push(EditOp::ReplaceRange { start: line_idx, old: vec![old], new: vec![new] });
+            }
+        }
+        // Loading the changed lines may have moved the cursor; put it back
+        self.cursor = cursor;
+        if let Err(e) = self.ensure_line_loaded(self.cursor.y) {
+            failed = failed.or(Some(format!("Reloading failed: {}", e)));
+        }
+        let lines_changed = edits.len();
+        if lines_changed > 0 {
+            self.record_edits(edits);
+            self.modified = true;
+        }
+        self.status_message = match failed {
+            Some(message) => message,
+            None => format!("{} substitution(s) on {} line(s)", count, lines_changed),
+        };
+    }
+
+    // `:[range]retab` rewrites only the leading whitespace of each line to tabs or spaces,
+    // following `expandtab`, while keeping its width under the current `tabstop`. Like
+    // `:s`, the range is read from the file, so lines outside the loaded chunk count too.
+    fn retab(&mut self, range: (usize, usize)) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let tabstop = self.tabstop.max(1);
+        let expandtab = self.expandtab;
+        let mut ops = Vec::new();
+        let scanned = self.buffer.for_each_file_line_in(range.0, range.1, |line_idx, line| {
+            let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+            let width = line[..indent_len]
+                .chars()
+                .fold(0, |width, c| if c == '\t' { (width / tabstop + 1) * tabstop } else { width + 1 });
+            let indent = if expandtab {
+                " ".repeat(width)
+            } else {
+                format!("{}{}", "\t".repeat(width / tabstop), " ".repeat(width % tabstop))
+            };
+            if indent != line[..indent_len] {
+                ops.push((line_idx, line.to_string(), format!("{}{}", indent, &line[indent_len..])));
+            }
+            Ok(())
+        });
+        if let Err(e) = scanned {
+            self.status_message = format!("{}; nothing retabbed", e);
+            return;
+        }
+
+        let cursor = self.cursor.clone();
+        let mut edits = Vec::new();
+        let mut failed = None;
+        for (line_idx, old, new) in ops {
+            if let Err(e) = self.ensure_line_loaded(line_idx) {
+                failed = Some(format!("Stopped at line {}: {}", line_idx + 1, e));
+                break;
+            }
+            if let Some(line) = self.buffer.get_line_mut(line_idx) {
+                *line = new.clone();
+                edits.push(EditOp::ReplaceRange { start: line_idx, old: vec![old], new: vec![new] });
+            }
+        }
+        // Loading the changed lines may have moved the cursor; put it back
+        self.cursor = cursor;
+        if let Err(e) = self.ensure_line_loaded(self.cursor.y) {
+            failed = failed.or(Some(format!("Reloading failed: {}", e)));
+        }
+        let lines_changed = edits.len();
+        if lines_changed > 0 {
+            self.record_edits(edits);
+            self.modified = true;
+        }
+        self.status_message = match failed {
+            Some(message) => message,
+            None => format!(
+                "Retabbed {} line(s) to {}",
+                lines_changed,
+                if self.expandtab { "spaces" } else { "tabs" }
+            ),
+        };
+    }
+
+    // The lines held in memory, for commands that default to the loaded chunk
+    fn loaded_range(&self) -> (usize, usize) {
+        let start = self.buffer.start_line_number;
+        (start, start + self.buffer.lines.len().max(1) - 1)
+    }
+
+    // `:[range]sort[!] [n][u]` sorts the range, or the loaded chunk without one: `!`
+    // reverses, `n` orders by the first number on each line (lines without one go
+    // first) and `u` drops lines equal to the one before
+    fn sort_lines(&mut self, range: (usize, usize), reverse: bool, flags: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let flags = flags.trim();
+        if let Some(flag) = flags.chars().find(|c| !matches!(c, 'n' | 'u' | ' ')) {
+            self.status_message = format!("Unknown sort flag: {}", flag);
+            return;
+        }
+        let (start, end) = range;
+        let mut lines: Vec<String> = (start..=end).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        let count = lines.len();
+        if count != end - start + 1 {
+            self.status_message = "Sort range is outside the loaded chunk".to_string();
+            return;
+        }
+
+        if flags.contains('n') {
+            let number = Regex::new(r"-?\d+(\.\d+)?").expect("valid number pattern");
+            let key = |line: &String| number.find(line).and_then(|found| found.as_str().parse::<f64>().ok());
+            lines.sort_by(|a, b| match (key(a), key(b)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            });
+        } else {
+            lines.sort();
+        }
+        if reverse {
+            lines.reverse();
+        }
+        if flags.contains('u') {
+            lines.dedup();
+        }
+
+        let removed = count - lines.len();
+        self.replace_line_range(start, count, lines);
+        self.cursor.y = start;
+        self.cursor.x = 0;
+        self.status_message = if removed > 0 {
+            format!("Sorted {} lines, removed {} duplicate(s)", count, removed)
+        } else {
+            format!("Sorted {} lines", count)
+        };
+    }
+
+    // `:[range]uniq [i]` collapses runs of identical lines in the range, or the loaded
+    // chunk without one; `i` compares ignoring case and keeps the first of each run
+    fn uniq_lines(&mut self, range: (usize, usize), args: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let ignore_case = match args.trim() {
+            "" => false,
+            "i" => true,
+            other => {
+                self.status_message = format!("Unknown uniq flag: {}", other);
+                return;
+            }
+        };
+        let (start, end) = range;
+        let mut lines: Vec<String> = (start..=end).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        let count = lines.len();
+        if count != end - start + 1 {
+            self.status_message = "Uniq range is outside the loaded chunk".to_string();
+            return;
+        }
+        if ignore_case {
+            lines.dedup_by(|line, previous| line.to_lowercase() == previous.to_lowercase());
+        } else {
+            lines.dedup();
+        }
+
+        let removed = count - lines.len();
+        if removed > 0 {
+            let kept = lines.len();
+            self.replace_line_range(start, count, lines);
+            if self.cursor.y > end {
+                self.cursor.y -= removed;
+            } else if self.cursor.y >= start + kept {
+                self.cursor.y = start + kept - 1;
+            }
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+        }
+        self.status_message = format!("Removed {} duplicate line(s)", removed);
+    }
+
+    // `:[range]numfmt` rewrites every number found in the range; lines without numbers
+    // are left alone. Like `:s`, the range is read from the file, so lines outside the
+    // loaded chunk count too.
+    fn format_numbers(&mut self, range: (usize, usize), args: &str) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let format = match NumberFormat::parse(args) {
+            Ok(format) => format,
+            Err(message) => {
+                self.status_message = message;
+                return;
+            }
+        };
+        let number = Regex::new(r"[-+]?(?:\d{1,3}(?:,\d{3})+|\d+)(?:\.\d+)?").expect("number pattern is valid");
+
+        let mut count = 0;
+        let mut ops = Vec::new();
+        let scanned = self.buffer.for_each_file_line_in(range.0, range.1, |line_idx, line| {
+            let new_line = number.replace_all(line, |caps: &regex::Captures| {
+                let found = &caps[0];
+                match format.apply(found) {
+                    Some(formatted) if formatted != found => {
+                        count += 1;
+                        formatted
+                    }
+                    _ => found.to_string(),
+                }
+            }).into_owned();
+            if new_line == line {
+                return Ok(());
+            }
+            if new_line.len() > MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {} would become too long", line_idx + 1),
+                ));
+            }
+            ops.push((line_idx, line.to_string(), new_line));
+            Ok(())
+        });
+        if let Err(e) = scanned {
+            self.status_message = format!("{}; nothing reformatted", e);
+            return;
+        }
+
+        if ops.is_empty() {
+            self.status_message = "No numbers to reformat".to_string();
+            return;
+        }
+
+        let cursor = self.cursor.clone();
+        let mut edits = Vec::new();
+        let mut failed = None;
+        for (line_idx, old, new) in ops {
+            if let Err(e) = self.ensure_line_loaded(line_idx) {
+                failed = Some(format!("Stopped at line {}: {}", line_idx + 1, e));
+                break;
+            }
+            if let Some(line) = self.buffer.get_line_mut(line_idx) {
+                *line = new.clone();
+                edits.push(EditOp::ReplaceRange { start: line_idx, old: vec![old], new: vec![new] });
+            }
+        }
+        // Loading the changed lines may have moved the cursor; put it back
+        self.cursor = cursor;
+        if let Err(e) = self.ensure_line_loaded(self.cursor.y) {
+            failed = failed.or(Some(format!("Reloading failed: {}", e)));
+        }
+        let lines_changed = edits.len();
+        if lines_changed > 0 {
+            self.record_edits(edits);
+            self.modified = true;
+        }
+        self.status_message = match failed {
+            Some(message) => message,
+            None => format!("Reformatted {} number(s) on {} line(s)", count, lines_changed),
+        };
+    }
+
+    fn handle_key_event(&mut self, key: KeyEvent) -> io::Result<bool> {
+        // An expanded status area collapses on the next keystroke
+        if self.status_rows > 1 {
+            self.status_message.clear();
+            self.status_rows = 1;
+        }
+        // Keys typed by a replayed macro are not recorded again
+        let recording = self.recording.is_some() && self.macro_depth == 0;
+        let tracking = self.macro_depth == 0;
+        if tracking && self.change_idle() {
+            self.change_keys.clear();
+            self.change_edits = self.edit_count;
+        }
+        let result = if self.pending_prompt.is_some() {
+            self.handle_prompt(key)
+        } else if self.popup.is_some() {
+            self.handle_popup(key)
+        } else {
+            match self.mode {
+                Mode::Normal => self.handle_normal_mode(key),
+                Mode::Insert => self.handle_insert_mode(key),
+                Mode::Command => self.handle_command_mode(key),
+                Mode::Search => self.handle_search_mode(key),
+                Mode::Replace => self.handle_replace_mode(key),
+                Mode::ConfirmReplace => self.handle_confirm_replace_mode(key),
+                Mode::Visual | Mode::VisualLine | Mode::VisualBlock => self.handle_visual_mode(key),
+            }
+        };
+        // The key that stopped the recording is left out, as are the ones that started it
+        if recording {
+            if let Some((_, keys)) = self.recording.as_mut() {
+                keys.push(key);
+            }
+        }
+        if tracking {
+            self.change_keys.push(key);
+            if self.change_idle() && self.edit_count != self.change_edits {
+                self.last_change = std::mem::take(&mut self.change_keys);
+            }
+        }
+        
+        if self.needs_reload {
+            self.reload_current_chunk()?;
+            self.needs_reload = false;
+        }
+        if self.exit_requested {
+            return Ok(false);
+        }
+        
+        result
+    }
+
+    // Normal mode with nothing half-typed: the next key starts a new command
+    fn change_idle(&self) -> bool {
+        self.mode == Mode::Normal
+            && self.pending_keys.is_empty()
+            && !self.pending_window
+            && self.pending_prompt.is_none()
+            && self.popup.is_none()
+    }
+
+    // `.` types the keys of the last command that changed the buffer again, from
+    // where the cursor is now
+    fn repeat_last_change(&mut self) -> io::Result<bool> {
+        if self.last_change.is_empty() {
+            self.status_message = "No previous change to repeat".to_string();
+            return Ok(true);
+        }
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.status_message = "Macro nested too deeply".to_string();
+            return Ok(true);
+        }
+        let keys = self.last_change.clone();
+        self.macro_depth += 1;
+        let mut result = Ok(true);
+        for key in keys {
+            result = self.handle_key_event(key);
+            if !matches!(result, Ok(true)) {
+                break;
+            }
+        }
+        self.macro_depth -= 1;
+        // The replay is not a new change, so `.` keeps repeating the original
+        self.change_edits = self.edit_count;
+        result
+    }
+
+    // Keys that can kick off a slow whole-chunk operation; the main loop shows a busy
+    // indicator when one runs long so the UI never looks frozen
+    fn is_long_operation(&self, key: &KeyEvent) -> bool {
+        key.code == KeyCode::Enter
+            && matches!(self.mode, Mode::Command | Mode::Search | Mode::Replace)
+            && self.pending_prompt.is_none()
+    }
+
+    // Left click places the cursor (in the other pane of a split, focusing it first) or
+    // picks a tab; dragging selects; the wheel scrolls. Anything outside the panes and
+    // the tab strip, or while a prompt, popup or command line is open, is ignored.
+    fn handle_mouse_event(&mut self, mouse: MouseEvent) -> io::Result<()> {
+        let editing = matches!(
+            self.mode,
+            Mode::Normal | Mode::Insert | Mode::Visual | Mode::VisualLine | Mode::VisualBlock
+        );
+        if !editing || self.pending_prompt.is_some() || self.popup.is_some() {
+            return Ok(());
+        }
+        let inside = |area: Rect| {
+            mouse.column >= area.x
+                && mouse.column < area.x + area.width
+                && mouse.row >= area.y
+                && mouse.row < area.y + area.height
+        };
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                if inside(self.tab_bar_area) {
+                    let column = (mouse.column - self.tab_bar_area.x) as usize;
+                    let tab = self.tab_labels(self.tab_bar_area.width as usize)
+                        .iter()
+                        .rposition(|(start, _)| *start <= column);
+                    if let Some(tab) = tab {
+                        self.switch_to_buffer(tab);
+                    }
+                    return Ok(());
+                }
+                if self.other_pane_area.is_some_and(inside) {
+                    self.focus_other_pane()?;
+                    if let Some(other) = self.other_pane_area.take() {
+                        self.other_pane_area = Some(self.pane_area);
+                        self.pane_area = other;
+                    }
+                } else if !inside(self.pane_area) {
+                    return Ok(());
+                }
+                if matches!(self.mode, Mode::Visual | Mode::VisualLine | Mode::VisualBlock) {
+                    self.mode = Mode::Normal;
+                    self.status_message = "Normal mode".to_string();
+                }
+                self.place_cursor_at(mouse.column, mouse.row)?;
+                self.mouse_anchor = Some(self.cursor.clone());
+            }
+            MouseEventKind::Drag(MouseButton::Left) => {
+                let anchor = match self.mouse_anchor.clone() {
+                    Some(anchor) if self.mode != Mode::Insert => anchor,
+                    _ => return Ok(()),
+                };
+                // Dragging past the top or bottom edge scrolls a line at a time
+                let text = self.pane_text_area(self.pane_area);
+                if mouse.row < text.y {
+                    self.offset_y = self.offset_y.saturating_sub(1);
+                } else if mouse.row >= text.y + text.height {
+                    self.offset_y = (self.offset_y + 1).min(self.buffer.total_lines.saturating_sub(1));
+                }
+                self.place_cursor_at(mouse.column, mouse.row)?;
+                if self.mode == Mode::Normal && (self.cursor.y, self.cursor.x) != (anchor.y, anchor.x) {
+                    self.start_visual(Mode::Visual);
+                    self.visual_anchor = anchor;
+                }
+            }
+            MouseEventKind::Up(MouseButton::Left) => self.mouse_anchor = None,
+            MouseEventKind::ScrollUp if inside(self.pane_area) => self.scroll_view(false)?,
+            MouseEventKind::ScrollDown if inside(self.pane_area) => self.scroll_view(true)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The part of a pane at `area` that shows text, as `render_editor` lays it out:
+    // below the sticky header and right of the line numbers
+    fn pane_text_area(&self, area: Rect) -> Rect {
+        let mut text = area;
+        if text.height > 1 && self.sticky_scope_line().is_some() {
+            text.y += 1;
+            text.height -= 1;
+        }
+        if self.gutter_visible() {
+            let gutter = (self.line_number_width() as u16).min(text.width);
+            text.x += gutter;
+            text.width -= gutter;
+        }
+        text
+    }
+
+    // Moves the cursor to the character drawn at a screen cell of the focused pane.
+    // Cells past the text clamp to it; a click on the line numbers lands at the start.
+    fn place_cursor_at(&mut self, column: u16, row: u16) -> io::Result<()> {
+        let text = self.pane_text_area(self.pane_area);
+        let row = row.clamp(text.y, text.y + text.height.saturating_sub(1)) - text.y;
+        let y = (self.offset_y + row as usize).min(self.buffer.total_lines.saturating_sub(1));
+        self.ensure_line_loaded(y)?;
+        if self.buffer.get_line(y).is_none() {
+            return Ok(());
+        }
+        self.cursor.y = y;
+        let column = column.saturating_sub(text.x) as usize + self.offset_x;
+        let line = self.buffer.get_line(y).map_or("", |line| line.as_str());
+        let x = byte_to_char(line, byte_at_column(line, column, self.tabstop));
+        let len = line.chars().count();
+        // Outside insert mode the cursor rests on the last character, not after it
+        self.cursor.x = if x >= len && self.mode != Mode::Insert { len.saturating_sub(1) } else { x };
+        Ok(())
+    }
+
+    // The mouse wheel: moves the view MOUSE_SCROLL_LINES lines and drags the cursor
+    // along when it would leave the screen
+    fn scroll_view(&mut self, down: bool) -> io::Result<()> {
+        let rows = self.pane_text_area(self.pane_area).height.max(1) as usize;
+        let last = self.buffer.total_lines.saturating_sub(1);
+        self.offset_y = if down {
+            (self.offset_y + MOUSE_SCROLL_LINES).min(last)
+        } else {
+            self.offset_y.saturating_sub(MOUSE_SCROLL_LINES)
+        };
+        let y = self.cursor.y.clamp(self.offset_y, (self.offset_y + rows - 1).min(last));
+        if y != self.cursor.y {
+            self.ensure_line_loaded(y)?;
+            if self.buffer.get_line(y).is_some() {
+                self.cursor.y = y;
+            }
+            let line_len = self.buffer.get_line(self.cursor.y).map_or(0, |line| line.chars().count());
+            self.cursor.x = self.cursor.x.min(line_len);
+        }
+        Ok(())
+    }
+
+    // Ctrl+D / Ctrl+U: moves the cursor and the view together by half the pane, so the
+    // cursor keeps its row on screen
+    fn scroll_half_page(&mut self, down: bool) -> io::Result<()> {
+        let half = (self.pane_text_area(self.pane_area).height as usize / 2).max(1);
+        let last = self.buffer.total_lines.saturating_sub(1);
+        let row = self.cursor.y.saturating_sub(self.offset_y);
+        let target = if down { (self.cursor.y + half).min(last) } else { self.cursor.y.saturating_sub(half) };
+        self.offset_y = target.saturating_sub(row);
+        self.move_cursor(0, target as isize - self.cursor.y as isize)
+    }
+
+    fn open_keymap(&mut self) {
+        let items = KEY_BINDINGS
+            .iter()
+            .map(|binding| format!("{:<8} {:<18} {}", binding.mode, binding.keys, binding.action))
+            .collect();
+        self.popup = Some(Popup::new(PopupKind::KeyMap, " Key Mappings ", items));
+    }
+
+    // `:browse oldfiles` lists remembered files, most recent first
+    fn open_oldfiles(&mut self) {
+        let entries = PositionStore::load();
+        if entries.is_empty() {
+            self.status_message = "No recently edited files".to_string();
+            return;
+        }
+        let items = entries
+            .iter()
+            .map(|(path, y, _)| format!("{}  (line {})", path.display(), y + 1))
+            .collect();
+        let dimmed = entries.iter().map(|(path, _, _)| !path.exists()).collect();
+        let mut popup = Popup::new(PopupKind::OldFiles(entries), " Recent Files ", items);
+        popup.dimmed = dimmed;
+        self.popup = Some(popup);
+    }
+
+    // `:whitespace` streams the whole file and lists hygiene problems in a popup
+    fn open_whitespace_report(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.filename {
+            self.buffer.scan_mapped(path, usize::MAX)?;
+        }
+        let mut report = WhitespaceReport::default();
+        let mut y = 0;
+        self.for_each_line_in((0, self.buffer.total_lines.saturating_sub(1)), |line| {
+            report.scan_line(y, line);
+            y += 1;
+            Ok(())
+        })?;
+        let (items, targets): (Vec<String>, Vec<Option<usize>>) = report.rows().into_iter().unzip();
+        self.popup = Some(Popup::new(PopupKind::Whitespace(targets), " Whitespace ", items));
+        Ok(())
+    }
+
+    // `:references` / `gr`: greps the working directory for the word under the cursor on
+    // a background thread; `poll_references` picks up the result
+    fn start_references(&mut self) {
+        let word = match self.word_under_cursor() {
+            Some((_, _, word)) => word,
+            None => {
+                self.status_message = "No word under cursor".to_string();
+                return;
+            }
+        };
+        let root = match std::env::current_dir() {
+            Ok(root) => root,
+            Err(e) => {
+                self.status_message = format!("references: {}", e);
+                return;
+            }
+        };
+        let (sender, receiver) = mpsc::channel();
+        let keywords = self.iskeyword.clone();
+        let needle = word.clone();
+        std::thread::spawn(move || {
+            let _ = sender.send(find_references(&root, &needle, &keywords));
+        });
+        self.status_message = format!("Searching for references to '{}'…", word);
+        self.references_job = Some((word, receiver));
+    }
+
+    fn poll_references(&mut self) {
+        let result = match self.references_job {
+            Some((_, ref receiver)) => receiver.try_recv(),
+            None => return,
+        };
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => Vec::new(),
+        };
+        let word = self.references_job.take().map(|(word, _)| word).unwrap_or_default();
+        if entries.is_empty() {
+            self.status_message = format!("No references to '{}'", word);
+            return;
+        }
+        let files = entries.iter().map(|(path, ..)| path).collect::<HashSet<_>>().len();
+        self.status_message = format!("{} references to '{}' in {} file(s)", entries.len(), word, files);
+        self.quickfix = entries;
+        self.quickfix_index = 0;
+        self.open_quickfix();
+    }
+
+    fn open_quickfix(&mut self) {
+        if self.quickfix.is_empty() {
+            self.status_message = "Quickfix list is empty".to_string();
+            return;
+        }
+        let items = self
+            .quickfix
+            .iter()
+            .map(|(path, y, _, text)| format!("{}:{}: {}", path.display(), y + 1, text))
+            .collect();
+        let mut popup = Popup::new(PopupKind::Quickfix, " Quickfix ", items);
+        popup.selected = self.quickfix_index;
+        self.popup = Some(popup);
+    }
+
+    // Opens quickfix entry `index`, switching files when needed
+    fn jump_to_quickfix(&mut self, index: usize) -> io::Result<()> {
+        let (path, y, x, _) = match self.quickfix.get(index) {
+            Some(entry) => entry.clone(),
+            None => return Ok(()),
+        };
+        self.quickfix_index = index;
+        self.edit_file(&path.to_string_lossy(), false)?;
+        if self.filename.as_ref().is_some_and(|current| same_path(current, &path)) {
+            self.jump_to_saved_position(y, x)?;
+            self.status_message = format!("({} of {}) {}:{}", index + 1, self.quickfix.len(), path.display(), y + 1);
+        }
+        Ok(())
+    }
+
+    // `:cn` / `:cp`
+    fn step_quickfix(&mut self, forward: bool) -> io::Result<()> {
+        if self.quickfix.is_empty() {
+            self.status_message = "Quickfix list is empty".to_string();
+            return Ok(());
+        }
+        let index = if forward {
+            (self.quickfix_index + 1).min(self.quickfix.len() - 1)
+        } else {
+            self.quickfix_index.saturating_sub(1)
+        };
+        self.jump_to_quickfix(index)
+    }
+
+    fn accept_popup(&mut self, popup: Popup) -> io::Result<()> {
+        let index = popup.selected_index();
+        if let (PopupKind::Themes, Some(index)) = (&popup.kind, index) {
+            self.set_theme(&popup.items[index]);
+            return Ok(());
+        }
+        if let (PopupKind::Quickfix, Some(index)) = (&popup.kind, index) {
+            return self.jump_to_quickfix(index);
+        }
+        if let (PopupKind::Whitespace(targets), Some(index)) = (&popup.kind, index) {
+            if let Some(y) = targets[index] {
+                self.jump_to_saved_position(y, 0)?;
+            }
+            return Ok(());
+        }
+        if let (PopupKind::OldFiles(entries), Some(index)) = (popup.kind, index) {
+            let (path, y, x) = entries[index].clone();
+            if !path.exists() {
+                self.status_message = format!("{} no longer exists", path.display());
+                return Ok(());
+            }
+            self.edit_file(&path.to_string_lossy(), false)?;
+            if self.filename.as_ref() == Some(&path) {
+                self.jump_to_saved_position(y, x)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_popup(&mut self, key: KeyEvent) -> io::Result<bool> {
+        let popup = match self.popup.as_mut() {
+            Some(popup) => popup,
+            None => return Ok(true),
+        };
+        let visible = popup.visible_items().len();
+
+        match key.code {
+            KeyCode::Esc => {
+                self.popup = None;
+            }
+            KeyCode::Enter => {
+                if let Some(popup) = self.popup.take() {
+                    self.accept_popup(popup)?;
+                }
+            }
+            KeyCode::Up => popup.selected = popup.selected.saturating_sub(1),
+            KeyCode::Down => popup.selected = (popup.selected + 1).min(visible.saturating_sub(1)),
+            KeyCode::PageUp => popup.selected = popup.selected.saturating_sub(10),
+            KeyCode::PageDown => popup.selected = (popup.selected + 10).min(visible.saturating_sub(1)),
+            KeyCode::Backspace => {
+                popup.filter.pop();
+                popup.selected = 0;
+            }
+            KeyCode::Char(c) => {
+                popup.filter.push(c);
+                popup.selected = 0;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn handle_prompt(&mut self, key: KeyEvent) -> io::Result<bool> {
+        let prompt = match self.pending_prompt.take() {
+            Some(prompt) => prompt,
+            None => return Ok(true),
+        };
+
+        let confirmed = match key.code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => true,
+            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => false,
+            _ => {
+                // Keep asking until we get a clear answer
+                self.pending_prompt = Some(prompt);
+                return Ok(true);
+            }
+        };
+
+        match prompt {
+            Prompt::CreateDir { dir, quit_after } => {
+                if !confirmed {
+                    self.status_message = format!("Save aborted: {} was not created", dir.display());
+                    return Ok(true);
+                }
+                if let Err(e) = fs::create_dir_all(&dir) {
+                    self.status_message = format!("Error creating directory: {}", e);
+                    return Ok(true);
+                }
+                match self.save_file(true) {
+                    Ok(()) if quit_after && !self.modified => return Ok(false),
+                    Ok(()) => {}
+                    Err(e) => self.status_message = format!("Error saving: {}", e),
+                }
+            }
+            Prompt::RecreateDeleted { quit_after } => {
+                if !confirmed {
+                    self.status_message = "Save aborted: the file stays deleted".to_string();
+                    return Ok(true);
+                }
+                match self.save_file(true) {
+                    Ok(()) if quit_after && !self.modified => return Ok(false),
+                    Ok(()) => {}
+                    Err(e) => self.status_message = format!("Error saving: {}", e),
+                }
+            }
+            Prompt::CloseBuffer => {
+                if confirmed {
+                    self.delete_buffer(true);
+                } else {
+                    self.status_message = "Buffer kept open".to_string();
+                }
+            }
+            Prompt::OverwriteChanged { quit_after } => {
+                if !confirmed {
+                    self.status_message = "Save aborted: the file on disk was left alone (:merge combines both)".to_string();
+                    return Ok(true);
+                }
+                match self.save_file(true) {
+                    Ok(()) if quit_after && !self.modified => return Ok(false),
+                    Ok(()) => {}
+                    Err(e) => self.status_message = format!("Error saving: {}", e),
+                }
+            }
+            Prompt::DiscardAndReload => {
+                if !confirmed {
+                    self.status_message = "Reload cancelled; your changes are kept".to_string();
+                    return Ok(true);
+                }
+                if let Err(e) = self.reopen_with_options("", true) {
+                    self.status_message = format!("Error reloading: {}", e);
+                }
+            }
+            Prompt::OverwriteFile { path, rename } => {
+                if !confirmed {
+                    self.status_message = format!("Not written: {} already exists", path.display());
+                    return Ok(true);
+                }
+                if let Err(e) = self.write_to_path(path, rename) {
+                    self.status_message = format!("Error writing: {}", e);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Multi-key sequences such as `gS<char>` or `3]<space>`, in normal mode with an
+    // optional leading count
+    fn handle_pending_keys(&mut self, key: KeyEvent) -> io::Result<bool> {
+        let only_count = self.pending_keys.chars().all(|c| c.is_ascii_digit());
+        let c = match key.code {
+            KeyCode::Char(c) => c,
+            _ => {
+                let count = Self::parse_count(&self.pending_keys);
+                self.pending_keys.clear();
+                if only_count {
+                    return self.run_counted(key, count);
+                }
+                if self.mode == Mode::Normal {
+                    self.status_message = "Normal mode".to_string();
+                }
+                return Ok(true);
+            }
+        };
+        self.pending_keys.push(c);
+
+        let digits = self.pending_keys.chars().take_while(|c| c.is_ascii_digit()).count();
+        let count = (digits > 0).then(|| Self::parse_count(&self.pending_keys[..digits]));
+        let sequence = self.pending_keys[digits..].to_string();
+        if sequence.is_empty() {
+            // Still typing the count
+            return Ok(true);
+        }
+        match self.sequence_binding(&sequence) {
+            SequenceMatch::Partial => return Ok(true),
+            SequenceMatch::Complete(binding) => {
+                self.pending_keys.clear();
+                (binding.run)(self, key, count)?;
+            }
+            SequenceMatch::NoMatch if digits > 0 && sequence.chars().count() == 1 => {
+                self.pending_keys.clear();
+                return self.run_counted(key, count.unwrap_or(1));
+            }
+            SequenceMatch::NoMatch => {
+                self.pending_keys.clear();
+                self.status_message = format!("Unknown command: {}", sequence);
+            }
+        }
+        Ok(true)
+    }
+
+    // The modes whose rows of KEY_BINDINGS apply now, most specific first
+    fn binding_modes(&self) -> &'static [&'static str] {
+        match self.mode {
+            Mode::Normal => &["normal"],
+            Mode::Insert => &["insert"],
+            Mode::Command => &["command"],
+            Mode::Search => &["search"],
+            Mode::Replace => &["replace"],
+            Mode::ConfirmReplace => &["confirm"],
+            Mode::Visual | Mode::VisualLine => &["visual"],
+            Mode::VisualBlock => &["block", "visual"],
+        }
+    }
+
+    // The first binding of the current mode that `key` presses
+    fn pressed_binding(&self, key: &KeyEvent) -> Option<&'static KeyBinding> {
+        let held = key.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT);
+        self.binding_modes().iter().find_map(|mode| {
+            KEY_BINDINGS.iter().find(|binding| {
+                binding.mode == *mode
+                    && matches!(binding.trigger, KeyTrigger::Press(presses)
+                        if presses.iter().any(|&(code, modifiers)| code == key.code && modifiers == held))
+            })
+        })
+    }
+
+    // The sequence binding of the current mode that `typed` completes or begins
+    fn sequence_binding(&self, typed: &str) -> SequenceMatch {
+        let mut partial = false;
+        for mode in self.binding_modes() {
+            for binding in KEY_BINDINGS.iter().filter(|binding| binding.mode == *mode) {
+                let pattern = match binding.trigger {
+                    KeyTrigger::Sequence(pattern) => pattern,
+                    _ => continue,
+                };
+                // A trailing `<char>` / `<reg>` takes any one character
+                let (fixed, any) = match pattern.find('<') {
+                    Some(at) => (&pattern[..at], true),
+                    None => (pattern, false),
+                };
+                let complete = if any {
+                    typed.strip_prefix(fixed).is_some_and(|rest| rest.chars().count() == 1)
+                } else {
+                    typed == fixed
+                };
+                if complete {
+                    return SequenceMatch::Complete(binding);
+                }
+                partial |= fixed.starts_with(typed);
+            }
+        }
+        if partial { SequenceMatch::Partial } else { SequenceMatch::NoMatch }
+    }
+
+    // A plain character that begins a sequence binding, or a count in normal mode
+    fn starts_sequence(&self, key: &KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+                (self.mode == Mode::Normal && matches!(c, '1'..='9'))
+                    || !matches!(self.sequence_binding(&c.to_string()), SequenceMatch::NoMatch)
+            }
+            _ => false,
+        }
+    }
+
+    // The count typed before a command: 1 without one, and at most MAX_COUNT
+    fn parse_count(digits: &str) -> usize {
+        if digits.is_empty() {
+            return 1;
+        }
+        digits.parse::<usize>().unwrap_or(MAX_COUNT).clamp(1, MAX_COUNT)
+    }
+
+    // A count in front of a motion, undo or `.` repeats it (`5↓`, `3w`, `2u`); other keys
+    // ignore it and run once. Repeating stops as soon as a round changes nothing, such as
+    // a motion stuck at the end of the file.
+    fn run_counted(&mut self, key: KeyEvent, count: usize) -> io::Result<bool> {
+        let repeats = matches!(key.code, KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right)
+            || (key.modifiers.is_empty() && matches!(key.code, KeyCode::Char('w' | 'b' | 'e' | 'u' | 'n' | 'N' | '.')));
+        for _ in 0..if repeats { count.min(MAX_COUNT) } else { 1 } {
+            let before = (self.cursor.y, self.cursor.x, self.edit_count, self.undo_stack.len());
+            self.handle_normal_mode(key)?;
+            if (self.cursor.y, self.cursor.x, self.edit_count, self.undo_stack.len()) == before {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    // Lines carrying a marker of an enabled kind, sorted and de-duplicated. Annotations
+    // cover the whole file; search matches only the loaded region.
+    fn marker_lines(&self) -> Vec<usize> {
+        let mut lines = Vec::new();
+        for kind in &self.marker_kinds {
+            match kind {
+                MarkerKind::Annotation => lines.extend(self.buffer.virtual_text.keys().copied()),
+                MarkerKind::Search if !self.search_query.is_empty() => {
+                    lines.extend(self.search().into_iter().map(|(y, _)| y));
+                }
+                MarkerKind::Search => {}
+            }
+        }
+        lines.sort_unstable();
+        lines.dedup();
+        lines
+    }
+
+    // `]d` / `[d`: move to the next / previous marked line, `count` markers away
+    fn jump_to_marker(&mut self, forward: bool, count: usize) -> io::Result<()> {
+        let lines = self.marker_lines();
+        let y = self.cursor.y;
+        let target = if forward {
+            lines.iter().filter(|&&line| line > y).nth(count - 1)
+        } else {
+            lines.iter().rev().filter(|&&line| line < y).nth(count - 1)
+        };
+        match target {
+            Some(&line) => {
+                self.ensure_line_loaded(line)?;
+                self.cursor.y = line;
+                self.cursor.x = 0;
+                let position = lines.iter().position(|&l| l == line).map_or(0, |i| i + 1);
+                self.status_message = format!("Marker {} of {}", position, lines.len());
+            }
+            None if lines.is_empty() => self.status_message = "No markers".to_string(),
+            None => self.status_message = "No more markers".to_string(),
+        }
+        Ok(())
+    }
+
+    // `]<space>` / `[<space>`: add empty lines below/above without leaving normal mode
+    fn insert_blank_lines(&mut self, count: usize, below: bool) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let at = if below { self.cursor.y + 1 } else { self.cursor.y };
+        if !self.replace_line_range(at, 0, vec![String::new(); count]) {
+            self.status_message = "Line is outside the loaded chunk".to_string();
+            return;
+        }
+        if !below {
+            // Stay on the same text, which moved down
+            self.cursor.y += count;
+        }
+        self.status_message = format!("Added {} blank line(s) {}", count, if below { "below" } else { "above" });
+    }
+
+    // Alt+Up / Alt+Down: swaps the current line, or every line the selection touches,
+    // with the line above / below. The cursor and selection travel with the text.
+    fn move_lines(&mut self, up: bool) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let (start, end) = if matches!(self.mode, Mode::Visual | Mode::VisualLine | Mode::VisualBlock) {
+            let ((start, _), (end, _)) = self.selection_bounds();
+            (start, end)
+        } else {
+            (self.cursor.y, self.cursor.y)
+        };
+        if (up && start == 0) || (!up && end + 1 >= self.buffer.total_lines) {
+            return;
+        }
+        let (first, last) = if up { (start - 1, end) } else { (start, end + 1) };
+        let mut lines: Vec<String> = (first..=last).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        if up {
+            lines.rotate_left(1);
+        } else {
+            lines.rotate_right(1);
+        }
+        if !self.replace_line_range(first, last - first + 1, lines) {
+            self.status_message = "Line is outside the loaded chunk".to_string();
+            return;
+        }
+        if up {
+            self.cursor.y -= 1;
+            self.visual_anchor.y = self.visual_anchor.y.saturating_sub(1);
+        } else {
+            self.cursor.y += 1;
+            self.visual_anchor.y += 1;
         }
     }
 
-    fn load_chunk(&mut self, file_path: &PathBuf, start_line: usize) -> io::Result<()> {
-        self.lines.clear();
-        
-        if !file_path.exists() {
-            self.lines.push_back(String::new());
-            self.total_lines = 1;
-            self.start_line_number = 0;
-            return Ok(());
+    // `q<reg>` starts recording keystrokes into a register; an uppercase name appends
+    // to the lowercase one, as in vim
+    fn start_recording(&mut self, register: char) {
+        if !register.is_ascii_alphanumeric() {
+            self.status_message = format!("Invalid register: {}", register);
+            return;
         }
+        let name = register.to_ascii_lowercase();
+        let keys = if register.is_ascii_uppercase() {
+            self.macros.get(&name).cloned().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        self.recording = Some((name, keys));
+        self.status_message = format!("Recording @{}", name);
+    }
 
-        let file = File::open(file_path)?;
-        let reader = BufReader::new(file);
-        let all_lines: Vec<String> = reader.lines().collect::<Result<Vec<_>, _>>()?;
-        
-        self.total_lines = if all_lines.is_empty() { 1 } else { all_lines.len() };
-        
-        if all_lines.is_empty() {
-            self.lines.push_back(String::new());
-            self.start_line_number = 0;
-            return Ok(());
+    fn stop_recording(&mut self) {
+        if let Some((name, keys)) = self.recording.take() {
+            self.status_message = format!("Recorded {} key(s) into @{}", keys.len(), name);
+            self.macros.insert(name, keys);
         }
+    }
 
-        let actual_start = start_line.min(all_lines.len().saturating_sub(1));
-        let end = (actual_start + self.max_lines).min(all_lines.len());
-        
-        for i in actual_start..end {
-            let line = &all_lines[i];
-            if line.len() > MAX_LINE_LENGTH {
-                self.lines.push_back(line[..MAX_LINE_LENGTH].to_string());
-            } else {
-                self.lines.push_back(line.clone());
+    // `@<reg>` feeds the register's keys back through `handle_key_event`, `count` times;
+    // `@@` repeats the last macro played. Stops early if a key asks to quit. A register
+    // may not play itself, directly or through another one.
+    fn play_macro(&mut self, register: char, count: usize) -> io::Result<bool> {
+        let name = if register == '@' {
+            match self.last_macro {
+                Some(name) => name,
+                None => {
+                    self.status_message = "No previous macro".to_string();
+                    return Ok(true);
+                }
+            }
+        } else {
+            register.to_ascii_lowercase()
+        };
+        let keys = match self.macros.get(&name) {
+            Some(keys) => keys.clone(),
+            None => {
+                self.status_message = format!("Register @{} is empty", name);
+                return Ok(true);
             }
+        };
+        if self.playing_macros.contains(&name) {
+            self.status_message = format!("Macro @{} calls itself", name);
+            return Ok(true);
         }
-        
-        self.start_line_number = actual_start;
-        Ok(())
+        if self.macro_depth >= MAX_MACRO_DEPTH {
+            self.status_message = "Macro nested too deeply".to_string();
+            return Ok(true);
+        }
+        self.last_macro = Some(name);
+        self.macro_depth += 1;
+        self.playing_macros.push(name);
+        let mut result = Ok(true);
+        'replay: for _ in 0..count.min(MAX_COUNT) {
+            for &key in &keys {
+                result = self.handle_key_event(key);
+                if !matches!(result, Ok(true)) {
+                    break 'replay;
+                }
+            }
+        }
+        self.playing_macros.pop();
+        self.macro_depth -= 1;
+        result
     }
 
-    fn get_line(&self, index: usize) -> Option<&String> {
-        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
-            self.lines.get(index - self.start_line_number)
+    fn handle_normal_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if self.pending_window {
+            self.handle_window_key(key)?;
+            return Ok(true);
+        }
+        if !self.pending_keys.is_empty() {
+            return self.handle_pending_keys(key);
+        }
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if self.starts_sequence(&key) {
+            return self.handle_pending_keys(key);
         } else {
-            None
+            self.quit_requested = false;
         }
+        Ok(true)
     }
 
-    fn get_line_mut(&mut self, index: usize) -> Option<&mut String> {
-        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
-            self.lines.get_mut(index - self.start_line_number)
-        } else {
-            None
+    // `i` / `R`: insert or overwrite at the cursor. Read-only buffers refuse, and then
+    // this returns false.
+    fn start_insert(&mut self, overwrite: bool) -> bool {
+        if self.refuse_read_only() {
+            return false;
         }
+        self.mode = Mode::Insert;
+        self.overwrite = overwrite;
+        self.status_message = self.insert_mode_label();
+        true
     }
 
-    fn insert_line(&mut self, index: usize, content: String) {
-        if index >= self.start_line_number && index <= self.start_line_number + self.lines.len() {
-            let local_index = index - self.start_line_number;
-            let truncated = if content.len() > MAX_LINE_LENGTH {
-                content[..MAX_LINE_LENGTH].to_string()
-            } else {
-                content
-            };
-            self.lines.insert(local_index, truncated);
-            self.total_lines += 1;
-            
-            // Keep buffer size manageable
-            if self.lines.len() > self.max_lines {
-                self.lines.pop_back();
+    // Arrows, Home, End and PageUp / PageDown
+    fn move_by_key(&mut self, code: KeyCode) {
+        // Moving off the edge of the file is not an error worth reporting
+        let _ = match code {
+            KeyCode::Up => self.move_cursor(0, -1),
+            KeyCode::Down => self.move_cursor(0, 1),
+            KeyCode::Left => self.move_cursor(-1, 0),
+            KeyCode::Right => self.move_cursor(1, 0),
+            KeyCode::PageUp | KeyCode::PageDown => {
+                self.needs_reload = true;
+                self.move_cursor(0, if code == KeyCode::PageUp { -20 } else { 20 })
             }
-        }
+            KeyCode::Home => {
+                self.move_home();
+                Ok(())
+            }
+            KeyCode::End => {
+                if let Some(line) = self.buffer.get_line(self.cursor.y) {
+                    self.cursor.x = line.chars().count().saturating_sub(1);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        };
     }
 
-    fn remove_line(&mut self, index: usize) -> Option<String> {
-        if index >= self.start_line_number && index < self.start_line_number + self.lines.len() {
-            let local_index = index - self.start_line_number;
-            self.total_lines = self.total_lines.saturating_sub(1);
-            if self.total_lines == 0 {
-                self.total_lines = 1;
-            }
-            self.lines.remove(local_index)
-        } else {
-            None
+    // Shows `result`'s error, if any, in the status line after `context`
+    fn report_error(&mut self, context: &str, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.status_message = format!("{}: {}", context, e);
         }
     }
-}
 
-struct Editor {
-    buffer: LineBuffer,
-    cursor: Cursor,
-    offset_y: usize,
-    mode: Mode,
-    filename: Option<PathBuf>,
-    modified: bool,
-    status_message: String,
-    command_buffer: String,
-    search_query: String,
-    replace_query: String,
-    replace_with: String,
-    syntax_set: SyntaxSet,
-    theme: Theme,
-    show_line_numbers: bool,
-    show_help: bool,
-    clipboard: String,
-    undo_buffer: VecDeque<String>,
-    quit_requested: bool,
-    last_operation: Instant,
-    needs_reload: bool,
-}
+    fn start_visual(&mut self, mode: Mode) {
+        self.visual_anchor = self.cursor.clone();
+        self.status_message = match mode {
+            Mode::VisualLine => "-- VISUAL LINE --",
+            Mode::VisualBlock => "-- VISUAL BLOCK --",
+            _ => "-- VISUAL --",
+        }.to_string();
+        self.mode = mode;
+    }
 
-impl Editor {
-    fn new() -> Self {
-        let syntax_set = SyntaxSet::load_defaults_newlines();
-        let theme_set = ThemeSet::load_defaults();
-        let theme = theme_set.themes.get("base16-ocean.dark")
-            .or_else(|| theme_set.themes.values().next())
-            .unwrap()
-            .clone();
-        
-        Self {
-            buffer: LineBuffer::new(MAX_VISIBLE_LINES),
-            cursor: Cursor { x: 0, y: 0 },
-            offset_y: 0,
-            mode: Mode::Normal,
-            filename: None,
-            modified: false,
-            status_message: "TuxPad - Press F1 for help | ESC for normal mode".to_string(),
-            command_buffer: String::new(),
-            search_query: String::new(),
-            replace_query: String::new(),
-            replace_with: String::new(),
-            syntax_set,
-            theme,
-            show_line_numbers: true,
-            show_help: false,
-            clipboard: String::new(),
-            undo_buffer: VecDeque::new(),
-            quit_requested: false,
-            last_operation: Instant::now(),
-            needs_reload: false,
+    // Pressing the key of the current visual mode leaves it; the other one switches
+    // between charwise and linewise while keeping the anchor and cursor
+    fn switch_visual(&mut self, mode: Mode) {
+        if self.mode == mode {
+            self.mode = Mode::Normal;
+            self.status_message = "Normal mode".to_string();
+        } else {
+            let anchor = self.visual_anchor.clone();
+            self.start_visual(mode);
+            self.visual_anchor = anchor;
         }
     }
 
-    fn load_file(&mut self, path: &PathBuf) -> io::Result<()> {
-        if let Err(e) = self.buffer.load_chunk(path, 0) {
-            return Err(e);
-        }
-        
-        self.filename = Some(path.clone());
-        self.cursor = Cursor { x: 0, y: 0 };
-        self.offset_y = 0;
-        self.modified = false;
-        self.status_message = format!("Loaded: {} ({} lines)", path.display(), self.buffer.total_lines);
-        Ok(())
+    // Ordered (start, end) of the selection in (line, character); `end` is inclusive
+    fn selection_bounds(&self) -> ((usize, usize), (usize, usize)) {
+        let anchor = (self.visual_anchor.y, self.visual_anchor.x);
+        let cursor = (self.cursor.y, self.cursor.x);
+        if anchor <= cursor { (anchor, cursor) } else { (cursor, anchor) }
     }
 
-    fn save_file(&mut self) -> io::Result<()> {
-        if let Some(ref path) = self.filename {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
+    // Screen columns spanned by a block selection, both inclusive
+    fn block_columns(&self) -> (usize, usize) {
+        let column = |cursor: &Cursor| {
+            self.buffer.get_line(cursor.y)
+                .map_or(cursor.x, |line| display_column(line, char_to_byte(line, cursor.x), self.tabstop))
+        };
+        let (anchor, cursor) = (column(&self.visual_anchor), column(&self.cursor));
+        (anchor.min(cursor), anchor.max(cursor))
+    }
+
+    // Bytes of `line` drawn within screen columns `left..=right`; `None` when the line
+    // ends before the block starts
+    fn block_byte_range(line: &str, left: usize, right: usize, tabstop: usize) -> Option<(usize, usize)> {
+        let tabstop = tabstop.max(1);
+        let mut range: Option<(usize, usize)> = None;
+        let mut column = 0;
+        for (i, c) in line.char_indices() {
+            if column > right {
+                break;
             }
-            
-            let file = File::create(path)?;
-            let mut writer = BufWriter::new(file);
-            
-            // For large files, we need to reconstruct from chunks
-            if self.buffer.total_lines > MAX_VISIBLE_LINES {
-                // This is a simplified approach - in production you'd want 
-                // to maintain the full file state or implement proper chunking
-                for (i, line) in self.buffer.lines.iter().enumerate() {
-                    writeln!(writer, "{}", line)?;
-                }
-            } else {
-                for (i, line) in self.buffer.lines.iter().enumerate() {
-                    if i == self.buffer.lines.len() - 1 {
-                        write!(writer, "{}", line)?;
-                    } else {
-                        writeln!(writer, "{}", line)?;
-                    }
-                }
+            let width = if c == '\t' { tabstop - column % tabstop } else { char_width(c).max(1) };
+            if column + width > left {
+                range = Some((range.map_or(i, |(from, _)| from), i + c.len_utf8()));
             }
-            
-            writer.flush()?;
-            self.modified = false;
-            self.status_message = format!("Saved: {} ({} lines)", path.display(), self.buffer.total_lines);
-        } else {
-            self.status_message = "No filename specified. Use :w filename to save".to_string();
+            column += if c == '\t' { width } else { char_width(c) };
         }
-        Ok(())
+        range
     }
 
-    fn save_undo_state(&mut self) {
-        if let Some(line) = self.buffer.get_line(self.cursor.y) {
-            self.undo_buffer.push_back(line.clone());
-            if self.undo_buffer.len() > 50 {
-                self.undo_buffer.pop_front();
-            }
+    // The cached block rows, if they were worked out for the current selection
+    fn cached_block(&self) -> Option<&BlockCache> {
+        self.block_cache.as_ref().filter(|cache| {
+            cache.anchor == (self.visual_anchor.y, self.visual_anchor.x)
+                && cache.cursor == (self.cursor.y, self.cursor.x)
+                && cache.tabstop == self.tabstop
+        })
+    }
+
+    // Works out the block selection's rows for the redraw, unless the selection is the
+    // one already cached
+    fn refresh_block_cache(&mut self) {
+        if self.mode != Mode::VisualBlock {
+            self.block_cache = None;
+            return;
+        }
+        if self.cached_block().is_some() {
+            return;
         }
+        let anchor = (self.visual_anchor.y, self.visual_anchor.x);
+        let cursor = (self.cursor.y, self.cursor.x);
+        let ((start_y, _), (end_y, _)) = self.selection_bounds();
+        let (left, right) = self.block_columns();
+        let ranges = (start_y..=end_y)
+            .filter_map(|y| Some((y, Self::block_byte_range(self.buffer.get_line(y)?, left, right, self.tabstop))))
+            .collect();
+        self.block_cache = Some(BlockCache { anchor, cursor, tabstop: self.tabstop, ranges });
     }
 
-    fn insert_char(&mut self, c: char) -> io::Result<()> {
-        self.save_undo_state();
-        
-        if let Some(line) = self.buffer.get_line_mut(self.cursor.y) {
-            if line.len() < MAX_LINE_LENGTH {
-                let insert_pos = self.cursor.x.min(line.len());
-                line.insert(insert_pos, c);
-                self.cursor.x = insert_pos + 1;
-                self.modified = true;
-            } else {
-                self.status_message = "Line too long".to_string();
+    // `r<char>` in visual modes overwrites every selected character, as one undo step.
+    // Wide characters become as many copies as they took cells, keeping columns aligned.
+    fn replace_selection(&mut self, replacement: char) {
+        if self.refuse_read_only() {
+            return;
+        }
+        let ((start_y, _), (end_y, _)) = self.selection_bounds();
+        let mut edits = Vec::new();
+        for line_idx in start_y..=end_y {
+            let line = match self.buffer.get_line(line_idx) {
+                Some(line) => line.clone(),
+                None => continue,
+            };
+            let (from, to) = match self.selection_on_line(line_idx, line.len()) {
+                Some(range) if range.0 < range.1 => range,
+                _ => continue,
+            };
+            let replaced: String = line[from..to]
+                .chars()
+                .flat_map(|c| std::iter::repeat_n(replacement, char_width(c).max(1)))
+                .collect();
+            let new_line = format!("{}{}{}", &line[..from], replaced, &line[to..]);
+            if new_line != line {
+                edits.push(EditOp::ReplaceRange { start: line_idx, old: vec![line], new: vec![new_line] });
             }
-        } else {
-            // Need to reload chunk
-            self.reload_current_chunk()?;
-            if let Some(line) = self.buffer.get_line_mut(self.cursor.y) {
-                if line.len() < MAX_LINE_LENGTH {
-                    let insert_pos = self.cursor.x.min(line.len());
-                    line.insert(insert_pos, c);
-                    self.cursor.x = insert_pos + 1;
-                    self.modified = true;
+        }
+
+        let lines_changed = edits.len();
+        for edit in &edits {
+            if let EditOp::ReplaceRange { start, new, .. } = edit {
+                if let Some(line) = self.buffer.get_line_mut(*start) {
+                    *line = new[0].clone();
                 }
             }
         }
-        Ok(())
+        if lines_changed > 0 {
+            self.record_edits(edits);
+            self.modified = true;
+        }
+        self.mode = Mode::Normal;
+        self.status_message = format!("Replaced selection on {} line(s)", lines_changed);
     }
 
-    fn delete_char(&mut self) -> io::Result<()> {
-        if self.cursor.x > 0 {
-            self.save_undo_state();
-            if let Some(line) = self.buffer.get_line_mut(self.cursor.y) {
-                if self.cursor.x <= line.len() && !line.is_empty() {
-                    line.remove(self.cursor.x - 1);
-                    self.cursor.x -= 1;
-                    self.modified = true;
-                }
-            }
-        } else if self.cursor.y > 0 {
-            self.save_undo_state();
-            // Handle line joining carefully for large files
-            if let (Some(current_line), Some(prev_line)) = (
-                self.buffer.get_line(self.cursor.y).cloned(),
-                self.buffer.get_line_mut(self.cursor.y - 1)
-            ) {
-                if prev_line.len() + current_line.len() < MAX_LINE_LENGTH {
-                    let new_x = prev_line.len();
-                    prev_line.push_str(&current_line);
-                    self.buffer.remove_line(self.cursor.y);
-                    self.cursor.y -= 1;
-                    self.cursor.x = new_x;
-                    self.modified = true;
-                } else {
-                    self.status_message = "Cannot join: resulting line would be too long".to_string();
-                }
+    // The selected text, one entry per selected line; `None` if part of the selection
+    // lies outside the loaded chunk
+    fn selected_lines(&self) -> Option<Vec<String>> {
+        let ((start_y, _), (end_y, _)) = self.selection_bounds();
+        (start_y..=end_y)
+            .map(|line_idx| {
+                let line = self.buffer.get_line(line_idx)?;
+                let (from, to) = self.selection_on_line(line_idx, line.len()).unwrap_or((0, 0));
+                Some(line[from..to].to_string())
+            })
+            .collect()
+    }
+
+    // `y` in visual mode: copies the selection, leaving the cursor at its start
+    fn yank_selection(&mut self) {
+        let ((start_y, start_x), (end_y, _)) = self.selection_bounds();
+        let lines = match self.selected_lines() {
+            Some(lines) => lines,
+            None => {
+                self.status_message = "Selection reaches outside the loaded chunk".to_string();
+                return;
             }
-        }
-        Ok(())
+        };
+        let linewise = std::mem::replace(&mut self.mode, Mode::Normal) == Mode::VisualLine;
+        self.cursor.y = start_y;
+        self.cursor.x = if linewise { 0 } else { start_x };
+        self.status_message = format!("{} line(s) yanked", lines.len());
+        self.yank(lines, start_y, end_y);
     }
 
-    fn insert_newline(&mut self) -> io::Result<()> {
-        self.save_undo_state();
-        
-        if let Some(current_line) = self.buffer.get_line(self.cursor.y).cloned() {
-            let split_pos = self.cursor.x.min(current_line.len());
-            let new_line = current_line[split_pos..].to_string();
-            
-            if let Some(line) = self.buffer.get_line_mut(self.cursor.y) {
-                line.truncate(split_pos);
+    // `d` / `x` in visual mode: cuts the selection into the clipboard as one undo step.
+    // A charwise selection over several lines joins what's left of its first and last.
+    fn delete_selection(&mut self) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        let ((start_y, _), (end_y, _)) = self.selection_bounds();
+        let lines = match self.selected_lines() {
+            Some(lines) => lines,
+            None => {
+                self.status_message = "Selection reaches outside the loaded chunk".to_string();
+                return Ok(());
             }
-            
-            self.buffer.insert_line(self.cursor.y + 1, new_line);
-            self.cursor.y += 1;
-            self.cursor.x = 0;
-            self.modified = true;
+        };
+        let first = self.buffer.get_line(start_y).cloned().unwrap_or_default();
+        let last = self.buffer.get_line(end_y).cloned().unwrap_or_default();
+        let from = self.selection_on_line(start_y, first.len()).map_or(0, |(from, _)| from);
+        let to = self.selection_on_line(end_y, last.len()).map_or(0, |(_, to)| to);
+        if std::mem::replace(&mut self.mode, Mode::Normal) == Mode::VisualLine {
+            self.cursor.y = start_y;
+            return self.cut_lines(end_y - start_y + 1);
+        }
+
+        let joined = format!("{}{}", &first[..from], &last[to..]);
+        if self.replace_line_range(start_y, end_y - start_y + 1, vec![joined]) {
+            self.cursor.y = start_y;
+            self.cursor.x = first[..from].chars().count();
+            self.status_message = format!("{} line(s) cut", lines.len());
+            self.set_clipboard(lines.join("\n"));
         }
         Ok(())
     }
 
-    fn move_cursor(&mut self, dx: isize, dy: isize) -> io::Result<()> {
-        let old_y = self.cursor.y;
-        
-        // Vertical movement
-        if dy != 0 {
-            let new_y = (self.cursor.y as isize + dy).max(0) as usize;
-            self.cursor.y = new_y.min(self.buffer.total_lines.saturating_sub(1));
-            
-            // Check if we need to reload chunk
-            if self.cursor.y < self.buffer.start_line_number || 
-               self.cursor.y >= self.buffer.start_line_number + self.buffer.lines.len() {
-                self.reload_current_chunk()?;
-            }
+    // `d` / `x` in block mode: removes the block from every row, short rows losing only
+    // what they have of it
+    fn delete_block(&mut self) {
+        if self.refuse_read_only() {
+            return;
         }
-        
-        // Horizontal movement
-        if let Some(line) = self.buffer.get_line(self.cursor.y) {
-            let line_len = line.len();
-            
-            if dx != 0 {
-                let new_x = (self.cursor.x as isize + dx).max(0) as usize;
-                self.cursor.x = match self.mode {
-                    Mode::Insert => new_x.min(line_len),
-                    _ => new_x.min(line_len.saturating_sub(1).max(0)),
-                };
-            } else if dy != 0 {
-                // Clamp x when moving vertically
-                self.cursor.x = match self.mode {
-                    Mode::Insert => self.cursor.x.min(line_len),
-                    _ => self.cursor.x.min(line_len.saturating_sub(1).max(0)),
-                };
+        let ((start_y, _), (end_y, _)) = self.selection_bounds();
+        let (left, right) = self.block_columns();
+        let lines = match self.selected_lines() {
+            Some(lines) => lines,
+            None => {
+                self.status_message = "Selection reaches outside the loaded chunk".to_string();
+                return;
             }
-        } else {
-            self.cursor.x = 0;
+        };
+        let new_lines: Vec<String> = (start_y..=end_y)
+            .filter_map(|y| self.buffer.get_line(y))
+            .map(|line| match Self::block_byte_range(line, left, right, self.tabstop) {
+                Some((from, to)) => format!("{}{}", &line[..from], &line[to..]),
+                None => line.clone(),
+            })
+            .collect();
+        self.mode = Mode::Normal;
+        if self.replace_line_range(start_y, end_y - start_y + 1, new_lines) {
+            self.cursor.y = start_y;
+            self.cursor.x = self.buffer.get_line(start_y)
+                .map_or(0, |line| byte_to_char(line, byte_at_column(line, left, self.tabstop)));
+            self.status_message = format!("Block cut from {} line(s)", lines.len());
+            self.set_clipboard(lines.join("\n"));
         }
-        
-        Ok(())
     }
 
-    fn reload_current_chunk(&mut self) -> io::Result<()> {
-        if let Some(ref path) = self.filename {
-            let chunk_start = self.cursor.y.saturating_sub(MAX_VISIBLE_LINES / 2);
-            self.buffer.load_chunk(path, chunk_start)?;
+    // `I` in block mode: insert at the block's left edge on the first row. Esc repeats
+    // the typed text on the other rows.
+    fn start_block_insert(&mut self) {
+        if self.refuse_read_only() {
+            return;
         }
-        Ok(())
+        let ((first, _), (last, _)) = self.selection_bounds();
+        let (column, _) = self.block_columns();
+        let old: Vec<String> = (first..=last).filter_map(|y| self.buffer.get_line(y).cloned()).collect();
+        if old.len() != last - first + 1 {
+            self.status_message = "Selection reaches outside the loaded chunk".to_string();
+            return;
+        }
+        let x = byte_at_column(&old[0], column, self.tabstop);
+        self.cursor.y = first;
+        self.cursor.x = byte_to_char(&old[0], x);
+        // Typing must start a group of its own to be told apart from earlier edits
+        self.seal_undo_group();
+        self.block_insert = Some(BlockInsert { first, last, column, x, old, undo_mark: self.edit_count });
+        self.mode = Mode::Insert;
+        self.status_message = "-- INSERT (block) --".to_string();
     }
 
-    fn copy_line(&mut self) {
-        if let Some(line) = self.buffer.get_line(self.cursor.y) {
-            self.clipboard = line.clone();
-            self.status_message = "Line copied".to_string();
+    // Copies what was typed on the first row of a block insert to the rest of its rows.
+    // Rows ending before the block's column are left alone, as are all of them when the
+    // typing went beyond that one row (a new line, or edits elsewhere).
+    fn finish_block_insert(&mut self) {
+        let insert = match self.block_insert.take() {
+            Some(insert) => insert,
+            None => return,
+        };
+        let (old_first, new_first) = match self.buffer.get_line(insert.first) {
+            Some(line) => (&insert.old[0], line.clone()),
+            None => return,
+        };
+        let typed_len = new_first.len().saturating_sub(old_first.len());
+        let typed = match new_first.get(insert.x..insert.x + typed_len) {
+            Some(typed) if !typed.is_empty()
+                && self.cursor.y == insert.first
+                && new_first[..insert.x] == old_first[..insert.x]
+                && new_first[insert.x + typed_len..] == old_first[insert.x..] => typed.to_string(),
+            _ => return,
+        };
+        let rows = insert.last - insert.first + 1;
+        let mut new_lines = vec![new_first];
+        for line in &insert.old[1..] {
+            let width = display_column(line, line.len(), self.tabstop);
+            new_lines.push(if width < insert.column {
+                line.clone()
+            } else {
+                let at = byte_at_column(line, insert.column, self.tabstop);
+                format!("{}{}{}", &line[..at], typed, &line[at..])
+            });
+        }
+        if new_lines.iter().any(|line| line.len() > MAX_LINE_LENGTH)
+            || (insert.first..=insert.last).any(|y| self.buffer.get_line(y).is_none())
+        {
+            self.status_message = "Block insert only applied to the first line".to_string();
+            return;
+        }
+
+        // Swap the keystroke-by-keystroke groups for a single edit covering every row
+        while self.undo_stack.back().is_some_and(|group| group.id > insert.undo_mark) {
+            self.undo_stack.pop_back();
         }
+        self.buffer.replace_lines(insert.first, rows, &new_lines);
+        self.record_edits(vec![EditOp::ReplaceRange { start: insert.first, old: insert.old, new: new_lines }]);
+        self.modified = true;
     }
 
-    fn cut_line(&mut self) -> io::Result<()> {
-        if let Some(line) = self.buffer.get_line(self.cursor.y).cloned() {
-            self.save_undo_state();
-            self.clipboard = line;
-            self.buffer.remove_line(self.cursor.y);
-            
-            if self.buffer.total_lines == 0 {
-                self.buffer.lines.push_back(String::new());
-                self.buffer.total_lines = 1;
+    // Byte range of `line_idx` covered by the selection, if any
+    fn selection_on_line(&self, line_idx: usize, line_len: usize) -> Option<(usize, usize)> {
+        let ((start_y, start_x), (end_y, end_x)) = self.selection_bounds();
+        if line_idx < start_y || line_idx > end_y {
+            return None;
+        }
+        match self.mode {
+            Mode::VisualLine => Some((0, line_len)),
+            Mode::VisualBlock => {
+                if let Some(&range) = self.cached_block().and_then(|cache| cache.ranges.get(&line_idx)) {
+                    return range;
+                }
+                let (left, right) = self.block_columns();
+                self.buffer.get_line(line_idx)
+                    .and_then(|line| Self::block_byte_range(line, left, right, self.tabstop))
             }
-            
-            if self.cursor.y >= self.buffer.total_lines {
-                self.cursor.y = self.buffer.total_lines.saturating_sub(1);
+            Mode::Visual => {
+                let byte = |x: usize| {
+                    self.buffer.get_line(line_idx).map_or(line_len, |line| char_to_byte(line, x).min(line_len))
+                };
+                let from = if line_idx == start_y { byte(start_x) } else { 0 };
+                // The selection includes the character under the cursor
+                let to = if line_idx == end_y { byte(end_x + 1) } else { line_len };
+                Some((from, to))
             }
-            self.cursor.x = 0;
-            self.modified = true;
-            self.status_message = "Line cut".to_string();
+            _ => None,
         }
-        Ok(())
     }
 
-    fn paste_line(&mut self) -> io::Result<()> {
-        if !self.clipboard.is_empty() {
-            self.save_undo_state();
-            self.buffer.insert_line(self.cursor.y + 1, self.clipboard.clone());
-            self.cursor.y += 1;
-            self.cursor.x = 0;
-            self.modified = true;
-            self.status_message = "Line pasted".to_string();
+    fn handle_visual_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if !self.pending_keys.is_empty() {
+            return self.handle_pending_keys(key);
         }
-        Ok(())
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if self.starts_sequence(&key) {
+            return self.handle_pending_keys(key);
+        }
+        Ok(true)
     }
 
-    fn search(&self, query: &str) -> Vec<(usize, usize)> {
-        let mut matches = Vec::new();
-        
-        // Only search in currently loaded chunk to avoid performance issues
-        for (local_idx, line) in self.buffer.lines.iter().enumerate() {
-            let line_idx = self.buffer.start_line_number + local_idx;
-            let mut start = 0;
-            while let Some(pos) = line[start..].find(query) {
-                matches.push((line_idx, start + pos));
-                start += pos + 1;
-                if matches.len() > 100 { // Limit matches to prevent slowdown
-                    break;
-                }
-            }
-            if matches.len() > 100 {
-                break;
+    fn handle_insert_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if let KeyCode::Char(c) = key.code {
+            if let Err(e) = self.insert_typed_char(c) {
+                self.status_message = format!("Insert error: {}", e);
             }
         }
-        matches
+        Ok(true)
     }
 
-    fn replace_in_chunk(&mut self, search: &str, replace: &str) -> usize {
-        if search.is_empty() {
-            return 0;
+    // Candidates for the word being typed: command names, `:set` options, or paths for
+    // commands that take a file
+    fn command_completions(&self) -> (usize, Vec<String>) {
+        let buffer = self.command_buffer.as_str();
+        let (start, word) = match buffer.rfind(' ') {
+            Some(space) => (space + 1, &buffer[space + 1..]),
+            None => (0, buffer),
+        };
+        if start == 0 {
+            let mut names: Vec<String> = EX_COMMANDS
+                .iter()
+                .map(|command| command.names[0])
+                .filter(|name| name.starts_with(word))
+                .map(|name| name.to_string())
+                .collect();
+            names.sort();
+            return (start, names);
         }
-        
-        self.save_undo_state();
-        let mut count = 0;
-        
-        for line in self.buffer.lines.iter_mut() {
-            let new_line = line.replace(search, replace);
-            if new_line != *line {
-                if new_line.len() <= MAX_LINE_LENGTH {
-                    count += line.matches(search).count();
-                    *line = new_line;
-                } else {
-                    // Truncate if replacement makes line too long
-                    *line = new_line[..MAX_LINE_LENGTH].to_string();
-                    count += 1;
-                }
+        let command = self.parse_range(buffer).map_or(buffer, |(_, rest)| rest);
+        let items = match Self::find_ex_command(command).map(|(command, _, _)| command.arg) {
+            Some(ExArg::Setting) => {
+                let mut names: Vec<String> = SET_OPTIONS
+                    .iter()
+                    .flat_map(|option| match option.set {
+                        OptionSetter::Flag(_) => vec![option.names[0].to_string(), format!("no{}", option.names[0])],
+                        OptionSetter::Value(_) => vec![format!("{}=", option.names[0])],
+                        OptionSetter::Query(_) => Vec::new(),
+                    })
+                    .filter(|name| name.starts_with(word))
+                    .collect();
+                names.sort();
+                names
             }
-        }
-        
-        if count > 0 {
-            self.modified = true;
-        }
-        count
+            Some(ExArg::File) => Self::path_completions(word),
+            _ => Vec::new(),
+        };
+        (start, items)
     }
 
-    fn handle_key_event(&mut self, key: KeyEvent) -> io::Result<bool> {
-        // Throttle rapid operations to prevent crashes
-        let now = Instant::now();
-        if now.duration_since(self.last_operation).as_millis() < 10 {
-            return Ok(true);
+    fn path_completions(partial: &str) -> Vec<String> {
+        let (dir, prefix) = match partial.rfind('/') {
+            Some(slash) => (&partial[..slash + 1], &partial[slash + 1..]),
+            None => ("", partial),
+        };
+        let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut items: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                // Hidden files only when asked for
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                    return None;
+                }
+                let suffix = if entry.path().is_dir() { "/" } else { "" };
+                Some(format!("{}{}{}", dir, name, suffix))
+            })
+            .collect();
+        items.sort();
+        items
+    }
+
+    // Tab / Shift+Tab: start completing, or move through the open candidate list
+    fn cycle_completion(&mut self, forward: bool) {
+        if let Some(menu) = self.wildmenu.as_mut() {
+            let len = menu.items.len();
+            menu.selected = if forward { (menu.selected + 1) % len } else { (menu.selected + len - 1) % len };
+        } else {
+            let (start, items) = self.command_completions();
+            if items.is_empty() {
+                self.status_message = "No completions".to_string();
+                return;
+            }
+            let original = self.command_buffer[start..].to_string();
+            let selected = if forward { 0 } else { items.len() - 1 };
+            self.wildmenu = Some(Wildmenu { items, selected, start, original });
         }
-        self.last_operation = now;
 
-        let result = match self.mode {
-            Mode::Normal => self.handle_normal_mode(key),
-            Mode::Insert => self.handle_insert_mode(key),
-            Mode::Command => self.handle_command_mode(key),
-            Mode::Search => self.handle_search_mode(key),
-            Mode::Replace => self.handle_replace_mode(key),
-        };
-        
-        if self.needs_reload {
-            self.reload_current_chunk()?;
-            self.needs_reload = false;
+        if let Some(menu) = self.wildmenu.as_ref() {
+            self.command_buffer.truncate(menu.start);
+            self.command_buffer.push_str(&menu.items[menu.selected]);
+            if menu.items.len() == 1 {
+                self.wildmenu = None;
+            }
         }
-        
-        result
     }
 
-    fn handle_normal_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
-        match key.code {
-            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if self.modified && !self.quit_requested {
-                    self.status_message = "File modified! Press Ctrl+Q again to quit without saving".to_string();
-                    self.quit_requested = true;
+    fn handle_command_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if self.wildmenu.is_some() {
+            match key.code {
+                KeyCode::Tab | KeyCode::Down => {
+                    self.cycle_completion(true);
                     return Ok(true);
                 }
-                return Ok(false);
-            }
-            KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if let Err(e) = self.save_file() {
-                    self.status_message = format!("Error saving: {}", e);
-                }
-                self.quit_requested = false;
-            }
-            KeyCode::Char('i') => {
-                self.mode = Mode::Insert;
-                self.status_message = "-- INSERT --".to_string();
-            }
-            KeyCode::Char('a') => {
-                self.mode = Mode::Insert;
-                if let Err(e) = self.move_cursor(1, 0) {
-                    self.status_message = format!("Movement error: {}", e);
-                }
-                self.status_message = "-- INSERT --".to_string();
-            }
-            KeyCode::Char('o') => {
-                self.mode = Mode::Insert;
-                if let Some(line) = self.buffer.get_line(self.cursor.y) {
-                    self.cursor.x = line.len();
-                }
-                if let Err(e) = self.insert_newline() {
-                    self.status_message = format!("Insert error: {}", e);
-                } else {
-                    self.status_message = "-- INSERT --".to_string();
+                KeyCode::BackTab | KeyCode::Up => {
+                    self.cycle_completion(false);
+                    return Ok(true);
                 }
-            }
-            KeyCode::Char(':') => {
-                self.mode = Mode::Command;
-                self.command_buffer.clear();
-                self.status_message = "Command mode".to_string();
-            }
-            KeyCode::Char('/') => {
-                self.mode = Mode::Search;
-                self.search_query.clear();
-                self.status_message = "Search mode".to_string();
-            }
-            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.mode = Mode::Replace;
-                self.replace_query.clear();
-                self.replace_with.clear();
-                self.status_message = "Replace mode".to_string();
-            }
-            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                self.copy_line();
-            }
-            KeyCode::Char('x') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if let Err(e) = self.cut_line() {
-                    self.status_message = format!("Cut error: {}", e);
+                KeyCode::Enter => {
+                    // Accept the highlighted candidate without running the command yet
+                    self.wildmenu = None;
+                    return Ok(true);
                 }
-            }
-            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if let Err(e) = self.paste_line() {
-                    self.status_message = format!("Paste error: {}", e);
+                KeyCode::Esc => {
+                    if let Some(menu) = self.wildmenu.take() {
+                        self.command_buffer.truncate(menu.start);
+                        self.command_buffer.push_str(&menu.original);
+                    }
+                    return Ok(true);
                 }
+                _ => self.wildmenu = None,
             }
-            KeyCode::F(1) => {
-                self.show_help = !self.show_help;
-                self.status_message = if self.show_help { "Help shown" } else { "Help hidden" }.to_string();
-            }
-            KeyCode::F(2) => {
-                self.show_line_numbers = !self.show_line_numbers;
-                self.status_message = if self.show_line_numbers { "Line numbers shown" } else { "Line numbers hidden" }.to_string();
+        }
+
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if let KeyCode::Char(c) = key.code {
+            if self.command_buffer.len() < MAX_INPUT_LENGTH {
+                self.command_buffer.push(c);
             }
-            KeyCode::Up => { let _ = self.move_cursor(0, -1); }
-            KeyCode::Down => { let _ = self.move_cursor(0, 1); }
-            KeyCode::Left => { let _ = self.move_cursor(-1, 0); }
-            KeyCode::Right => { let _ = self.move_cursor(1, 0); }
-            KeyCode::Home => self.cursor.x = 0,
-            KeyCode::End => {
-                if let Some(line) = self.buffer.get_line(self.cursor.y) {
-                    self.cursor.x = line.len();
-                }
+        }
+        Ok(true)
+    }
+
+    fn handle_search_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if let KeyCode::Char(c) = key.code {
+            if self.search_query.len() < MAX_INPUT_LENGTH {
+                self.search_query.push(c);
             }
-            KeyCode::PageUp => { 
-                let _ = self.move_cursor(0, -20);
-                self.needs_reload = true;
+        }
+        self.preview_search();
+        Ok(true)
+    }
+
+    // Moves the cursor to the first match after where `/` was pressed while the query is
+    // typed, or back there when nothing matches yet. Rescanning the whole file on every
+    // keystroke would stall on big files, so the preview looks in the loaded chunk only;
+    // Enter runs the real whole-file search.
+    fn preview_search(&mut self) {
+        let origin = match self.search_origin {
+            Some((ref cursor, _, _)) => cursor.clone(),
+            None => return,
+        };
+        let here = (origin.y, self.buffer.get_line(origin.y).map_or(0, |line| char_to_byte(line, origin.x)));
+        let matches = self.find_matches(MAX_SEARCH_COUNT);
+        let target = matches.iter().find(|&&m| m > here).or(matches.first()).copied();
+        self.highlight_search = true;
+        match target {
+            Some((y, x)) => {
+                self.cursor.y = y;
+                self.set_cursor_byte(x);
             }
-            KeyCode::PageDown => { 
-                let _ = self.move_cursor(0, 20);
-                self.needs_reload = true;
+            None => self.cursor = origin,
+        }
+    }
+
+    fn handle_replace_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
+        } else if let KeyCode::Char(c) = key.code {
+            // Typing goes into the search term until Tab moves on to the replacement
+            let target = if self.replace_query.is_empty() || self.status_message.contains("search") {
+                &mut self.replace_query
+            } else {
+                &mut self.replace_with
+            };
+            if target.len() < MAX_INPUT_LENGTH {
+                target.push(c);
             }
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                self.status_message = "Normal mode".to_string();
-                self.quit_requested = false;
+        }
+        Ok(true)
+    }
+
+    // Alt+Enter in replace mode: walks every match in the file from the top, asking
+    // `y`/`n`/`a`/`q` about each. Lines with matches are found first so the walk can
+    // load whichever chunk holds the next one.
+    fn start_confirm_replace(&mut self) {
+        self.mode = Mode::Normal;
+        let pattern = match Regex::new(&regex::escape(&self.replace_query)) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.status_message = format!("Replace failed: {}", e);
+                return;
             }
-            _ => {
-                self.quit_requested = false;
+        };
+        let (mut lines, mut total) = (Vec::new(), 0);
+        let scanned = self.buffer.for_each_file_line(|y, line| {
+            let hits = Self::match_ranges(line, &pattern, None).len();
+            if hits > 0 {
+                lines.push(y);
+                total += hits;
             }
+            Ok(())
+        });
+        if let Err(e) = scanned {
+            self.status_message = format!("Replace failed: {}", e);
+            return;
+        }
+        if lines.is_empty() {
+            self.status_message = format!("Pattern not found: {}", self.replace_query);
+            return;
+        }
+
+        self.confirm_replace = Some(ConfirmReplace {
+            pattern,
+            replacement: self.replace_with.clone(),
+            lines,
+            line: 0,
+            from: 0,
+            current_end: 0,
+            seen: 0,
+            total,
+            replaced: 0,
+            ops: Vec::new(),
+        });
+        self.mode = Mode::ConfirmReplace;
+        self.next_confirm_match();
+    }
+
+    fn handle_confirm_replace_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
+        if let Some(binding) = self.pressed_binding(&key) {
+            (binding.run)(self, key, None)?;
         }
         Ok(true)
     }
 
-    fn handle_insert_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                if let Err(e) = self.move_cursor(-1, 0) {
-                    // Ignore movement errors on mode switch
+    // Moves the cursor to the next match, loading its chunk if needed. Replacements in
+    // the chunk left behind stay in memory until the next save.
+    fn next_confirm_match(&mut self) {
+        loop {
+            let y = match self.confirm_replace.as_ref().and_then(|state| state.lines.get(state.line)) {
+                Some(&y) => y,
+                None => return self.finish_confirm_replace(),
+            };
+            if self.buffer.get_line(y).is_none() {
+                self.cursor.y = y;
+                if let Err(e) = self.reload_current_chunk() {
+                    self.status_message = format!("Replace failed: {}", e);
+                    self.confirm_replace = None;
+                    self.mode = Mode::Normal;
+                    return;
                 }
-                self.status_message = "Normal mode".to_string();
             }
-            KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                if let Err(e) = self.save_file() {
-                    self.status_message = format!("Error saving: {}", e);
+
+            let state = match self.confirm_replace.as_mut() {
+                Some(state) => state,
+                None => return,
+            };
+            let found = self.buffer.get_line(y).and_then(|line| {
+                state.pattern.find_at(line, state.from.min(line.len())).filter(|found| !found.is_empty())
+            });
+            match found {
+                Some(found) => {
+                    state.seen += 1;
+                    state.current_end = found.end();
+                    self.cursor.y = y;
+                    self.cursor.x = self.buffer.get_line(y).map_or(0, |line| byte_to_char(line, found.start()));
+                    self.status_message = format!(
+                        "Replace with '{}'? (y/n/a/q) [{}/{}]",
+                        state.replacement, state.seen, state.total
+                    );
+                    return;
                 }
-            }
-            KeyCode::Char(c) => {
-                if let Err(e) = self.insert_char(c) {
-                    self.status_message = format!("Insert error: {}", e);
+                None => {
+                    state.line += 1;
+                    state.from = 0;
                 }
             }
-            KeyCode::Enter => {
-                if let Err(e) = self.insert_newline() {
-                    self.status_message = format!("Newline error: {}", e);
-                }
+        }
+    }
+
+    // `y`: swaps the match under the cursor for the replacement. A line that would grow
+    // past MAX_LINE_LENGTH is left alone, as if the match were skipped.
+    fn replace_confirmed_match(&mut self) {
+        let (y, start) = (self.cursor.y, self.cursor_byte());
+        let (end, new_line) = match (self.confirm_replace.as_ref(), self.buffer.get_line(y)) {
+            (Some(state), Some(line)) if start <= state.current_end && state.current_end <= line.len() => (
+                state.current_end,
+                format!("{}{}{}", &line[..start], state.replacement, &line[state.current_end..]),
+            ),
+            _ => return,
+        };
+        let replaced = self.confirm_edit_line(y, new_line);
+        if let Some(state) = self.confirm_replace.as_mut() {
+            if replaced {
+                state.from = start + state.replacement.len();
+                state.replaced += 1;
+            } else {
+                state.from = end;
             }
-            KeyCode::Backspace => {
-                if let Err(e) = self.delete_char() {
-                    self.status_message = format!("Delete error: {}", e);
-                }
-            }
-            KeyCode::Up => { let _ = self.move_cursor(0, -1); }
-            KeyCode::Down => { let _ = self.move_cursor(0, 1); }
-            KeyCode::Left => { let _ = self.move_cursor(-1, 0); }
-            KeyCode::Right => { let _ = self.move_cursor(1, 0); }
-            KeyCode::Tab => {
-                for _ in 0..4 {
-                    if let Err(e) = self.insert_char(' ') {
-                        self.status_message = format!("Tab insert error: {}", e);
-                        break;
-                    }
+        }
+    }
+
+    // `a`: replaces this match and all after it, loading the chunks that hold them in turn
+    fn replace_remaining_matches(&mut self) {
+        self.replace_confirmed_match();
+        while let Some(state) = self.confirm_replace.as_mut() {
+            let y = match state.lines.get(state.line) {
+                Some(&y) => y,
+                None => break,
+            };
+            let from = state.from;
+            state.line += 1;
+            state.from = 0;
+            if let Err(e) = self.ensure_line_loaded(y) {
+                self.finish_confirm_replace();
+                self.status_message = format!("{}; stopped at line {}: {}", self.status_message, y + 1, e);
+                return;
+            }
+            let (hits, new_line) = match (self.confirm_replace.as_ref(), self.buffer.get_line(y)) {
+                (Some(state), Some(line)) if from <= line.len() => {
+                    let tail = &line[from..];
+                    let hits = Self::match_ranges(tail, &state.pattern, None).len();
+                    let replaced = state.pattern.replace_all(tail, regex::NoExpand(&state.replacement));
+                    (hits, format!("{}{}", &line[..from], replaced))
+                }
+                _ => continue,
+            };
+            if hits > 0 && self.confirm_edit_line(y, new_line) {
+                if let Some(state) = self.confirm_replace.as_mut() {
+                    state.replaced += hits;
                 }
             }
-            _ => {}
         }
-        Ok(true)
+        self.finish_confirm_replace();
     }
 
-    fn handle_command_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                self.command_buffer.clear();
-                self.status_message = "Normal mode".to_string();
-            }
-            KeyCode::Enter => {
-                self.execute_command()?;
-                self.mode = Mode::Normal;
+    // Puts `new_line` in place of line `y` for a confirmed replace, folding repeated
+    // edits of one line into a single undo step. False if the line isn't loaded or the
+    // result would be too long.
+    fn confirm_edit_line(&mut self, y: usize, new_line: String) -> bool {
+        if new_line.len() > MAX_LINE_LENGTH {
+            return false;
+        }
+        let old = match self.buffer.get_line_mut(y) {
+            Some(line) => std::mem::replace(line, new_line.clone()),
+            None => return false,
+        };
+        if let Some(state) = self.confirm_replace.as_mut() {
+            match state.ops.last_mut() {
+                Some(EditOp::ReplaceRange { start, new, .. }) if *start == y => *new = vec![new_line],
+                _ => state.ops.push(EditOp::ReplaceRange { start: y, old: vec![old], new: vec![new_line] }),
             }
-            KeyCode::Char(c) => {
-                if self.command_buffer.len() < 100 {
-                    self.command_buffer.push(c);
+        }
+        self.modified = true;
+        self.search_cache = None;
+        self.block_cache = None;
+        true
+    }
+
+    fn flush_confirm_edits(&mut self) {
+        let ops = self.confirm_replace.as_mut().map(|state| std::mem::take(&mut state.ops)).unwrap_or_default();
+        self.record_edits(ops);
+    }
+
+    fn finish_confirm_replace(&mut self) {
+        self.flush_confirm_edits();
+        self.mode = Mode::Normal;
+        if let Some(state) = self.confirm_replace.take() {
+            self.status_message = format!("Replaced {} of {} occurrences", state.replaced, state.total);
+        }
+    }
+
+    // Parses a leading line range (`5,10`, `%`, `.`, `$`, `.,$`) into 0-based inclusive
+    // bounds and returns the command that follows it. A bare address is a goto instead.
+    fn parse_range<'a>(&self, cmd: &'a str) -> Option<((usize, usize), &'a str)> {
+        let (range, rest) = self.parse_addresses(cmd.trim_start())?;
+        let rest = rest.trim_start();
+        if rest.is_empty() { None } else { Some((range, rest)) }
+    }
+
+    // `:4096`, `:$` or `:N,M` on their own: the line to go to, the second one of a pair
+    fn parse_goto(&self, cmd: &str) -> Option<usize> {
+        match self.parse_addresses(cmd.trim())? {
+            ((_, end), "") => Some(end),
+            _ => None,
+        }
+    }
+
+    fn parse_addresses<'a>(&self, cmd: &'a str) -> Option<((usize, usize), &'a str)> {
+        if let Some(rest) = cmd.strip_prefix('%') {
+            return Some(((0, self.buffer.total_lines.saturating_sub(1)), rest));
+        }
+        let (start, rest) = self.parse_line_address(cmd)?;
+        if let Some(after_comma) = rest.strip_prefix(',') {
+            let (end, rest) = self.parse_line_address(after_comma)?;
+            Some(((start.min(end), start.max(end)), rest))
+        } else {
+            Some(((start, start), rest))
+        }
+    }
+
+    fn parse_line_address<'a>(&self, s: &'a str) -> Option<(usize, &'a str)> {
+        if let Some(rest) = s.strip_prefix('.') {
+            return Some((self.cursor.y, rest));
+        }
+        if let Some(rest) = s.strip_prefix('$') {
+            return Some((self.buffer.total_lines.saturating_sub(1), rest));
+        }
+        if let Some(rest) = s.strip_prefix("'<") {
+            return Some((self.visual_lines?.0, rest));
+        }
+        if let Some(rest) = s.strip_prefix("'>") {
+            return Some((self.visual_lines?.1, rest));
+        }
+        let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            return None;
+        }
+        let line: usize = s[..digits].parse().ok()?;
+        Some((line.saturating_sub(1), &s[digits..]))
+    }
+
+    // Runs `cmd`, which followed `range` if one was typed: a substitute, a shell command
+    // (a filter with a range), or a row of EX_COMMANDS
+    fn run_ex_command(&mut self, range: Option<(usize, usize)>, cmd: &str) {
+        let line = (self.cursor.y, self.cursor.y);
+        if Self::is_substitute(cmd) {
+            // A substitute on a block selection only touches the block's columns
+            let columns = if self.command_buffer.starts_with("'<,'>") { self.visual_columns } else { None };
+            self.substitute(range.unwrap_or(line), columns, cmd);
+            return;
+        }
+        if let Some(shell) = cmd.strip_prefix('!') {
+            match range {
+                Some(range) => {
+                    let result = self.filter_range(range, shell.trim());
+                    self.report_error("Filter failed", result);
                 }
+                None => self.run_shell_command(shell.trim()),
             }
-            KeyCode::Backspace => {
-                self.command_buffer.pop();
+            return;
+        }
+        match Self::find_ex_command(cmd) {
+            Some((command, bang, arg)) if command.range || range.is_none() => {
+                (command.run)(self, ExCall { range, bang, arg });
             }
-            _ => {}
+            _ => self.status_message = format!("Unknown command: {}", self.command_buffer),
         }
-        Ok(true)
     }
 
-    fn handle_search_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                self.search_query.clear();
-                self.status_message = "Normal mode".to_string();
+    // The row of EX_COMMANDS that `cmd` calls, whether `!` followed its name, and the
+    // text after the name
+    fn find_ex_command(cmd: &str) -> Option<(&'static ExCommand, bool, &str)> {
+        EX_COMMANDS.iter().find_map(|command| {
+            command.names.iter().find_map(|name| {
+                let rest = cmd.strip_prefix(name)?;
+                let (bang, rest) = match rest.strip_prefix('!') {
+                    Some(rest) if command.bang => (true, rest),
+                    _ => (false, rest),
+                };
+                let arg = match rest.strip_prefix(' ') {
+                    Some(arg) if command.arg != ExArg::None => arg,
+                    _ if rest.is_empty() => "",
+                    _ => return None,
+                };
+                Some((command, bang, arg))
+            })
+        })
+    }
+
+    // Moves to line `line` (0-based), loading the chunk around it. Lines past the end
+    // land on the last line with a warning.
+    fn goto_line(&mut self, line: usize) -> io::Result<()> {
+        if let Some(ref path) = self.filename {
+            self.buffer.scan_mapped(path, line)?;
+        }
+        let last = self.buffer.total_lines.saturating_sub(1);
+        let target = line.min(last);
+        self.cursor.y = target;
+        self.ensure_line_loaded(target)?;
+        if self.cursor.y != target {
+            // Refused to leave a chunk with unsaved edits; the reason is in the status line
+            return Ok(());
+        }
+        self.cursor.x = 0;
+        self.status_message = if line > last {
+            format!("Line {} is past the end; moved to the last line ({})", line + 1, last + 1)
+        } else {
+            format!("Line {} of {}", target + 1, self.buffer.total_lines)
+        };
+        Ok(())
+    }
+
+    // Visits the lines of `range` as the buffer sees them, unsaved edits included. Lines
+    // outside the loaded and stashed chunks are streamed from disk.
+    fn for_each_line_in<F>(&mut self, range: (usize, usize), mut f: F) -> io::Result<usize>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+    {
+        let (start, end) = range;
+        let chunk_end = self.buffer.start_line_number + self.buffer.lines.len();
+        if start >= self.buffer.start_line_number && end < chunk_end {
+            for y in start..=end {
+                f(self.buffer.get_line(y).map_or("", |line| line.as_str()))?;
             }
-            KeyCode::Enter => {
-                let matches = self.search(&self.search_query);
-                if !matches.is_empty() {
-                    self.cursor.y = matches[0].0;
-                    self.cursor.x = matches[0].1;
-                    self.needs_reload = true;
-                    self.status_message = format!("Found {} matches in current chunk", matches.len());
-                } else {
-                    self.status_message = "No matches found in current view".to_string();
-                }
-                self.mode = Mode::Normal;
+            return Ok(end + 1 - start);
+        }
+        if self.buffer.source.is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "range is beyond the buffer"));
+        }
+        let mut count = 0;
+        self.buffer.for_each_file_line_in(start, end, |_, line| {
+            count += 1;
+            f(line)
+        })?;
+        Ok(count)
+    }
+
+    // `:N,M!cmd` pipes the lines through a shell command and puts its output in their
+    // place; the output may have any number of lines
+    fn filter_range(&mut self, range: (usize, usize), command: &str) -> io::Result<()> {
+        if self.refuse_read_only() {
+            return Ok(());
+        }
+        if command.is_empty() {
+            self.status_message = "Usage: :[range]!command".to_string();
+            return Ok(());
+        }
+        let mut input = String::new();
+        let count = self.for_each_line_in(range, |line| {
+            input.push_str(line);
+            input.push('\n');
+            Ok(())
+        })?;
+
+        let output = match run_filter(command, &input) {
+            Ok(output) => output,
+            Err(message) => {
+                self.status_message = format!("Filter failed: {}", message);
+                return Ok(());
             }
-            KeyCode::Char(c) => {
-                if self.search_query.len() < 100 {
-                    self.search_query.push(c);
-                }
+        };
+        let mut lines: Vec<String> = output.lines().map(|line| line.to_string()).collect();
+        if lines.is_empty() && count == self.buffer.total_lines {
+            // The buffer always keeps at least one line
+            lines.push(String::new());
+        }
+        let produced = lines.len();
+        if !self.replace_line_range(range.0, count, lines) {
+            self.status_message = "Filtered range must lie within the loaded lines".to_string();
+            return Ok(());
+        }
+        self.cursor.y = range.0.min(self.buffer.total_lines.saturating_sub(1));
+        self.cursor.x = 0;
+        self.status_message = format!("Filtered {} line(s) through '{}', got {}", count, command, produced);
+        Ok(())
+    }
+
+    // `:!cmd` without a range just runs the command and shows what it printed
+    fn run_shell_command(&mut self, command: &str) {
+        self.status_message = match run_filter(command, "") {
+            Ok(output) if output.trim().is_empty() => format!("'{}' finished", command),
+            Ok(output) => output.trim_end().to_string(),
+            Err(message) => format!("'{}' failed: {}", command, message),
+        };
+    }
+
+    // `:N,Mextract <file>` writes a slice of the buffer to another file. An existing file
+    // is only replaced with `:extract!`.
+    fn extract_to_file(&mut self, range: (usize, usize), target: &PathBuf, force: bool) -> io::Result<()> {
+        let file = if force {
+            File::create(target)
+        } else {
+            fs::OpenOptions::new().write(true).create_new(true).open(target)
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                self.status_message = format!("{} exists (add ! to overwrite)", target.display());
+                return Ok(());
             }
-            KeyCode::Backspace => {
-                self.search_query.pop();
+            Err(e) => return Err(e),
+        };
+        let mut writer = BufWriter::new(file);
+        let (encoding, ending) = (self.buffer.encoding, self.buffer.line_ending);
+        let count = self.for_each_line_in(range, |line| {
+            LineBuffer::write_encoded(&mut writer, line, encoding, Some(ending))
+        })?;
+        writer.flush()?;
+        self.status_message = format!("Extracted {} lines to {}", count, target.display());
+        Ok(())
+    }
+
+    // `:N,Mextract` without a file replaces the view with an unnamed scratch buffer
+    fn extract_to_scratch(&mut self, range: (usize, usize)) -> io::Result<()> {
+        if self.modified {
+            self.status_message = "No write since last change (give :extract a file name)".to_string();
+            return Ok(());
+        }
+        // A scratch buffer has no file to page from, so it must fit in memory
+        if range.1 + 1 - range.0 > self.buffer.max_lines {
+            self.status_message = format!(
+                "Range too large for a scratch buffer (max {} lines); give :extract a file name",
+                self.buffer.max_lines
+            );
+            return Ok(());
+        }
+        let mut lines = VecDeque::new();
+        let count = self.for_each_line_in(range, |line| {
+            lines.push_back(line.to_string());
+            Ok(())
+        })?;
+        if lines.is_empty() {
+            lines.push_back(String::new());
+        }
+
+        self.close_file();
+        self.buffer.mapped = None;
+        self.buffer.total_lines = lines.len();
+        self.buffer.lines = lines;
+        self.buffer.start_line_number = 0;
+        self.buffer.source = None;
+        self.buffer.disk_start = 0;
+        self.buffer.disk_count = 0;
+        self.buffer.stashed.clear();
+        self.search_cache = None;
+        self.block_cache = None;
+        self.filename = None;
+        self.read_only = false;
+        self.following = false;
+        self.follow_state = None;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.undo_base = self.edit_count;
+        self.saved_undo_state = None;
+        self.cursor = Cursor { x: 0, y: 0 };
+        self.offset_y = 0;
+        self.modified = true;
+        self.status_message = format!("Extracted {} lines into a scratch buffer", count);
+        Ok(())
+    }
+
+    fn execute_command(&mut self) -> io::Result<()> {
+        let command = self.command_buffer.clone();
+        if let Some(line) = self.parse_goto(&command) {
+            if let Err(e) = self.goto_line(line) {
+                self.status_message = format!("Error loading line: {}", e);
             }
-            _ => {}
+            self.command_buffer.clear();
+            return Ok(());
         }
-        Ok(true)
+        match self.parse_range(&command) {
+            Some((range, rest)) => self.run_ex_command(Some(range), rest),
+            None => self.run_ex_command(None, &command),
+        }
+        self.command_buffer.clear();
+        Ok(())
     }
 
-    fn handle_replace_mode(&mut self, key: KeyEvent) -> io::Result<bool> {
-        match key.code {
-            KeyCode::Esc => {
-                self.mode = Mode::Normal;
-                self.replace_query.clear();
-                self.replace_with.clear();
-                self.status_message = "Normal mode".to_string();
-            }
-            KeyCode::Enter => {
-                if !self.replace_query.is_empty() {
-                    let search = self.replace_query.clone();
-                    let replace = self.replace_with.clone();
-                    let count = self.replace_in_chunk(&search, &replace);
-                    self.status_message = format!("Replaced {} occurrences in current chunk", count);
-                }
-                self.mode = Mode::Normal;
-            }
-            KeyCode::Tab => {
-                if self.replace_query.is_empty() {
-                    self.status_message = "Enter search term first".to_string();
-                } else {
-                    self.status_message = "Enter replacement text:".to_string();
-                }
-            }
-            KeyCode::Char(c) => {
-                if self.replace_query.is_empty() || self.status_message.contains("search") {
-                    if self.replace_query.len() < 100 {
-                        self.replace_query.push(c);
-                    }
-                } else {
-                    if self.replace_with.len() < 100 {
-                        self.replace_with.push(c);
-                    }
-                }
-            }
-            KeyCode::Backspace => {
-                if !self.replace_with.is_empty() {
-                    self.replace_with.pop();
-                } else {
-                    self.replace_query.pop();
-                }
-            }
-            _ => {}
+    // `:q` refuses while any buffer has unsaved changes; `:q!` quits regardless
+    fn quit_command(&mut self, force: bool) {
+        if force {
+            self.exit_editor();
+        } else if self.modified {
+            self.status_message = "File modified! Use 'q!' to quit without saving".to_string();
+        } else if self.any_modified() {
+            self.status_message = "Another buffer has unsaved changes (:bn to it, or 'q!' to quit)".to_string();
+        } else {
+            self.exit_editor();
         }
-        Ok(true)
     }
 
-    fn execute_command(&mut self) -> io::Result<()> {
-        match self.command_buffer.as_str() {
-            "q" => {
-                if !self.modified {
-                    std::process::exit(0);
-                } else {
-                    self.status_message = "File modified! Use 'q!' to quit without saving".to_string();
-                }
-            }
-            "q!" => std::process::exit(0),
-            "w" => {
-                if let Err(e) = self.save_file() {
-                    self.status_message = format!("Error saving: {}", e);
-                }
-            }
-            "wq" => {
-                if self.save_file().is_ok() {
-                    std::process::exit(0);
-                }
+    // `:wq` saves this buffer and quits once the save has gone through, which may be
+    // after a prompt
+    fn write_and_quit(&mut self) {
+        // Only this buffer gets saved, so the others must have nothing to lose
+        if self.others_modified() {
+            self.status_message = "Another buffer has unsaved changes (:bn to it, or 'q!' to quit)".to_string();
+        } else if self.save_file(false).is_ok() {
+            if let Some(
+                Prompt::CreateDir { quit_after, .. }
+                | Prompt::RecreateDeleted { quit_after }
+                | Prompt::OverwriteChanged { quit_after },
+            ) = self.pending_prompt.as_mut()
+            {
+                *quit_after = true;
+            } else {
+                self.exit_editor();
             }
-            cmd if cmd.starts_with("w ") => {
-                let filename = cmd[2..].trim();
-                self.filename = Some(PathBuf::from(filename));
-                if let Err(e) = self.save_file() {
-                    self.status_message = format!("Error saving: {}", e);
+        }
+    }
+
+    // Runs the row of SET_OPTIONS that `option` names
+    fn apply_setting(&mut self, option: &str) {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let named = |names: &[&str], name: Option<&str>| name.is_some_and(|name| names.contains(&name));
+        for row in SET_OPTIONS {
+            match (row.set, value) {
+                (OptionSetter::Value(set), Some(value)) if named(row.names, Some(name)) => return set(self, value),
+                (OptionSetter::Flag(set), None) if named(row.names, Some(name)) => return set(self, true),
+                (OptionSetter::Flag(set), None) if named(row.names, name.strip_prefix("no")) => {
+                    return set(self, false)
                 }
-            }
-            _ => {
-                self.status_message = format!("Unknown command: {}", self.command_buffer);
+                (OptionSetter::Query(query), None) if named(row.names, name.strip_suffix('?')) => return query(self),
+                _ => {}
             }
         }
-        self.command_buffer.clear();
-        Ok(())
+        self.status_message = format!("Unknown option: {}", option);
     }
 
-    fn update_scroll(&mut self, terminal_height: u16) {
-        let height = terminal_height.saturating_sub(4) as usize;
+    // Scrolls so the cursor stays inside a pane `rows` lines tall
+    fn update_scroll(&mut self, rows: usize) {
+        // The sticky header takes a row from the text
+        let header = if self.stickyscroll { 1 } else { 0 };
+        let height = rows.saturating_sub(header).max(1);
         
         if self.cursor.y < self.offset_y {
             self.offset_y = self.cursor.y;
@@ -806,24 +9151,80 @@ impl Editor {
         }
     }
 
+    // Scrolls sideways so the cursor column stays HSCROLL_MARGIN columns inside a text
+    // area `width` columns wide, or less on narrow panes
+    fn update_scroll_x(&mut self, width: usize) {
+        if self.wrap || width == 0 {
+            self.offset_x = 0;
+            return;
+        }
+        let margin = HSCROLL_MARGIN.min(width / 4);
+        let column = self.cursor_display_column();
+        if column < self.offset_x + margin {
+            self.offset_x = column.saturating_sub(margin);
+        } else if column + margin >= self.offset_x + width {
+            self.offset_x = column + margin + 1 - width;
+        }
+    }
+
+    // Rows the status area needs: one while typing input, otherwise enough to wrap the
+    // whole message, up to a third of the screen
+    fn status_height(&self, width: u16, height: u16) -> u16 {
+        if matches!(self.mode, Mode::Command | Mode::Search | Mode::Replace) || width == 0 {
+            return 1;
+        }
+        let width = width as usize;
+        let rows: usize = format!(" {}", self.status_message)
+            .split('\n')
+            .map(|line| {
+                let columns: usize = line.chars().map(char_width).sum();
+                columns.max(1).div_ceil(width)
+            })
+            .sum();
+        rows.clamp(1, (height / 3).max(1) as usize) as u16
+    }
+
+    // Rows left for text on a terminal `height` rows tall, after the tab strip, title,
+    // mode bar and status area; zero on terminals too small for any
+    fn text_rows(&self, height: u16) -> u16 {
+        height.saturating_sub(self.status_rows + 2 + if self.tab_bar_visible() { 1 } else { 0 })
+    }
+
+    // The terminal changed size: keep the cursor on screen for the new height before
+    // the next frame is drawn
+    fn resize(&mut self, width: u16, height: u16) {
+        self.status_rows = self.status_height(width, height);
+        self.update_scroll(self.text_rows(height) as usize);
+    }
+
     fn render(&mut self, frame: &mut Frame) -> io::Result<()> {
         let size = frame.size();
-        self.update_scroll(size.height);
+        self.status_rows = self.status_height(size.width, size.height);
+        self.update_scroll(self.text_rows(size.height) as usize);
 
         if self.show_help {
             self.render_help(frame, size);
+            self.pane_area = Rect::default();
+            self.other_pane_area = None;
+            self.tab_bar_area = Rect::default();
             return Ok(());
         }
 
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
+                Constraint::Length(if self.tab_bar_visible() { 1 } else { 0 }), // Tab strip
                 Constraint::Length(1), // Title bar
                 Constraint::Min(0),    // Editor
                 Constraint::Length(1), // Mode bar
-                Constraint::Length(1), // Status bar
+                Constraint::Length(self.status_rows), // Status bar, taller for long messages
             ])
             .split(size);
+        if self.tab_bar_visible() {
+            self.render_tab_bar(frame, chunks[0]);
+        }
+        self.tab_bar_area = chunks[0];
+        let chunks = &chunks[1..];
 
         // Title bar
         let filename = self.filename
@@ -831,9 +9232,18 @@ impl Editor {
             .map(|p| p.display().to_string())
             .unwrap_or_else(|| "[New File]".to_string());
         
+        let buffer_count = if self.buffers.len() > 1 {
+            format!(" [{}/{}]", self.active_buffer + 1, self.buffers.len())
+        } else {
+            String::new()
+        };
         let title = format!(
-            " 🐧 TuxPad │ {} {} │ {}/{} lines",
+            " 🐧 TuxPad │ {}{}{}{}{} {} │ {}/{} lines",
             filename,
+            buffer_count,
+            if self.read_only { " [readonly]" } else { "" },
+            if self.deleted && self.filename.is_some() { " [deleted]" } else { "" },
+            if self.buffer.line_ending == LineEnding::Dos { " [dos]" } else { "" },
             if self.modified { "●" } else { "" },
             self.cursor.y + 1,
             self.buffer.total_lines
@@ -844,33 +9254,102 @@ impl Editor {
         frame.render_widget(title_block, chunks[0]);
 
         // Editor area
-        self.render_editor(frame, chunks[1])?;
+        let editor_area = self.constrained_area(chunks[1]);
+        if editor_area != chunks[1] {
+            frame.render_widget(Block::default().style(Style::default().bg(Color::Rgb(20, 20, 20))), chunks[1]);
+        }
+        let gutter = if self.gutter_visible() { self.line_number_width() } else { 0 };
+        let editor_area = match self.split_areas(editor_area) {
+            Some((focused, other, separator)) => {
+                let vertical = self.split.as_ref().is_some_and(|split| split.vertical);
+                let border = if vertical { Borders::LEFT } else { Borders::TOP };
+                frame.render_widget(
+                    Block::default().borders(border).border_style(Style::default().fg(Color::DarkGray)),
+                    separator,
+                );
+                self.swap_views();
+                self.update_scroll(other.height as usize);
+                self.update_scroll_x((other.width as usize).saturating_sub(gutter));
+                let first = self.offset_y;
+                self.load_split_lines(first, (first + other.height as usize).min(self.buffer.total_lines));
+                self.refresh_highlights(first + other.height as usize);
+                let rendered = self.render_editor(frame, other, false);
+                self.swap_views();
+                rendered?;
+                self.update_scroll(focused.height as usize);
+                self.other_pane_area = Some(other);
+                focused
+            }
+            None => {
+                self.other_pane_area = None;
+                editor_area
+            }
+        };
+        self.pane_area = editor_area;
+        self.update_scroll_x((editor_area.width as usize).saturating_sub(gutter));
+        self.refresh_highlights(self.offset_y + editor_area.height as usize);
+        self.refresh_block_cache();
+        self.render_editor(frame, editor_area, true)?;
 
         // Mode bar
+        let mode_name = if self.mode == Mode::Insert && self.overwrite {
+            "Overwrite".to_string()
+        } else {
+            format!("{:?}", self.mode)
+        };
+        let unsaved = self.unsaved_for();
         let mode_text = format!(
-            " {:?} │ Ln {}, Col {} │ Chunk: {}-{} ",
-            self.mode,
+            " {}{}{} │ Ln {}, Col {} │ Chunk: {}-{} {}",
+            self.recording.as_ref().map_or(String::new(), |(name, _)| format!("recording @{} │ ", name)),
+            mode_name,
+            // Keys typed so far of a count or multi-key command, like vim's showcmd
+            if self.pending_keys.is_empty() { String::new() } else { format!(" {}", self.pending_keys) },
             self.cursor.y + 1,
             self.cursor.x + 1,
             self.buffer.start_line_number + 1,
-            self.buffer.start_line_number + self.buffer.lines.len()
+            self.buffer.start_line_number + self.buffer.lines.len(),
+            unsaved.map_or(String::new(), |elapsed| format!("│ modified {} ", Self::format_elapsed(elapsed)))
         );
         
+        // Past the reminder threshold the bar turns red to nudge a save
+        let overdue = !self.save_reminder.is_zero() && unsaved.is_some_and(|elapsed| elapsed >= self.save_reminder);
         let mode_style = match self.mode {
             Mode::Insert => Style::default().bg(Color::Green).fg(Color::Black),
             Mode::Command => Style::default().bg(Color::Blue).fg(Color::White),
             Mode::Search => Style::default().bg(Color::Magenta).fg(Color::White),
-            Mode::Replace => Style::default().bg(Color::Red).fg(Color::White),
+            Mode::Replace | Mode::ConfirmReplace => Style::default().bg(Color::Red).fg(Color::White),
+            _ if overdue => Style::default().bg(Color::Rgb(140, 40, 40)).fg(Color::White),
             _ => Style::default().bg(Color::DarkGray).fg(Color::White),
         };
         
         let mode_bar = Paragraph::new(mode_text).style(mode_style);
         frame.render_widget(mode_bar, chunks[2]);
+        if let Some(count) = self.search_count() {
+            let text = format!("/{} {} ", self.search_query, count);
+            let width = text.chars().map(char_width).sum::<usize>() as u16;
+            let count_bar = Paragraph::new(text).alignment(Alignment::Right).style(mode_style);
+            let area = Rect {
+                x: chunks[2].x + chunks[2].width.saturating_sub(width),
+                width: width.min(chunks[2].width),
+                ..chunks[2]
+            };
+            frame.render_widget(count_bar, area);
+        }
 
         // Status bar
         let status_text = match self.mode {
             Mode::Command => format!(" :{}", self.command_buffer),
-            Mode::Search => format!(" 🔍 /{}", self.search_query),
+            Mode::Search => {
+                let mut flags = Vec::new();
+                if self.search_is_regex {
+                    flags.push("regex");
+                }
+                if self.ignorecase {
+                    flags.push(if self.smartcase { "smartcase" } else { "ignore case" });
+                }
+                let flags = if flags.is_empty() { String::new() } else { format!("[{}] ", flags.join(", ")) };
+                format!(" 🔍 {}/{}", flags, self.search_query)
+            }
             Mode::Replace => {
                 if self.replace_query.is_empty() {
                     " 🔄 Replace: Enter search term".to_string()
@@ -882,22 +9361,190 @@ impl Editor {
             }
             _ => format!(" {}", self.status_message),
         };
+
+        // Input being typed sits at the end of the line, so scroll long input to keep its tail visible
+        let status_text = match self.mode {
+            Mode::Command | Mode::Search | Mode::Replace => {
+                Self::scroll_to_tail(&status_text, chunks[3].width as usize)
+            }
+            _ => status_text,
+        };
         
         let status_bar = Paragraph::new(status_text)
+            .wrap(Wrap { trim: false })
             .style(Style::default().bg(Color::Rgb(40, 40, 40)).fg(Color::White));
         frame.render_widget(status_bar, chunks[3]);
+
+        if let Some(ref popup) = self.popup {
+            self.render_popup(frame, size, popup);
+        }
+        if let (Some(menu), true) = (self.wildmenu.as_ref(), self.show_wildmenu) {
+            self.render_wildmenu(frame, chunks[3], menu);
+        }
         
         Ok(())
     }
 
-    fn render_editor(&self, frame: &mut Frame, area: Rect) -> io::Result<()> {
-        let line_number_width = if self.show_line_numbers {
+    fn render_popup(&self, frame: &mut Frame, area: Rect, popup: &Popup) {
+        let popup_area = Rect {
+            x: area.width / 8,
+            y: area.height / 10,
+            width: area.width * 3 / 4,
+            height: area.height * 4 / 5,
+        };
+        let visible = popup.visible_indices();
+        let rows = popup_area.height.saturating_sub(3) as usize;
+
+        // Scroll so the selected row stays on screen
+        let first = popup.selected.saturating_sub(rows.saturating_sub(1));
+        let items: Vec<ListItem> = visible
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .map(|(i, &index)| {
+                let fg = if popup.dimmed[index] { Color::DarkGray } else { Color::White };
+                let style = if i == popup.selected {
+                    Style::default().bg(Color::Blue).fg(fg)
+                } else {
+                    Style::default().fg(fg)
+                };
+                ListItem::new(popup.items[index].as_str()).style(style)
+            })
+            .collect();
+
+        let title = format!(
+            "{}│ {}/{} │ filter: {} ",
+            popup.title,
+            visible.len(),
+            popup.items.len(),
+            if popup.filter.is_empty() { "type to filter" } else { popup.filter.as_str() }
+        );
+        let list = List::new(items)
+            .block(Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Blue))
+            );
+
+        frame.render_widget(Clear, popup_area);
+        frame.render_widget(list, popup_area);
+    }
+
+    // Completion list drawn just above the status bar, starting under the completed word
+    fn render_wildmenu(&self, frame: &mut Frame, status_area: Rect, menu: &Wildmenu) {
+        let rows = menu.items.len().min(8).min(status_area.y.saturating_sub(1) as usize);
+        if rows == 0 {
+            return;
+        }
+        let widest = menu.items.iter().map(|item| item.chars().count()).max().unwrap_or(0);
+        let width = (widest as u16 + 2).min(status_area.width);
+        let x = (status_area.x + 2 + menu.start as u16).min(status_area.width.saturating_sub(width));
+        let area = Rect { x, y: status_area.y - rows as u16 - 1, width, height: rows as u16 + 1 };
+
+        let first = menu.selected.saturating_sub(rows - 1);
+        let items: Vec<ListItem> = menu.items
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .map(|(i, item)| {
+                let style = if i == menu.selected {
+                    Style::default().bg(Color::Blue).fg(Color::White)
+                } else {
+                    Style::default().bg(Color::DarkGray).fg(Color::White)
+                };
+                ListItem::new(format!(" {}", item)).style(style)
+            })
+            .collect();
+        let list = List::new(items).block(Block::default()
+            .title(format!(" {}/{} ", menu.selected + 1, menu.items.len()))
+            .borders(Borders::TOP)
+            .border_style(Style::default().fg(Color::Blue)));
+
+        frame.render_widget(Clear, area);
+        frame.render_widget(list, area);
+    }
+
+    fn gutter_visible(&self) -> bool {
+        self.show_line_numbers || self.relative_numbers
+    }
+
+    // Number shown in the gutter for a line: absolute, relative to the cursor, or both
+    // (hybrid) where the cursor line keeps its absolute number
+    fn gutter_number(&self, line_idx: usize) -> usize {
+        if !self.relative_numbers || (line_idx == self.cursor.y && self.show_line_numbers) {
+            line_idx + 1
+        } else {
+            line_idx.abs_diff(self.cursor.y)
+        }
+    }
+
+    fn scroll_to_tail(text: &str, width: usize) -> String {
+        let len = text.chars().count();
+        if len <= width || width < 2 {
+            return text.to_string();
+        }
+        let tail: String = text.chars().skip(len - (width - 2)).collect();
+        format!(" …{}", tail)
+    }
+
+    // Relative offsets never exceed the line count, so its width covers every mode
+    fn line_number_width(&self) -> usize {
+        if self.gutter_visible() {
             cmp::max(format!("{}", self.buffer.total_lines).len(), 3) + 1
         } else {
             0
+        }
+    }
+
+    // The part of `area` the text is drawn in: `winwidth` text columns plus the gutter,
+    // centred, never wider than the terminal
+    fn constrained_area(&self, area: Rect) -> Rect {
+        if self.winwidth == 0 {
+            return area;
+        }
+        let width = (self.winwidth + self.line_number_width()).min(area.width as usize) as u16;
+        let margin = (area.width - width) / 2;
+        Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(margin), Constraint::Length(width), Constraint::Min(0)])
+            .split(area)[1]
+    }
+
+    // The focused pane, the other pane and the separator between them when split
+    fn split_areas(&self, area: Rect) -> Option<(Rect, Rect, Rect)> {
+        let split = self.split.as_ref()?;
+        let (first, separator, second) = if split.vertical {
+            let width = area.width.saturating_sub(1) / 2;
+            (
+                Rect { width, ..area },
+                Rect { x: area.x + width, width: 1.min(area.width), ..area },
+                Rect { x: area.x + width + 1, width: area.width.saturating_sub(width + 1), ..area },
+            )
+        } else {
+            let height = area.height.saturating_sub(1) / 2;
+            (
+                Rect { height, ..area },
+                Rect { y: area.y + height, height: 1.min(area.height), ..area },
+                Rect { y: area.y + height + 1, height: area.height.saturating_sub(height + 1), ..area },
+            )
+        };
+        Some(if split.focus_second { (second, first, separator) } else { (first, second, separator) })
+    }
+
+    // Draws the text in `area`; only the `focused` pane gets the cursor
+    fn render_editor(&self, frame: &mut Frame, area: Rect, focused: bool) -> io::Result<()> {
+        let line_number_width = self.line_number_width();
+        let area = match self.sticky_scope_line() {
+            Some(line) if area.height > 1 => {
+                self.render_sticky_header(frame, Rect { height: 1, ..area }, line, line_number_width);
+                Rect { y: area.y + 1, height: area.height - 1, ..area }
+            }
+            _ => area,
         };
 
-        let editor_chunks = if self.show_line_numbers {
+        let editor_chunks = if self.gutter_visible() {
             Layout::default()
                 .direction(Direction::Horizontal)
                 .constraints([
@@ -913,13 +9560,13 @@ impl Editor {
         };
 
         // Line numbers
-        if self.show_line_numbers {
+        if self.gutter_visible() {
             let mut line_numbers = Vec::new();
             let start_display = self.offset_y;
             let end_display = (self.offset_y + area.height as usize).min(self.buffer.total_lines);
             
             for i in start_display..end_display {
-                let line_num = i + 1;
+                let line_num = self.gutter_number(i);
                 let style = if i == self.cursor.y {
                     Style::default().fg(Color::Yellow).bg(Color::DarkGray)
                 } else {
@@ -937,7 +9584,7 @@ impl Editor {
         }
 
         // Main editor content
-        let editor_area = if self.show_line_numbers {
+        let editor_area = if self.gutter_visible() {
             editor_chunks[1]
         } else {
             editor_chunks[0]
@@ -947,64 +9594,309 @@ impl Editor {
         let start_display = self.offset_y;
         let end_display = (self.offset_y + editor_area.height as usize).min(self.buffer.total_lines);
 
-        // Get file extension for syntax highlighting
-        let extension = self.filename.as_ref()
-            .and_then(|p| p.extension())
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
+        let cursor_column = if self.cursorcolumn { Some(self.cursor_display_column()) } else { None };
+        let search_pattern = match self.search_pattern() {
+            Ok(pattern) if self.highlight_search && !self.search_query.is_empty() => Some(pattern),
+            _ => None,
+        };
+        let words = if self.search_whole_word { Some(&self.iskeyword) } else { None };
+        let bracket_pair = self.find_matching_bracket().map(|other| [(self.cursor.y, self.cursor.x), other]);
 
         for line_idx in start_display..end_display {
             let line_content = self.buffer.get_line(line_idx)
+                .or_else(|| self.split.as_ref()?.lines.get(&line_idx))
                 .map(|s| s.as_str())
                 .unwrap_or("");
 
             let mut spans = if line_content.is_empty() {
                 vec![Span::raw(" ")]
             } else {
-                self.highlight_line_safe(line_content, extension)
+                self.highlighted_spans(line_idx, line_content)
             };
 
             // Highlight current line
-            if line_idx == self.cursor.y {
+            if self.cursorline && line_idx == self.cursor.y {
                 for span in &mut spans {
                     span.style = span.style.bg(Color::Rgb(40, 40, 40));
                 }
             }
 
+            if let Some((first, last, until)) = self.yank_flash {
+                if (first..=last).contains(&line_idx) && Instant::now() < until {
+                    for span in &mut spans {
+                        span.style = span.style.bg(self.yank_flash_color);
+                    }
+                }
+            }
+
+            if let Some(ref pattern) = search_pattern {
+                // Regex matches always start and end on character boundaries
+                for (from, to) in Self::match_ranges(line_content, pattern, words) {
+                    spans = Self::restyle_range(spans, from, to, |style| style.bg(Color::Yellow).fg(Color::Black));
+                }
+            }
+
+            for &(y, x) in bracket_pair.iter().flatten() {
+                if y == line_idx {
+                    let x = char_to_byte(line_content, x);
+                    spans = Self::restyle_range(spans, x, x + 1, |style| style.bg(Color::Rgb(0, 95, 135)).fg(Color::White));
+                }
+            }
+
+            if let Some((from, to)) = self.selection_on_line(line_idx, line_content.len()) {
+                spans = if line_content.is_empty() {
+                    vec![Span::styled(" ", Style::default().bg(Color::Rgb(60, 60, 120)))]
+                } else {
+                    Self::restyle_range(spans, from, to, |style| style.bg(Color::Rgb(60, 60, 120)))
+                };
+            }
+
+            spans = Self::skip_columns(Self::expand_tabs(spans, self.tabstop), self.offset_x);
+            if let Some((annotation, style)) = self.buffer.virtual_text.get(&line_idx) {
+                spans = Self::append_virtual_text(spans, annotation, *style, editor_area.width as usize);
+            }
+            for &column in &self.colorcolumns {
+                if let Some(column) = column.checked_sub(self.offset_x) {
+                    spans = Self::highlight_column(spans, column, Color::Rgb(60, 30, 30));
+                }
+            }
+            if let Some(column) = cursor_column.and_then(|column| column.checked_sub(self.offset_x)) {
+                spans = Self::highlight_column(spans, column, Color::Rgb(50, 50, 50));
+            }
+
             text_lines.push(Line::from(spans));
         }
         
-        // Fill remaining area
+        // Fill remaining area, marking lines past the end of the file
         while text_lines.len() < editor_area.height as usize {
-            text_lines.push(Line::from(vec![Span::raw(" ")]));
+            if self.show_eob {
+                text_lines.push(Line::from(vec![
+                    Span::styled(self.eob_char.to_string(), Style::default().fg(Color::DarkGray))
+                ]));
+            } else {
+                text_lines.push(Line::from(vec![Span::raw(" ")]));
+            }
         }
 
-        let editor_paragraph = Paragraph::new(text_lines)
-            .wrap(Wrap { trim: false })
+        let mut editor_paragraph = Paragraph::new(text_lines)
             .style(Style::default().bg(Color::Black));
+        if self.wrap {
+            editor_paragraph = editor_paragraph.wrap(Wrap { trim: false });
+        }
 
         frame.render_widget(editor_paragraph, editor_area);
 
-        // Render cursor
-        self.render_cursor(frame, editor_area, line_number_width)?;
+        if focused {
+            self.render_cursor(frame, editor_area)?;
+        }
         
         Ok(())
     }
 
-    fn render_cursor(&self, frame: &mut Frame, editor_area: Rect, line_number_width: usize) -> io::Result<()> {
+    // `:set stickyscroll`: the line opening the block the top of the screen is inside,
+    // found by scanning up the loaded chunk for a line indented less than the first
+    // non-blank visible one. Lines that only close brackets don't open a scope.
+    fn sticky_scope_line(&self) -> Option<usize> {
+        if !self.stickyscroll {
+            return None;
+        }
+        let start = self.buffer.start_line_number;
+        let end = start + self.buffer.lines.len();
+        let indent_of = |line: &str| {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            display_column(indent, indent.len(), self.tabstop)
+        };
+        let top = (self.offset_y..end).find(|&y| self.buffer.get_line(y).is_some_and(|l| !l.trim().is_empty()))?;
+        let indent = indent_of(self.buffer.get_line(top)?);
+        if indent == 0 {
+            return None;
+        }
+        (start..self.offset_y.min(end)).rev().find(|&y| {
+            let line = self.buffer.get_line(y).map_or("", |line| line.as_str());
+            let text = line.trim();
+            !text.is_empty()
+                && !text.chars().all(|c| matches!(c, '}' | ')' | ']' | ';' | ','))
+                && indent_of(line) < indent
+        })
+    }
+
+    fn render_sticky_header(&self, frame: &mut Frame, area: Rect, line_idx: usize, line_number_width: usize) {
+        let style = Style::default().fg(Color::Gray).bg(Color::Rgb(30, 30, 45)).add_modifier(Modifier::DIM);
+        let mut spans = Vec::new();
+        if self.gutter_visible() {
+            spans.push(Span::styled(
+                format!("{:>width$}│", line_idx + 1, width = line_number_width - 1),
+                style.fg(Color::DarkGray),
+            ));
+        }
+        let text = self.buffer.get_line(line_idx).map_or("", |line| line.as_str());
+        spans.extend(Self::expand_tabs(vec![Span::styled(text, style)], self.tabstop));
+        let header = Paragraph::new(Line::from(spans)).style(style);
+        frame.render_widget(header, area);
+    }
+
+    // Applies `restyle` to the bytes `from..to` of the text spread across `spans`,
+    // splitting spans at the range edges (which must be character boundaries)
+    fn restyle_range<'a>(
+        spans: Vec<Span<'a>>,
+        from: usize,
+        to: usize,
+        restyle: impl Fn(Style) -> Style,
+    ) -> Vec<Span<'a>> {
+        let mut result = Vec::with_capacity(spans.len() + 2);
+        let mut offset = 0;
+
+        for span in spans {
+            let text = span.content.to_string();
+            let span_start = offset;
+            let span_end = offset + text.len();
+            offset = span_end;
+
+            if span_end <= from || span_start >= to {
+                result.push(span);
+                continue;
+            }
+
+            let cut_from = from.saturating_sub(span_start).min(text.len());
+            let cut_to = to.saturating_sub(span_start).min(text.len());
+            if !text.is_char_boundary(cut_from) || !text.is_char_boundary(cut_to) {
+                result.push(span);
+                continue;
+            }
+            if cut_from > 0 {
+                result.push(Span::styled(text[..cut_from].to_string(), span.style));
+            }
+            result.push(Span::styled(text[cut_from..cut_to].to_string(), restyle(span.style)));
+            if cut_to < text.len() {
+                result.push(Span::styled(text[cut_to..].to_string(), span.style));
+            }
+        }
+        result
+    }
+
+    fn cursor_display_column(&self) -> usize {
+        self.buffer.get_line(self.cursor.y)
+            .map_or(0, |line| display_column(line, char_to_byte(line, self.cursor.x), self.tabstop))
+    }
+
+    // Byte offset of the cursor within its line, for slicing
+    fn cursor_byte(&self) -> usize {
+        self.buffer.get_line(self.cursor.y).map_or(0, |line| char_to_byte(line, self.cursor.x))
+    }
+
+    // Places the cursor on the character at byte `x` of the cursor line
+    fn set_cursor_byte(&mut self, x: usize) {
+        self.cursor.x = self.buffer.get_line(self.cursor.y).map_or(0, |line| byte_to_char(line, x));
+    }
+
+    // Replaces tabs with spaces up to the next tab stop so that screen columns line up
+    // with `display_column`
+    fn expand_tabs(spans: Vec<Span>, tabstop: usize) -> Vec<Span> {
+        if !spans.iter().any(|span| span.content.contains('\t')) {
+            return spans;
+        }
+        let mut column = 0;
+        spans
+            .into_iter()
+            .map(|span| {
+                let mut text = String::with_capacity(span.content.len());
+                for c in span.content.chars() {
+                    if c == '\t' {
+                        let next = (column / tabstop + 1) * tabstop;
+                        text.extend(std::iter::repeat_n(' ', next - column));
+                        column = next;
+                    } else {
+                        text.push(c);
+                        column += char_width(c);
+                    }
+                }
+                Span::styled(text, span.style)
+            })
+            .collect()
+    }
+
+    // Drops the first `columns` screen columns of a tab-expanded line for horizontal
+    // scrolling; a wide character cut in half leaves spaces behind
+    fn skip_columns(spans: Vec<Span>, columns: usize) -> Vec<Span> {
+        if columns == 0 {
+            return spans;
+        }
+        let mut column = 0;
+        spans
+            .into_iter()
+            .filter_map(|span| {
+                let mut text = String::new();
+                for c in span.content.chars() {
+                    let width = char_width(c);
+                    if column >= columns {
+                        text.push(c);
+                    } else if column + width > columns {
+                        text.extend(std::iter::repeat_n(' ', column + width - columns));
+                    }
+                    column += width;
+                }
+                if text.is_empty() { None } else { Some(Span::styled(text, span.style)) }
+            })
+            .collect()
+    }
+
+    // Adds an annotation after the line's text, cut to what fits so it never wraps
+    fn append_virtual_text<'a>(mut spans: Vec<Span<'a>>, text: &str, style: Style, width: usize) -> Vec<Span<'a>> {
+        let used: usize = spans.iter().flat_map(|span| span.content.chars()).map(char_width).sum();
+        // Keep one space between the text and the annotation
+        let mut room = width.saturating_sub(used + 2);
+        let mut shown = String::new();
+        for c in text.chars() {
+            let w = char_width(c);
+            if w > room {
+                break;
+            }
+            room -= w;
+            shown.push(c);
+        }
+        if !shown.is_empty() {
+            spans.push(Span::raw(" "));
+            spans.push(Span::styled(shown, style));
+        }
+        spans
+    }
+
+    // Paints the cell at screen `column` of an already tab-expanded line, padding short
+    // lines so the column stays continuous down the screen
+    fn highlight_column(spans: Vec<Span>, column: usize, color: Color) -> Vec<Span> {
+        let mut width = 0;
+        let mut offset = 0;
+        for span in &spans {
+            for (i, c) in span.content.char_indices() {
+                let w = char_width(c);
+                if w > 0 && width + w > column {
+                    let from = offset + i;
+                    return Self::restyle_range(spans, from, from + c.len_utf8(), |style| style.bg(color));
+                }
+                width += w;
+            }
+            offset += span.content.len();
+        }
+        let mut spans = spans;
+        if column > width {
+            spans.push(Span::raw(" ".repeat(column - width)));
+        }
+        spans.push(Span::styled(" ", Style::default().bg(color)));
+        spans
+    }
+
+    // `editor_area` is the text area, right of the gutter
+    fn render_cursor(&self, frame: &mut Frame, editor_area: Rect) -> io::Result<()> {
         if self.cursor.y >= self.offset_y && self.cursor.y < self.offset_y + editor_area.height as usize {
             let cursor_y = (self.cursor.y - self.offset_y) as u16;
-            let cursor_x = self.cursor.x as u16;
-
-            let adjusted_cursor_x = cursor_x + if self.show_line_numbers {
-                line_number_width as u16
-            } else {
-                0
+            let cursor_x = match self.cursor_display_column().checked_sub(self.offset_x) {
+                Some(column) => column.min(u16::MAX as usize) as u16,
+                None => return Ok(()),
             };
 
-            if adjusted_cursor_x < editor_area.width && cursor_y < editor_area.height {
+            if cursor_x < editor_area.width && cursor_y < editor_area.height {
                 let cursor_area = Rect {
-                    x: editor_area.x + adjusted_cursor_x,
+                    x: editor_area.x + cursor_x,
                     y: editor_area.y + cursor_y,
                     width: 1,
                     height: 1,
@@ -1012,13 +9904,14 @@ impl Editor {
                 
                 let cursor_char = self.buffer.get_line(self.cursor.y)
                     .and_then(|line| line.chars().nth(self.cursor.x))
+                    .filter(|&c| c != '\t')
                     .unwrap_or(' ');
 
                 let cursor_style = match self.mode {
                     Mode::Insert => Style::default().bg(Color::Green).fg(Color::Black),
                     Mode::Command => Style::default().bg(Color::Blue).fg(Color::White),
                     Mode::Search => Style::default().bg(Color::Magenta).fg(Color::White),
-                    Mode::Replace => Style::default().bg(Color::Red).fg(Color::White),
+                    Mode::Replace | Mode::ConfirmReplace => Style::default().bg(Color::Red).fg(Color::White),
                     _ => Style::default().bg(Color::Yellow).fg(Color::Black),
                 };
 
@@ -1030,95 +9923,110 @@ impl Editor {
         Ok(())
     }
 
-    fn highlight_line_safe<'a>(&self, line: &'a str, extension: &str) -> Vec<Span<'a>> {
-        // Safe highlighting that won't crash on large content
-        if line.len() > 500 {
-            return vec![Span::raw(line)];
-        }
-
-        let keywords = match extension {
-            "rs" => vec!["fn", "let", "mut", "if", "else", "match", "struct", "enum", "impl", "use", "pub"],
-            "py" => vec!["def", "class", "if", "else", "elif", "for", "while", "import", "from", "return"],
-            "js" | "ts" => vec!["function", "const", "let", "var", "if", "else", "for", "while", "class"],
-            "c" | "cpp" => vec!["int", "char", "float", "double", "if", "else", "for", "while", "struct"],
-            _ => vec![],
-        };
-
-        let mut spans = Vec::new();
-        let mut current_word = String::new();
-        let mut in_string = false;
-        let mut string_char = '"';
-        let mut in_comment = false;
-
-        for (i, ch) in line.chars().enumerate() {
-            if in_comment {
-                spans.push(Span::styled(ch.to_string(), Style::default().fg(Color::Gray)));
-                continue;
+    // Brings the syntax colours of the loaded chunk up to date as far as line `end`
+    // (exclusive). Files without a known syntax are drawn plain.
+    fn refresh_highlights(&mut self, end: usize) {
+        let extension = self.filename.as_ref()
+            .and_then(|p| p.extension())
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_string();
+        let (syntax_set, theme) = match (&self.syntax_set, &self.theme) {
+            (Some(syntax_set), Some(theme)) => (syntax_set, theme),
+            _ => {
+                self.highlights = None;
+                return;
             }
-
-            if in_string {
-                current_word.push(ch);
-                if ch == string_char {
-                    spans.push(Span::styled(current_word.clone(), Style::default().fg(Color::Green)));
-                    current_word.clear();
-                    in_string = false;
-                }
-                continue;
+        };
+        let syntax = match syntax_set.find_syntax_by_extension(&extension) {
+            Some(syntax) => syntax,
+            None => {
+                self.highlights = None;
+                return;
             }
+        };
+        let start = self.buffer.start_line_number;
+        let stale = self.highlights.as_ref().is_none_or(|cache| cache.start_line != start || cache.extension != extension);
+        if stale {
+            self.highlights = Some(HighlightCache { start_line: start, extension, lines: Vec::new() });
+        }
+        let cache = match self.highlights.as_mut() {
+            Some(cache) => cache,
+            None => return,
+        };
 
-            if ch == '"' || ch == '\'' {
-                if !current_word.is_empty() {
-                    if keywords.contains(&current_word.as_str()) {
-                        spans.push(Span::styled(current_word, Style::default().fg(Color::Blue)));
-                    } else {
-                        spans.push(Span::raw(current_word));
+        let unchanged = cache.lines.iter()
+            .zip(self.buffer.lines.iter())
+            .take_while(|(cached, line)| cached.text == **line)
+            .count();
+        cache.lines.truncate(unchanged);
+        let end = end.saturating_sub(start).min(self.buffer.lines.len());
+        while cache.lines.len() < end {
+            let line = &self.buffer.lines[cache.lines.len()];
+            let mut highlighter = match cache.lines.last() {
+                Some(previous) => HighlightLines::from_state(theme, previous.state.0.clone(), previous.state.1.clone()),
+                None => HighlightLines::new(syntax, theme),
+            };
+            // Huge lines are drawn plain rather than stall the frame; the parser state
+            // carries over them unchanged
+            let mut styles = Vec::new();
+            if line.len() <= 500 {
+                // The syntaxes are loaded in their newline-terminated form
+                let terminated = format!("{}\n", line);
+                if let Ok(regions) = highlighter.highlight_line(&terminated, syntax_set) {
+                    let mut from = 0;
+                    for (style, piece) in regions {
+                        let to = (from + piece.len()).min(line.len());
+                        if to > from {
+                            styles.push((Self::convert_style(style), from, to));
+                        }
+                        from += piece.len();
                     }
-                    current_word = String::new();
-                }
-                current_word.push(ch);
-                string_char = ch;
-                in_string = true;
-                continue;
-            }
-
-            if line[i..].starts_with("//") || line[i..].starts_with("#") {
-                if !current_word.is_empty() {
-                    spans.push(Span::raw(current_word));
-                    current_word = String::new();
                 }
-                spans.push(Span::styled(line[i..].to_string(), Style::default().fg(Color::Gray)));
-                break;
             }
+            cache.lines.push(HighlightedLine { text: line.clone(), state: highlighter.state(), styles });
+        }
+    }
 
-            if ch.is_alphanumeric() || ch == '_' {
-                current_word.push(ch);
-            } else {
-                if !current_word.is_empty() {
-                    if keywords.contains(&current_word.as_str()) {
-                        spans.push(Span::styled(current_word, Style::default().fg(Color::Blue)));
-                    } else if current_word.chars().all(|c| c.is_ascii_digit()) {
-                        spans.push(Span::styled(current_word, Style::default().fg(Color::Magenta)));
-                    } else {
-                        spans.push(Span::raw(current_word));
-                    }
-                    current_word = String::new();
-                }
-                spans.push(Span::raw(ch.to_string()));
-            }
+    // The theme's foreground and font style; its background is left to the terminal so
+    // the cursor line and selections stay visible
+    fn convert_style(style: syntect::highlighting::Style) -> Style {
+        let fg = style.foreground;
+        let mut converted = Style::default().fg(Color::Rgb(fg.r, fg.g, fg.b));
+        if style.font_style.contains(FontStyle::BOLD) {
+            converted = converted.add_modifier(Modifier::BOLD);
+        }
+        if style.font_style.contains(FontStyle::ITALIC) {
+            converted = converted.add_modifier(Modifier::ITALIC);
         }
+        if style.font_style.contains(FontStyle::UNDERLINE) {
+            converted = converted.add_modifier(Modifier::UNDERLINED);
+        }
+        converted
+    }
 
-        if !current_word.is_empty() {
-            if keywords.contains(&current_word.as_str()) {
-                spans.push(Span::styled(current_word, Style::default().fg(Color::Blue)));
-            } else {
-                spans.push(Span::raw(current_word));
+    // Spans for line `y` from the highlight cache; lines it doesn't cover, such as a
+    // split pane's rows streamed from disk, are drawn plain
+    fn highlighted_spans<'a>(&self, y: usize, line: &'a str) -> Vec<Span<'a>> {
+        let cached = self.highlights.as_ref()
+            .and_then(|cache| cache.lines.get(y.checked_sub(cache.start_line)?))
+            .filter(|cached| cached.text == line);
+        let styles = match cached {
+            Some(cached) if !cached.styles.is_empty() => &cached.styles,
+            _ => return vec![Span::raw(line)],
+        };
+        let mut spans = Vec::new();
+        let mut at = 0;
+        for &(style, from, to) in styles {
+            if from > at {
+                spans.push(Span::raw(&line[at..from]));
             }
+            spans.push(Span::styled(&line[from..to], style));
+            at = to;
         }
-
-        if spans.is_empty() {
-            spans.push(Span::raw(" "));
+        if at < line.len() {
+            spans.push(Span::raw(&line[at..]));
         }
-
         spans
     }
 
@@ -1130,6 +10038,15 @@ impl Editor {
             "  i           - Enter insert mode",
             "  a           - Insert after cursor",
             "  o           - Insert new line below",
+            "  v / V       - Visual / visual line (switch with v/V)",
+            "                y copies the selection, d or x cuts it",
+            "  Alt+v       - Visual block (r<char>, y, d, I, :s inside the block)",
+            "  gS<char>    - Split line on <char>",
+            "  ]<space>    - Blank line below ([<space> above)",
+            "  ]d / [d     - Next / previous marker (:set markers=)",
+            "  gqq         - Reflow paragraph (:set tw=72, wrap/nowrap for display)",
+            "  R / Insert  - Enter overwrite mode",
+            "  Insert      - Toggle insert/overwrite while typing",
             "  ESC         - Return to normal mode",
             "",
             "File Operations:",
@@ -1138,24 +10055,92 @@ impl Editor {
             "  :w          - Save",
             "  :q          - Quit",
             "  :wq         - Save and quit",
+            "  :w <file>   - Write a copy (:w! <file> replaces an existing one)",
+            "  :saveas <file> - Save under a new name and keep editing it",
+            "  :N / :$     - Go to line N / the last line",
+            "  :e ++enc=X  - Reload with encoding X (++ff=dos/unix)",
+            "  :merge      - Merge changes made on disk into unsaved edits",
+            "  :e / :e!    - Reload the file from disk (! drops unsaved edits)",
+            "  :set ro     - Refuse edits and saves (tuxpad -R opens files this way)",
             "",
             "Movement:",
             "  Arrow Keys  - Move cursor",
-            "  Home/End    - Start/End of line",
+            "  Home/End    - Start/End of line (Home stops at the indent first)",
+            "  w / b / e   - Next word / previous word / end of word",
+            "  %           - Jump to the matching bracket",
+            "  gg / G      - First / last line (5G goes to line 5)",
+            "  3w / 5↓ / 0 - Counts repeat motions; 0 is the start of the line",
             "  Page Up/Dn  - Scroll pages",
+            "  Ctrl+D / U  - Scroll half a page down / up",
+            "  Mouse       - Click to move, drag to select, wheel to scroll",
+            "  F           - Follow growing file (:set follow)",
             "",
             "Edit Operations:",
             "  Ctrl+C      - Copy current line",
             "  Ctrl+X      - Cut current line",
             "  Ctrl+V      - Paste line",
+            "  3dd / 2yy   - Cut / copy lines (count optional)",
+            "  :set clipboard - Share copy/paste with the system (--clipboard)",
+            "  u / Ctrl+Z  - Undo (Ctrl+Y redo, :set undolevels=N)",
+            "  .           - Repeat the last change at the cursor",
+            "  Ctrl+T      - Transpose characters (insert; Alt+T words in normal)",
+            "  Alt+Up/Down - Move the line or selected lines up / down",
+            "  Alt+D / :t. - Duplicate the line or selected lines below",
+            "  :5,10join   - Join lines (join! or join <sep>)",
+            "  J / 3J      - Join the next line / three lines with a space",
+            "  :sort[!] nu - Sort the selection or loaded lines (! reverse, n numeric, u unique)",
+            "  :uniq [i]   - Collapse repeated adjacent lines (i ignores case)",
+            "  :dupcomment - Comment out the line, keep an editable copy below",
+            "  Ctrl+/      - Comment or uncomment the line or selected lines",
+            "  Tab / S-Tab - Indent / dedent the selected lines (visual)",
+            "  qa ... q    - Record a macro into register a (qA appends)",
+            "  @a / @@     - Play macro a / the last macro (10@a repeats it)",
             "",
             "Search/Replace:",
-            "  /           - Search in current chunk",
+            "  /           - Search the whole file (Ctrl+R toggles regex)",
+            "  :set ic scs - Ignore case, unless the query has capitals (Alt+C in /)",
+            "  :s//new/    - Substitute the last search ($1 for its groups)",
+            "  :noh        - Clear search highlighting until the next search",
+            "  * / #       - Search word under cursor fwd/back",
+            "  n / N       - Next / previous match ([3/27] shown, :set nosearchcount)",
             "  Ctrl+R      - Replace in current chunk",
+            "                Alt+Enter asks per match (y/n/a/q) across the file",
+            "  :s/re/new/g - Regex substitute ($1, \\1, ${name})",
+            "  :set isk=@,48-57,_,- - Characters that form words",
+            "  :set undobreak=insert|word|char - Size of undo steps",
+            "  :w!         - Write even when nothing changed",
+            "  :e <file>   - Open a file in another buffer (:e! reloads this one)",
+            "  :bn / :bp   - Next / previous buffer (:bd closes, :bd! discards changes)",
+            "  gt / gT     - Next / previous tab (3gt or Alt+3 for the third)",
+            "  :sp / :vs   - Split the view in two (Ctrl+W arrow moves, :close, :only)",
+            "  :browse oldfiles - Pick a recently edited file",
+            "  :annotate [text] - Note at end of line (:annotate! clears all)",
+            "  Tab         - Complete commands, options and paths",
+            "  Up / Down   - Recall earlier commands and searches (kept across sessions)",
+            "  :A          - Alternate file (foo.c <-> foo.h, :set alternates= altpath=)",
+            "  :N,Mextract[!] [file] - Copy lines to a file or scratch buffer",
+            "  :numfmt sep round=N width=N - Reformat numbers in range",
+            "  :N,M!cmd    - Filter lines through cmd (:%! whole file)",
+            "  :retab      - Convert indentation (select lines, then :retab)",
+            "  :set ts=4 et - Tab width; Tab types spaces (noet types a real tab)",
+            "  :whitespace - Trailing whitespace and indent report",
+            "  gr / :references - Grep the project for the word (:copen, :cn, :cp)",
             "",
             "Display:",
             "  F1          - Toggle this help",
-            "  F2          - Toggle line numbers",
+            "  F2          - Cycle line numbers (abs/rel/hybrid/off)",
+            "  :map        - List key bindings",
+            "  :theme name - Switch the colour theme (:theme lists them)",
+            "  :set nu rnu - Hybrid line numbers",
+            "  :set eob    - Show ~ past end of file (noeob hides)",
+            "  :set eobchar=c - Character for end-of-file lines",
+            "  :set yankflash=ms yankflashcolor=#rrggbb - Yank highlight",
+            "  :set cul cuc cc=80 - Cursor line/column, color columns",
+            "  :set winwidth=72 - Preview text at a narrower width (0 = full)",
+            "  :set stickyscroll - Pin the enclosing block's first line on top",
+            "  :set ff=unix|dos - Line endings to save with (detected on load)",
+            "  :set noeol  - Leave the last line unterminated (eol ends it with the ff line ending)",
+            "  :set savereminder=min - Highlight unsaved changes after a while",
             "",
             "Large File Support:",
             "  - Loads files in chunks for performance",
@@ -1186,6 +10171,49 @@ impl Editor {
     }
 }
 
+// Puts the terminal back the way the shell left it: off the alternate screen first, so
+// the previous contents reappear, then out of raw mode, flushing so nothing lingers.
+// Anything printed to stderr must come after this or it lands on the alternate screen
+// and vanishes. Manual check: quitting after a render error, or a panic, should leave
+// the shell's earlier output intact with any message below it.
+fn restore_terminal() {
+    let mut stdout = io::stdout();
+    let _ = execute!(stdout, DisableMouseCapture, LeaveAlternateScreen, cursor::Show);
+    let _ = disable_raw_mode();
+    let _ = stdout.flush();
+}
+
+// Shows "Working…" on the mode bar once a command has run for BUSY_DELAY. The UI thread
+// is tied up running it, so the notice is written to the terminal from a helper thread.
+struct BusyIndicator {
+    done: mpsc::Sender<()>,
+    shown: std::thread::JoinHandle<bool>,
+}
+
+impl BusyIndicator {
+    fn start(row: u16) -> Self {
+        let (done, finished) = mpsc::channel();
+        let shown = std::thread::spawn(move || match finished.recv_timeout(BUSY_DELAY) {
+            Err(mpsc::RecvTimeoutError::Timeout) => execute!(
+                io::stdout(),
+                cursor::SavePosition,
+                cursor::MoveTo(0, row),
+                crossterm::style::Print(" ⠿ Working… "),
+                cursor::RestorePosition
+            )
+            .is_ok(),
+            _ => false,
+        });
+        Self { done, shown }
+    }
+
+    // True when the notice was drawn, behind the back of the next frame's diff
+    fn finish(self) -> bool {
+        let _ = self.done.send(());
+        self.shown.join().unwrap_or(false)
+    }
+}
+
 fn main() -> io::Result<()> {
     let args = Args::parse();
     
@@ -1196,26 +10224,50 @@ fn main() -> io::Result<()> {
     }
     
     let mut stdout = io::stdout();
-    if let Err(e) = execute!(stdout, EnterAlternateScreen) {
+    if let Err(e) = execute!(stdout, EnterAlternateScreen, EnableMouseCapture) {
         let _ = disable_raw_mode();
         eprintln!("Failed to enter alternate screen: {}", e);
         return Err(e);
     }
+
+    // A panic message printed on the alternate screen would be wiped along with it
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
     
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = match Terminal::new(backend) {
         Ok(t) => t,
         Err(e) => {
-            let _ = disable_raw_mode();
+            restore_terminal();
             eprintln!("Failed to create terminal: {}", e);
             return Err(e);
         }
     };
     
     let mut editor = Editor::new();
+    HistoryStore::load(&mut editor.command_history, &mut editor.search_history);
+    editor.restore_position = !args.no_restore;
+    editor.syntax_enabled = !args.no_syntax;
+    editor.system_clipboard = args.clipboard;
+    editor.read_only = args.readonly;
     
     // Load file if specified
     if let Some(filename) = args.file {
+        if args.mmap {
+            match MappedFile::open(&filename) {
+                Ok(mapped) => {
+                    editor.buffer.mapped = Some(mapped);
+                    editor.read_only = true;
+                }
+                Err(e) => {
+                    // Fall back to the regular buffered loader
+                    editor.status_message = format!("Memory map failed ({}), loading normally", e);
+                }
+            }
+        }
         if let Err(e) = editor.load_file(&filename) {
             editor.status_message = format!("Error loading file: {}", e);
         }
@@ -1235,31 +10287,1237 @@ fn main() -> io::Result<()> {
             }
         }
         
-        match event::read() {
-            Ok(Event::Key(key)) => {
-                match editor.handle_key_event(key) {
-                    Ok(should_continue) => {
-                        if !should_continue {
-                            break Ok(());
-                        }
+        // Wait up to one tick for input, then drain everything already queued so a burst of
+        // keystrokes (fast typing, pasted text) is handled in full before the next redraw
+        let mut events = Vec::new();
+        match event::poll(editor.tick_timeout()) {
+            Ok(true) => match event::read() {
+                Ok(ev) => events.push(ev),
+                Err(e) => {
+                    editor.status_message = format!("Event read error: {}", e);
+                    // Continue on event read errors
+                }
+            },
+            Ok(false) => {
+                if let Err(e) = editor.on_tick() {
+                    editor.status_message = format!("Background update error: {}", e);
+                }
+            }
+            Err(e) => {
+                editor.status_message = format!("Event poll error: {}", e);
+            }
+        }
+        while let Ok(true) = event::poll(Duration::from_millis(0)) {
+            match event::read() {
+                Ok(ev) => events.push(ev),
+                Err(e) => {
+                    editor.status_message = format!("Event read error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut should_quit = false;
+        for ev in events {
+            let key = match ev {
+                Event::Key(key) => key,
+                Event::Resize(width, height) => {
+                    // Wipe what the old layout left behind; the next draw fills the new size
+                    let _ = terminal.autoresize();
+                    let _ = terminal.clear();
+                    editor.resize(width, height);
+                    continue;
+                }
+                Event::Mouse(mouse) => {
+                    if let Err(e) = editor.handle_mouse_event(mouse) {
+                        editor.status_message = format!("Mouse error: {}", e);
                     }
-                    Err(e) => {
-                        editor.status_message = format!("Key handling error: {}", e);
-                        // Don't break on key handling errors
+                    continue;
+                }
+                _ => continue, // Ignore other events
+            };
+
+            let busy = if editor.is_long_operation(&key) {
+                let row = terminal.size().map_or(0, |size| size.height.saturating_sub(editor.status_rows + 1));
+                Some(BusyIndicator::start(row))
+            } else {
+                None
+            };
+
+            match editor.handle_key_event(key) {
+                Ok(should_continue) => {
+                    if !should_continue {
+                        should_quit = true;
                     }
                 }
+                Err(e) => {
+                    editor.status_message = format!("Key handling error: {}", e);
+                    // Don't break on key handling errors
+                }
             }
-            Ok(_) => {}, // Ignore other events
-            Err(e) => {
-                editor.status_message = format!("Event read error: {}", e);
-                // Continue on event read errors
+            if busy.is_some_and(BusyIndicator::finish) {
+                let _ = terminal.clear();
+            }
+
+            if should_quit {
+                break;
             }
         }
+
+        if should_quit {
+            break Ok(());
+        }
     };
     
-    // Cleanup
-    let _ = disable_raw_mode();
-    let _ = execute!(terminal.backend_mut(), LeaveAlternateScreen);
+    editor.close_all_buffers();
+    // Losing the history is not worth holding up the exit for
+    let _ = HistoryStore::save(&editor.command_history, &editor.search_history);
+
+    // Cleanup; `main` reports an `Err` only after this, on the normal screen
+    let _ = terminal.flush();
+    drop(terminal);
+    restore_terminal();
     
     result
   }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_with(lines: &[&str]) -> Editor {
+        let mut editor = Editor::new();
+        editor.buffer.lines = lines.iter().map(|line| line.to_string()).collect();
+        editor.buffer.total_lines = lines.len();
+        editor
+    }
+
+    fn lines(editor: &Editor) -> Vec<String> {
+        editor.buffer.lines.iter().cloned().collect()
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode, modifiers: KeyModifiers) {
+        editor.handle_key_event(KeyEvent::new(code, modifiers)).unwrap();
+    }
+
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            press(editor, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+    }
+
+    fn run_command(editor: &mut Editor, command: &str) {
+        editor.command_buffer = command.to_string();
+        editor.execute_command().unwrap();
+    }
+
+    // A file of `count` numbered lines in the temp directory, unique to the test
+    fn numbered_file(name: &str, count: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tuxpad-{}-{}", std::process::id(), name));
+        let text: String = (1..=count).map(|n| format!("line {}\n", n)).collect();
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    // Draws the editor pane alone onto a `width` x `height` test screen
+    fn render_pane(editor: &Editor, width: u16, height: u16) -> ratatui::buffer::Buffer {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| editor.render_editor(frame, frame.size(), true).unwrap()).unwrap();
+        terminal.backend().buffer().clone()
+    }
+
+    fn screen_row(screen: &ratatui::buffer::Buffer, y: u16) -> String {
+        (0..screen.area.width).map(|x| screen.get(x, y).symbol()).collect()
+    }
+
+    fn edit_line(editor: &mut Editor, y: usize, text: &str) {
+        editor.cursor = Cursor { x: 0, y };
+        editor.reload_current_chunk().unwrap();
+        type_keys(editor, "i");
+        type_keys(editor, text);
+        press(editor, KeyCode::Esc, KeyModifiers::NONE);
+    }
+
+    #[test]
+    fn block_insert_undoes_as_one_step_after_undo_history_is_trimmed() {
+        let mut editor = editor_with(&["", "one", "two", "three"]);
+        run_command(&mut editor, "set undobreak=char");
+        type_keys(&mut editor, "i");
+        type_keys(&mut editor, &"x".repeat(60));
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(editor.undo_stack.len(), editor.undolevels);
+
+        editor.cursor = Cursor { x: 0, y: 1 };
+        press(&mut editor, KeyCode::Char('v'), KeyModifiers::ALT);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        type_keys(&mut editor, "I> ");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(lines(&editor)[1..], ["> one", "> two", "> three"]);
+
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor)[1..], ["one", "two", "three"]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), ["x".repeat(59).as_str(), "one", "two", "three"]);
+    }
+
+    #[test]
+    fn saving_a_big_file_keeps_the_lines_outside_the_chunk() {
+        let path = numbered_file("splice", 5000);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        edit_line(&mut editor, 2499, "edited ");
+        editor.save_file(false).unwrap();
+
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let saved: Vec<&str> = saved.lines().collect();
+        assert_eq!(saved.len(), 5000);
+        for (y, line) in saved.iter().enumerate() {
+            if y == 2499 {
+                assert_eq!(*line, "edited line 2500");
+            } else {
+                assert_eq!(*line, format!("line {}", y + 1));
+            }
+        }
+    }
+
+    #[test]
+    fn edits_survive_scrolling_to_another_chunk_until_saved() {
+        let path = numbered_file("stash", 5000);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        edit_line(&mut editor, 10, "first ");
+        run_command(&mut editor, "11t.");
+        edit_line(&mut editor, 4000, "second ");
+        assert!(editor.buffer.get_line(10).is_none());
+        assert_eq!(editor.buffer.get_line(4000).map(String::as_str), Some("second line 4000"));
+        assert_eq!(editor.buffer.total_lines, 5001);
+
+        editor.cursor.y = 10;
+        editor.reload_current_chunk().unwrap();
+        assert_eq!(editor.buffer.get_line(10).map(String::as_str), Some("first line 11"));
+        editor.save_file(false).unwrap();
+
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let saved: Vec<&str> = saved.lines().collect();
+        assert_eq!(saved.len(), 5001);
+        assert_eq!(saved[10..12], ["first line 11", "first line 11"]);
+        assert_eq!(saved[4000], "second line 4000");
+        assert_eq!(saved[5000], "line 5000");
+    }
+
+    #[test]
+    fn confirm_replace_across_chunks_leaves_the_file_alone_until_saved() {
+        let path = numbered_file("confirm", 3000);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        editor.replace_query = "line".to_string();
+        editor.replace_with = "row".to_string();
+        editor.start_confirm_replace();
+        type_keys(&mut editor, "ny");
+        assert_eq!(editor.buffer.get_line(1).map(String::as_str), Some("row 2"));
+        type_keys(&mut editor, "a");
+
+        assert!(editor.modified);
+        assert_eq!(editor.status_message, "Replaced 2999 of 3000 occurrences");
+        assert!(fs::read_to_string(&path).unwrap().starts_with("line 1\nline 2\n"));
+        editor.save_file(false).unwrap();
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(saved.starts_with("line 1\nrow 2\n"));
+        assert_eq!(saved.matches("row").count(), 2999);
+    }
+
+    #[test]
+    fn substitute_over_several_chunks_is_one_undoable_edit() {
+        let path = numbered_file("substitute", 3000);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        editor.cursor.y = 1500;
+        editor.reload_current_chunk().unwrap();
+        run_command(&mut editor, "%s/line/row/g");
+        assert_eq!(editor.status_message, "3000 substitution(s) on 3000 line(s)");
+        assert!(editor.modified);
+        assert_eq!(editor.cursor.y, 1500);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("line 1\n"));
+
+        let mut rows = 0;
+        editor.buffer.for_each_file_line(|y, line| {
+            assert_eq!(line, format!("row {}", y + 1));
+            rows += 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(rows, 3000);
+
+        editor.undo().unwrap();
+        editor.buffer.for_each_file_line(|y, line| {
+            assert_eq!(line, format!("line {}", y + 1));
+            Ok(())
+        }).unwrap();
+        editor.redo().unwrap();
+        editor.save_file(false).unwrap();
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.lines().filter(|line| line.starts_with("row ")).count(), 3000);
+    }
+
+    #[test]
+    fn saving_through_links_keeps_them_linked() {
+        let target = numbered_file("link-target", 3);
+        let symlink = target.with_extension("symlink");
+        let hard_link = target.with_extension("hardlink");
+        let _ = fs::remove_file(&symlink);
+        let _ = fs::remove_file(&hard_link);
+        std::os::unix::fs::symlink(&target, &symlink).unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&symlink).unwrap();
+        edit_line(&mut editor, 0, "via symlink ");
+        editor.save_file(false).unwrap();
+        assert!(fs::symlink_metadata(&symlink).unwrap().file_type().is_symlink());
+        assert!(!editor.status_message.contains("not atomically"));
+
+        editor.close_file();
+
+        fs::hard_link(&target, &hard_link).unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&hard_link).unwrap();
+        edit_line(&mut editor, 1, "via hard link ");
+        editor.save_file(false).unwrap();
+        assert!(editor.status_message.contains("not atomically"));
+        editor.close_file();
+
+        let saved = fs::read_to_string(&target).unwrap();
+        for path in [&target, &symlink, &hard_link] {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(saved, "via symlink line 1\nvia hard link line 2\nline 3\n");
+    }
+
+    #[test]
+    fn buffers_keep_their_own_indentation_and_wq_guards_them() {
+        let first = numbered_file("indent-first", 2);
+        let second = numbered_file("indent-second", 2);
+        let mut editor = Editor::new();
+        editor.load_file(&first).unwrap();
+        run_command(&mut editor, "set tabstop=8");
+        run_command(&mut editor, "set noexpandtab");
+        edit_line(&mut editor, 0, "changed ");
+        editor.edit_file(second.to_str().unwrap(), false).unwrap();
+        run_command(&mut editor, "set tabstop=2");
+
+        editor.switch_to_buffer(0);
+        assert_eq!((editor.tabstop, editor.expandtab), (8, false));
+        editor.switch_to_buffer(1);
+        assert_eq!((editor.tabstop, editor.expandtab), (2, false));
+
+        run_command(&mut editor, "wq");
+        assert!(!editor.exit_requested);
+        assert!(editor.status_message.starts_with("Another buffer has unsaved changes"));
+        editor.close_all_buffers();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+
+    #[test]
+    fn huge_counts_are_capped() {
+        let mut editor = editor_with(&["one", "two"]);
+        type_keys(&mut editor, "99999999999999999999] ");
+        assert_eq!(editor.buffer.total_lines, 2 + MAX_COUNT);
+        assert_eq!(lines(&editor)[..2], ["one", ""]);
+    }
+
+    #[test]
+    fn counted_motion_stops_at_the_end_of_the_file() {
+        let mut editor = editor_with(&["one", "two", "three"]);
+        type_keys(&mut editor, "9999");
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        assert_eq!(editor.cursor.y, 2);
+        assert!(editor.pending_keys.is_empty());
+        type_keys(&mut editor, "99999999999999999999u");
+        assert_eq!(editor.status_message, "Already at oldest change");
+    }
+
+    #[test]
+    fn macro_that_plays_itself_is_refused() {
+        let mut editor = editor_with(&["a", "b", "c"]);
+        type_keys(&mut editor, "qa");
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        type_keys(&mut editor, "@aq");
+        editor.cursor.y = 0;
+        type_keys(&mut editor, "@a");
+        assert_eq!(editor.cursor.y, 1);
+        assert_eq!(editor.status_message, "Macro @a calls itself");
+        assert!(editor.playing_macros.is_empty());
+    }
+
+    #[test]
+    fn annotations_follow_their_lines_through_edits() {
+        let mut editor = editor_with(&["one", "two", "three", "four"]);
+        editor.buffer.set_virtual_text(1, "on two", Style::default());
+        editor.buffer.set_virtual_text(3, "on four", Style::default());
+        editor.cursor = Cursor { x: 0, y: 0 };
+        type_keys(&mut editor, "o");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        let mut keys: Vec<usize> = editor.buffer.virtual_text.keys().copied().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, [2, 4]);
+
+        editor.cursor = Cursor { x: 0, y: 2 };
+        type_keys(&mut editor, "dd");
+        assert_eq!(editor.buffer.virtual_text.keys().copied().collect::<Vec<_>>(), [3]);
+        assert_eq!(editor.buffer.virtual_text[&3].0, "on four");
+    }
+
+    #[test]
+    fn extract_keeps_an_existing_file_unless_forced() {
+        let target = numbered_file("extract", 1);
+        let mut editor = editor_with(&["one", "two", "three"]);
+        run_command(&mut editor, &format!("2,3extract {}", target.display()));
+        assert!(editor.status_message.ends_with("exists (add ! to overwrite)"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "line 1\n");
+
+        run_command(&mut editor, &format!("2,3extract! {}", target.display()));
+        let written = fs::read_to_string(&target).unwrap();
+        fs::remove_file(&target).unwrap();
+        assert_eq!(written, "two\nthree\n");
+    }
+
+    #[test]
+    fn undobreak_insert_undoes_each_insert_session_at_once() {
+        let mut editor = editor_with(&[""]);
+        assert_eq!(editor.undo_break, UndoBreak::Word);
+        run_command(&mut editor, "set undobreak=insert");
+        type_keys(&mut editor, "ihello world");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        type_keys(&mut editor, "a again");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["hello world again"]);
+
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), ["hello world"]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), [""]);
+    }
+
+    #[test]
+    fn merge3_takes_each_sides_changes_and_marks_conflicts() {
+        let base = "a\nb\nc\nd\n";
+        assert_eq!(merge3(base, "a\nB\nc\nd\n", "a\nb\nc\nD\n"), Ok("a\nB\nc\nD\n".to_string()));
+        assert_eq!(merge3(base, "a\nc\nd\n", "a\nb\nc\nd\ne\n"), Ok("a\nc\nd\ne\n".to_string()));
+        assert_eq!(merge3(base, "a\nX\nc\nd\n", "a\nX\nc\nd\n"), Ok("a\nX\nc\nd\n".to_string()));
+        assert_eq!(
+            merge3(base, "a\nours\nc\nd\n", "a\ntheirs\nc\nd\n"),
+            Err("a\n<<<<<<< ours\nours\n||||||| original\nb\n=======\ntheirs\n>>>>>>> theirs\nc\nd\n".to_string())
+        );
+    }
+
+    #[test]
+    fn cursor_moves_and_edits_by_whole_characters() {
+        let mut editor = editor_with(&["naïve 日本語 🎉"]);
+        type_keys(&mut editor, "i");
+        for _ in 0..3 {
+            press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        }
+        assert_eq!(editor.cursor.x, 3);
+        type_keys(&mut editor, "X");
+        assert_eq!(lines(&editor), ["naïXve 日本語 🎉"]);
+        assert_eq!(editor.cursor.x, 4);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["nave 日本語 🎉"]);
+        assert_eq!(editor.cursor.x, 2);
+
+        editor.cursor.x = 10;
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        for _ in 0..3 {
+            press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        }
+        assert_eq!(editor.cursor.x, 6);
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        type_keys(&mut editor, "🎉");
+        assert_eq!(lines(&editor), ["nave 日", "🎉本語 "]);
+        assert_eq!(editor.cursor_display_column(), 2);
+
+        // Vertical moves keep the screen column, two cells per wide character
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert_eq!(editor.cursor.x, 2);
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        editor.cursor.x = 0;
+        type_keys(&mut editor, "w");
+        assert_eq!(editor.cursor.x, 5);
+        assert_eq!(editor.word_under_cursor().map(|(_, _, word)| word), Some("日".to_string()));
+    }
+
+    #[test]
+    fn reflow_counts_screen_columns_not_bytes() {
+        let mut editor = editor_with(&["naïve café über", "日本語 日本語 日本語"]);
+        editor.textwidth = 11;
+        editor.reflow_lines(0, 0);
+        assert_eq!(lines(&editor), ["naïve café", "über", "日本語 日本語 日本語"]);
+        editor.reflow_lines(2, 2);
+        assert_eq!(lines(&editor), ["naïve café", "über", "日本語", "日本語", "日本語"]);
+        editor.textwidth = 13;
+        editor.reflow_lines(2, 4);
+        assert_eq!(lines(&editor), ["naïve café", "über", "日本語 日本語", "日本語"]);
+    }
+
+    #[test]
+    fn block_selection_is_worked_out_once_per_selection() {
+        let mut editor = editor_with(&["a\tbcdef", "日本語xyz", "ab"]);
+        press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Char('v'), KeyModifiers::ALT);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        editor.refresh_block_cache();
+        let ranges = |editor: &Editor| (0..3).map(|y| editor.selection_on_line(y, 0)).collect::<Vec<_>>();
+        assert_eq!(ranges(&editor), [Some((0, 2)), Some((0, 3)), Some((0, 2))]);
+        assert!(editor.cached_block().is_some());
+
+        // Moving the cursor leaves the cache behind until the next redraw
+        press(&mut editor, KeyCode::Up, KeyModifiers::NONE);
+        assert!(editor.cached_block().is_none());
+        assert_eq!(ranges(&editor)[0], Some((0, 2)));
+        editor.refresh_block_cache();
+        assert_eq!(ranges(&editor), [Some((0, 2)), Some((0, 3)), None]);
+    }
+
+    #[test]
+    fn swap_files_live_in_the_cache_directory() {
+        let path = numbered_file("swapped.txt", 3);
+        let swap = SwapFile::path(&path).unwrap();
+        assert!(swap.starts_with(cache_dir().unwrap().join("swap")));
+        assert!(swap.starts_with(std::env::temp_dir()));
+        let absolute = fs::canonicalize(&path).unwrap();
+        assert_eq!(swap.file_name().unwrap().to_str().unwrap(), format!("{}.swp", absolute.to_str().unwrap().replace('/', "%")));
+
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&swap).unwrap().trim(), std::process::id().to_string());
+        assert!(fs::read_dir(path.parent().unwrap()).unwrap().all(|entry| {
+            !entry.unwrap().file_name().to_string_lossy().ends_with("swapped.txt.swp")
+        }));
+        editor.close_file();
+        assert!(!swap.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unmodified_save_still_asks_when_the_file_changed_on_disk() {
+        let path = numbered_file("changed-on-disk.txt", 3);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+        let before = modified();
+        run_command(&mut editor, "w");
+        assert!(editor.status_message.starts_with("No changes to write"));
+        assert_eq!(modified(), before);
+
+        fs::write(&path, "someone else's text\n").unwrap();
+        run_command(&mut editor, "w");
+        assert!(editor.status_message.contains("Overwrite it?"), "{}", editor.status_message);
+        type_keys(&mut editor, "y");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 1\nline 2\nline 3\n");
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn retab_covers_lines_outside_the_loaded_chunk() {
+        let path = numbered_file("retab", 3000);
+        let indented: String = fs::read_to_string(&path).unwrap().lines().map(|line| format!("        {}\n", line)).collect();
+        fs::write(&path, indented).unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        editor.expandtab = false;
+        editor.tabstop = 8;
+        run_command(&mut editor, "retab");
+        assert_eq!(editor.status_message, "Retabbed 3000 line(s) to tabs");
+        assert_eq!(editor.cursor.y, 0);
+        editor.save_file(false).unwrap();
+
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.lines().count(), 3000);
+        assert!(saved.lines().enumerate().all(|(y, line)| line == format!("\tline {}", y + 1)));
+    }
+
+    #[test]
+    fn bare_addresses_go_to_a_line_and_ranges_need_a_command() {
+        let mut editor = editor_with(&["one", "two", "three", "four", "five"]);
+        run_command(&mut editor, "4");
+        assert_eq!(editor.cursor.y, 3);
+        run_command(&mut editor, " 2 ");
+        assert_eq!(editor.cursor.y, 1);
+        run_command(&mut editor, "$");
+        assert_eq!(editor.cursor.y, 4);
+        run_command(&mut editor, "1,3");
+        assert_eq!(editor.cursor.y, 2);
+
+        run_command(&mut editor, "2x");
+        assert_eq!(editor.cursor.y, 2);
+        assert_eq!(editor.status_message, "Unknown command: 2x");
+        run_command(&mut editor, "1,2 join");
+        assert_eq!(lines(&editor), ["one two", "three", "four", "five"]);
+    }
+
+    #[test]
+    fn undoing_back_to_the_saved_text_clears_modified() {
+        let path = numbered_file("undo-saved", 3);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        edit_line(&mut editor, 0, "a ");
+        editor.undo().unwrap();
+        assert!(!editor.modified);
+        editor.redo().unwrap();
+        assert!(editor.modified);
+
+        editor.save_file(false).unwrap();
+        edit_line(&mut editor, 1, "b ");
+        assert!(editor.modified);
+        editor.undo().unwrap();
+        assert!(!editor.modified);
+        editor.undo().unwrap();
+        assert!(editor.modified);
+        editor.redo().unwrap();
+        assert!(!editor.modified);
+
+        // Emptying a trimmed history does not bring back the saved text
+        editor.undolevels = 1;
+        edit_line(&mut editor, 2, "c ");
+        editor.undo().unwrap();
+        assert!(!editor.modified);
+        editor.mark_unmodified();
+        edit_line(&mut editor, 2, "d ");
+        edit_line(&mut editor, 2, "e ");
+        editor.undo().unwrap();
+        assert!(editor.modified);
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn busy_indicator_stays_hidden_for_quick_commands() {
+        let mut editor = editor_with(&["one"]);
+        editor.mode = Mode::Command;
+        assert!(editor.is_long_operation(&KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)));
+        let busy = BusyIndicator::start(0);
+        run_command(&mut editor, "1");
+        assert!(!busy.finish());
+    }
+
+    #[test]
+    fn every_yank_flashes_the_yanked_lines() {
+        let mut editor = editor_with(&["one", "two", "three", "four"]);
+        type_keys(&mut editor, "2yy");
+        assert_eq!(editor.clipboard, "one\ntwo");
+        assert_eq!(editor.yank_flash.map(|(start, end, _)| (start, end)), Some((0, 1)));
+
+        editor.cursor.y = 2;
+        type_keys(&mut editor, "V");
+        press(&mut editor, KeyCode::Down, KeyModifiers::NONE);
+        type_keys(&mut editor, "y");
+        assert_eq!(editor.clipboard, "three\nfour");
+        assert_eq!(editor.yank_flash.map(|(start, end, _)| (start, end)), Some((2, 3)));
+
+        press(&mut editor, KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(editor.yank_flash.map(|(start, end, _)| (start, end)), Some((2, 2)));
+
+        editor.yank_flash = None;
+        run_command(&mut editor, "set yankflash=0");
+        type_keys(&mut editor, "yy");
+        assert_eq!(editor.clipboard, "three");
+        assert!(editor.yank_flash.is_none());
+    }
+
+    #[test]
+    fn truncating_a_mapped_file_falls_back_to_reading_it() {
+        let path = numbered_file("mapped.txt", 50);
+        let mut editor = Editor::new();
+        editor.buffer.mapped = Some(MappedFile::open(&path).unwrap());
+        editor.load_file(&path).unwrap();
+        assert_eq!(editor.buffer.get_line(0).unwrap(), "line 1");
+
+        fs::write(&path, "short\n").unwrap();
+        editor.jump_to_end_of_file().unwrap();
+        assert!(editor.buffer.mapped.is_none());
+        assert_eq!(editor.buffer.total_lines, 1);
+        assert_eq!(editor.buffer.get_line(0).unwrap(), "short");
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn syntax_sets_load_only_for_highlightable_files() {
+        let plain = numbered_file("plain.txt", 3);
+        let mut editor = Editor::new();
+        assert!(editor.syntax_set.is_none() && editor.theme_set.is_none());
+        editor.load_file(&plain).unwrap();
+        assert!(editor.syntax_set.is_none() && editor.theme_set.is_none());
+        editor.close_file();
+
+        let code = numbered_file("code.rs", 3);
+        editor.load_file(&code).unwrap();
+        assert!(editor.syntax_set.is_some() && editor.theme_set.is_some());
+
+        let mut editor = Editor::new();
+        editor.syntax_enabled = false;
+        editor.load_file(&code).unwrap();
+        assert!(editor.syntax_set.is_none());
+        editor.close_file();
+        fs::remove_file(&plain).unwrap();
+        fs::remove_file(&code).unwrap();
+    }
+
+    #[test]
+    fn every_command_and_key_binding_dispatches() {
+        for command in EX_COMMANDS {
+            for name in command.names {
+                let (found, bang, arg) = Editor::find_ex_command(name).unwrap();
+                assert_eq!((found.names, bang, arg), (command.names, false, ""), ":{}", name);
+            }
+        }
+
+        let same = |a: &KeyBinding, b: &KeyBinding| (a.mode, a.keys, a.action) == (b.mode, b.keys, b.action);
+        let mut editor = editor_with(&["one"]);
+        for binding in KEY_BINDINGS {
+            editor.mode = match binding.mode {
+                "normal" => Mode::Normal,
+                "visual" => Mode::Visual,
+                "block" => Mode::VisualBlock,
+                "insert" => Mode::Insert,
+                "command" => Mode::Command,
+                "search" => Mode::Search,
+                "replace" => Mode::Replace,
+                "confirm" => Mode::ConfirmReplace,
+                mode => panic!("unknown mode {}", mode),
+            };
+            match binding.trigger {
+                KeyTrigger::Press(presses) => {
+                    for &(code, modifiers) in presses {
+                        let found = editor.pressed_binding(&KeyEvent::new(code, modifiers));
+                        assert!(found.is_some_and(|found| same(found, binding)), "{} {}", binding.mode, binding.keys);
+                    }
+                }
+                KeyTrigger::Sequence(pattern) => {
+                    let typed = match pattern.find('<') {
+                        Some(at) => format!("{}a", &pattern[..at]),
+                        None => pattern.to_string(),
+                    };
+                    let found = match editor.sequence_binding(&typed) {
+                        SequenceMatch::Complete(found) => same(found, binding),
+                        _ => false,
+                    };
+                    assert!(found, "{} {}", binding.mode, binding.keys);
+                }
+                KeyTrigger::Elsewhere => {}
+            }
+        }
+    }
+
+    #[test]
+    fn every_completed_set_option_is_known() {
+        let mut editor = editor_with(&["one"]);
+        editor.command_buffer = "set ".to_string();
+        let (_, names) = editor.command_completions();
+        assert!(names.contains(&"nowrap".to_string()) && names.contains(&"tabstop=".to_string()));
+        for name in names {
+            editor.apply_setting(&name);
+            assert!(!editor.status_message.starts_with("Unknown option"), "{}", name);
+        }
+
+        run_command(&mut editor, "set nonu ts=4 isk?");
+        assert!(!editor.show_line_numbers);
+        assert_eq!(editor.tabstop, 4);
+        assert!(editor.status_message.starts_with("iskeyword="));
+        run_command(&mut editor, "set nots");
+        assert_eq!(editor.status_message, "Unknown option: nots");
+    }
+
+    #[test]
+    fn hybrid_numbers_keep_the_cursor_line_absolute() {
+        let mut editor = editor_with(&["text"; 12]);
+        editor.cursor.y = 5;
+        run_command(&mut editor, "set number relativenumber");
+        let screen = render_pane(&editor, 20, 12);
+        let gutter: Vec<String> = (0..12).map(|y| screen_row(&screen, y)[..3].trim().to_string()).collect();
+        assert_eq!(gutter, ["5", "4", "3", "2", "1", "6", "1", "2", "3", "4", "5", "6"]);
+
+        run_command(&mut editor, "set nonumber");
+        let screen = render_pane(&editor, 20, 12);
+        assert_eq!(screen_row(&screen, 5)[..3].trim(), "0");
+
+        // The gutter is wide enough for the largest absolute number
+        editor.buffer.total_lines = 12345;
+        run_command(&mut editor, "set number");
+        assert_eq!(editor.line_number_width(), 6);
+    }
+
+    #[test]
+    fn reopening_a_file_returns_to_the_last_position() {
+        let path = numbered_file("reopened.txt", 40);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        editor.cursor = Cursor { x: 3, y: 29 };
+        editor.close_file();
+
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        assert_eq!((editor.cursor.y, editor.cursor.x), (29, 3));
+        editor.close_file();
+
+        // A position past the end of a file that has since shrunk is clamped
+        fs::write(&path, "short\nfile\n").unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        assert_eq!((editor.cursor.y, editor.cursor.x), (1, 3));
+        assert!(editor.status_message.contains("past the end of the file"));
+        editor.close_file();
+
+        // `--no-restore` opens at the top
+        let mut editor = Editor::new();
+        editor.restore_position = false;
+        editor.load_file(&path).unwrap();
+        assert_eq!((editor.cursor.y, editor.cursor.x), (0, 0));
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn long_commands_are_typed_in_full_and_scroll_in_the_status_bar() {
+        let mut editor = editor_with(&[&"x".repeat(95)]);
+        let command = format!("s/{}/{}/", "x".repeat(95), "y".repeat(101));
+        assert_eq!(command.len(), 200);
+        type_keys(&mut editor, ":");
+        type_keys(&mut editor, &command);
+        assert_eq!(editor.command_buffer, command);
+        let shown = Editor::scroll_to_tail(&format!(":{}", command), 40);
+        assert_eq!(shown.chars().count(), 40);
+        assert!(shown.starts_with(" …") && shown.ends_with("yyy/"));
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["y".repeat(101)]);
+
+        let query = "z".repeat(150);
+        type_keys(&mut editor, "/");
+        type_keys(&mut editor, &query);
+        assert_eq!(editor.search_query, query);
+    }
+
+    #[test]
+    fn iskeyword_decides_whether_a_dash_joins_words() {
+        let mut editor = editor_with(&["foo-bar baz"]);
+        type_keys(&mut editor, "w");
+        assert_eq!(editor.cursor.x, 3);
+        editor.cursor.x = 0;
+        assert_eq!(editor.word_under_cursor().map(|(_, _, word)| word), Some("foo".to_string()));
+
+        run_command(&mut editor, "set iskeyword=@,48-57,_,-");
+        type_keys(&mut editor, "w");
+        assert_eq!(editor.cursor.x, 8);
+        editor.cursor.x = 0;
+        assert_eq!(editor.word_under_cursor().map(|(_, _, word)| word), Some("foo-bar".to_string()));
+
+        run_command(&mut editor, "set iskeyword=@,^x");
+        assert!(editor.is_word_char('a') && !editor.is_word_char('x') && !editor.is_word_char('1'));
+    }
+
+    #[test]
+    fn cursorcolumn_follows_the_cursor_screen_column() {
+        let mut editor = editor_with(&["\tab", "日本語x", "a", "abcdefghij"]);
+        editor.tabstop = 4;
+        run_command(&mut editor, "set nonumber nocursorline cursorcolumn colorcolumn=10");
+        // Highlighted columns of the rows other than the cursor's, where the cursor is drawn
+        let highlighted = |editor: &Editor| -> Vec<Vec<u16>> {
+            let screen = render_pane(editor, 20, 4);
+            (0..4)
+                .filter(|&y| y as usize != editor.cursor.y)
+                .map(|y| (0..20).filter(|&x| screen.get(x, y).bg == Color::Rgb(50, 50, 50)).collect())
+                .collect()
+        };
+
+        editor.cursor = Cursor { x: 6, y: 3 };
+        assert_eq!(highlighted(&editor), [[6], [6], [6]]);
+        // After a tab
+        editor.cursor = Cursor { x: 1, y: 0 };
+        assert_eq!(highlighted(&editor), [[4], [4], [4]]);
+        // On a wide character, which starts at the third column
+        editor.cursor = Cursor { x: 1, y: 1 };
+        assert_eq!(highlighted(&editor), [[2], [2], [2]]);
+
+        // Scrolled sideways, with the colorcolumn in its own shade
+        editor.cursor = Cursor { x: 8, y: 3 };
+        editor.offset_x = 2;
+        assert_eq!(highlighted(&editor), [[6], [6], [6]]);
+        let screen = render_pane(&editor, 20, 4);
+        assert_eq!(screen.get(7, 2).bg, Color::Rgb(60, 30, 30));
+    }
+
+    #[test]
+    fn combining_marks_and_dead_keys_compose_in_insert_mode() {
+        let mut editor = editor_with(&[""]);
+        type_keys(&mut editor, "ie\u{301}");
+        assert_eq!(lines(&editor), ["é"]);
+        assert_eq!(editor.cursor.x, 1);
+
+        // No precomposed form: the mark stays a character of its own after its base
+        type_keys(&mut editor, "q\u{301}x");
+        assert_eq!(lines(&editor), ["éq\u{301}x"]);
+        assert_eq!(editor.cursor.x, 4);
+
+        // A dead key waits for the letter it accents
+        type_keys(&mut editor, "\u{B4}");
+        assert_eq!(lines(&editor), ["éq\u{301}x"]);
+        type_keys(&mut editor, "a\u{A8}z");
+        assert_eq!(lines(&editor), ["éq\u{301}xá\u{A8}z"]);
+        assert_eq!(editor.cursor.x, editor.buffer.lines[0].chars().count());
+    }
+
+    #[test]
+    fn saving_keeps_the_executable_bit() {
+        let path = numbered_file("script.sh", 3);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o754)).unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        edit_line(&mut editor, 0, "#!/bin/sh ");
+        run_command(&mut editor, "w");
+        assert!(!editor.modified, "{}", editor.status_message);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("#!/bin/sh line 1\n"));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o754);
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_megabyte_on_one_line_opens_in_read_only_pieces() {
+        let path = std::env::temp_dir().join(format!("tuxpad-{}-one-line.json", std::process::id()));
+        // Two-byte characters throughout, so pieces must not cut one in half
+        let text = "abcdefghé".repeat(110_000);
+        assert!(text.len() > 1 << 20);
+        fs::write(&path, &text).unwrap();
+
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        assert!(editor.read_only);
+        assert!(editor.status_message.contains("shown in 4096-byte pieces"));
+        editor.jump_to_end_of_file().unwrap();
+        assert!(editor.buffer.total_lines >= text.len().div_ceil(LONG_LINE_SEGMENT));
+        assert!(editor.buffer.lines.iter().all(|line| line.len() <= LONG_LINE_SEGMENT));
+        assert_eq!(editor.buffer.lines.iter().map(|line| line.as_str()).collect::<String>(), text);
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transposing_fixes_teh_and_undoes() {
+        let mut editor = editor_with(&["teh", "日本語"]);
+        type_keys(&mut editor, "i");
+        editor.cursor.x = 3;
+        press(&mut editor, KeyCode::Char('t'), KeyModifiers::CONTROL);
+        assert_eq!(lines(&editor), ["the", "日本語"]);
+        assert_eq!(editor.cursor.x, 3);
+
+        editor.cursor = Cursor { x: 2, y: 1 };
+        press(&mut editor, KeyCode::Char('t'), KeyModifiers::CONTROL);
+        assert_eq!(lines(&editor), ["the", "本日語"]);
+        editor.cursor.x = 0;
+        press(&mut editor, KeyCode::Char('t'), KeyModifiers::CONTROL);
+        assert_eq!(editor.status_message, "Nothing to transpose");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        // Each swap is its own undo step
+        type_keys(&mut editor, "u");
+        assert_eq!(lines(&editor), ["the", "日本語"]);
+        type_keys(&mut editor, "u");
+        assert_eq!(lines(&editor), ["teh", "日本語"]);
+
+        let mut editor = editor_with(&["hello world again"]);
+        press(&mut editor, KeyCode::Char('t'), KeyModifiers::ALT);
+        assert_eq!(lines(&editor), ["world hello again"]);
+        press(&mut editor, KeyCode::Char('t'), KeyModifiers::ALT);
+        assert_eq!(lines(&editor), ["world again hello"]);
+        type_keys(&mut editor, "u");
+        assert_eq!(lines(&editor), ["world hello again"]);
+    }
+
+    #[test]
+    fn saving_a_file_deleted_on_disk_asks_before_recreating_it() {
+        let path = numbered_file("deleted.txt", 3);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        edit_line(&mut editor, 0, "edited ");
+        fs::remove_file(&path).unwrap();
+        editor.poll_deleted_file();
+        assert_eq!(editor.status_message, "Warning: the file was deleted on disk");
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 10)).unwrap();
+        terminal.draw(|frame| editor.render(frame).unwrap()).unwrap();
+        assert!(screen_row(terminal.backend().buffer(), 0).contains("[deleted]"));
+
+        run_command(&mut editor, "w");
+        assert!(editor.status_message.contains("Recreate it?"));
+        type_keys(&mut editor, "n");
+        assert_eq!(editor.status_message, "Save aborted: the file stays deleted");
+        assert!(!path.exists());
+
+        run_command(&mut editor, "w");
+        type_keys(&mut editor, "y");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "edited line 1\nline 2\nline 3\n");
+        assert!(!editor.deleted && !editor.modified);
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn winwidth_wraps_text_at_the_set_width() {
+        let mut editor = editor_with(&["abcdefghijklmnopqrstuvwxy"]);
+        run_command(&mut editor, "set nonumber wrap winwidth=10");
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(40, 8)).unwrap();
+        terminal.draw(|frame| editor.render(frame).unwrap()).unwrap();
+        let rows: Vec<String> = (1..4).map(|y| screen_row(terminal.backend().buffer(), y)).collect();
+        // Centred: 15 columns of margin either side
+        assert_eq!(rows, [
+            format!("{:15}abcdefghij{:15}", "", ""),
+            format!("{:15}klmnopqrst{:15}", "", ""),
+            format!("{:15}uvwxy{:20}", "", ""),
+        ]);
+
+        // Never wider than the terminal
+        run_command(&mut editor, "set winwidth=100");
+        terminal.draw(|frame| editor.render(frame).unwrap()).unwrap();
+        assert_eq!(screen_row(terminal.backend().buffer(), 1).trim_end(), "abcdefghijklmnopqrstuvwxy");
+    }
+
+    #[test]
+    fn crlf_files_save_with_their_line_endings() {
+        let path = std::env::temp_dir().join(format!("tuxpad-{}-dos.txt", std::process::id()));
+        fs::write(&path, "one\r\ntwo\r\nthree\r\n").unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        assert_eq!(lines(&editor), ["one", "two", "three"]);
+        edit_line(&mut editor, 1, "2 ");
+        run_command(&mut editor, "w");
+        assert_eq!(fs::read(&path).unwrap(), b"one\r\n2 two\r\nthree\r\n");
+
+        // Saving unchanged text gives back the same bytes
+        run_command(&mut editor, "w!");
+        assert_eq!(fs::read(&path).unwrap(), b"one\r\n2 two\r\nthree\r\n");
+        editor.close_file();
+
+        // A missing final line ending stays missing
+        fs::write(&path, "one\r\ntwo").unwrap();
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        run_command(&mut editor, "w!");
+        assert_eq!(fs::read(&path).unwrap(), b"one\r\ntwo");
+
+        run_command(&mut editor, "set ff=unix");
+        run_command(&mut editor, "w");
+        assert_eq!(fs::read(&path).unwrap(), b"one\ntwo");
+        editor.close_file();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_burst_of_keys_is_typed_in_full() {
+        let burst: String = (0..500).map(|n| if n % 7 == 6 { ' ' } else { (b'a' + (n % 26) as u8) as char }).collect();
+        let mut editor = editor_with(&[""]);
+        type_keys(&mut editor, "i");
+        type_keys(&mut editor, &burst);
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), [burst]);
+    }
+
+    #[test]
+    fn join_then_split_round_trips_and_undoes() {
+        let mut editor = editor_with(&["alpha", "beta", "gamma", "delta"]);
+        run_command(&mut editor, "1,3join ,");
+        assert_eq!(lines(&editor), ["alpha,beta,gamma", "delta"]);
+
+        type_keys(&mut editor, "gS,");
+        assert_eq!(lines(&editor), ["alpha", "beta", "gamma", "delta"]);
+        assert_eq!(editor.status_message, "Split into 3 lines");
+
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), ["alpha,beta,gamma", "delta"]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), ["alpha", "beta", "gamma", "delta"]);
+        assert!(!editor.modified);
+    }
+
+    #[test]
+    fn typing_undoes_a_word_at_a_time_by_default() {
+        let mut editor = editor_with(&[""]);
+        type_keys(&mut editor, "ihello world");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["hello world"]);
+
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), ["hello "]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), [""]);
+        editor.redo().unwrap();
+        assert_eq!(lines(&editor), ["hello "]);
+    }
+
+    #[test]
+    fn substitute_expands_numbered_and_named_groups() {
+        let mut editor = editor_with(&["key=value", "a=b", "cost 5"]);
+        run_command(&mut editor, r"1s/(\w+)=(\w+)/$2=$1/");
+        run_command(&mut editor, r"2s/(\w+)=(\w+)/\2:\1/");
+        run_command(&mut editor, r"3s/(?P<word>\w+) (?P<n>\d+)/${n} \$${word}/");
+        assert_eq!(lines(&editor), ["value=key", "b:a", "5 $cost"]);
+
+        assert_eq!(Editor::translate_replacement(r"\1-\2 \$ $3"), "${1}-${2} $$ $3");
+        let regex = Regex::new(r"(?P<key>\w+)=(\w+)").unwrap();
+        assert_eq!(Editor::validate_replacement(&regex, "$2=$1 ${key} $$5"), Ok(()));
+        assert_eq!(
+            Editor::validate_replacement(&regex, &Editor::translate_replacement(r"\3")),
+            Err("Replacement refers to group 3 but the pattern has 2".to_string())
+        );
+        assert_eq!(
+            Editor::validate_replacement(&regex, "${value}"),
+            Err("Replacement refers to unknown group 'value'".to_string())
+        );
+
+        run_command(&mut editor, r"1s/(\w+)=(\w+)/$3/");
+        assert_eq!(editor.status_message, "Replacement refers to group 3 but the pattern has 2");
+        assert_eq!(lines(&editor)[0], "value=key");
+    }
+
+    #[test]
+    fn block_replace_and_substitute_stay_inside_the_block() {
+        let grid = ["abcdef", "gh", "ijklmn", "opqrst"];
+        let select_block = |editor: &mut Editor| {
+            editor.cursor = Cursor { x: 1, y: 0 };
+            press(editor, KeyCode::Char('v'), KeyModifiers::ALT);
+            for _ in 0..3 {
+                press(editor, KeyCode::Down, KeyModifiers::NONE);
+            }
+            editor.cursor.x = 3;
+        };
+
+        let mut editor = editor_with(&grid);
+        select_block(&mut editor);
+        type_keys(&mut editor, "r#");
+        assert_eq!(lines(&editor), ["a###ef", "g#", "i###mn", "o###st"]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), grid);
+
+        select_block(&mut editor);
+        type_keys(&mut editor, ":s/[a-z]/X/g");
+        press(&mut editor, KeyCode::Enter, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["aXXXef", "gX", "iXXXmn", "oXXXst"]);
+        editor.undo().unwrap();
+        assert_eq!(lines(&editor), grid);
+    }
+
+    #[test]
+    fn textwidth_breaks_typed_lines_without_wrap() {
+        let mut editor = editor_with(&[""]);
+        run_command(&mut editor, "set textwidth=20 nowrap nonumber");
+        type_keys(&mut editor, "ithe quick brown fox jumps over the lazy dog");
+        press(&mut editor, KeyCode::Esc, KeyModifiers::NONE);
+        assert_eq!(lines(&editor), ["the quick brown fox", "jumps over the lazy", "dog"]);
+
+        // On a screen narrower than textwidth the lines are cut off, not folded
+        editor.cursor = Cursor { x: 0, y: 0 };
+        editor.offset_x = 0;
+        let screen = render_pane(&editor, 12, 3);
+        let rows: Vec<String> = (0..3).map(|y| screen_row(&screen, y)).collect();
+        assert_eq!(rows, ["the quick br", "jumps over t", "dog         "]);
+    }
+
+    #[test]
+    fn editorconfig_sections_set_the_indentation_per_file() {
+        let dir = std::env::temp_dir().join(format!("tuxpad-{}-editorconfig", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = "root = true\n\n[*]\nindent_style = space\nindent_size = 4\n\n[*.go]\nindent_style = tab\ntab_width = 8\n";
+        fs::write(dir.join(".editorconfig"), config).unwrap();
+        let (text, go) = (dir.join("notes.txt"), dir.join("main.go"));
+        fs::write(&text, "notes\n").unwrap();
+        fs::write(&go, "package main\n").unwrap();
+
+        let mut editor = Editor::new();
+        run_command(&mut editor, "set tabstop=2 noexpandtab");
+        editor.load_file(&text).unwrap();
+        assert_eq!((editor.tabstop, editor.expandtab), (4, true));
+        editor.close_file();
+        editor.load_file(&go).unwrap();
+        assert_eq!((editor.tabstop, editor.expandtab), (8, false));
+        editor.close_file();
+
+        // A file outside the directory gets the `:set` values back
+        let outside = numbered_file("outside-editorconfig.txt", 1);
+        editor.load_file(&outside).unwrap();
+        assert_eq!((editor.tabstop, editor.expandtab), (2, false));
+        editor.close_file();
+        fs::remove_file(&outside).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn numfmt_covers_lines_outside_the_loaded_chunk() {
+        let path = numbered_file("numfmt", 3000);
+        let mut editor = Editor::new();
+        editor.load_file(&path).unwrap();
+        run_command(&mut editor, "1,3000numfmt round=1");
+        assert_eq!(editor.status_message, "Reformatted 3000 number(s) on 3000 line(s)");
+        assert_eq!(editor.cursor.y, 0);
+        editor.save_file(false).unwrap();
+
+        editor.close_file();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.lines().count(), 3000);
+        assert!(saved.lines().enumerate().all(|(y, line)| line == format!("line {}.0", y + 1)));
+    }
+
+    // Peak resident memory of this process so far, from /proc
+    fn peak_rss_kib() -> usize {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    // A benchmark rather than a test: `cargo test --release -- --ignored --nocapture
+    // line_index` writes a 1 GiB file, indexes it and loads chunks from its middle and end
+    #[test]
+    #[ignore]
+    fn line_index_memory_stays_flat_on_a_1gb_file() {
+        let path = std::env::temp_dir().join(format!("tuxpad-{}-1gb", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&path).unwrap());
+        let (mut written, mut n) = (0, 0);
+        while written < 1 << 30 {
+            let line = format!("{:>10} the quick brown fox jumps over the lazy dog and keeps on running\n", n);
+            writer.write_all(line.as_bytes()).unwrap();
+            written += line.len();
+            n += 1;
+        }
+        drop(writer);
+
+        let before = peak_rss_kib();
+        let started = Instant::now();
+        let mut buffer = LineBuffer::new(MAX_VISIBLE_LINES);
+        buffer.load_chunk(&path, 0).unwrap();
+        let indexed = started.elapsed();
+        let total = buffer.total_lines;
+        let started = Instant::now();
+        buffer.load_chunk(&path, total / 2).unwrap();
+        buffer.load_chunk(&path, total - MAX_VISIBLE_LINES).unwrap();
+        let loads = started.elapsed();
+        let growth = peak_rss_kib() - before;
+        fs::remove_file(&path).unwrap();
+
+        println!(
+            "{} lines: first load with indexing {:?}, two chunk loads {:?}, peak RSS grew {} KiB",
+            total, indexed, loads, growth
+        );
+        assert_eq!(total, n);
+        assert!(buffer.lines.back().is_some_and(|line| line.trim_start().starts_with(&format!("{} ", n - 1))));
+        assert!(growth < 64 * 1024);
+    }
+}